@@ -1013,6 +1013,68 @@ mod windows {
     }
 }
 
+/// Builds llama.cpp as static libraries linked into the crate, for targets without dynamic
+/// loading. `LLAMA_CPP_LIB_DIR` points to prebuilt ones instead, e.g. from an emscripten build.
+mod static_libs {
+    use std::{env, path::PathBuf};
+
+    pub fn link() {
+        let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+        println!("cargo:rerun-if-env-changed=LLAMA_CPP_LIB_DIR");
+        let dirs = match env::var("LLAMA_CPP_LIB_DIR") {
+            Ok(dir) => vec![PathBuf::from(dir)],
+            Err(_) => build(&target_os),
+        };
+        for dir in dirs {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+        }
+        // dependents first
+        for lib in ["llava_static", "llama", "ggml"] {
+            println!("cargo:rustc-link-lib=static={lib}");
+        }
+        let family = env::var("CARGO_CFG_TARGET_FAMILY").unwrap_or_default();
+        match target_os.as_str() {
+            "windows" => {}
+            "macos" | "ios" => println!("cargo:rustc-link-lib=c++"),
+            "android" => println!("cargo:rustc-link-lib=c++_shared"),
+            _ if family.split(',').any(|f| f == "wasm") => {
+                println!("cargo:rustc-link-lib=c++");
+                println!("cargo:rustc-link-lib=c++abi");
+            }
+            _ => println!("cargo:rustc-link-lib=stdc++"),
+        }
+    }
+
+    fn build(target_os: &str) -> Vec<PathBuf> {
+        println!("cargo:warning=building static llama.cpp for {target_os}");
+        // cpu only, without dependencies on system libraries
+        let dst = cmake::Config::new("llama.cpp")
+            .profile("Release")
+            .define("BUILD_SHARED_LIBS", "off")
+            .define("LLAMA_BUILD_TESTS", "off")
+            .define("LLAMA_BUILD_SERVER", "off")
+            .define("LLAMA_CURL", "off")
+            .define("GGML_OPENMP", "off")
+            .define("GGML_METAL", "off")
+            .define("GGML_CUDA", "off")
+            .define("GGML_BLAS", "off")
+            .define("GGML_ACCELERATE", "off")
+            // depends on llama and ggml, which are built along
+            .build_target("llava_static")
+            .very_verbose(true)
+            .build();
+        let build_dir = dst.join("build");
+        ["src", "ggml/src", "examples/llava"]
+            .iter()
+            .flat_map(|d| {
+                let dir = build_dir.join(d);
+                // multi-config generators like visual studio's put the libraries in a sub-directory
+                [dir.join("Release"), dir]
+            })
+            .collect()
+    }
+}
+
 fn main() {
     if !Path::new("llama.cpp/ggml/src/ggml.c").exists() {
         panic!("llama.cpp seems to not be populated, try running `git submodule update --init --recursive` to init.")
@@ -1047,6 +1109,8 @@ fn main() {
         .allowlist_type("llama_token_attr")
         .allowlist_type("llama_model")
         .allowlist_type("llama_model_params")
+        .allowlist_type("llama_model_quantize_params")
+        .allowlist_type("llama_ftype")
         .allowlist_type("clip_ctx")
         .allowlist_type("llama_grammar_element")
         .allowlist_type("llama_logit_bias")
@@ -1069,6 +1133,17 @@ fn main() {
         .write_to_file(out_path.join("bindings.rs"))
         .expect("failed to write bindings to file");
 
+    println!("cargo:rustc-check-cfg=cfg(static_libs)");
+    let wasm = env::var("CARGO_CFG_TARGET_FAMILY")
+        .unwrap_or_default()
+        .split(',')
+        .any(|f| f == "wasm");
+    if wasm || env::var("CARGO_FEATURE_STATIC").is_ok() {
+        println!("cargo:rustc-cfg=static_libs");
+        static_libs::link();
+        return;
+    }
+
     #[cfg(feature = "build")]
     #[cfg(target_os = "macos")]
     macos::bbuild();
@@ -46,9 +46,55 @@ pub fn get_mem() -> crate::Result<crate::MemInfo> {
     Ok(crate::MemInfo { total: fm, free: 0 })
 }
 
-#[cfg(target_os = "linux")]
+#[cfg(target_os = "ios")]
+pub fn get_mem() -> crate::Result<crate::MemInfo> {
+    extern "C" {
+        // memory the app can still allocate before it is terminated, ios 13 and newer
+        fn os_proc_available_memory() -> usize;
+    }
+    let total = unsafe { objc2_foundation::NSProcessInfo::processInfo().physicalMemory() };
+    let free = unsafe { os_proc_available_memory() } as u64;
+    Ok(crate::MemInfo { total, free })
+}
+
+/// Memory figures of Android's `ActivityManager.MemoryInfo`, in bytes.
+#[cfg(target_os = "android")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AndroidMemoryInfo {
+    /// `totalMem`
+    pub total: u64,
+    /// `availMem`
+    pub available: u64,
+    /// `threshold`, below which the system kills background processes.
+    pub threshold: u64,
+}
+
+#[cfg(target_os = "android")]
+lazy_static::lazy_static! {
+    static ref ANDROID_MEMORY_INFO: std::sync::RwLock<Option<AndroidMemoryInfo>> =
+        std::sync::RwLock::new(None);
+}
+
+#[cfg(target_os = "android")]
+pub fn set_memory_info(info: Option<AndroidMemoryInfo>) {
+    *ANDROID_MEMORY_INFO
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = info;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn get_mem() -> crate::Result<crate::MemInfo> {
     use procfs::Current;
+    #[cfg(target_os = "android")]
+    if let Some(info) = *ANDROID_MEMORY_INFO
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+    {
+        return Ok(crate::MemInfo {
+            total: info.total,
+            free: info.available.saturating_sub(info.threshold),
+        });
+    }
     let meminfo = procfs::Meminfo::current()?;
     if let (mt, Some(ma)) = (meminfo.mem_total, meminfo.mem_available) {
         if mt > 0 && ma > 0 {
@@ -63,3 +109,150 @@ pub fn get_mem() -> crate::Result<crate::MemInfo> {
         free: meminfo.mem_free + meminfo.buffers + meminfo.cached,
     })
 }
+
+#[cfg(not(any(
+    target_os = "windows",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "android"
+)))]
+pub fn get_mem() -> crate::Result<crate::MemInfo> {
+    Err(crate::Error::Unimplemented(file!(), line!()))
+}
+
+/// Core counts of the cpu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// Hardware threads.
+    pub logical: usize,
+    /// Physical cores, hyperthreads not counted.
+    pub physical: usize,
+    /// Physical cores of the fastest kind, fewer than `physical` on hybrid (big.LITTLE) cpus.
+    pub performance: usize,
+}
+
+fn logical_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Parses a cpu list like `0-3,8,10-11`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_cpu_list(list: &str) -> Option<std::collections::HashSet<u64>> {
+    let mut cpus = std::collections::HashSet::new();
+    for range in list.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (first, last): (u64, u64) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => (range.parse().ok()?, range.parse().ok()?),
+        };
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Cpus the process may run on, the `sched_getaffinity` mask as the kernel reports it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn allowed_cpus() -> Option<std::collections::HashSet<u64>> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let list = status
+        .lines()
+        .find_map(|l| l.strip_prefix("Cpus_allowed_list:"))?;
+    parse_cpu_list(list)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_topology() -> CpuTopology {
+    use std::collections::HashSet;
+
+    // available_parallelism honors the affinity mask and cgroup quotas, cores outside the
+    // mask are skipped below so a pinned process doesn't count cores it can't use
+    let logical = logical_cpus();
+    let allowed = allowed_cpus();
+    let read = |path: std::path::PathBuf| -> Option<u64> {
+        std::fs::read_to_string(path).ok()?.trim().parse().ok()
+    };
+    // (package, core) of every cpu and its maximum frequency
+    let mut cores = vec![];
+    if let Ok(entries) = std::fs::read_dir("/sys/devices/system/cpu") {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(cpu) = name
+                .to_string_lossy()
+                .strip_prefix("cpu")
+                .and_then(|n| n.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            if allowed.as_ref().is_some_and(|a| !a.contains(&cpu)) {
+                continue;
+            }
+            let dir = entry.path();
+            let (Some(package), Some(core)) = (
+                read(dir.join("topology/physical_package_id")),
+                read(dir.join("topology/core_id")),
+            ) else {
+                continue;
+            };
+            cores.push(((package, core), read(dir.join("cpufreq/cpuinfo_max_freq"))));
+        }
+    }
+    if cores.is_empty() {
+        return CpuTopology {
+            logical,
+            physical: logical,
+            performance: logical,
+        };
+    }
+    let physical = cores
+        .iter()
+        .map(|(c, _)| c)
+        .collect::<HashSet<_>>()
+        .len()
+        .min(logical);
+    // boost clocks differ slightly between cores of the same kind, efficiency cores are
+    // considerably slower
+    let max_freq = cores.iter().filter_map(|(_, f)| *f).max().unwrap_or(0);
+    let performance = cores
+        .iter()
+        .filter(|(_, f)| f.map_or(true, |f| f * 100 >= max_freq * 85))
+        .map(|(c, _)| c)
+        .collect::<HashSet<_>>()
+        .len()
+        .min(physical);
+    CpuTopology {
+        logical,
+        physical,
+        performance,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_topology() -> CpuTopology {
+    // no cheap way to tell hyperthreads apart, llama.cpp's own default does the same
+    let logical = logical_cpus();
+    CpuTopology {
+        logical,
+        physical: logical,
+        performance: logical,
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod test {
+    #[test]
+    fn parse_cpu_list_test() {
+        let cpus = super::parse_cpu_list("0-3,8,10-11\n").unwrap();
+        let mut cpus = cpus.into_iter().collect::<Vec<_>>();
+        cpus.sort();
+        assert_eq!(cpus, [0, 1, 2, 3, 8, 10, 11]);
+        assert!(super::parse_cpu_list("0-x").is_none());
+    }
+
+    #[test]
+    fn topology_test() {
+        let topology = super::get_topology();
+        assert!(topology.performance <= topology.physical);
+        assert!(topology.physical <= topology.logical);
+        assert!(topology.performance > 0);
+    }
+}
@@ -302,6 +302,22 @@ impl CudartHandle {
         }
     }
 
+    /// Newest cuda version the installed driver supports.
+    pub fn driver_version(&self) -> crate::Result<crate::DriverVersion> {
+        let cuda_driver_get_version: libloading::Symbol<unsafe extern "C" fn(*mut c_int) -> c_int> =
+            unsafe { self.handler.get(b"cudaDriverGetVersion").unwrap() };
+        let mut version = 0;
+        let res = unsafe { cuda_driver_get_version(&mut version) };
+        if res != 0 {
+            Err(crate::Error::CudartCall("cudaDriverGetVersion", res))
+        } else {
+            Ok(crate::DriverVersion {
+                major: version / 1000,
+                minor: version % 1000 / 10,
+            })
+        }
+    }
+
     pub fn bootstrap(&self, device: usize) -> crate::Result<crate::DeviceInfo> {
         self.set_device(device)?;
         let props = self.get_device_properties(device)?;
@@ -309,6 +325,12 @@ impl CudartHandle {
         let (mem_free, mem_total) = self.get_mem_info()?;
         props.memInfo.free = mem_free as u64;
         props.memInfo.total = mem_total as u64;
+        // an unknown version passes the requirement checks, a variant the driver can't run
+        // then fails to load on its own instead of hiding the device from every variant
+        props.driver_version = self.driver_version().unwrap_or_else(|e| {
+            log::warn!("{e}");
+            crate::DriverVersion::default()
+        });
         Ok(props)
     }
 
@@ -2,6 +2,21 @@ pub mod cudart;
 pub mod nvcuda;
 pub mod nvml;
 
+/// Release of the installed nvidia driver, e.g. `550.54.14`.
+pub fn driver_release(nvml: Option<&nvml::NvMlHandle>) -> Option<String> {
+    // NVRM version: NVIDIA UNIX x86_64 Kernel Module  550.54.14  Thu Feb 22 01:44:30 UTC 2024
+    #[cfg(target_os = "linux")]
+    if let Ok(version) = std::fs::read_to_string("/proc/driver/nvidia/version") {
+        return version
+            .lines()
+            .next()?
+            .split_whitespace()
+            .find(|w| w.contains('.') && w.chars().all(|c| c.is_ascii_digit() || c == '.'))
+            .map(str::to_string);
+    }
+    nvml?.driver_release()
+}
+
 pub fn find_libs(name: &str, patterns: &[&str]) -> Vec<std::path::PathBuf> {
     log::debug!("Searching for GPU library {}", name);
     #[cfg(target_os = "windows")]
@@ -1,4 +1,4 @@
-use std::ffi::{c_int, c_ulonglong, c_void};
+use std::ffi::{c_char, c_int, c_uint, c_ulonglong, c_void};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -24,6 +24,19 @@ const NVML_MGMT_NAME: &'static str = "nvml.dll";
 const NVML_MGMT_NAME: &'static str = "";
 
 impl NvMlHandle {
+    /// `nvmlSystemGetDriverVersion`, the release of the installed driver.
+    pub fn driver_release(&self) -> Option<String> {
+        let get_driver_version: libloading::Symbol<
+            unsafe extern "C" fn(*mut c_char, c_uint) -> c_int,
+        > = unsafe { self._handler.get(b"nvmlSystemGetDriverVersion").ok()? };
+        let mut buf = [0 as c_char; 80];
+        if unsafe { get_driver_version(buf.as_mut_ptr(), buf.len() as c_uint) } != 0 {
+            return None;
+        }
+        let release = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+        Some(release.to_string_lossy().into_owned())
+    }
+
     pub fn new() -> crate::Result<Self> {
         let pp = super::find_libs(NVML_MGMT_NAME, NVML_GLOBS);
         for p in pp.iter() {
@@ -4,16 +4,32 @@
 
 use std::fmt::Debug;
 
+#[macro_use]
+mod symbols;
+
 mod cpu;
 #[cfg(any(target_os = "windows", target_os = "linux"))]
 mod cuda;
+pub mod path;
+#[cfg(target_os = "android")]
+mod vulkan;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct MemInfo {
     total: u64,
     free: u64,
 }
 
+impl MemInfo {
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn free(&self) -> u64 {
+        self.free
+    }
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub enum CPUCapability {
     None,
@@ -61,9 +77,12 @@ pub struct DeviceInfo {
     pub name: String,
     pub compute: String,
     pub driver_version: DriverVersion,
+    /// Release of the installed gpu driver, e.g. `550.54.14`, empty if unknown.
+    pub driver_release: String,
 }
 
 impl DeviceInfo {
+    #[cfg(not(static_libs))]
     pub(crate) fn variants(&self, vars: &Vec<Variant>) -> Vec<Variant> {
         vars.iter()
             .filter(|v| {
@@ -78,12 +97,114 @@ impl DeviceInfo {
     }
 }
 
-#[derive(Default, Debug)]
+/// Newest cuda version a gpu driver supports.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DriverVersion {
     pub major: i32,
     pub minor: i32,
 }
 
+impl std::fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Minimum driver and compute capability of a gpu library variant.
+struct Requirements {
+    variant: &'static str,
+    cuda: DriverVersion,
+    // first nvidia driver release supporting `cuda`
+    driver: u32,
+    compute: DriverVersion,
+}
+
+const REQUIREMENTS: &[Requirements] = &[
+    Requirements {
+        variant: "cuda_v11",
+        cuda: DriverVersion {
+            major: 11,
+            minor: 3,
+        },
+        driver: 465,
+        compute: DriverVersion { major: 5, minor: 0 },
+    },
+    Requirements {
+        variant: "cuda_v12",
+        cuda: DriverVersion {
+            major: 12,
+            minor: 0,
+        },
+        driver: 525,
+        compute: DriverVersion { major: 5, minor: 0 },
+    },
+];
+
+/// A device the libraries of a variant can't run on.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RequirementError {
+    #[error(
+        "{variant} requires driver {driver} or newer (cuda {required}), found {} for cuda {found}",
+        found_driver(.found_release)
+    )]
+    Driver {
+        variant: String,
+        required: DriverVersion,
+        driver: u32,
+        found: DriverVersion,
+        /// Release of the installed driver, empty if unknown.
+        found_release: String,
+    },
+    #[error("{variant} requires compute capability {required} or newer, {device} has {found}")]
+    Compute {
+        variant: String,
+        device: String,
+        required: DriverVersion,
+        found: DriverVersion,
+    },
+}
+
+fn found_driver(release: &str) -> String {
+    if release.is_empty() {
+        "a driver".to_string()
+    } else {
+        format!("driver {release}")
+    }
+}
+
+impl DeviceInfo {
+    /// Checks the driver and compute capability of the device against the needs of `variant`.
+    ///
+    /// Unknown versions and variants without requirements, e.g. the cpu ones, pass.
+    pub fn check_requirements(&self, variant: &str) -> std::result::Result<(), RequirementError> {
+        let Some(req) = REQUIREMENTS.iter().find(|r| r.variant == variant) else {
+            return Ok(());
+        };
+        if self.driver_version != DriverVersion::default() && self.driver_version < req.cuda {
+            return Err(RequirementError::Driver {
+                variant: variant.to_string(),
+                required: req.cuda,
+                driver: req.driver,
+                found: self.driver_version,
+                found_release: self.driver_release.clone(),
+            });
+        }
+        let mut compute = self.compute.split('.').map(|v| v.parse::<i32>());
+        if let (Some(Ok(major)), Some(Ok(minor))) = (compute.next(), compute.next()) {
+            let found = DriverVersion { major, minor };
+            if found < req.compute {
+                return Err(RequirementError::Compute {
+                    variant: variant.to_string(),
+                    device: self.name.clone(),
+                    required: req.compute,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(any(target_os = "windows", target_os = "linux"))]
 struct CudaHandles {
     device_count: usize,
@@ -153,6 +274,10 @@ impl CudaHandles {
                 }
             };
         }
+        let release = cuda::driver_release(self._nvml.as_ref()).unwrap_or_default();
+        for device in &mut res {
+            device.driver_release = release.clone();
+        }
         res
     }
 }
@@ -182,10 +307,10 @@ impl CpuHandlers {
     }
 }
 
-#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+#[cfg(any(all(target_os = "macos", target_arch = "aarch64"), target_os = "ios"))]
 struct MetalHandlers {}
 
-#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+#[cfg(any(all(target_os = "macos", target_arch = "aarch64"), target_os = "ios"))]
 impl MetalHandlers {
     pub fn new() -> Result<Self> {
         use objc2_metal::MTLDevice;
@@ -205,23 +330,78 @@ impl MetalHandlers {
         gpu.library = "metal";
         gpu.id = "0".to_string();
         gpu.minimum_memory = 512 * 1024 * 1024;
-        let mm = unsafe {
-            iron_oxide::MTLCreateSystemDefaultDevice().get_recommended_max_working_set_size()
-        };
-        gpu.memInfo = MemInfo {
-            total: mm,
-            free: mm,
-        };
+        #[cfg(target_os = "macos")]
+        {
+            let mm = unsafe {
+                iron_oxide::MTLCreateSystemDefaultDevice().get_recommended_max_working_set_size()
+            };
+            gpu.memInfo = MemInfo {
+                total: mm,
+                free: mm,
+            };
+        }
+        // unified memory, the gpu gets what the app may still allocate
+        #[cfg(target_os = "ios")]
+        {
+            gpu.memInfo = CpuHandlers::get_mem();
+        }
         vec![gpu]
     }
 }
 
+#[cfg(target_os = "android")]
+struct VulkanHandlers {
+    handle: vulkan::VulkanHandle,
+}
+
+#[cfg(target_os = "android")]
+impl VulkanHandlers {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            handle: vulkan::VulkanHandle::new()?,
+        })
+    }
+
+    pub fn get_devices_info(&self) -> Vec<DeviceInfo> {
+        let system = CpuHandlers::get_mem();
+        self.handle
+            .devices
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let mut gpu = DeviceInfo::default();
+                gpu.library = "vulkan";
+                gpu.id = i.to_string();
+                gpu.name = d.name.clone();
+                gpu.compute = d.api_version.clone();
+                gpu.minimum_memory = 256 * 1024 * 1024;
+                // integrated gpus report their heap as about all of the system memory, the
+                // part still available to the app is what counts
+                gpu.memInfo = if d.integrated && system.total > 0 {
+                    MemInfo {
+                        total: d.memory.min(system.total),
+                        free: system.free,
+                    }
+                } else {
+                    MemInfo {
+                        total: d.memory,
+                        free: d.memory,
+                    }
+                };
+                gpu
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(static_libs))]
 #[derive(Debug, Clone)]
 struct Variant {
     pub library: String,
     pub variant: String,
 }
 
+#[cfg(not(static_libs))]
 impl std::fmt::Display for Variant {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -241,26 +421,33 @@ enum Handlers {
     Cpu(CpuHandlers),
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     Cuda(CudaHandles),
-    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+    #[cfg(any(all(target_os = "macos", target_arch = "aarch64"), target_os = "ios"))]
     Metal(MetalHandlers),
+    #[cfg(target_os = "android")]
+    Vulkan(VulkanHandlers),
 }
 
 impl Handlers {
     pub fn new() -> Result<Self> {
-        #[cfg(target_os = "macos")]
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
         {
-            #[cfg(target_arch = "aarch64")]
+            #[cfg(any(target_arch = "aarch64", target_os = "ios"))]
             if let Ok(h) = MetalHandlers::new() {
                 return Ok(Self::Metal(h));
             }
             return Ok(Self::Cpu(CpuHandlers::new()?));
         }
-        #[cfg(not(target_os = "macos"))]
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
         {
             #[cfg(any(target_os = "windows", target_os = "linux"))]
             if let Ok(cuda) = CudaHandles::new() {
                 return Ok(Self::Cuda(cuda));
             }
+            #[cfg(target_os = "android")]
+            match VulkanHandlers::new() {
+                Ok(vulkan) => return Ok(Self::Vulkan(vulkan)),
+                Err(e) => log::debug!("no vulkan device: {e}"),
+            }
             Ok(Self::Cpu(CpuHandlers::new()?))
         }
     }
@@ -270,11 +457,14 @@ impl Handlers {
             Self::Cpu(h) => h.get_devices_info(),
             #[cfg(any(target_os = "windows", target_os = "linux"))]
             Self::Cuda(h) => h.get_devices_info(),
-            #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+            #[cfg(any(all(target_os = "macos", target_arch = "aarch64"), target_os = "ios"))]
             Self::Metal(h) => h.get_devices_info(),
+            #[cfg(target_os = "android")]
+            Self::Vulkan(h) => h.get_devices_info(),
         }
     }
 
+    #[cfg(not(static_libs))]
     pub fn available_variants(&self) -> Vec<Variant> {
         let p = DEPENDENCIES_BASE_PATH.display().to_string();
         //can be remove on closing https://github.com/rust-lang/glob/issues/132
@@ -310,18 +500,27 @@ impl Handlers {
         }
     }
 
+    /// Loads the libraries of the first variant that works, only those of `preference` in
+    /// its order if set.
+    #[cfg(not(static_libs))]
     pub fn llama_cpp(
         &self,
+        preference: Option<Vec<String>>,
     ) -> Result<(
         libloading::Library,
         libloading::Library,
         libloading::Library,
+        String,
     )> {
         let devices = self.get_devices_info();
         log::debug!("{devices:#?}");
         let variants = self.available_variants();
         log::debug!("{variants:#?}");
+        let preferred = preferred_variant();
         let mut errs = vec![];
+        // reported on its own when no variant got as far as loading its libraries
+        let mut requirement = None;
+        let mut tried = false;
         for device in devices {
             let mut vars = device.variants(&variants);
             vars.sort_by(|a, b| {
@@ -350,6 +549,26 @@ impl Handlers {
                 }
             });
             vars.reverse();
+            if let Some(preferred) = &preferred {
+                if let Some(i) = vars.iter().position(|v| v.to_string() == *preferred) {
+                    let v = vars.remove(i);
+                    vars.insert(0, v);
+                } else {
+                    log::warn!("preferred variant {preferred} is not available");
+                }
+            }
+            if let Some(preference) = &preference {
+                vars = preference
+                    .iter()
+                    .filter_map(|p| vars.iter().find(|v| v.to_string() == *p).cloned())
+                    .collect();
+                if vars.is_empty() {
+                    errs.push(format!(
+                        "none of the variants {preference:?} can run on {}",
+                        device.library
+                    ));
+                }
+            }
             log::debug!("{vars:#?}");
             #[cfg(target_os = "windows")]
             {
@@ -370,6 +589,13 @@ impl Handlers {
                 std::env::var("PATH").unwrap_or_default()
             );
             for v in vars {
+                if let Err(e) = device.check_requirements(&v.to_string()) {
+                    errs.push(e.to_string());
+                    log::warn!("{e}");
+                    requirement.get_or_insert(e);
+                    continue;
+                }
+                tried = true;
                 let mut bp = DEPENDENCIES_BASE_PATH.clone();
                 if v.variant.is_empty() {
                     bp.push(v.library.clone());
@@ -379,30 +605,33 @@ impl Handlers {
                 let mut ggml_p = bp.clone();
                 #[cfg(target_os = "windows")]
                 ggml_p.push("ggml.dll");
-                #[cfg(target_os = "macos")]
+                #[cfg(any(target_os = "macos", target_os = "ios"))]
                 ggml_p.push("libggml.dylib");
-                #[cfg(target_os = "linux")]
+                #[cfg(any(target_os = "linux", target_os = "android"))]
                 ggml_p.push("libggml.so");
                 let mut llama_p = bp.clone();
                 #[cfg(target_os = "windows")]
                 llama_p.push("llama.dll");
-                #[cfg(target_os = "macos")]
+                #[cfg(any(target_os = "macos", target_os = "ios"))]
                 llama_p.push("libllama.dylib");
-                #[cfg(target_os = "linux")]
+                #[cfg(any(target_os = "linux", target_os = "android"))]
                 llama_p.push("libllama.so");
                 let mut llava_p = bp.clone();
                 #[cfg(target_os = "windows")]
                 llava_p.push("llava_shared.dll");
-                #[cfg(target_os = "macos")]
+                #[cfg(any(target_os = "macos", target_os = "ios"))]
                 llava_p.push("libllava_shared.dylib");
-                #[cfg(target_os = "linux")]
+                #[cfg(any(target_os = "linux", target_os = "android"))]
                 llava_p.push("libllava_shared.so");
+                let ggml_p = path::normalize(ggml_p);
+                let llama_p = path::normalize(llama_p);
+                let llava_p = path::normalize(llava_p);
                 match unsafe { libloading::Library::new(ggml_p.clone()) } {
                     Ok(ggml) => match unsafe { libloading::Library::new(llama_p.clone()) } {
                         Ok(llama) => match unsafe { libloading::Library::new(llava_p.clone()) } {
                             Ok(llava) => {
                                 log::debug!("variant {v} loaded successfully");
-                                return Ok((llama, llava, ggml));
+                                return Ok((llama, llava, ggml, v.to_string()));
                             }
                             Err(e) => {
                                 errs.push(format!("can`t load {}: {}`", llava_p.display(), e));
@@ -424,7 +653,10 @@ impl Handlers {
                 }
             }
         }
-        Err(Error::DependenciesLoading(errs))
+        match requirement {
+            Some(e) if !tried => Err(Error::Requirement(e)),
+            _ => Err(Error::DependenciesLoading(errs)),
+        }
     }
 }
 
@@ -436,27 +668,128 @@ mod test {
         assert!(s.is_ok());
         let s = s.unwrap();
         assert!(s.len() > 0);
-        assert!(["cpu", "cuda", "rocm", "metal"].contains(s[0].library));
+        assert!(["cpu", "cuda", "rocm", "metal", "vulkan"].contains(s[0].library));
         if s[0].library != "cpu" {
             assert!(s[0].memInfo.total > 0);
             assert!(s[0].memInfo.free > 0);
         }
     }
+
+    #[test]
+    fn requirements() {
+        use super::{DeviceInfo, DriverVersion, RequirementError};
+        let gpu = DeviceInfo {
+            library: "cuda",
+            name: "old gpu".to_string(),
+            compute: "6.1".to_string(),
+            driver_version: DriverVersion {
+                major: 11,
+                minor: 4,
+            },
+            ..Default::default()
+        };
+        assert!(gpu.check_requirements("cuda_v11").is_ok());
+        assert!(gpu.check_requirements("cpu_avx2").is_ok());
+        let err = gpu.check_requirements("cuda_v12").unwrap_err();
+        assert!(matches!(err, RequirementError::Driver { driver: 525, .. }));
+        assert_eq!(
+            err.to_string(),
+            "cuda_v12 requires driver 525 or newer (cuda 12.0), found a driver for cuda 11.4"
+        );
+        let gpu = DeviceInfo {
+            driver_release: "470.256.02".to_string(),
+            ..gpu
+        };
+        assert_eq!(
+            gpu.check_requirements("cuda_v12").unwrap_err().to_string(),
+            "cuda_v12 requires driver 525 or newer (cuda 12.0), found driver 470.256.02 for cuda 11.4"
+        );
+        let gpu = DeviceInfo {
+            compute: "3.5".to_string(),
+            ..gpu
+        };
+        assert!(matches!(
+            gpu.check_requirements("cuda_v11"),
+            Err(RequirementError::Compute { .. })
+        ));
+    }
+}
+
+pub use cpu::CpuTopology;
+
+/// Core counts of this machine's cpu.
+pub fn cpu_topology() -> CpuTopology {
+    cpu::get_topology()
+}
+
+/// Total and available system memory.
+pub fn system_memory() -> Result<MemInfo> {
+    cpu::get_mem()
+}
+
+/// Uses the memory figures of Android's `ActivityManager.MemoryInfo` instead of `/proc`, which
+/// doesn't know how much the system lets an app allocate before it starts killing processes.
+///
+/// `threshold` is where that starts, it is not counted as available. `None` goes back to
+/// `/proc`.
+#[cfg(target_os = "android")]
+pub fn set_android_memory_info(info: Option<AndroidMemoryInfo>) {
+    cpu::set_memory_info(info);
+}
+
+#[cfg(target_os = "android")]
+pub use cpu::AndroidMemoryInfo;
+
+/// Devices found on this machine, the cpu if there is no supported gpu.
+pub fn devices() -> Result<Vec<DeviceInfo>> {
+    Ok(Handlers::new()?.get_devices_info())
+}
+
+/// Checks that the devices of this machine can run the libraries of `variant`, e.g. that the
+/// gpu driver is recent enough for `cuda_v12`.
+pub fn check_requirements(variant: &str) -> Result<()> {
+    for device in Handlers::new()?.get_devices_info() {
+        device.check_requirements(variant)?;
+    }
+    Ok(())
+}
+
+/// Library variants found in the dependencies directory, e.g. `cpu_avx2` or `cuda_v12`.
+///
+/// Only [`STATIC_VARIANT`] when llama.cpp is linked statically.
+pub fn available_variants() -> Result<Vec<String>> {
+    #[cfg(static_libs)]
+    return Ok(vec![STATIC_VARIANT.to_string()]);
+    #[cfg(not(static_libs))]
+    Ok(Handlers::new()?
+        .available_variants()
+        .iter()
+        .map(|v| v.to_string())
+        .collect())
 }
 
+/// The variant of a llama.cpp linked into the binary, cpu only.
+pub const STATIC_VARIANT: &str = "cpu_static";
+
+// fields are dropped in declaration order, dependents first
+#[cfg(not(static_libs))]
 struct LlamaCppLibs {
+    pub llava: libloading::Library,
     pub llama_cpp: libloading::Library,
     pub _ggml: libloading::Library,
-    pub llava: libloading::Library,
+    pub variant: String,
 }
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(all(not(static_libs), target_arch = "x86_64"))]
 const ARCH: &'static str = "x86_64";
-#[cfg(target_arch = "aarch64")]
+#[cfg(all(not(static_libs), target_arch = "aarch64"))]
 const ARCH: &'static str = "arm64";
+#[cfg(all(not(static_libs), target_arch = "arm"))]
+const ARCH: &'static str = "armv7";
 
 lazy_static::lazy_static! {
 
+    #[cfg(not(static_libs))]
     static ref DEPENDENCIES_BASE_PATH: std::path::PathBuf = {
         let mut tt = resource_path::get().unwrap();
         #[cfg(target_os = "windows")]
@@ -465,29 +798,157 @@ lazy_static::lazy_static! {
         tt.push("linux");
         #[cfg(target_os = "macos")]
         tt.push("darwin");
+        #[cfg(target_os = "ios")]
+        tt.push("ios");
+        #[cfg(target_os = "android")]
+        tt.push("android");
 
         tt.push(ARCH);
         log::debug!("tmp_dir = {}", tt.display());
         tt
     };
 
-    static ref LIBS: LlamaCppLibs = {
-        match Handlers::new(){
-            Ok(h) => {
-                match h.llama_cpp(){
-                    Ok(s) => LlamaCppLibs{
-                        llama_cpp: s.0,
-                        _ggml: s.2,
-                        llava: s.1
-                    },
-                    Err(e) => panic!("can`t load dependencies: {e}")
-                }
-            }
-            Err(e) => panic!("can`t load dependencies: {e}`")
+    #[cfg(not(static_libs))]
+    static ref LIBS: std::sync::RwLock<Option<std::sync::Arc<LlamaCppLibs>>> = std::sync::RwLock::new(None);
+
+    static ref PREFERRED_VARIANT: std::sync::RwLock<Option<String>> = std::sync::RwLock::new(None);
+
+    static ref VARIANT_PREFERENCE: std::sync::RwLock<Option<Vec<String>>> = std::sync::RwLock::new(None);
+}
+
+/// Loads the llama.cpp libraries for the best variant available on this machine.
+///
+/// Does nothing if they are loaded already. Calling any binding loads them on demand,
+/// this function only allows to get the loading error instead of a panic.
+/// Statically linked llama.cpp needs no loading.
+pub fn load() -> Result<()> {
+    #[cfg(static_libs)]
+    return Ok(());
+    #[cfg(not(static_libs))]
+    libs_or_load(variant_preference()).map(|_| ())
+}
+
+/// Like [`load`], but tries only `variants`, in this order, instead of the
+/// [`variant_preference`]. The preference is not stored, later loads use their own.
+pub fn load_with_preference(variants: &[String]) -> Result<()> {
+    // statically linked there is only one variant
+    #[cfg(static_libs)]
+    {
+        let _ = variants;
+        Ok(())
+    }
+    #[cfg(not(static_libs))]
+    libs_or_load(Some(variants.to_vec())).map(|_| ())
+}
+
+/// Unloads the llama.cpp libraries.
+///
+/// The caller has to make sure no llama.cpp object is alive anymore. The next binding call
+/// loads the libraries again.
+pub fn unload() {
+    #[cfg(not(static_libs))]
+    let mut libs = LIBS.write().unwrap_or_else(|e| e.into_inner());
+    #[cfg(not(static_libs))]
+    if libs.take().is_some() {
+        log::debug!("llama_cpp dependencies unloaded");
+    }
+}
+
+/// Tries `variant`, e.g. `cpu_avx` or `cuda_v12`, before all others on the next load.
+///
+/// `None` restores the default order, best cpu extension and newest gpu runtime first.
+/// Libraries that are loaded already are not affected, [`unload`] them first.
+pub fn set_preferred_variant(variant: Option<String>) {
+    *PREFERRED_VARIANT.write().unwrap_or_else(|e| e.into_inner()) = variant;
+}
+
+pub fn preferred_variant() -> Option<String> {
+    PREFERRED_VARIANT
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Environment variable with the variants to load, comma separated in order of preference,
+/// e.g. `cuda_v12,cpu_avx2`. See [`set_variant_preference`].
+pub const VARIANT_PREFERENCE_ENV: &str = "NEBULA_BACKEND_PREFERENCE";
+
+/// Tries only `variants`, in this order, on the next load, instead of all available ones
+/// sorted by the gpu first, newest runtime and best cpu extension.
+///
+/// `None` falls back to [`VARIANT_PREFERENCE_ENV`], then to the default order. Libraries that
+/// are loaded already are not affected, [`unload`] them first.
+pub fn set_variant_preference(variants: Option<Vec<String>>) {
+    *VARIANT_PREFERENCE
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = variants;
+}
+
+/// The variants set with [`set_variant_preference`], or those of [`VARIANT_PREFERENCE_ENV`].
+pub fn variant_preference() -> Option<Vec<String>> {
+    if let Some(variants) = &*VARIANT_PREFERENCE.read().unwrap_or_else(|e| e.into_inner()) {
+        return Some(variants.clone());
+    }
+    let env = std::env::var(VARIANT_PREFERENCE_ENV).ok()?;
+    let variants: Vec<String> = env
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect();
+    (!variants.is_empty()).then_some(variants)
+}
+
+/// The variant the libraries were loaded from, `None` if they are not loaded.
+pub fn loaded_variant() -> Option<String> {
+    #[cfg(static_libs)]
+    return Some(STATIC_VARIANT.to_string());
+    #[cfg(not(static_libs))]
+    LIBS.read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|l| l.variant.clone())
+}
+
+#[cfg(not(static_libs))]
+fn libs_or_load(preference: Option<Vec<String>>) -> Result<std::sync::Arc<LlamaCppLibs>> {
+    if let Some(libs) = &*LIBS.read().unwrap_or_else(|e| e.into_inner()) {
+        return Ok(libs.clone());
+    }
+    let mut libs = LIBS.write().unwrap_or_else(|e| e.into_inner());
+    match &*libs {
+        Some(l) => Ok(l.clone()),
+        None => {
+            let (llama_cpp, llava, ggml, variant) = Handlers::new()?.llama_cpp(preference)?;
+            let l = std::sync::Arc::new(LlamaCppLibs {
+                llava,
+                llama_cpp,
+                _ggml: ggml,
+                variant,
+            });
+            *libs = Some(l.clone());
+            Ok(l)
         }
+    }
+}
 
-        //unsafe {libloading::Library::new("libllamacpp.so")}.expect("can`t find lammacpp library")
-    };
+#[cfg(not(static_libs))]
+fn libs() -> std::sync::Arc<LlamaCppLibs> {
+    match libs_or_load(variant_preference()) {
+        Ok(l) => l,
+        Err(e) => panic!("can`t load dependencies: {e}"),
+    }
+}
+
+/// What failed, see [`Error::kind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The llama.cpp libraries or their dependencies could not be loaded.
+    LibraryLoad,
+    /// The gpus or the memory of the machine could not be queried.
+    DeviceDiscovery,
+    InvalidInput,
+    Unsupported,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -496,22 +957,22 @@ pub enum Error {
     LIbLoading(#[from] libloading::Error),
     #[error("unimplemented: file: {0}, line: {1}")]
     Unimplemented(&'static str, u32),
-    #[error("")]
+    #[error("nvml can't be loaded")]
     NvMlLoad,
     #[error("Apple Paravirtual Device")]
     MacParaVirtualDevice,
-    #[error("{0}")]
+    #[error("nvmlInit_v2 returned {0}")]
     NvMlInit_v2(i32),
     #[cfg(any(target_os = "windows", target_os = "linux"))]
-    #[error("{0}")]
+    #[error("{0} returned {1}")]
     NvCudaCall(&'static str, i32),
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     #[error("nvcuda load")]
     NvCudaLoad,
     #[cfg(any(target_os = "windows", target_os = "linux"))]
-    #[error("{0}")]
+    #[error("{0} returned {1}")]
     CudartCall(&'static str, i32),
-    #[error("{0}")]
+    #[error("{0} returned {1}")]
     SystemCall(&'static str, i32),
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     #[error("cudart load")]
@@ -519,48 +980,90 @@ pub enum Error {
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     #[error("cuda device not found")]
     CudaNotFound,
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     #[error("{0}")]
     Proc(#[from] procfs::ProcError),
+    #[cfg(target_os = "android")]
+    #[error("{0}: {1}")]
+    VulkanCall(&'static str, i32),
+    #[cfg(target_os = "android")]
+    #[error("vulkan device not found")]
+    VulkanNotFound,
     #[error("can`t load llama_cpp dependencies {0:#?}")]
     DependenciesLoading(Vec<String>),
+    #[error("path {0:?} can`t be passed to llama.cpp")]
+    PathEncoding(std::path::PathBuf),
+    #[error("{0}")]
+    Requirement(#[from] RequirementError),
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
-
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::LIbLoading(_) | Error::DependenciesLoading(_) | Error::Requirement(_) => {
+                ErrorKind::LibraryLoad
+            }
+            Error::Unimplemented(..) => ErrorKind::Unsupported,
+            Error::PathEncoding(_) => ErrorKind::InvalidInput,
+            _ => ErrorKind::DeviceDiscovery,
+        }
+    }
 
-macro_rules! get_and_load_from_llama
-{
-    ($($name:tt($($v:ident: $t:ty),* $(,)?) -> $rt:ty),* $(,)?) => {
+    /// Identifies the error independent of its message, `kind.detail` in snake case.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::LIbLoading(_) => "library_load.open",
+            Error::DependenciesLoading(_) => "library_load.dependencies",
+            Error::Requirement(_) => "library_load.requirement",
+            Error::Unimplemented(..) => "unsupported.platform",
+            Error::PathEncoding(_) => "invalid_input.path_encoding",
+            Error::NvMlLoad => "device_discovery.nvml_load",
+            Error::NvMlInit_v2(_) => "device_discovery.nvml_init",
+            Error::MacParaVirtualDevice => "device_discovery.paravirtual_device",
+            Error::SystemCall(..) => "device_discovery.system_call",
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            Error::NvCudaCall(..) => "device_discovery.nvcuda_call",
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            Error::NvCudaLoad => "device_discovery.nvcuda_load",
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            Error::CudartCall(..) => "device_discovery.cudart_call",
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            Error::CudartLoad => "device_discovery.cudart_load",
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            Error::CudaNotFound => "device_discovery.cuda_not_found",
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Error::Proc(_) => "device_discovery.proc",
+            #[cfg(target_os = "android")]
+            Error::VulkanCall(..) => "device_discovery.vulkan_call",
+            #[cfg(target_os = "android")]
+            Error::VulkanNotFound => "device_discovery.vulkan_not_found",
+        }
+    }
 
-        $(pub unsafe fn $name($($v: $t),*) -> $rt
-        {
-            let func: libloading::Symbol<
-                unsafe extern "C" fn($($v: $t),*) -> $rt,
-                > = LIBS.llama_cpp.get(stringify!($name).as_bytes()).expect(&format!("function \"{}\" not found in llama_cpp lib", stringify!($name)));
-            func($($v),*)
+    /// What the user can do about the error, if anything.
+    pub fn hint(&self) -> Option<&'static str> {
+        if let Error::Requirement(_) = self {
+            return Some("update the gpu driver, or load a variant this machine can run");
         }
-        )*
-    };
+        match self.kind() {
+            ErrorKind::LibraryLoad => Some(
+                "the llama.cpp libraries of the variant or their dependencies are missing, \
+                 reinstall them or load another variant",
+            ),
+            ErrorKind::DeviceDiscovery => {
+                Some("the gpu driver could not be queried, update it or run on the cpu")
+            }
+            ErrorKind::InvalidInput => Some("pass a path that is valid unicode"),
+            ErrorKind::Unsupported => None,
+        }
+    }
 }
 
-macro_rules! get_and_load_from_llava
-{
-    ($($name:tt($($v:ident: $t:ty),* $(,)?) -> $rt:ty),* $(,)?) => {
+pub type Result<T> = std::result::Result<T, Error>;
 
-        $(pub unsafe fn $name($($v: $t),*) -> $rt
-        {
-            let func: libloading::Symbol<
-                unsafe extern "C" fn($($v: $t),*) -> $rt,
-                > = LIBS.llava.get(stringify!($name).as_bytes()).expect(&format!("function \"{}\" not found in llama_cpp lib", stringify!($name)));
-            func($($v),*)
-        }
-        )*
-    };
-}
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
-get_and_load_from_llava!(
+bindings!(Llava:
     clip_image_u8_init() -> *mut clip_image_u8,
     clip_image_u8_free(clip: *mut clip_image_u8) -> (),
     clip_image_load_from_bytes(
@@ -580,6 +1083,11 @@ get_and_load_from_llava!(
         verbocity: ::std::os::raw::c_int
     ) -> *mut clip_ctx,
     clip_free(ctx: *mut clip_ctx) -> (),
+    clip_model_quantize(
+        fname_inp: *const ::std::os::raw::c_char,
+        fname_out: *const ::std::os::raw::c_char,
+        itype: ::std::os::raw::c_int
+    ) -> bool,
     llava_image_embed_make_with_bytes(
         ctx_clip: *mut clip_ctx,
         n_threads: ::std::os::raw::c_int,
@@ -595,7 +1103,7 @@ get_and_load_from_llava!(
     ) -> bool,
 );
 
-get_and_load_from_llama!(
+bindings!(Llama:
     llama_load_model_from_file(
         path_model: *const ::std::os::raw::c_char,
         params: llama_model_params) -> *mut llama_model,
@@ -651,8 +1159,10 @@ get_and_load_from_llama!(
     llama_decode(ctx: *mut llama_context, batch: llama_batch) -> i32,
     llama_n_ctx(ctx: *const llama_context) -> u32,
     llama_n_batch(ctx: *const llama_context) -> u32,
+    llama_n_ubatch(ctx: *const llama_context) -> u32,
     llama_free(ctx: *mut llama_context) -> (),
     llama_set_state_data(ctx: *mut llama_context, src: *const u8) -> usize,
+    llama_state_set_data(ctx: *mut llama_context, src: *const u8, size: usize) -> usize,
     llama_copy_state_data(ctx: *mut llama_context, dst: *mut u8) -> usize,
     llama_get_state_size(ctx: *const llama_context) -> usize,
     llama_load_session_file(
@@ -715,6 +1225,7 @@ get_and_load_from_llama!(
     ) -> (),
     llama_supports_mlock() -> bool,
     llama_supports_mmap() -> bool,
+    llama_supports_gpu_offload() -> bool,
     llama_max_devices() -> usize,
     llama_sample_token_mirostat_v2(
         ctx: *mut llama_context,
@@ -792,5 +1303,11 @@ get_and_load_from_llama!(
     llama_time_us() -> i64,
     ggml_time_us() -> i64,
     llama_batch_init(n_tokens: i32, embd: i32, n_seq_max: i32) -> llama_batch,
-    llama_batch_free(batch: llama_batch) -> ()
+    llama_batch_free(batch: llama_batch) -> (),
+    llama_model_quantize_default_params() -> llama_model_quantize_params,
+    llama_model_quantize(
+        fname_inp: *const ::std::os::raw::c_char,
+        fname_out: *const ::std::os::raw::c_char,
+        params: *const llama_model_quantize_params,
+    ) -> u32
 );
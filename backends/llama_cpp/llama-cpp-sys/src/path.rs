@@ -0,0 +1,135 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+
+#[cfg(target_os = "windows")]
+const MAX_PATH: usize = 260;
+
+/// Returns `path` in a form every native loader accepts.
+///
+/// On Windows absolute paths that do not fit into `MAX_PATH` are rewritten to the
+/// extended-length form (`\\?\C:\...` or `\\?\UNC\server\share\...`). Extended-length
+/// paths are passed to the file system verbatim, so `.` and `..` are resolved here.
+/// On other platforms the path is returned unchanged.
+pub fn normalize<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use std::path::{Component, Prefix};
+
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            match std::env::current_dir() {
+                Ok(d) => d.join(path),
+                Err(_) => return path.to_path_buf(),
+            }
+        };
+        // leave room for the file name the loaders append to directories
+        if path.as_os_str().encode_wide().count() < MAX_PATH - 12 {
+            return path;
+        }
+        let mut res = std::ffi::OsString::new();
+        let mut parts = vec![];
+        for c in path.components() {
+            match c {
+                Component::Prefix(p) => match p.kind() {
+                    Prefix::Disk(_) => {
+                        res.push(r"\\?\");
+                        res.push(p.as_os_str());
+                    }
+                    Prefix::UNC(server, share) => {
+                        res.push(r"\\?\UNC\");
+                        res.push(server);
+                        res.push(r"\");
+                        res.push(share);
+                    }
+                    // already verbatim or a device path
+                    _ => return path,
+                },
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => {
+                    parts.pop();
+                }
+                Component::Normal(n) => parts.push(n),
+            }
+        }
+        for p in parts {
+            res.push(r"\");
+            res.push(p);
+        }
+        PathBuf::from(res)
+    }
+    #[cfg(not(target_os = "windows"))]
+    path.to_path_buf()
+}
+
+/// Converts `path` into the UTF-8 C string llama.cpp and llava expect.
+///
+/// On unix the raw bytes are passed through, so non UTF-8 file names keep working.
+/// On Windows llama.cpp converts the string back to UTF-16 before opening the file,
+/// which makes any path representable as UTF-8 safe to pass.
+pub fn to_cstring<P: AsRef<Path>>(path: P) -> Result<CString> {
+    let path = path.as_ref();
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path
+        .to_str()
+        .ok_or_else(|| Error::PathEncoding(path.to_path_buf()))?
+        .as_bytes()
+        .to_vec();
+    CString::new(bytes).map_err(|_| Error::PathEncoding(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unicode_path_to_cstring() {
+        let p = Path::new("models").join("модель-模型-🦙.gguf");
+        let c = to_cstring(&p).unwrap();
+        assert_eq!(c.to_str().unwrap(), p.to_str().unwrap());
+    }
+
+    #[test]
+    fn nul_in_path() {
+        assert!(to_cstring(Path::new("mod\0el.gguf")).is_err());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn unchanged_on_unix() {
+        let p = Path::new("/tmp/модель/模型.gguf");
+        assert_eq!(normalize(p), p);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn long_path_prefixed() {
+        let mut p = PathBuf::from(r"C:\");
+        for _ in 0..30 {
+            p.push("длинный_каталог");
+        }
+        p.push("..");
+        p.push("модель.gguf");
+        let n = normalize(&p);
+        let s = n.to_str().unwrap();
+        assert!(s.starts_with(r"\\?\C:\"));
+        assert!(!s.contains(".."));
+        assert!(s.ends_with(r"длинный_каталог\модель.gguf"));
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn short_path_untouched() {
+        let p = Path::new(r"C:\models\модель.gguf");
+        assert_eq!(normalize(p), p);
+    }
+}
@@ -0,0 +1,56 @@
+//! How the bindings reach the llama.cpp and llava functions.
+//!
+//! By default the functions are looked up in the shared libraries of the variant [`crate::load`]
+//! picked for this machine. With the `static` feature, and on wasm where there is no dynamic
+//! loading, llama.cpp is linked into the binary and the bindings call it directly. Only the
+//! cpu backend is linked in then.
+
+/// The shared library a binding is looked up in.
+#[cfg(not(static_libs))]
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Lib {
+    Llama,
+    Llava,
+}
+
+#[cfg(not(static_libs))]
+impl crate::LlamaCppLibs {
+    /// The function `name` of `lib`, panics if the library doesn't export it.
+    pub(crate) unsafe fn symbol<T>(&self, lib: Lib, name: &str) -> libloading::Symbol<'_, T> {
+        let library = match lib {
+            Lib::Llama => &self.llama_cpp,
+            Lib::Llava => &self.llava,
+        };
+        library
+            .get(name.as_bytes())
+            .unwrap_or_else(|e| panic!("function \"{name}\" not found in {lib:?} lib: {e}"))
+    }
+}
+
+/// Declares an `unsafe fn` calling the function of the same name in `$lib`, `Llama` or `Llava`.
+macro_rules! bindings
+{
+    ($lib:ident: $($name:tt($($v:ident: $t:ty),* $(,)?) -> $rt:ty),* $(,)?) => {
+
+        $(#[cfg(not(static_libs))]
+        pub unsafe fn $name($($v: $t),*) -> $rt
+        {
+            let libs = crate::libs();
+            let func = libs.symbol::<unsafe extern "C" fn($($v: $t),*) -> $rt>(
+                crate::symbols::Lib::$lib,
+                stringify!($name),
+            );
+            func($($v),*)
+        }
+
+        #[cfg(static_libs)]
+        pub unsafe fn $name($($v: $t),*) -> $rt
+        {
+            extern "C" {
+                fn $name($($v: $t),*) -> $rt;
+            }
+            $name($($v),*)
+        }
+        )*
+    };
+}
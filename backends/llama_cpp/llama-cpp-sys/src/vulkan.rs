@@ -0,0 +1,160 @@
+//! Vulkan gpus of Android devices, found through the system's vulkan loader.
+//!
+//! Only the few core 1.0 functions needed to list the devices are called, their structs are
+//! declared here up to the fields read and padded to their full size.
+use std::ffi::{c_char, c_void, CStr};
+
+type VkInstance = *mut c_void;
+type VkPhysicalDevice = *mut c_void;
+type VkResult = i32;
+
+const VK_SUCCESS: VkResult = 0;
+const VK_STRUCTURE_TYPE_INSTANCE_CREATE_INFO: u32 = 1;
+const VK_PHYSICAL_DEVICE_TYPE_INTEGRATED_GPU: u32 = 1;
+const VK_PHYSICAL_DEVICE_TYPE_CPU: u32 = 4;
+const VK_MEMORY_HEAP_DEVICE_LOCAL_BIT: u32 = 1;
+
+#[repr(C)]
+struct VkInstanceCreateInfo {
+    s_type: u32,
+    p_next: *const c_void,
+    flags: u32,
+    p_application_info: *const c_void,
+    enabled_layer_count: u32,
+    pp_enabled_layer_names: *const *const c_char,
+    enabled_extension_count: u32,
+    pp_enabled_extension_names: *const *const c_char,
+}
+
+// VkPhysicalDeviceProperties, 824 bytes
+#[repr(C)]
+struct VkPhysicalDeviceProperties {
+    api_version: u32,
+    _driver_version: u32,
+    _vendor_id: u32,
+    _device_id: u32,
+    device_type: u32,
+    device_name: [c_char; 256],
+    _rest: [u64; 68],
+}
+
+#[repr(C)]
+struct VkMemoryHeap {
+    size: u64,
+    flags: u32,
+}
+
+// VkPhysicalDeviceMemoryProperties, the memory types are not needed
+#[repr(C)]
+struct VkPhysicalDeviceMemoryProperties {
+    _memory_type_count: u32,
+    _memory_types: [[u32; 2]; 32],
+    memory_heap_count: u32,
+    memory_heaps: [VkMemoryHeap; 16],
+}
+
+type CreateInstance =
+    unsafe extern "C" fn(*const VkInstanceCreateInfo, *const c_void, *mut VkInstance) -> VkResult;
+type DestroyInstance = unsafe extern "C" fn(VkInstance, *const c_void);
+type EnumeratePhysicalDevices =
+    unsafe extern "C" fn(VkInstance, *mut u32, *mut VkPhysicalDevice) -> VkResult;
+type GetPhysicalDeviceProperties =
+    unsafe extern "C" fn(VkPhysicalDevice, *mut VkPhysicalDeviceProperties);
+type GetPhysicalDeviceMemoryProperties =
+    unsafe extern "C" fn(VkPhysicalDevice, *mut VkPhysicalDeviceMemoryProperties);
+
+/// A gpu vulkan reports.
+pub struct VulkanDevice {
+    pub name: String,
+    /// Api version the driver supports, `major.minor`.
+    pub api_version: String,
+    /// Size of the device local heaps, all of the memory of an integrated gpu.
+    pub memory: u64,
+    /// Whether the gpu shares the system memory, as mobile gpus do.
+    pub integrated: bool,
+}
+
+pub struct VulkanHandle {
+    _lib: libloading::Library,
+    pub devices: Vec<VulkanDevice>,
+}
+
+impl VulkanHandle {
+    pub fn new() -> crate::Result<Self> {
+        let lib = unsafe { libloading::Library::new("libvulkan.so")? };
+        let devices = unsafe { Self::list(&lib)? };
+        if devices.is_empty() {
+            return Err(crate::Error::VulkanNotFound);
+        }
+        Ok(Self { _lib: lib, devices })
+    }
+
+    unsafe fn list(lib: &libloading::Library) -> crate::Result<Vec<VulkanDevice>> {
+        let create_instance = lib.get::<CreateInstance>(b"vkCreateInstance")?;
+        let destroy_instance = lib.get::<DestroyInstance>(b"vkDestroyInstance")?;
+        let enumerate = lib.get::<EnumeratePhysicalDevices>(b"vkEnumeratePhysicalDevices")?;
+        let properties =
+            lib.get::<GetPhysicalDeviceProperties>(b"vkGetPhysicalDeviceProperties")?;
+        let memory_properties =
+            lib.get::<GetPhysicalDeviceMemoryProperties>(b"vkGetPhysicalDeviceMemoryProperties")?;
+
+        let info = VkInstanceCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_INSTANCE_CREATE_INFO,
+            p_next: std::ptr::null(),
+            flags: 0,
+            p_application_info: std::ptr::null(),
+            enabled_layer_count: 0,
+            pp_enabled_layer_names: std::ptr::null(),
+            enabled_extension_count: 0,
+            pp_enabled_extension_names: std::ptr::null(),
+        };
+        let mut instance = std::ptr::null_mut();
+        let res = create_instance(&info, std::ptr::null(), &mut instance);
+        if res != VK_SUCCESS {
+            return Err(crate::Error::VulkanCall("vkCreateInstance", res));
+        }
+        let mut count = 0;
+        let res = enumerate(instance, &mut count, std::ptr::null_mut());
+        let mut physical = vec![std::ptr::null_mut(); count as usize];
+        let res = if res == VK_SUCCESS {
+            enumerate(instance, &mut count, physical.as_mut_ptr())
+        } else {
+            res
+        };
+        if res != VK_SUCCESS {
+            destroy_instance(instance, std::ptr::null());
+            return Err(crate::Error::VulkanCall("vkEnumeratePhysicalDevices", res));
+        }
+        physical.truncate(count as usize);
+        let mut devices = vec![];
+        for device in physical {
+            let mut props: VkPhysicalDeviceProperties = std::mem::zeroed();
+            properties(device, &mut props);
+            // software renderers like swiftshader are slower than the cpu backend
+            if props.device_type == VK_PHYSICAL_DEVICE_TYPE_CPU {
+                continue;
+            }
+            let mut mem: VkPhysicalDeviceMemoryProperties = std::mem::zeroed();
+            memory_properties(device, &mut mem);
+            let memory = mem.memory_heaps[..(mem.memory_heap_count as usize).min(16)]
+                .iter()
+                .filter(|h| h.flags & VK_MEMORY_HEAP_DEVICE_LOCAL_BIT != 0)
+                .map(|h| h.size)
+                .sum();
+            devices.push(VulkanDevice {
+                name: CStr::from_ptr(props.device_name.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+                api_version: format!(
+                    "{}.{}",
+                    (props.api_version >> 22) & 0x7f,
+                    (props.api_version >> 12) & 0x3ff
+                ),
+                memory,
+                integrated: props.device_type == VK_PHYSICAL_DEVICE_TYPE_INTEGRATED_GPU,
+            });
+        }
+        destroy_instance(instance, std::ptr::null());
+        Ok(devices)
+    }
+}
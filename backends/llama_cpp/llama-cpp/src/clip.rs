@@ -1,5 +1,9 @@
 use crate::ClipError;
-use std::{ffi::CString, path::Path, ptr::NonNull, sync::Arc};
+use std::{
+    path::Path,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+};
 
 pub struct ImageEmbed {
     pub(crate) embed: NonNull<llama_cpp_sys::llava_image_embed>,
@@ -21,6 +25,8 @@ impl Drop for ImageEmbed {
 #[allow(clippy::module_name_repetitions)]
 pub struct ClipContextInternal {
     pub(crate) context: NonNull<llama_cpp_sys::clip_ctx>,
+    // clip_image_encode reuses the compute buffers of the context
+    encode: Mutex<()>,
 }
 unsafe impl Send for ClipContextInternal {}
 unsafe impl Sync for ClipContextInternal {}
@@ -29,49 +35,91 @@ unsafe impl Sync for ClipContextInternal {}
 #[allow(clippy::module_name_repetitions)]
 pub struct ClipContext {
     pub(crate) context: Arc<ClipContextInternal>,
+    n_threads: Option<usize>,
+}
+
+/// Type [`convert`] writes the matrices of a projector in, values of `ggml_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipType {
+    F32 = 0,
+    F16 = 1,
+}
+
+/// Writes the projector at `input` to `output` with its matrices in `typ`, the other tensors
+/// are copied as they are. The input has to be in F32 or F16.
+pub fn convert(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    typ: ClipType,
+) -> Result<(), ClipError> {
+    let input = llama_cpp_sys::path::to_cstring(&llama_cpp_sys::path::normalize(input))?;
+    let output = llama_cpp_sys::path::to_cstring(&llama_cpp_sys::path::normalize(output))?;
+    let lock = crate::llama_backend::alloc_lock();
+    let guard = crate::stdio::redirect();
+    let converted =
+        unsafe { llama_cpp_sys::clip_model_quantize(input.as_ptr(), output.as_ptr(), typ as i32) };
+    drop(guard);
+    drop(lock);
+    if converted {
+        Ok(())
+    } else {
+        Err(ClipError::ConvertFailed)
+    }
 }
 
 impl ClipContext {
     pub fn load(path: impl AsRef<Path>) -> Result<Self, ClipError> {
-        let path = path.as_ref();
-        debug_assert!(Path::new(path).exists(), "{path:?} does not exist");
-        let path = path
-            .to_str()
-            .ok_or(ClipError::PathToStrError(path.to_path_buf()))?;
-
-        let cstr = CString::new(path)?;
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        let guard = stdio_override::StderrOverride::from_file("/dev/null").unwrap();
-        #[cfg(target_os = "windows")]
-        let guard = stdio_override::StderrOverride::from_file("nul").unwrap();
+        let path = llama_cpp_sys::path::normalize(path);
+        debug_assert!(path.exists(), "{path:?} does not exist");
+        let cstr = llama_cpp_sys::path::to_cstring(&path)?;
+        let lock = crate::llama_backend::alloc_lock();
+        let guard = crate::stdio::redirect();
         #[cfg(debug_assertions)]
         let clip = unsafe { llama_cpp_sys::clip_model_load(cstr.as_ptr(), 0) };
         #[cfg(not(debug_assertions))]
         let clip = unsafe { llama_cpp_sys::clip_model_load(cstr.as_ptr(), 0) };
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
         drop(guard);
+        drop(lock);
         let context = NonNull::new(clip).ok_or(ClipError::NullReturn)?;
 
         tracing::debug!(?path, "Loaded model");
         Ok(Self {
-            context: Arc::new(ClipContextInternal { context }),
+            context: Arc::new(ClipContextInternal {
+                context,
+                encode: Mutex::new(()),
+            }),
+            n_threads: None,
         })
     }
 
+    /// Embeds images on `n_threads` threads, instead of the ones passed to
+    /// [`ClipContext::embed_image`].
+    #[must_use]
+    pub fn with_n_threads(mut self, n_threads: Option<usize>) -> Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    #[must_use]
+    pub fn n_threads(&self) -> Option<usize> {
+        self.n_threads
+    }
+
     pub fn embed_image(&self, n_threads: usize, image: &[u8]) -> Result<ImageEmbed, ClipError> {
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        let guard = stdio_override::StderrOverride::from_file("/dev/null").unwrap();
-        #[cfg(target_os = "windows")]
-        let guard = stdio_override::StdoutOverride::from_file("nul").unwrap();
+        let _encode = self
+            .context
+            .encode
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let guard = crate::stdio::redirect_with_stdout();
         let embed = unsafe {
             llama_cpp_sys::llava_image_embed_make_with_bytes(
                 self.context.context.as_ptr(),
-                n_threads as i32,
+                self.n_threads.unwrap_or(n_threads) as i32,
                 image.as_ptr(),
                 image.len() as i32,
             )
         };
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
         drop(guard);
         let embed = NonNull::new(embed).ok_or(ClipError::NullReturn)?;
         Ok(ImageEmbed { embed })
@@ -80,6 +128,7 @@ impl ClipContext {
 
 impl Drop for ClipContextInternal {
     fn drop(&mut self) {
+        let _lock = crate::llama_backend::alloc_lock();
         unsafe { llama_cpp_sys::clip_free(self.context.as_ptr()) }
     }
 }
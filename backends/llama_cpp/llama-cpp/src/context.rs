@@ -4,7 +4,8 @@ use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
 use std::ptr::NonNull;
 use std::slice;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::clip::ImageEmbed;
 use crate::context::params::LlamaContextParams;
@@ -12,8 +13,8 @@ use crate::llama_batch::LlamaBatch;
 use crate::model::{AddBos, LlamaModel};
 use crate::token::data::LlamaTokenData;
 use crate::token::LlamaToken;
-use crate::LlamaContextLoadError;
 use crate::{DecodeError, EmbeddingsError};
+use crate::{LLamaCppError, LlamaContextLoadError};
 
 pub mod kv_cache;
 pub mod params;
@@ -23,6 +24,12 @@ pub mod session;
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaContextInternal {
     pub(crate) context: NonNull<llama_cpp_sys::llama_context>,
+    /// serializes decoding and kv cache changes of contexts sharing this one
+    lock: Arc<Mutex<()>>,
+    /// sequence ids in use, indexed by id
+    seqs: Mutex<Vec<bool>>,
+    /// the sequence of the last decoded batch, its logits are the ones llama.cpp holds
+    last_seq: AtomicI32,
 }
 
 unsafe impl Send for LlamaContextInternal {}
@@ -41,6 +48,7 @@ impl Drop for ClipImageU8 {
 
 impl Drop for LlamaContextInternal {
     fn drop(&mut self) {
+        let _lock = crate::llama_backend::alloc_lock();
         unsafe { llama_cpp_sys::llama_free(self.context.as_ptr()) }
     }
 }
@@ -53,6 +61,7 @@ pub struct LlamaContext {
     pub model: LlamaModel,
     initialized_logits: Vec<i32>,
     embeddings_enabled: bool,
+    seq_id: i32,
 }
 
 impl Debug for LlamaContext {
@@ -66,27 +75,88 @@ impl Debug for LlamaContext {
 impl LlamaContext {
     pub(crate) fn new(llama_model: &LlamaModel, params: LlamaContextParams) -> crate::Result<Self> {
         let context_params = params.context_params;
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        let guard = stdio_override::StderrOverride::from_file("/dev/null").unwrap();
-        #[cfg(target_os = "windows")]
-        let guard = stdio_override::StderrOverride::from_file("nul").unwrap();
+        let lock = crate::llama_backend::alloc_lock();
+        let guard = crate::stdio::redirect();
         let context = unsafe {
             llama_cpp_sys::llama_new_context_with_model(
                 llama_model.model.model.as_ptr(),
                 context_params,
             )
         };
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
         drop(guard);
+        drop(lock);
         let context = NonNull::new(context).ok_or(LlamaContextLoadError::NullReturn)?;
+        let mut seqs = vec![false; context_params.n_seq_max.max(1) as usize];
+        seqs[0] = true;
         Ok(Self {
-            context: Arc::new(LlamaContextInternal { context }),
+            context: Arc::new(LlamaContextInternal {
+                context,
+                lock: Arc::new(Mutex::new(())),
+                seqs: Mutex::new(seqs),
+                last_seq: AtomicI32::new(0),
+            }),
             model: llama_model.clone(),
             initialized_logits: Vec::new(),
             embeddings_enabled: params.embeddings(),
+            seq_id: 0,
         })
     }
 
+    /// A context on a new sequence of the same `llama_context`, starting with a copy of this
+    /// one's kv cache.
+    ///
+    /// The copy shares the cache cells of the prefix, so no tokens have to be evaluated again.
+    /// Both contexts use the same `llama_context`, callers running them on different threads
+    /// have to hold [`Self::shared_lock`] while decoding.
+    ///
+    /// # Errors
+    ///
+    /// Fails if all `n_seq_max` sequences of the context are in use.
+    pub fn fork(&self) -> crate::Result<Self> {
+        let seq_id = {
+            let mut seqs = self.context.seqs.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(free) = seqs.iter().position(|used| !used) else {
+                return Err(LLamaCppError::NoFreeSequence(seqs.len()));
+            };
+            seqs[free] = true;
+            free as i32
+        };
+        let mut fork = Self {
+            context: self.context.clone(),
+            model: self.model.clone(),
+            initialized_logits: self.initialized_logits.clone(),
+            embeddings_enabled: self.embeddings_enabled,
+            seq_id,
+        };
+        fork.copy_kv_cache_seq(self.seq_id, seq_id, None, None);
+        Ok(fork)
+    }
+
+    /// The kv cache sequence this context evaluates into, `0` unless it was forked.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Whether forks of this context, or the context it was forked from, are alive.
+    #[must_use]
+    pub fn is_forked(&self) -> bool {
+        Arc::strong_count(&self.context) > 1
+    }
+
+    /// The lock shared by this context and its forks.
+    #[must_use]
+    pub fn shared_lock(&self) -> Arc<Mutex<()>> {
+        self.context.lock.clone()
+    }
+
+    /// Whether the logits llama.cpp holds were computed for this context's sequence, a fork
+    /// decoding in between replaces them.
+    #[must_use]
+    pub fn logits_current(&self) -> bool {
+        self.context.last_seq.load(Ordering::Acquire) == self.seq_id
+    }
+
     pub fn token_to_piece_with_special(
         &self,
         token: &LlamaToken,
@@ -104,6 +174,13 @@ impl LlamaContext {
         unsafe { llama_cpp_sys::llama_n_batch(self.context.context.as_ptr()) }
     }
 
+    /// Gets the max number of tokens computed at once, decoded batches are split into micro
+    /// batches of this size.
+    #[must_use]
+    pub fn n_ubatch(&self) -> u32 {
+        unsafe { llama_cpp_sys::llama_n_ubatch(self.context.context.as_ptr()) }
+    }
+
     /// Gets the size of the context.
     #[must_use]
     pub fn n_ctx(&self) -> u32 {
@@ -126,6 +203,7 @@ impl LlamaContext {
         match NonZeroI32::new(result) {
             None => {
                 self.initialized_logits = batch.initialized_logits.clone();
+                self.context.last_seq.store(self.seq_id, Ordering::Release);
                 Ok(())
             }
             Some(error) => Err(DecodeError::from(error)),
@@ -250,17 +328,34 @@ impl LlamaContext {
         batch: usize,
         n_curr: &mut i32,
     ) -> Result<i32, DecodeError> {
+        self.eval_tokens_with_progress(tokens, batch, n_curr, |_, _| {})
+    }
+
+    /// Decodes `tokens` in chunks of at most `batch` tokens, calling `progress` with the number
+    /// of tokens decoded so far and the total after every chunk.
+    pub fn eval_tokens_with_progress(
+        &mut self,
+        tokens: Vec<LlamaToken>,
+        batch: usize,
+        n_curr: &mut i32,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<i32, DecodeError> {
+        let batch = batch.max(1);
         let mut rr = 0;
-        for chunk in tokens.chunks(batch).into_iter() {
-            let mut batch = LlamaBatch::new(batch, 1);
+        let mut done = 0;
+        let mut llama_batch = LlamaBatch::new(batch, 1);
+        for chunk in tokens.chunks(batch) {
+            llama_batch.clear();
             let last_index = chunk.len() - 1;
-            chunk.into_iter().enumerate().try_for_each(|(i, t)| {
-                batch.add(t.clone(), *n_curr, &[0], i == last_index)?;
+            chunk.iter().enumerate().try_for_each(|(i, t)| {
+                llama_batch.add(*t, *n_curr, &[self.seq_id], i == last_index)?;
                 *n_curr += 1;
                 Ok::<(), DecodeError>(())
             })?;
-            self.decode(&mut batch)?;
-            rr = batch.n_tokens() - 1;
+            self.decode(&mut llama_batch)?;
+            rr = llama_batch.n_tokens() - 1;
+            done += chunk.len();
+            progress(done, tokens.len());
         }
         Ok(rr)
     }
@@ -269,6 +364,21 @@ impl LlamaContext {
         self.eval_tokens(vec![token], 1, n_curr)
     }
 
+    /// Decodes `tokens` in a single batch with logits for every one of them, those of the
+    /// `i`th token are at index `i`. Used to verify draft tokens.
+    pub fn eval_with_logits(
+        &mut self,
+        tokens: &[LlamaToken],
+        n_curr: &mut i32,
+    ) -> Result<(), DecodeError> {
+        let mut llama_batch = LlamaBatch::new(tokens.len(), 1);
+        for t in tokens {
+            llama_batch.add(*t, *n_curr, &[self.seq_id], true)?;
+            *n_curr += 1;
+        }
+        self.decode(&mut llama_batch)
+    }
+
     pub fn eval_string(
         &mut self,
         string: &str,
@@ -286,6 +396,10 @@ impl LlamaContext {
         batch: usize,
         n_curr: &mut i32,
     ) -> Result<i32, DecodeError> {
+        // llava always evaluates into sequence 0
+        if self.seq_id != 0 {
+            return Err(DecodeError::EvalEmbedImage);
+        }
         let res = unsafe {
             llama_cpp_sys::llava_eval_image_embed(
                 self.context.context.as_ptr(),
@@ -297,7 +411,22 @@ impl LlamaContext {
         if !res {
             Err(DecodeError::EvalEmbedImage)
         } else {
+            self.context.last_seq.store(0, Ordering::Release);
             Ok(0)
         }
     }
 }
+
+impl Drop for LlamaContext {
+    fn drop(&mut self) {
+        // forks outlive each other, release only this sequence's share of the cache
+        if !self.is_forked() {
+            return;
+        }
+        let lock = self.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.clear_kv_cache_seq(self.seq_id, None, None);
+        let mut seqs = self.context.seqs.lock().unwrap_or_else(|e| e.into_inner());
+        seqs[self.seq_id as usize] = false;
+    }
+}
@@ -4,6 +4,11 @@ use crate::context::LlamaContext;
 use std::ffi::c_int;
 use std::num::NonZeroU8;
 
+/// Converts an optional position to llama.cpp's convention where `-1` means unbounded.
+fn pos(p: Option<u32>) -> i32 {
+    p.map_or(-1, |p| i32::try_from(p).unwrap_or(i32::MAX))
+}
+
 impl LlamaContext {
     /// Copy the cache from one sequence to another.
     ///
@@ -26,9 +31,9 @@ impl LlamaContext {
     /// * `dest` - The sequence id to copy the cache to.
     /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is copied up to `p1`.
     /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is copied starting from `p0`.
-    pub fn copy_kv_cache_seq(&mut self, src: i32, dest: i32, p0: Option<u16>, p1: Option<u16>) {
-        let p0 = p0.map_or(-1, i32::from);
-        let p1 = p1.map_or(-1, i32::from);
+    pub fn copy_kv_cache_seq(&mut self, src: i32, dest: i32, p0: Option<u32>, p1: Option<u32>) {
+        let p0 = pos(p0);
+        let p1 = pos(p1);
         unsafe {
             llama_cpp_sys::llama_kv_cache_seq_cp(self.context.context.as_ptr(), src, dest, p0, p1);
         }
@@ -41,12 +46,13 @@ impl LlamaContext {
     /// * `src` - The sequence id to clear the cache for.
     /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is cleared up to `p1`.
     /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is cleared from `p0`.
-    pub fn clear_kv_cache_seq(&mut self, src: i32, p0: Option<u16>, p1: Option<u16>) {
-        let p0 = p0.map_or(-1, i32::from);
-        let p1 = p1.map_or(-1, i32::from);
-        unsafe {
-            llama_cpp_sys::llama_kv_cache_seq_rm(self.context.context.as_ptr(), src, p0, p1);
-        }
+    ///
+    /// Returns `false` if only a part of the sequence should be removed and the model
+    /// can't do that (recurrent models keep a single state per sequence).
+    pub fn clear_kv_cache_seq(&mut self, src: i32, p0: Option<u32>, p1: Option<u32>) -> bool {
+        let p0 = pos(p0);
+        let p1 = pos(p1);
+        unsafe { llama_cpp_sys::llama_kv_cache_seq_rm(self.context.context.as_ptr(), src, p0, p1) }
     }
 
     /// Returns the number of used KV cells (i.e. have at least one sequence assigned to them)
@@ -81,9 +87,9 @@ impl LlamaContext {
     /// * `p0` - The start position of the cache to update. If `None`, the entire cache is updated up to `p1`.
     /// * `p1` - The end position of the cache to update. If `None`, the entire cache is updated starting from `p0`.
     /// * `delta` - The relative position to add to the tokens
-    pub fn kv_cache_seq_add(&mut self, seq_id: i32, p0: Option<u16>, p1: Option<u16>, delta: i32) {
-        let p0 = p0.map_or(-1, i32::from);
-        let p1 = p1.map_or(-1, i32::from);
+    pub fn kv_cache_seq_add(&mut self, seq_id: i32, p0: Option<u32>, p1: Option<u32>, delta: i32) {
+        let p0 = pos(p0);
+        let p1 = pos(p1);
         unsafe {
             llama_cpp_sys::llama_kv_cache_seq_add(
                 self.context.context.as_ptr(),
@@ -109,12 +115,12 @@ impl LlamaContext {
     pub fn kv_cache_seq_div(
         &mut self,
         seq_id: i32,
-        p0: Option<u16>,
-        p1: Option<u16>,
+        p0: Option<u32>,
+        p1: Option<u32>,
         d: NonZeroU8,
     ) {
-        let p0 = p0.map_or(-1, i32::from);
-        let p1 = p1.map_or(-1, i32::from);
+        let p0 = pos(p0);
+        let p1 = pos(p1);
         let d = c_int::from(d.get());
         unsafe {
             llama_cpp_sys::llama_kv_cache_seq_div(self.context.context.as_ptr(), seq_id, p0, p1, d)
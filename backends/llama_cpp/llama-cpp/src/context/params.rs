@@ -43,6 +43,50 @@ impl From<RopeScalingType> for i32 {
     }
 }
 
+/// A rusty wrapper around `llama_pooling_type`, how the embeddings of a sequence's tokens
+/// are combined into one.
+#[repr(i8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LlamaPoolingType {
+    /// The pooling type of the model
+    Unspecified = -1,
+    /// No pooling, only token embeddings
+    None = 0,
+    /// Mean of the token embeddings
+    Mean = 1,
+    /// Embedding of the first token
+    Cls = 2,
+    /// Embedding of the last token
+    Last = 3,
+}
+
+/// Create a `LlamaPoolingType` from a `c_int` - returns `LlamaPoolingType::Unspecified` if
+/// the value is not recognized.
+impl From<i32> for LlamaPoolingType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Mean,
+            2 => Self::Cls,
+            3 => Self::Last,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Create a `c_int` from a `LlamaPoolingType`.
+impl From<LlamaPoolingType> for i32 {
+    fn from(value: LlamaPoolingType) -> Self {
+        match value {
+            LlamaPoolingType::None => 0,
+            LlamaPoolingType::Mean => 1,
+            LlamaPoolingType::Cls => 2,
+            LlamaPoolingType::Last => 3,
+            LlamaPoolingType::Unspecified => -1,
+        }
+    }
+}
+
 /// A safe wrapper around `llama_context_params`.
 ///
 /// Generally this should be created with [`Default::default()`] and then modified with `with_*` methods.
@@ -137,6 +181,67 @@ impl LlamaContextParams {
         self.context_params.n_batch
     }
 
+    /// Set the `n_ubatch`, the number of tokens computed at once. Embeddings of non-causal
+    /// models need a whole sequence in one micro batch.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_ubatch(1024);
+    /// assert_eq!(params.n_ubatch(), 1024);
+    /// ```
+    #[must_use]
+    pub fn with_n_ubatch(mut self, n_ubatch: u32) -> Self {
+        self.context_params.n_ubatch = n_ubatch;
+        self
+    }
+
+    /// Get the `n_ubatch`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.n_ubatch(), 512);
+    /// ```
+    #[must_use]
+    pub fn n_ubatch(&self) -> u32 {
+        self.context_params.n_ubatch
+    }
+
+    /// Set the maximum number of sequences, see [`crate::context::LlamaContext::fork`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_seq_max(4);
+    /// assert_eq!(params.n_seq_max(), 4);
+    /// ```
+    #[must_use]
+    pub fn with_n_seq_max(mut self, n_seq_max: u32) -> Self {
+        self.context_params.n_seq_max = n_seq_max;
+        self
+    }
+
+    /// Get the maximum number of sequences
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.n_seq_max(), 1);
+    /// ```
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        self.context_params.n_seq_max
+    }
+
     /// Set the type of rope scaling.
     ///
     /// # Examples
@@ -166,6 +271,36 @@ impl LlamaContextParams {
         RopeScalingType::from(self.context_params.rope_scaling_type)
     }
 
+    /// Set how sequence embeddings are pooled.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default()
+    ///     .with_pooling_type(LlamaPoolingType::Mean);
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Mean);
+    /// ```
+    #[must_use]
+    pub fn with_pooling_type(mut self, pooling_type: LlamaPoolingType) -> Self {
+        self.context_params.pooling_type = i32::from(pooling_type);
+        self
+    }
+
+    /// Get how sequence embeddings are pooled.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Unspecified);
+    /// ```
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::from(self.context_params.pooling_type)
+    }
+
     /// Set the rope frequency base.
     ///
     /// # Examples
@@ -224,6 +359,151 @@ impl LlamaContextParams {
         self.context_params.rope_freq_scale
     }
 
+    /// Set the YaRN extrapolation mix factor, negative means taken from the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_yarn_ext_factor(0.5);
+    /// assert_eq!(params.yarn_ext_factor(), 0.5);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_ext_factor(mut self, yarn_ext_factor: f32) -> Self {
+        self.context_params.yarn_ext_factor = yarn_ext_factor;
+        self
+    }
+
+    /// Get the YaRN extrapolation mix factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_ext_factor(), -1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_ext_factor(&self) -> f32 {
+        self.context_params.yarn_ext_factor
+    }
+
+    /// Set the YaRN magnitude scaling factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_yarn_attn_factor(0.8);
+    /// assert_eq!(params.yarn_attn_factor(), 0.8);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_attn_factor(mut self, yarn_attn_factor: f32) -> Self {
+        self.context_params.yarn_attn_factor = yarn_attn_factor;
+        self
+    }
+
+    /// Get the YaRN magnitude scaling factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_attn_factor(), 1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_attn_factor(&self) -> f32 {
+        self.context_params.yarn_attn_factor
+    }
+
+    /// Set the YaRN low correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_yarn_beta_fast(16.0);
+    /// assert_eq!(params.yarn_beta_fast(), 16.0);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_beta_fast(mut self, yarn_beta_fast: f32) -> Self {
+        self.context_params.yarn_beta_fast = yarn_beta_fast;
+        self
+    }
+
+    /// Get the YaRN low correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_beta_fast(), 32.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_beta_fast(&self) -> f32 {
+        self.context_params.yarn_beta_fast
+    }
+
+    /// Set the YaRN high correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_yarn_beta_slow(2.0);
+    /// assert_eq!(params.yarn_beta_slow(), 2.0);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_beta_slow(mut self, yarn_beta_slow: f32) -> Self {
+        self.context_params.yarn_beta_slow = yarn_beta_slow;
+        self
+    }
+
+    /// Get the YaRN high correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_beta_slow(), 1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_beta_slow(&self) -> f32 {
+        self.context_params.yarn_beta_slow
+    }
+
+    /// Set the YaRN original context size of the model, 0 means taken from the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_yarn_orig_ctx(8192);
+    /// assert_eq!(params.yarn_orig_ctx(), 8192);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_orig_ctx(mut self, yarn_orig_ctx: u32) -> Self {
+        self.context_params.yarn_orig_ctx = yarn_orig_ctx;
+        self
+    }
+
+    /// Get the YaRN original context size of the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_orig_ctx(), 0);
+    /// ```
+    #[must_use]
+    pub fn yarn_orig_ctx(&self) -> u32 {
+        self.context_params.yarn_orig_ctx
+    }
+
     /// Get the number of threads.
     ///
     /// # Examples
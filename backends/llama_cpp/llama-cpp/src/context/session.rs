@@ -152,6 +152,44 @@ impl LlamaContext {
         unsafe { llama_cpp_sys::llama_copy_state_data(self.context.context.as_ptr(), dest) }
     }
 
+    /// The state (rng, logits, embeddings and kv cache) as bytes, see [`Self::restore_state`].
+    #[must_use]
+    pub fn state_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.get_state_size()];
+        let n = unsafe { self.copy_state_data(buf.as_mut_ptr()) };
+        buf.truncate(n);
+        buf
+    }
+
+    /// Restores a state returned by [`Self::state_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if llama.cpp reads a different number of bytes than `state` holds.
+    ///
+    /// # Safety
+    ///
+    /// `state` has to be a complete state of a context of the same model with the same
+    /// parameters. llama.cpp stops at the end of `state`, but libraries older than the sized
+    /// `llama_state_set_data` ignore its length.
+    pub unsafe fn restore_state(&mut self, state: &[u8]) -> Result<(), LoadSessionError> {
+        if state.len() > self.get_state_size() {
+            return Err(LoadSessionError::FailedToLoad);
+        }
+        let read = unsafe {
+            llama_cpp_sys::llama_state_set_data(
+                self.context.context.as_ptr(),
+                state.as_ptr(),
+                state.len(),
+            )
+        };
+        if read == state.len() {
+            Ok(())
+        } else {
+            Err(LoadSessionError::FailedToLoad)
+        }
+    }
+
     /// Set the state reading from the specified address
     /// Returns the number of bytes read
     ///
@@ -27,12 +27,26 @@ pub mod context;
 //pub mod grammar;
 pub mod llama_backend;
 pub mod llama_batch;
+pub mod logging;
 pub mod model;
+pub mod quantize;
 pub mod sample;
+pub mod stdio;
 //pub mod timing;
 pub mod token;
 pub mod token_type;
 
+pub use quantize::QuantizeError;
+
+pub use llama_cpp_sys::{
+    cpu_topology, CPUCapability, CpuTopology, DeviceInfo, DriverVersion, MemInfo, RequirementError,
+    VARIANT_PREFERENCE_ENV,
+};
+pub use llama_cpp_sys::{Error as SysError, ErrorKind as SysErrorKind};
+
+#[cfg(target_os = "android")]
+pub use llama_cpp_sys::{set_android_memory_info, AndroidMemoryInfo};
+
 /// A failable result from a llama.cpp function.
 pub type Result<T> = std::result::Result<T, LLamaCppError>;
 
@@ -47,7 +61,7 @@ pub enum LLamaCppError {
     Nul(#[from] NulError),
     #[error("{0}")]
     FromUtf8(#[from] FromUtf8Error),
-    #[error("{0}")]
+    #[error("llama.cpp needs {0} bytes for the text, the buffer has {1}")]
     InsufficientBufferSpace(i32, usize),
     /// There was an error while decoding a batch.
     #[error("{0}")]
@@ -72,9 +86,17 @@ pub enum LLamaCppError {
     TokenToString(#[from] TokenToStringError),
     #[error("{0}")]
     Sys(#[from] llama_cpp_sys::Error),
-    #[error("sampler_init_grammar")]
+    #[error("{0}")]
+    Quantize(#[from] quantize::QuantizeError),
+    /// Every sequence of the context is used by a fork already.
+    #[error("all {0} sequences of the context are in use")]
+    NoFreeSequence(usize),
+    /// A device can't run the libraries of a variant.
+    #[error("{0}")]
+    Requirement(#[from] RequirementError),
+    #[error("llama.cpp rejected the grammar")]
     SamplerInitGramar,
-    #[error("sampler_init_chain")]
+    #[error("llama.cpp could not create the sampler chain")]
     SamplerInitChain,
 }
 
@@ -88,13 +110,16 @@ pub enum ClipError {
     NullError(#[from] NulError),
     #[error("{0}")]
     Sys(#[from] llama_cpp_sys::Error),
+    /// llama.cpp could not convert a projector, details are in its log.
+    #[error("converting the projector failed")]
+    ConvertFailed,
 }
 
 /// Failed to Load context
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum LlamaContextLoadError {
     /// llama.cpp returned null
-    #[error("null reference from llama.cpp")]
+    #[error("llama.cpp could not create the context, its log has the reason")]
     NullReturn,
 }
 
@@ -102,32 +127,32 @@ pub enum LlamaContextLoadError {
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum DecodeError {
     /// No kv cache slot was available.
-    #[error("Decode Error 1: NoKvCacheSlot")]
+    #[error("llama_decode returned 1: no kv cache slot is free for the batch")]
     NoKvCacheSlot,
     /// The number of tokens in the batch was 0.
-    #[error("Decode Error -1: n_tokens == 0")]
+    #[error("llama_decode returned -1: the batch is empty")]
     NTokensZero,
     /// An unknown error occurred.
-    #[error("Decode Error {0}: unknown")]
+    #[error("llama_decode returned {0}")]
     Unknown(c_int),
     #[error("{0}")]
     StringToToken(#[from] StringToTokenError),
     #[error("{0}")]
     BatcAdd(#[from] BatchAddError),
-    #[error("")]
+    #[error("llava could not evaluate the image embedding")]
     EvalEmbedImage,
 }
 
 /// Failed to decode a batch.
 #[derive(Debug, thiserror::Error)]
 pub enum PredictError {
-    #[error("")]
+    #[error("clip could not allocate an image")]
     ClipImageU8Init,
-    #[error("")]
+    #[error("the image could not be decoded")]
     LoadImageFromBytes,
-    #[error("")]
+    #[error("clip could not embed the image")]
     ImageEmbed,
-    #[error("")]
+    #[error("images need a model with a multimodal projector")]
     ImagesWithoutMMProj,
     #[error("{0}")]
     Decode(#[from] DecodeError),
@@ -169,7 +194,7 @@ pub enum LlamaModelLoadError {
     #[error("null byte in string {0}")]
     NullError(#[from] NulError),
     /// llama.cpp returned a nullptr - this could be many different causes.
-    #[error("null result from llama cpp")]
+    #[error("llama.cpp could not load the model, its log has the reason")]
     NullResult,
     /// Failed to convert the path to a rust str. This means the path was not valid unicode
     #[error("failed to convert path {0} to str")]
@@ -191,6 +216,106 @@ pub fn llama_time_us() -> i64 {
     unsafe { llama_cpp_sys::llama_time_us() }
 }
 
+/// Load the native llama.cpp libraries now instead of on first use.
+///
+/// # Errors
+///
+/// Returns the loading error the bindings would otherwise panic with.
+pub fn load_libraries() -> Result<()> {
+    llama_cpp_sys::load()?;
+    logging::reinstall();
+    Ok(())
+}
+
+/// Like [`load_libraries`], but loads only `variants`, in this order, instead of the
+/// [`variant_preference`]. The preference is not kept for later loads.
+///
+/// # Errors
+///
+/// Returns the loading error the bindings would otherwise panic with.
+pub fn load_libraries_with_preference(variants: &[String]) -> Result<()> {
+    llama_cpp_sys::load_with_preference(variants)?;
+    logging::reinstall();
+    Ok(())
+}
+
+/// Unload the native llama.cpp libraries.
+///
+/// Every model, context and backend has to be dropped before, they are loaded again on next use.
+pub fn unload_libraries() {
+    llama_cpp_sys::unload()
+}
+
+/// Devices found on this machine, the cpu if there is no supported gpu.
+///
+/// # Errors
+///
+/// Fails if the device drivers can not be queried.
+pub fn devices() -> Result<Vec<DeviceInfo>> {
+    Ok(llama_cpp_sys::devices()?)
+}
+
+/// Total and available system memory.
+///
+/// # Errors
+///
+/// Fails if the operating system can not be queried.
+pub fn system_memory() -> Result<MemInfo> {
+    Ok(llama_cpp_sys::system_memory()?)
+}
+
+/// Library variants present in the dependencies directory.
+///
+/// # Errors
+///
+/// Fails if the device drivers can not be queried.
+pub fn available_variants() -> Result<Vec<String>> {
+    Ok(llama_cpp_sys::available_variants()?)
+}
+
+/// Checks that the drivers and gpus of this machine can run the libraries of `variant`.
+///
+/// # Errors
+///
+/// [`RequirementError`] for the first device that can't, or if the devices can not be queried.
+pub fn check_requirements(variant: &str) -> Result<()> {
+    match llama_cpp_sys::check_requirements(variant) {
+        Err(llama_cpp_sys::Error::Requirement(e)) => Err(e.into()),
+        res => Ok(res?),
+    }
+}
+
+/// Tries `variant` before all others when the libraries are loaded next.
+///
+/// Only takes effect after [`unload_libraries`] if they are loaded already.
+pub fn set_preferred_variant(variant: Option<String>) {
+    llama_cpp_sys::set_preferred_variant(variant);
+}
+
+#[must_use]
+pub fn preferred_variant() -> Option<String> {
+    llama_cpp_sys::preferred_variant()
+}
+
+/// Loads only `variants`, in this order, when the libraries are loaded next.
+///
+/// Only takes effect after [`unload_libraries`] if they are loaded already.
+pub fn set_variant_preference(variants: Option<Vec<String>>) {
+    llama_cpp_sys::set_variant_preference(variants);
+}
+
+/// The variants set with [`set_variant_preference`], or those of [`VARIANT_PREFERENCE_ENV`].
+#[must_use]
+pub fn variant_preference() -> Option<Vec<String>> {
+    llama_cpp_sys::variant_preference()
+}
+
+/// The variant the loaded libraries come from, `None` if nothing is loaded.
+#[must_use]
+pub fn loaded_variant() -> Option<String> {
+    llama_cpp_sys::loaded_variant()
+}
+
 /// get the max number of devices according to llama.cpp (this is generally cuda devices)
 /// ```
 /// # use llama_cpp_2::max_devices;
@@ -228,6 +353,12 @@ pub fn mlock_supported() -> bool {
     unsafe { llama_cpp_sys::llama_supports_mlock() }
 }
 
+/// whether the loaded variant can offload layers to a gpu
+#[must_use]
+pub fn gpu_offload_supported() -> bool {
+    unsafe { llama_cpp_sys::llama_supports_gpu_offload() }
+}
+
 /// An error that can occur when converting a token to a string.
 #[derive(Debug, thiserror::Error, Clone)]
 #[non_exhaustive]
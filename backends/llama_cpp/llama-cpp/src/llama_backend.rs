@@ -4,15 +4,28 @@ use crate::LLamaCppError;
 use llama_cpp_sys::ggml_log_level;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Mutex, MutexGuard};
 
 /// Representation of an initialized llama backend
 /// This is required as a parameter for most llama functions as the backend must be initialized
 /// before any llama functions are called. This type is proof of initialization.
 #[derive(Eq, PartialEq, Debug)]
-pub struct LlamaBackend {}
+pub struct LlamaBackend {
+    numa: NumaStrategy,
+}
 
 static LLAMA_BACKEND_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+static ALLOC_LOCK: Mutex<()> = Mutex::new(());
+
+/// Serializes the llama.cpp calls that create or free models, contexts and projectors.
+///
+/// They initialize devices and allocate backend buffers through global ggml state, which is
+/// not safe to touch from several threads at once. Decoding on distinct contexts is.
+pub(crate) fn alloc_lock() -> MutexGuard<'static, ()> {
+    ALLOC_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 impl LlamaBackend {
     /// Mark the llama backend as initialized
     fn mark_init() -> crate::Result<()> {
@@ -45,7 +58,9 @@ impl LlamaBackend {
     pub fn init() -> crate::Result<LlamaBackend> {
         Self::mark_init()?;
         unsafe { llama_cpp_sys::llama_backend_init() }
-        Ok(LlamaBackend {})
+        Ok(LlamaBackend {
+            numa: NumaStrategy::DISABLED,
+        })
     }
 
     /// Initialize the llama backend (with numa).
@@ -65,9 +80,16 @@ impl LlamaBackend {
     pub fn init_numa(strategy: NumaStrategy) -> crate::Result<LlamaBackend> {
         Self::mark_init()?;
         unsafe {
+            llama_cpp_sys::llama_backend_init();
             llama_cpp_sys::llama_numa_init(llama_cpp_sys::ggml_numa_strategy::from(strategy));
         }
-        Ok(LlamaBackend {})
+        Ok(LlamaBackend { numa: strategy })
+    }
+
+    /// The numa strategy the backend was initialized with.
+    #[must_use]
+    pub fn numa_strategy(&self) -> NumaStrategy {
+        self.numa
     }
 
     /// Change the output of llama.cpp's logging to be voided instead of pushed to `stderr`.
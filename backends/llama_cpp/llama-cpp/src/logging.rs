@@ -0,0 +1,164 @@
+//! Routing of llama.cpp's own log output into the [`log`] crate.
+//!
+//! llama.cpp hands log text to the callback in fragments, a record is emitted once a line is
+//! complete. Records use the target `llama.cpp`, anything above the configured level is
+//! dropped before it is formatted.
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter};
+
+pub const TARGET: &str = "llama.cpp";
+
+// u8::MAX while llama.cpp prints to stderr itself, otherwise a `LevelFilter` as u8
+const DEFAULT: u8 = u8::MAX;
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT);
+
+type LineHook = Box<dyn FnMut(&str) + Send>;
+static LINE_HOOK: Mutex<Option<LineHook>> = Mutex::new(None);
+// set while LINE_HOOK is, spares the callback the lock otherwise
+static HOOKED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // the unfinished line and the level it started with
+    static PENDING: RefCell<(String, Option<Level>)> = const { RefCell::new((String::new(), None)) };
+}
+
+fn level_filter(v: u8) -> LevelFilter {
+    match v {
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => LevelFilter::Off,
+    }
+}
+
+fn level(level: llama_cpp_sys::ggml_log_level) -> Option<Level> {
+    match level {
+        llama_cpp_sys::GGML_LOG_LEVEL_ERROR => Some(Level::Error),
+        llama_cpp_sys::GGML_LOG_LEVEL_WARN => Some(Level::Warn),
+        llama_cpp_sys::GGML_LOG_LEVEL_INFO => Some(Level::Info),
+        llama_cpp_sys::GGML_LOG_LEVEL_DEBUG => Some(Level::Debug),
+        // continuation of the previous fragment
+        _ => None,
+    }
+}
+
+unsafe extern "C" fn log_callback(
+    ggml_level: llama_cpp_sys::ggml_log_level,
+    text: *const c_char,
+    _user_data: *mut c_void,
+) {
+    if text.is_null() {
+        return;
+    }
+    let max_level = MAX_LEVEL.load(Ordering::Relaxed);
+    let max = level_filter(max_level);
+    let hooked = HOOKED.load(Ordering::Relaxed);
+    let text = CStr::from_ptr(text).to_string_lossy();
+    let _ = PENDING.try_with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let (buf, lvl) = &mut *pending;
+        if let Some(l) = level(ggml_level) {
+            if buf.is_empty() {
+                *lvl = Some(l);
+            }
+        }
+        let l = lvl.unwrap_or(Level::Info);
+        if l > max && !hooked {
+            if text.ends_with('\n') {
+                buf.clear();
+                *lvl = None;
+            }
+            return;
+        }
+        buf.push_str(&text);
+        if buf.ends_with('\n') {
+            if hooked {
+                if let Some(hook) = LINE_HOOK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+                    hook(buf.trim_end());
+                }
+            }
+            if max_level == DEFAULT {
+                // only installed for the hook, keep printing like llama.cpp does
+                eprint!("{buf}");
+            } else if l <= max {
+                log::log!(target: TARGET, l, "{}", buf.trim_end());
+            }
+            buf.clear();
+            *lvl = None;
+        }
+    });
+}
+
+fn install() {
+    unsafe { llama_cpp_sys::llama_log_set(Some(log_callback), std::ptr::null_mut()) }
+}
+
+/// Sends llama.cpp log lines up to `max_level` to [`log`], `LevelFilter::Off` drops all of them.
+pub fn set_max_level(max_level: LevelFilter) {
+    MAX_LEVEL.store(max_level as u8, Ordering::Relaxed);
+    install();
+}
+
+/// Lets llama.cpp print to stderr again.
+pub fn reset() {
+    MAX_LEVEL.store(DEFAULT, Ordering::Relaxed);
+    unsafe { llama_cpp_sys::llama_log_set(None, std::ptr::null_mut()) }
+}
+
+/// The configured maximum level, `None` if llama.cpp prints to stderr.
+#[must_use]
+pub fn max_level() -> Option<LevelFilter> {
+    match MAX_LEVEL.load(Ordering::Relaxed) {
+        DEFAULT => None,
+        v => Some(level_filter(v)),
+    }
+}
+
+/// Runs `f` while every complete log line is also passed to `hook`, whatever the level.
+pub(crate) fn with_line_hook<R>(
+    hook: impl FnMut(&str) + Send + 'static,
+    f: impl FnOnce() -> R,
+) -> R {
+    *LINE_HOOK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    HOOKED.store(true, Ordering::Relaxed);
+    install();
+    let res = f();
+    HOOKED.store(false, Ordering::Relaxed);
+    *LINE_HOOK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    if max_level().is_none() {
+        reset();
+    }
+    res
+}
+
+/// Installs the callback again in freshly loaded libraries.
+pub(crate) fn reinstall() {
+    if max_level().is_some() {
+        install();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn level_filter_round_trip() {
+        for l in [
+            LevelFilter::Off,
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ] {
+            assert_eq!(level_filter(l as u8), l);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 //! A safe wrapper around `llama_model`.
 use std::ffi::CString;
-use std::os::raw::c_int;
+use std::os::raw::{c_char, c_int};
 use std::path::Path;
 use std::ptr::NonNull;
 use std::sync::Arc;
@@ -28,6 +28,7 @@ unsafe impl Sync for LlamaModelInternal {}
 
 impl Drop for LlamaModelInternal {
     fn drop(&mut self) {
+        let _lock = crate::llama_backend::alloc_lock();
         unsafe { llama_cpp_sys::llama_free_model(self.model.as_ptr()) }
     }
 }
@@ -160,6 +161,21 @@ impl LlamaModel {
         &self,
         str: &str,
         add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.str_to_token_with_special(str, add_bos, true)
+    }
+
+    /// Convert a string to a Vector of tokens, parsing special tokens like `<|im_start|>` only
+    /// if `parse_special` is set. Otherwise they are tokenized as plain text.
+    ///
+    /// # Errors
+    ///
+    /// - if [`str`] contains a null byte.
+    pub fn str_to_token_with_special(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+        parse_special: bool,
     ) -> Result<Vec<LlamaToken>, StringToTokenError> {
         let add_bos = match add_bos {
             AddBos::Always => true,
@@ -181,7 +197,7 @@ impl LlamaModel {
                 buffer.as_mut_ptr(),
                 buffer_capacity,
                 add_bos,
-                true,
+                parse_special,
             )
         };
 
@@ -197,7 +213,7 @@ impl LlamaModel {
                     buffer.as_mut_ptr(),
                     -size,
                     add_bos,
-                    false,
+                    parse_special,
                 )
             }
         } else {
@@ -291,6 +307,55 @@ impl LlamaModel {
             }
         }
     }
+    /// The raw bytes of a token's text.
+    ///
+    /// Unlike [`LlamaModel::token_to_str`] this also decodes byte fallback tokens and pieces
+    /// holding only a part of a multi-byte character, which are not valid utf8 on their own.
+    /// Use [`crate::token::utf8::Utf8Decoder`] to join them into text.
+    ///
+    /// # Errors
+    ///
+    /// - if the token type is unknown
+    pub fn token_to_bytes(
+        &self,
+        token: &LlamaToken,
+        special: bool,
+    ) -> Result<Vec<u8>, TokenToStringError> {
+        match self.token_type(token) {
+            LlamaTokenType::Normal | LlamaTokenType::UserDefined | LlamaTokenType::Byte => {}
+            LlamaTokenType::Control => {
+                if token == &self.token_bos() || token == &self.token_eos() {
+                    return Ok(vec![]);
+                }
+            }
+            LlamaTokenType::Unknown | LlamaTokenType::Undefined | LlamaTokenType::Unused => {
+                return Ok(vec![]);
+            }
+        }
+        let mut buf = vec![0u8; 32];
+        loop {
+            let len = c_int::try_from(buf.len()).expect("length fits into c_int");
+            let size = unsafe {
+                llama_cpp_sys::llama_token_to_piece(
+                    self.model.model.as_ptr(),
+                    token.0,
+                    buf.as_mut_ptr().cast::<c_char>(),
+                    len,
+                    special,
+                )
+            };
+            match size {
+                0 => return Err(TokenToStringError::UnknownTokenType),
+                // the negated size the piece needs
+                i if i.is_negative() => buf.resize(i.unsigned_abs() as usize, 0),
+                size => {
+                    buf.truncate(usize::try_from(size).expect("size is positive"));
+                    return Ok(buf);
+                }
+            }
+        }
+    }
+
     /// The number of tokens the model was trained on.
     ///
     /// This returns a `c_int` for maximum compatibility. Most of the time it can be cast to an i32
@@ -356,21 +421,15 @@ impl LlamaModel {
         path: impl AsRef<Path>,
         params: &LlamaModelParams,
     ) -> Result<Self, LlamaModelLoadError> {
-        let path = path.as_ref();
-        debug_assert!(Path::new(path).exists(), "{path:?} does not exist");
-        let path = path
-            .to_str()
-            .ok_or(LlamaModelLoadError::PathToStrError(path.to_path_buf()))?;
-
-        let cstr = CString::new(path)?;
-        #[cfg(any(target_os = "linux", target_os = "macos"))]
-        let guard = stdio_override::StderrOverride::from_file("/dev/null").unwrap();
-        #[cfg(target_os = "windows")]
-        let guard = gag::Gag::stderr().unwrap();
+        let path = llama_cpp_sys::path::normalize(path);
+        debug_assert!(path.exists(), "{path:?} does not exist");
+        let cstr = llama_cpp_sys::path::to_cstring(&path)?;
+        let lock = crate::llama_backend::alloc_lock();
+        let guard = crate::stdio::redirect();
         let llama_model =
             unsafe { llama_cpp_sys::llama_load_model_from_file(cstr.as_ptr(), params.params) };
-        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
         drop(guard);
+        drop(lock);
         let model = NonNull::new(llama_model).ok_or(LlamaModelLoadError::NullResult)?;
 
         tracing::debug!(?path, "Loaded model");
@@ -18,6 +18,8 @@ extern "C" fn do_something_handler(x: f32, arg: *mut std::ffi::c_void) -> bool {
 pub struct LlamaModelParams {
     pub(crate) params: llama_cpp_sys::llama_model_params,
     kv_overrides: Vec<llama_cpp_sys::llama_model_kv_override>,
+    // owns `params.progress_callback_user_data`
+    progress_callback: Option<Box<Box<dyn FnMut(f32) -> bool>>>,
 }
 
 impl Debug for LlamaModelParams {
@@ -173,6 +175,13 @@ impl LlamaModelParams {
         self
     }
 
+    /// sets `use_mmap`
+    #[must_use]
+    pub fn with_use_mmap(mut self, use_mmap: bool) -> Self {
+        self.params.use_mmap = use_mmap;
+        self
+    }
+
     /// sets `use_mlock`
     #[must_use]
     pub fn with_use_mlock(mut self, use_mlock: bool) -> Self {
@@ -180,16 +189,18 @@ impl LlamaModelParams {
         self
     }
 
-    /// sets `use_mlock`
+    /// sets the load progress callback, it receives the progress between 0 and 1 and
+    /// aborts the load by returning `false`
     #[must_use]
     pub fn with_load_process_callback<F>(mut self, callback: F) -> Self
     where
         F: FnMut(f32) -> bool,
         F: 'static,
     {
-        let cb: Box<Box<dyn FnMut(f32) -> bool>> = Box::new(Box::new(callback));
+        let mut cb: Box<Box<dyn FnMut(f32) -> bool>> = Box::new(Box::new(callback));
         self.params.progress_callback = Some(do_something_handler);
-        self.params.progress_callback_user_data = Box::into_raw(cb) as *mut _;
+        self.params.progress_callback_user_data = std::ptr::addr_of_mut!(*cb) as *mut _;
+        self.progress_callback = Some(cb);
         self
     }
 }
@@ -216,6 +227,7 @@ impl Default for LlamaModelParams {
                     val_i64: 0,
                 },
             }],
+            progress_callback: None,
         }
     }
 }
@@ -0,0 +1,178 @@
+//! A safe wrapper around `llama_model_quantize`.
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub use llama_cpp_sys::{
+    llama_ftype, LLAMA_FTYPE_ALL_F32, LLAMA_FTYPE_MOSTLY_BF16, LLAMA_FTYPE_MOSTLY_F16,
+    LLAMA_FTYPE_MOSTLY_Q2_K, LLAMA_FTYPE_MOSTLY_Q2_K_S, LLAMA_FTYPE_MOSTLY_Q3_K_L,
+    LLAMA_FTYPE_MOSTLY_Q3_K_M, LLAMA_FTYPE_MOSTLY_Q3_K_S, LLAMA_FTYPE_MOSTLY_Q4_0,
+    LLAMA_FTYPE_MOSTLY_Q4_1, LLAMA_FTYPE_MOSTLY_Q4_K_M, LLAMA_FTYPE_MOSTLY_Q4_K_S,
+    LLAMA_FTYPE_MOSTLY_Q5_0, LLAMA_FTYPE_MOSTLY_Q5_1, LLAMA_FTYPE_MOSTLY_Q5_K_M,
+    LLAMA_FTYPE_MOSTLY_Q5_K_S, LLAMA_FTYPE_MOSTLY_Q6_K, LLAMA_FTYPE_MOSTLY_Q8_0,
+};
+
+/// Failed to quantize a model.
+#[derive(Debug, thiserror::Error)]
+pub enum QuantizeError {
+    #[error("{0:?} and {1:?} are the same file")]
+    SameFile(PathBuf, PathBuf),
+    /// llama.cpp returned a non zero status, details are in its log.
+    #[error("quantization failed with status {0}")]
+    Failed(u32),
+    #[error("{0}")]
+    Sys(#[from] llama_cpp_sys::Error),
+}
+
+/// A safe wrapper around `llama_model_quantize_params`.
+#[derive(Debug)]
+pub struct LlamaQuantizeParams {
+    pub(crate) params: llama_cpp_sys::llama_model_quantize_params,
+}
+
+impl Default for LlamaQuantizeParams {
+    fn default() -> Self {
+        Self {
+            params: unsafe { llama_cpp_sys::llama_model_quantize_default_params() },
+        }
+    }
+}
+
+impl LlamaQuantizeParams {
+    /// The target type, one of the `LLAMA_FTYPE_*` constants.
+    #[must_use]
+    pub fn with_ftype(mut self, ftype: llama_ftype) -> Self {
+        self.params.ftype = ftype;
+        self
+    }
+
+    /// Threads used, `0` uses all hardware threads.
+    #[must_use]
+    pub fn with_n_threads(mut self, n_threads: i32) -> Self {
+        self.params.nthread = n_threads;
+        self
+    }
+
+    /// Allow quantizing tensors that are quantized already.
+    #[must_use]
+    pub fn with_allow_requantize(mut self, allow_requantize: bool) -> Self {
+        self.params.allow_requantize = allow_requantize;
+        self
+    }
+
+    /// Quantize `output.weight` too.
+    #[must_use]
+    pub fn with_quantize_output_tensor(mut self, quantize_output_tensor: bool) -> Self {
+        self.params.quantize_output_tensor = quantize_output_tensor;
+        self
+    }
+
+    /// Quantize all tensors to the target type, instead of keeping sensitive ones at a higher
+    /// precision as the k-quant mixes do.
+    #[must_use]
+    pub fn with_pure(mut self, pure: bool) -> Self {
+        self.params.pure = pure;
+        self
+    }
+
+    #[must_use]
+    pub fn ftype(&self) -> llama_ftype {
+        self.params.ftype
+    }
+
+    #[must_use]
+    pub fn n_threads(&self) -> i32 {
+        self.params.nthread
+    }
+}
+
+// llama.cpp prints "[  12/ 291] blk.0.attn_k.weight - ..." before every tensor
+fn parse_progress(line: &str) -> Option<(usize, usize)> {
+    let (counts, _) = line.trim_start().strip_prefix('[')?.split_once(']')?;
+    let (done, total) = counts.split_once('/')?;
+    Some((done.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+/// Quantizes the GGUF model at `input` into `output`.
+///
+/// `progress` is called with the index of the tensor being processed and the number of
+/// tensors, as reported in llama.cpp's log. Quantizations run one at a time.
+///
+/// # Errors
+///
+/// Fails if the paths can't be passed to llama.cpp or llama.cpp reports an error.
+pub fn model_quantize(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    params: &LlamaQuantizeParams,
+    mut progress: impl FnMut(usize, usize) + Send + 'static,
+) -> Result<(), QuantizeError> {
+    static QUANTIZE: Mutex<()> = Mutex::new(());
+
+    let input = llama_cpp_sys::path::normalize(input);
+    let output = llama_cpp_sys::path::normalize(output);
+    if is_same_file(&input, &output) {
+        return Err(QuantizeError::SameFile(input, output));
+    }
+    let c_input = llama_cpp_sys::path::to_cstring(&input)?;
+    let c_output = llama_cpp_sys::path::to_cstring(&output)?;
+    let _lock = QUANTIZE.lock().unwrap_or_else(|e| e.into_inner());
+    let status = crate::logging::with_line_hook(
+        move |line| {
+            if let Some((done, total)) = parse_progress(line) {
+                progress(done, total);
+            }
+        },
+        || unsafe {
+            llama_cpp_sys::llama_model_quantize(c_input.as_ptr(), c_output.as_ptr(), &params.params)
+        },
+    );
+    match status {
+        0 => Ok(()),
+        s => Err(QuantizeError::Failed(s)),
+    }
+}
+
+/// Whether `a` and `b` name the same file, also through symlinks, `..` or a relative path.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        // a file that doesn't exist yet is a different one than an existing file
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_same_file, parse_progress};
+
+    #[test]
+    fn progress_lines() {
+        assert_eq!(
+            parse_progress("[  12/ 291]                  blk.0.attn_k.weight - [ 2048,   256]"),
+            Some((12, 291))
+        );
+        assert_eq!(
+            parse_progress("llama_model_quantize_internal: meta size"),
+            None
+        );
+        assert_eq!(parse_progress("[ ab/ 291]"), None);
+    }
+
+    #[test]
+    fn same_file() {
+        let dir = std::env::temp_dir().join("llama_cpp_quantize_same_file");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let model = dir.join("model.gguf");
+        std::fs::write(&model, b"GGUF").unwrap();
+        assert!(is_same_file(&model, &dir.join("sub/../model.gguf")));
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.gguf");
+            let _ = std::fs::remove_file(&link);
+            std::os::unix::fs::symlink(&model, &link).unwrap();
+            assert!(is_same_file(&model, &link));
+        }
+        assert!(!is_same_file(&model, &dir.join("model_q4_0.gguf")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
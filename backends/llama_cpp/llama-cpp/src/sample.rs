@@ -6,6 +6,9 @@ use crate::{
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use std::{ffi::CString, ptr::NonNull};
 
+/// Seed value asking llama.cpp to pick a random seed.
+pub const DEFAULT_SEED: u32 = llama_cpp_sys::LLAMA_DEFAULT_SEED;
+
 #[derive(Debug, Clone)]
 pub enum SamplerType {
     None = 0,
@@ -19,7 +22,7 @@ pub enum SamplerType {
 
 #[derive(Debug, Clone, bon::Builder)]
 pub struct SamplingParams {
-    #[builder(default = llama_cpp_sys::LLAMA_DEFAULT_SEED)]
+    #[builder(default = DEFAULT_SEED)]
     pub seed: u32,
     #[builder(default = 64)]
     pub n_prev: i32,
@@ -82,6 +85,17 @@ impl Default for SamplingParams {
     }
 }
 
+impl SamplingParams {
+    /// Add `bias` to the logit of `token` before any other sampler runs,
+    /// `f32::NEG_INFINITY` bans the token.
+    pub fn add_logit_bias(&mut self, token: LlamaToken, bias: f32) {
+        self.logit_bias.push(llama_cpp_sys::llama_logit_bias {
+            token: token.0,
+            bias,
+        });
+    }
+}
+
 #[derive(Debug)]
 pub struct Sampler {
     params: SamplingParams,
@@ -312,9 +326,9 @@ impl Sampler {
         Ok(())
     }
 
-    fn set_logits(&mut self, ctx: &LlamaContext, index: i32) -> crate::Result<()> {
+    fn set_logits(&mut self, logits: &[f32]) -> crate::Result<()> {
         let cur = (0_i32..)
-            .zip(ctx.get_logits_ith(index))
+            .zip(logits)
             .map(|(i, logit)| {
                 let token = LlamaToken::new(i);
                 LlamaTokenData::new(token, *logit, 0_f32)
@@ -330,7 +344,17 @@ impl Sampler {
         index: i32,
         grammar_first: bool,
     ) -> crate::Result<LlamaToken> {
-        self.set_logits(ctx, index)?;
+        self.sample_logits(ctx.get_logits_ith(index), grammar_first)
+    }
+
+    /// Samples from `logits` instead of the logits of a context, e.g. after blending them
+    /// with the logits of another sequence.
+    pub fn sample_logits(
+        &mut self,
+        logits: &[f32],
+        grammar_first: bool,
+    ) -> crate::Result<LlamaToken> {
+        self.set_logits(logits)?;
         if grammar_first {
             unsafe {
                 self.cur_p.modify_as_c_llama_token_data_array(|t| {
@@ -365,7 +389,7 @@ impl Sampler {
             }
         }
 
-        self.set_logits(ctx, index)?;
+        self.set_logits(logits)?;
         unsafe {
             self.cur_p.modify_as_c_llama_token_data_array(|t| {
                 llama_cpp_sys::llama_sampler_apply(self.grmr.as_mut(), t)
@@ -0,0 +1,189 @@
+//! What happens to the output llama.cpp and llava print straight to stderr and stdout.
+//!
+//! Redirecting a stream swaps the process wide file descriptor, so while a redirection is active
+//! the host application's own output is affected as well. A [`StdioPolicy`] per stream decides
+//! whether that window is silenced, captured for later inspection or left alone.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// How output of the native libraries is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StdioPolicy {
+    /// Discard everything written to the stream while llama.cpp is loading.
+    #[default]
+    Silence,
+    /// Collect everything written to the stream while llama.cpp is loading, see [`captured`].
+    Capture,
+    /// Do not touch the stream at all.
+    PassThrough,
+}
+
+impl StdioPolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            x if x == StdioPolicy::Capture as u8 => StdioPolicy::Capture,
+            x if x == StdioPolicy::PassThrough as u8 => StdioPolicy::PassThrough,
+            _ => StdioPolicy::Silence,
+        }
+    }
+}
+
+// llava prints its image encoding progress to stdout on windows
+#[cfg(target_os = "windows")]
+const STDOUT_DEFAULT: StdioPolicy = StdioPolicy::Silence;
+#[cfg(not(target_os = "windows"))]
+const STDOUT_DEFAULT: StdioPolicy = StdioPolicy::PassThrough;
+
+static POLICY: AtomicU8 = AtomicU8::new(StdioPolicy::Silence as u8);
+static STDOUT_POLICY: AtomicU8 = AtomicU8::new(STDOUT_DEFAULT as u8);
+static CAPTURED: Mutex<String> = Mutex::new(String::new());
+// the descriptors are process wide, overlapping redirections would restore each other's
+static REDIRECT: Mutex<()> = Mutex::new(());
+static CAPTURE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the stderr policy used by every later model, context and projector load.
+pub fn set_policy(policy: StdioPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+#[must_use]
+pub fn policy() -> StdioPolicy {
+    StdioPolicy::from_u8(POLICY.load(Ordering::SeqCst))
+}
+
+/// Sets the stdout policy used while images are embedded.
+///
+/// The default silences stdout on windows, where llava prints to it, and passes it through
+/// everywhere else.
+pub fn set_stdout_policy(policy: StdioPolicy) {
+    STDOUT_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+#[must_use]
+pub fn stdout_policy() -> StdioPolicy {
+    StdioPolicy::from_u8(STDOUT_POLICY.load(Ordering::SeqCst))
+}
+
+/// Returns everything captured so far under [`StdioPolicy::Capture`], from both streams.
+#[must_use]
+pub fn captured() -> String {
+    CAPTURED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Returns everything captured so far and clears the buffer.
+pub fn take_captured() -> String {
+    std::mem::take(&mut *CAPTURED.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(target_os = "windows")]
+const NULL_DEVICE: &str = "nul";
+#[cfg(not(target_os = "windows"))]
+const NULL_DEVICE: &str = "/dev/null";
+
+/// Applies the current policies until dropped.
+pub(crate) struct Redirect {
+    stderr: Option<stdio_override::StderrOverride>,
+    stdout: Option<stdio_override::StdoutOverride>,
+    captures: Vec<PathBuf>,
+    _lock: Option<MutexGuard<'static, ()>>,
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        drop(self.stderr.take());
+        drop(self.stdout.take());
+        for path in self.captures.drain(..) {
+            if let Ok(bytes) = std::fs::read(&path) {
+                CAPTURED
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push_str(&String::from_utf8_lossy(&bytes));
+            }
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Where a stream goes under `policy`, `None` leaves it untouched.
+fn target(policy: StdioPolicy, stream: &str) -> Option<(PathBuf, bool)> {
+    match policy {
+        StdioPolicy::PassThrough => None,
+        StdioPolicy::Silence => Some((PathBuf::from(NULL_DEVICE), false)),
+        StdioPolicy::Capture => Some((
+            std::env::temp_dir().join(format!(
+                "llama-cpp-{stream}-{}-{}.log",
+                std::process::id(),
+                CAPTURE_ID.fetch_add(1, Ordering::SeqCst)
+            )),
+            true,
+        )),
+    }
+}
+
+/// Redirects stderr according to [`policy`]. Failing to redirect leaves stderr untouched.
+pub(crate) fn redirect() -> Redirect {
+    redirect_streams(policy(), StdioPolicy::PassThrough)
+}
+
+/// Redirects stderr according to [`policy`] and stdout according to [`stdout_policy`].
+pub(crate) fn redirect_with_stdout() -> Redirect {
+    redirect_streams(policy(), stdout_policy())
+}
+
+fn redirect_streams(stderr: StdioPolicy, stdout: StdioPolicy) -> Redirect {
+    let mut redirect = Redirect {
+        stderr: None,
+        stdout: None,
+        captures: Vec::new(),
+        _lock: None,
+    };
+    if stderr == StdioPolicy::PassThrough && stdout == StdioPolicy::PassThrough {
+        return redirect;
+    }
+    redirect._lock = Some(REDIRECT.lock().unwrap_or_else(|e| e.into_inner()));
+    if let Some((path, capture)) = target(stderr, "stderr") {
+        match stdio_override::StderrOverride::from_file(&path) {
+            Ok(guard) => {
+                redirect.stderr = Some(guard);
+                if capture {
+                    redirect.captures.push(path);
+                }
+            }
+            Err(e) => tracing::warn!("could not redirect stderr: {e}"),
+        }
+    }
+    if let Some((path, capture)) = target(stdout, "stdout") {
+        match stdio_override::StdoutOverride::from_file(&path) {
+            Ok(guard) => {
+                redirect.stdout = Some(guard);
+                if capture {
+                    redirect.captures.push(path);
+                }
+            }
+            Err(e) => tracing::warn!("could not redirect stdout: {e}"),
+        }
+    }
+    redirect
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy_round_trip() {
+        for p in [
+            StdioPolicy::PassThrough,
+            StdioPolicy::Capture,
+            StdioPolicy::Silence,
+        ] {
+            set_policy(p);
+            assert_eq!(policy(), p);
+            set_stdout_policy(p);
+            assert_eq!(stdout_policy(), p);
+        }
+        set_policy(StdioPolicy::default());
+        set_stdout_policy(STDOUT_DEFAULT);
+    }
+}
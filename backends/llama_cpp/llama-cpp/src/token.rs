@@ -5,6 +5,7 @@ use std::fmt::Display;
 
 pub mod data;
 pub mod data_array;
+pub mod utf8;
 
 /// A safe wrapper for `llama_token`.
 #[repr(transparent)]
@@ -0,0 +1,115 @@
+//! Joining token bytes into valid utf8 text.
+
+/// Buffers the bytes of multi-byte characters split over several tokens.
+///
+/// Byte fallback tokens and the pieces of some tokenizers hold only a part of a character,
+/// e.g. an emoji or a CJK character is often generated as 2 to 4 tokens.
+#[derive(Debug, Default, Clone)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the bytes of the next token and returns the text that is complete now.
+    ///
+    /// Bytes that can't start or continue a character are replaced with U+FFFD, the start of
+    /// an unfinished character is held back until the next call.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut out = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    out.push_str(s);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // SAFETY: from_utf8 validated the bytes up to valid_up_to
+                    out.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+                    match e.error_len() {
+                        Some(n) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[n..];
+                        }
+                        // the input ends in the middle of a character
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        out
+    }
+
+    /// Whether the start of a character is held back.
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Returns the held back bytes of an unfinished character as U+FFFD and resets the decoder.
+    pub fn finish(&mut self) -> String {
+        let out = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Utf8Decoder;
+
+    // feeds `text` one byte at a time, as byte fallback tokens do
+    fn bytewise(text: &str) -> Vec<String> {
+        let mut decoder = Utf8Decoder::new();
+        let mut chunks: Vec<String> = text.bytes().map(|b| decoder.push(&[b])).collect();
+        chunks.push(decoder.finish());
+        chunks
+    }
+
+    #[test]
+    fn cjk() {
+        let text = "你好，世界！こんにちは";
+        let chunks = bytewise(text);
+        assert_eq!(chunks.concat(), text);
+        assert!(chunks
+            .iter()
+            .filter(|c| !c.is_empty())
+            .all(|c| c.chars().count() == 1));
+    }
+
+    #[test]
+    fn emoji() {
+        let text = "ok 👍🏽 🦀🦀 👨‍👩‍👧";
+        assert_eq!(bytewise(text).concat(), text);
+
+        // pieces ending and starting in the middle of characters
+        let bytes = "🦀é🦀".as_bytes();
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.push(&bytes[..2]), "");
+        assert!(decoder.is_pending());
+        assert_eq!(decoder.push(&bytes[2..5]), "🦀");
+        assert_eq!(decoder.push(&bytes[5..]), "é🦀");
+        assert!(!decoder.is_pending());
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.push(b"a\xffb"), "a\u{fffd}b");
+        assert_eq!(decoder.push(b"\xe4\xbd"), "");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+        assert!(!decoder.is_pending());
+    }
+}
@@ -44,7 +44,8 @@ impl TryFrom<llama_cpp_sys::llama_token_attr> for LlamaTokenType {
     type Error = LlamaTokenTypeFromIntError;
 
     fn try_from(value: llama_cpp_sys::llama_vocab_type) -> Result<Self, Self::Error> {
-        match value {
+        // the upper bits are modifiers (normalized, lstrip, rstrip, single word)
+        match value & (llama_cpp_sys::LLAMA_TOKEN_ATTR_NORMALIZED - 1) {
             llama_cpp_sys::LLAMA_TOKEN_ATTR_UNDEFINED => Ok(LlamaTokenType::Undefined),
             llama_cpp_sys::LLAMA_TOKEN_ATTR_NORMAL => Ok(LlamaTokenType::Normal),
             llama_cpp_sys::LLAMA_TOKEN_ATTR_UNKNOWN => Ok(LlamaTokenType::Unknown),
@@ -4,7 +4,7 @@ use super::EmbeddingsBackend;
 
 use anyhow::Error as E;
 use std::path::{Path, PathBuf};
-use hf_hub::{api::sync::Api, Repo, RepoType};
+use hf_hub::{Repo, RepoType};
 use candle_transformers::models::jina_bert::{
     BertModel as JinaBertModel, Config as JinaBertConfig
 };
@@ -71,7 +71,7 @@ impl JinaBertBackend {
 }
 
 fn get_jina_bert_model_source_from_hugging_face_repo(key: String) -> PathBuf {
-    let model_source = Api::new()
+    let model_source = crate::paths::hub_api()
         .unwrap()
         .repo(Repo::new(
             key,
@@ -83,7 +83,7 @@ fn get_jina_bert_model_source_from_hugging_face_repo(key: String) -> PathBuf {
 }
 
 fn get_jina_bert_tokenizer_source_from_hugging_face_repo(key: String) -> PathBuf {
-    let tokenizer_source = Api::new()
+    let tokenizer_source = crate::paths::hub_api()
         .unwrap()
         .repo(Repo::new(
             key,
@@ -249,7 +249,7 @@ impl T5Backend {
 
 fn get_t5_model_source_from_hugging_face_repo(key: String, revision: String) -> Vec<PathBuf> {
     let repo = Repo::with_revision(key.clone(), RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let repo = api.repo(repo);
     if key == "google/flan-t5-xxl".to_string() || key == "google/flan-ul2".to_string() {
         hub_load_safetensors(&repo, "model.safetensors.index.json").unwrap()
@@ -260,7 +260,7 @@ fn get_t5_model_source_from_hugging_face_repo(key: String, revision: String) ->
 
 fn get_t5_tokenizer_source_from_hugging_face_repo(key: String, revision: String) -> PathBuf {
     let repo = Repo::with_revision(key.clone(), RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let repo = api.repo(repo);
     if key == "google/mt5-base".to_string() {
         api
@@ -287,7 +287,7 @@ fn get_t5_config_source_from_hugging_face_repo(key: String, revision: String) ->
     println!("Key: {}", key);
     println!("Revision: {}", revision);
     let repo = Repo::with_revision(key.clone(), RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let repo = api.repo(repo);
     repo.get("config.json").unwrap()
 }
@@ -434,7 +434,7 @@ impl BertBackend {
 
 fn get_bert_model_from_hugging_face_repo(key: String, revision: String) -> PathBuf {
     let repo = Repo::with_revision(key, RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let api = api.repo(repo);
     let model = api.get("model.safetensors").unwrap();
     model
@@ -442,7 +442,7 @@ fn get_bert_model_from_hugging_face_repo(key: String, revision: String) -> PathB
 
 fn get_bert_tokernizer_from_hugging_face_repo(key: String, revision: String) -> PathBuf {
     let repo = Repo::with_revision(key, RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let api = api.repo(repo);
     let tokernizer = api.get("tokenizer.json").unwrap();
     tokernizer
@@ -450,7 +450,7 @@ fn get_bert_tokernizer_from_hugging_face_repo(key: String, revision: String) ->
 
 fn get_bert_config_from_hugging_face_repo(key: String, revision: String) -> PathBuf {
     let repo = Repo::with_revision(key, RepoType::Model, revision);
-    let api = Api::new().unwrap();
+    let api = crate::paths::hub_api().unwrap();
     let api = api.repo(repo);
     let config = api.get("config.json").unwrap();
     config
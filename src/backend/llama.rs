@@ -1,35 +1,194 @@
 #![allow(clippy::too_many_arguments)]
 use std::{
-    borrow::Cow,
-    io::{BufWriter, Write},
-    num::NonZeroU32,
+    num::{NonZeroU32, NonZeroU8},
     path::{Path, PathBuf},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
 };
 
 use crate::{
-    options::{ContextOptions, Message, ModelOptions, PredictOptions, Role},
+    gguf::{Architecture, Capabilities, Gguf, Value},
+    health::{Health, Placement, Warmup},
+    options::{
+        ContextOptions, CustomSampler, EmbeddingOptions, FinishReason, LoadFallback, Message,
+        ModelOptions, NumaStrategy, PredictOptions, ProjectorOptions, ProjectorPrecision,
+        PromptProgressCallback, QuantType, QuantizeOptions, Role, Token, Usage,
+    },
+    template::{self, Templated},
     Result,
 };
 use llama_cpp::{
-    clip::ClipContext,
+    clip::{self, ClipContext, ClipType},
     context::params::LlamaContextParams,
     llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
     model::{params::LlamaModelParams, AddBos, LlamaModel},
-    sample::Sampler,
+    quantize::LlamaQuantizeParams,
+    sample::{Sampler, SamplingParams, DEFAULT_SEED},
     token::LlamaToken,
+    token_type::LlamaTokenType,
+    DecodeError,
 };
 
-use super::{Context, Model};
+use super::{sequence::SequenceDecoder, Context, Model};
 
 lazy_static::lazy_static! {
-    static ref LLAMA_BACKEND: Arc<LlamaBackend> = Arc::new(LlamaBackend::init().unwrap());
+    static ref LLAMA_BACKEND: Mutex<Option<Arc<LlamaBackend>>> = Mutex::new(None);
 }
 
-impl From<ModelOptions> for LlamaModelParams {
-    fn from(val: ModelOptions) -> Self {
-        let lmp = Self::default();
+fn backend(numa: NumaStrategy) -> Result<Arc<LlamaBackend>> {
+    let mut backend = LLAMA_BACKEND.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(b) = &*backend {
+        if numa != NumaStrategy::Disabled && b.numa_strategy() != numa.into() {
+            return Err(crate::error::Error::Unsupported(
+                "the NUMA strategy can only be chosen by the first model loaded",
+            ));
+        }
+        return Ok(b.clone());
+    }
+    llama_cpp::load_libraries()?;
+    let b = Arc::new(match numa {
+        NumaStrategy::Disabled => LlamaBackend::init()?,
+        numa => LlamaBackend::init_numa(numa.into())?,
+    });
+    *backend = Some(b.clone());
+    Ok(b)
+}
+
+fn check_capabilities(options: &ModelOptions) -> Result<()> {
+    if options.numa_strategy != NumaStrategy::Disabled && !cfg!(target_os = "linux") {
+        return Err(crate::error::Error::Unsupported(
+            "NUMA strategies are only supported on Linux",
+        ));
+    }
+    if !options.backend_preference.is_empty() {
+        // only this load, the global preference stays as it is
+        crate::devices::check_backend_preference(&options.backend_preference)?;
+        llama_cpp::load_libraries_with_preference(&options.backend_preference)?;
+    } else {
+        if let Some(env) = llama_cpp::variant_preference() {
+            // a typo in the environment variable shouldn't silently load nothing
+            crate::devices::check_backend_preference(&env)?;
+        }
+        llama_cpp::load_libraries()?;
+    }
+    if options.use_mmap == Some(true) && !llama_cpp::mmap_supported() {
+        return Err(crate::error::Error::Unsupported(
+            "mmap is not supported on this platform, set use_mmap to false",
+        ));
+    }
+    if options.use_mlock && !llama_cpp::mlock_supported() {
+        return Err(crate::error::Error::Unsupported(
+            "mlock is not supported on this platform, set use_mlock to false",
+        ));
+    }
+    Ok(())
+}
+
+/// The `n_gpu_layers` to try, in order, when the model fails to load as configured.
+fn fallback_layers(options: &ModelOptions, path: &Path, metadata: &OnceLock<Gguf>) -> Vec<u32> {
+    if options.cpu || options.n_gpu_layers == 0 {
+        return vec![];
+    }
+    match options.fallback {
+        LoadFallback::None => vec![],
+        LoadFallback::Cpu => vec![0],
+        LoadFallback::FewerLayers => {
+            let n_layers = if options.n_gpu_layers > 0 {
+                options.n_gpu_layers as u32
+            } else {
+                // all layers, the output layer counts as one more
+                match crate::gguf::read(path) {
+                    Ok(header) => {
+                        let n_layers = header
+                            .arch_value("block_count")
+                            .and_then(Value::as_u64)
+                            .map_or(0, |n| n as u32 + 1);
+                        let _ = metadata.set(header);
+                        n_layers
+                    }
+                    Err(_) => 0,
+                }
+            };
+            let mut layers = vec![];
+            let mut n = n_layers / 2;
+            while n > 0 {
+                layers.push(n);
+                n /= 2;
+            }
+            layers.push(0);
+            layers
+        }
+    }
+}
+
+pub fn quantize(
+    input: &Path,
+    output: &Path,
+    quant_type: QuantType,
+    options: &QuantizeOptions,
+) -> Result<()> {
+    crate::paths::init_dependencies()?;
+    llama_cpp::load_libraries()?;
+    let params = LlamaQuantizeParams::default()
+        .with_ftype(quant_type.into())
+        .with_n_threads(options.n_threads as i32)
+        .with_allow_requantize(options.allow_requantize)
+        .with_quantize_output_tensor(options.quantize_output_tensor)
+        .with_pure(options.pure);
+    let progress = options.progress.clone();
+    let span = tracing::info_span!("quantize", ?input, ?output, ?quant_type);
+    let _enter = span.enter();
+    llama_cpp::quantize::model_quantize(input, output, &params, move |done, total| {
+        if let Some(cb) = &progress {
+            cb(done as f32 / total.max(1) as f32);
+        }
+    })?;
+    if let Some(cb) = &options.progress {
+        cb(1.0);
+    }
+    Ok(())
+}
+
+pub fn shutdown(timeout: std::time::Duration) -> Result<()> {
+    let deadline = std::time::Instant::now() + timeout;
+    // held while waiting, so models loaded meanwhile wait for the shutdown to finish
+    let mut backend = LLAMA_BACKEND.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(mut b) = backend.take() {
+        // every model and context holds a reference
+        loop {
+            match Arc::try_unwrap(b) {
+                Ok(b) => {
+                    drop(b);
+                    break;
+                }
+                Err(shared) if std::time::Instant::now() >= deadline => {
+                    let in_use = Arc::strong_count(&shared) - 1;
+                    *backend = Some(shared);
+                    return Err(crate::error::Error::ResourcesInUse(in_use));
+                }
+                Err(shared) => {
+                    b = shared;
+                    std::thread::sleep(SHUTDOWN_POLL);
+                }
+            }
+        }
+    }
+    llama_cpp::unload_libraries();
+    Ok(())
+}
+
+// how often a shutdown checks whether the last models and contexts are gone
+const SHUTDOWN_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
+impl From<&ModelOptions> for LlamaModelParams {
+    fn from(val: &ModelOptions) -> Self {
+        let lmp = Self::default()
+            .with_use_mmap(val.use_mmap.unwrap_or(true))
+            .with_use_mlock(val.use_mlock);
         if !val.cpu {
             lmp.with_n_gpu_layers(val.n_gpu_layers as u32)
         } else {
@@ -40,17 +199,203 @@ impl From<ModelOptions> for LlamaModelParams {
 
 impl From<&ContextOptions> for LlamaContextParams {
     fn from(val: &ContextOptions) -> Self {
+        let (n_threads, n_threads_batch) = if val.deterministic {
+            (1, 1)
+        } else {
+            (
+                val.n_threads as i32,
+                val.n_threads_batch.unwrap_or(val.n_threads) as i32,
+            )
+        };
         Self::default()
             .with_n_ctx(NonZeroU32::new(val.n_ctx as u32))
-            .with_n_threads(val.n_threads as i32)
-            .with_n_batch(2048)
+            .with_n_threads(n_threads)
+            .with_n_threads_batch(n_threads_batch)
+            .with_n_batch(val.n_batch as u32)
+            .with_n_ubatch(val.n_ubatch() as u32)
+            .with_n_seq_max(val.n_sequences() as u32)
+            .with_rope_scaling_type(val.rope_scaling_type.into())
+            .with_rope_freq_base(val.rope_freq_base)
+            .with_rope_freq_scale(val.rope_freq_scale)
+            .with_yarn_ext_factor(val.yarn_ext_factor)
+            .with_yarn_attn_factor(val.yarn_attn_factor)
+            .with_yarn_beta_fast(val.yarn_beta_fast)
+            .with_yarn_beta_slow(val.yarn_beta_slow)
+            .with_yarn_orig_ctx(val.yarn_orig_ctx)
+    }
+}
+
+// layout of LlamaContext::state_bytes: magic, version, n_vocab, n_embd, n_ctx, n_curr, logit,
+// last token, healing token, healing text and the llama.cpp state, lengths as u64, followed by
+// the crc32 of all of it
+const STATE_MAGIC: &[u8; 4] = b"NBST";
+const STATE_VERSION: u32 = 2;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(crate::error::Error::InvalidState("truncated".to_string()));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
     }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Fills in the thread counts and batch size of `options` for this machine.
+fn auto_tune(options: &mut ContextOptions, offloaded: bool) {
+    let cpu = llama_cpp::cpu_topology();
+    options.n_threads = cpu.performance.max(1);
+    options.n_threads_batch = Some(cpu.physical.max(1));
+    options.n_batch = if offloaded { 2048 } else { 512 }.min(options.n_ctx.max(1));
+    log::debug!(
+        "auto tuned for {cpu:?}: n_threads {}, n_threads_batch {}, n_batch {}",
+        options.n_threads,
+        cpu.physical,
+        options.n_batch
+    );
 }
 
-#[derive(Debug)]
-pub enum Templated {
-    Str(String),
-    Image(Vec<u8>),
+// sequences llama.cpp accepts in one context
+const MAX_EMBED_SEQUENCES: usize = 64;
+
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|l| (l - max).exp()).sum();
+    max + sum.ln()
+}
+
+fn log_softmax(logits: &[f32], token: LlamaToken) -> f32 {
+    logits[token.0 as usize] - log_sum_exp(logits)
+}
+
+/// Classifier-free guidance: replaces `logits` with their log-probabilities moved away from
+/// those of the negative prompt by `scale`, like llama.cpp's former
+/// `llama_sample_apply_guidance`.
+fn apply_guidance(logits: &mut [f32], negative: &[f32], scale: f32) {
+    let (norm, norm_negative) = (log_sum_exp(logits), log_sum_exp(negative));
+    for (l, n) in logits.iter_mut().zip(negative) {
+        let n = n - norm_negative;
+        *l = scale * (*l - norm - n) + n;
+    }
+}
+
+// private use characters around message contents that are tokenized without parsing
+// special tokens, see `ContextOptions::escape_special_tokens`
+const CONTENT_START: char = '\u{E000}';
+const CONTENT_END: char = '\u{E001}';
+
+/// Marks `content` to be tokenized as plain text. Surrounding whitespace stays outside the
+/// markers, so templates that trim the content still do.
+fn mark_content(content: &str) -> String {
+    let content = content.replace([CONTENT_START, CONTENT_END], "");
+    let start = content.len() - content.trim_start().len();
+    let end = content.trim_end().len();
+    if start >= end {
+        return content;
+    }
+    format!(
+        "{}{CONTENT_START}{}{CONTENT_END}{}",
+        &content[..start],
+        &content[start..end],
+        &content[end..]
+    )
+}
+
+/// Loads the vision projector at `path` the way `options` ask for.
+pub(crate) fn load_projector(path: &Path, options: &ProjectorOptions) -> Result<ClipContext> {
+    // llava places the encoder on the gpu the library was built for
+    if options.require_cpu && llama_cpp::gpu_offload_supported() {
+        return Err(crate::error::Error::Unsupported(
+            "a cpu vision encoder with a gpu library variant, prefer a cpu variant",
+        ));
+    }
+    let path = match options.precision {
+        ProjectorPrecision::File => path.to_path_buf(),
+        ProjectorPrecision::F16 => convert_projector(path, ClipType::F16)?,
+        ProjectorPrecision::F32 => convert_projector(path, ClipType::F32)?,
+    };
+    Ok(ClipContext::load(&path)?.with_n_threads(options.n_threads))
+}
+
+/// The projector at `path` converted to `typ`, in [`crate::paths::projectors_dir`].
+pub(crate) fn convert_projector(path: &Path, typ: ClipType) -> Result<PathBuf> {
+    let suffix = match typ {
+        ClipType::F16 => "f16",
+        ClipType::F32 => "f32",
+    };
+    cached_projector(path, suffix, |output| {
+        tracing::info!(?path, ?output, "converting projector");
+        Ok(clip::convert(path, output, typ)?)
+    })
+}
+
+/// The vision encoder embedded in the model file at `path` on its own, in
+/// [`crate::paths::projectors_dir`], so llava doesn't read the language model's weights too.
+fn extract_projector(path: &Path) -> Result<PathBuf> {
+    cached_projector(path, "mmproj", |output| {
+        tracing::info!(?path, ?output, "extracting embedded projector");
+        // the keys and tensor names llava's clip model reads
+        crate::gguf::write_subset(
+            path,
+            output,
+            |key| key.starts_with("clip.") || key.starts_with("general."),
+            |tensor| {
+                ["v.", "mm.", "resampler."]
+                    .iter()
+                    .any(|p| tensor.starts_with(p))
+            },
+        )
+    })
+}
+
+/// `{stem}-{hash}.{suffix}.gguf` in [`crate::paths::projectors_dir`], made from the file at
+/// `path` by `make` unless a copy newer than the file is there already.
+fn cached_projector(
+    path: &Path,
+    suffix: &str,
+    make: impl FnOnce(&Path) -> Result<()>,
+) -> Result<PathBuf> {
+    let hash = fnv1a(std::fs::canonicalize(path)?.as_os_str().as_encoded_bytes());
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let output = crate::paths::ensure(crate::paths::projectors_dir())?
+        .join(format!("{stem}-{hash:016x}.{suffix}.gguf"));
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified());
+    if let (Ok(made), Ok(source)) = (modified(&output), modified(path)) {
+        if made >= source {
+            return Ok(output);
+        }
+    }
+    // a partly written file would be reused by the next load, another process may be
+    // making the same one
+    let partial = output.with_extension(format!("{}.part", std::process::id()));
+    make(&partial)?;
+    std::fs::rename(&partial, &output)?;
+    Ok(output)
+}
+
+/// 64 bit FNV-1a of `bytes`, the same with every Rust release unlike `DefaultHasher`, so
+/// cached files are found again after an update.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
 }
 
 #[derive(Clone)]
@@ -58,6 +403,44 @@ pub struct Llama {
     name: String,
     model: LlamaModel,
     mmproj: Option<ClipContext>,
+    projector_options: ProjectorOptions,
+    backend: Arc<LlamaBackend>,
+    max_contexts: Option<usize>,
+    // layers are offloaded to a gpu
+    offloaded: bool,
+    // offloaded layers and whether ModelOptions::fallback picked them
+    n_gpu_layers: i32,
+    fell_back: bool,
+    // keeps a fixed size state per sequence instead of a kv cache, from the architecture as
+    // not every llama.cpp library exports llama_model_is_recurrent
+    recurrent: bool,
+    // shared by all clones, see ContextSlot
+    contexts: Arc<AtomicUsize>,
+    warmed_up: Arc<AtomicBool>,
+    // header of the model file, read on first use
+    metadata: Arc<OnceLock<Gguf>>,
+}
+
+/// Counts a live context against [`ModelOptions::max_contexts`] until dropped.
+struct ContextSlot(Arc<AtomicUsize>);
+
+impl ContextSlot {
+    fn acquire(model: &Llama) -> Result<Self> {
+        let max = model.max_contexts.unwrap_or(usize::MAX);
+        model
+            .contexts
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .map_err(crate::error::Error::TooManyContexts)?;
+        Ok(Self(model.contexts.clone()))
+    }
+}
+
+impl Drop for ContextSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Llama {
@@ -66,627 +449,312 @@ impl Llama {
         options: ModelOptions,
         callback: Option<impl FnMut(f32) -> bool + 'static>,
     ) -> Result<Self> {
-        let mut lmp: LlamaModelParams = options.into();
-        if let Some(cb) = callback {
-            lmp = lmp.with_load_process_callback(cb);
+        let mut lmp: LlamaModelParams = (&options).into();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut callback = callback;
+        let load_progress = options.load_progress.clone();
+        if callback.is_some() || load_progress.is_some() {
+            let cancelled = cancelled.clone();
+            lmp = lmp.with_load_process_callback(move |p| {
+                let go_on = load_progress.as_ref().map_or(true, |cb| cb(p))
+                    && callback.as_mut().map_or(true, |cb| cb(p));
+                if !go_on {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                go_on
+            });
         }
-        let model_params = Box::pin(lmp);
+        let mut model_params = lmp;
         let mm: PathBuf = model_path.into();
-        let model = LlamaModel::load_from_file(&LLAMA_BACKEND, Path::new(&mm), &model_params)?;
-        Ok(Self {
+        check_capabilities(&options)?;
+        if options.use_mmap.is_none() && !llama_cpp::mmap_supported() {
+            tracing::warn!("mmap is not supported on this platform, reading the model file");
+            model_params = model_params.with_use_mmap(false);
+        }
+        if let Some(reserve) = options
+            .vram_reserve
+            .filter(|_| !options.cpu && options.n_gpu_layers < 0)
+        {
+            // free memory as of now, other processes may have taken some since the last query
+            match crate::memory::fit_gpu_layers(&mm, reserve) {
+                Ok(Some(n_gpu_layers)) => {
+                    tracing::info!(n_gpu_layers, reserve, "fitted gpu layers into free memory");
+                    model_params = model_params.with_n_gpu_layers(n_gpu_layers);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!(error = %e, "fitting gpu layers failed, offloading all"),
+            }
+        }
+        let backend = backend(options.numa_strategy)?;
+        let metadata = Arc::new(OnceLock::new());
+        let mut fallbacks = fallback_layers(&options, &mm, &metadata).into_iter();
+        let mut fell_back = false;
+        let model = loop {
+            match LlamaModel::load_from_file(&backend, Path::new(&mm), &model_params) {
+                Err(_) if cancelled.load(Ordering::SeqCst) => {
+                    return Err(crate::error::Error::ModelLoadCancelled)
+                }
+                Err(e) => match fallbacks.next() {
+                    Some(n_gpu_layers) => {
+                        tracing::warn!(error = %e, n_gpu_layers, "model load failed, retrying");
+                        model_params = model_params.with_n_gpu_layers(n_gpu_layers);
+                        fell_back = true;
+                    }
+                    None => {
+                        return Err(crate::error::Error::ModelLoad {
+                            path: mm,
+                            source: e,
+                        })
+                    }
+                },
+                Ok(model) => break model,
+            }
+        };
+        let n_gpu_layers = model_params.n_gpu_layers();
+        let recurrent = model
+            .meta_val_str("general.architecture")
+            .ok()
+            .flatten()
+            .is_some_and(|name| Architecture::from_name(&name).is_recurrent());
+        let mut llama = Self {
             name: mm.to_str().unwrap().to_string(),
             model,
             mmproj: None,
-        })
+            projector_options: options.projector.clone(),
+            backend,
+            max_contexts: options.max_contexts,
+            offloaded: !options.cpu && n_gpu_layers != 0,
+            n_gpu_layers: if options.cpu { 0 } else { n_gpu_layers },
+            fell_back,
+            recurrent,
+            contexts: Arc::new(AtomicUsize::new(0)),
+            warmed_up: Arc::new(AtomicBool::new(false)),
+            metadata,
+        };
+        // some files carry the vision projector next to the language model
+        let embedded_projector = llama
+            .metadata()
+            .is_ok_and(|m| m.get("clip.has_vision_encoder") == Some(&Value::Bool(true)));
+        if embedded_projector {
+            let path = extract_projector(&mm)?;
+            llama.mmproj = Some(load_projector(&path, &llama.projector_options)?);
+        }
+        Ok(llama)
     }
 
-    pub fn token_is_eog(&self, id: LlamaToken) -> Result<bool> {
-        Ok(self.model.token_is_eog(id))
+    fn metadata(&self) -> Result<&Gguf> {
+        if self.metadata.get().is_none() {
+            let _ = self.metadata.set(crate::gguf::read(&self.name)?);
+        }
+        Ok(self.metadata.get().expect("set above"))
     }
 
-    pub fn template_stops(&self, template: Option<String>) -> Result<Vec<&'static str>> {
-        let template: Cow<str> = if let Some(tt) = template {
-            tt.into()
-        } else if let Some(tt) = self.model.meta_val_str("tokenizer.chat_template")? {
-            tt.into()
-        } else {
-            "chatml".into()
+    pub fn architecture(&self) -> Result<Architecture> {
+        self.metadata()?
+            .typed_architecture()
+            .ok_or_else(|| crate::error::Error::InvalidGguf("no general.architecture".to_string()))
+    }
+
+    pub fn capabilities(&self) -> Result<Capabilities> {
+        let mut capabilities = self.metadata()?.capabilities();
+        capabilities.supports_vision |= self.mmproj.is_some();
+        capabilities.is_recurrent |= self.recurrent;
+        Ok(capabilities)
+    }
+
+    /// Decodes the bos and eos tokens in a throwaway context, as llama.cpp's tools do before
+    /// the first prompt.
+    pub fn warmup(&self) -> Result<Warmup> {
+        let _slot = ContextSlot::acquire(self)?;
+        let _span = tracing::debug_span!("warmup").entered();
+        let start = std::time::Instant::now();
+        let mut tokens: Vec<LlamaToken> = [self.model.token_bos(), self.model.token_eos()]
+            .into_iter()
+            .filter(|t| t.0 >= 0)
+            .collect();
+        if tokens.is_empty() {
+            tokens.push(LlamaToken(0));
+        }
+        let n_tokens = tokens.len();
+        let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(512));
+        let mut ctx = self.model.new_context(&self.backend, params)?;
+        ctx.eval_tokens(tokens, n_tokens, &mut 0)?;
+        ctx.clear_kv_cache();
+        drop(ctx);
+        let warmup = Warmup {
+            tokens: n_tokens,
+            elapsed_ms: start.elapsed().as_secs_f64() * 1000.0,
         };
-        if template == "chatml" || template.contains("<|im_start|>") {
-            Ok(vec!["<|im_start|>", "<|im_end|>"])
-        } else if template == "llama2" || template == "mistral" || template.contains("[INST]") {
-            Ok(vec!["[INST]", "[/INST]", "<<SYS>>", "<</SYS>>"])
-        } else if template == "phi3"
-            || (template.contains("<|assistant|>") && template.contains("<|end|>"))
-        {
-            Ok(vec!["<|end|>", "<|system|>", "<|user|>", "<|assistant|>"])
-        } else if template == "zephyr" || template.contains("<|user|>") {
-            Ok(vec!["<|system|>", "</s>", "<|user|>", "<|assistant|>"])
-        } else if template == "monarch" || template.contains("bos_token + message['role']") {
-            Ok(vec![])
-        } else if template == "gemma"
-            || template == "gemma2"
-            || template.contains("<start_of_turn>")
-        {
-            Ok(vec!["<start_of_turn>", "<end_of_turn>"])
-        } else if template == "orion" || template.contains("'\\n\\nAssistant: ' + eos_token") {
-            Ok(vec![])
-        } else if template == "openchat" || template.contains("GPT4 Correct ") {
-            Ok(vec!["<|end_of_turn|>"])
-        } else if template == "vicuna"
-            || template == "vicuna-orca"
-            || (template.contains("USER: ") && template.contains("ASSISTANT: "))
-        {
-            Ok(vec!["USER:", "ASSISTANT:"])
-        //        } else if false
-        //            && (template == "deepseek"
-        //                || (template.contains("### Instruction:") && template.contains("<|EOT|>")))
-        //        {
-        //            Ok(vec!["<|EOT|>"])
-        } else if template == "command-r"
-            || (template.contains("<|START_OF_TURN_TOKEN|>") && template.contains("<|USER_TOKEN|>"))
-        {
-            Ok(vec![])
-        } else if template == "llama3"
-            || (template.contains("<|start_header_id|>") && template.contains("<|end_header_id|>"))
-        {
-            Ok(vec![
-                "<|start_header_id|>",
-                "<|end_header_id|>",
-                "<|eot_id|>",
-            ])
-        } else if template == "chatglm3"
-            || template.contains("[gMASK]sop")
-            || template == "chatglm4"
-            || template.contains("[gMASK]<sop>")
-            || template == "minicpm"
-            || template.contains("<用户>")
-            || template == "deepseek2"
-            || template.contains("'Assistant: ' + message['content'] + eos_token")
-            || template == "exaone3"
-            || (template.contains("[|system|]")
-                && template.contains("[|assistant|]")
-                && template.contains("[|endofturn|]"))
-        {
-            Ok(vec![])
+        self.warmed_up.store(true, Ordering::SeqCst);
+        tracing::debug!(elapsed_ms = warmup.elapsed_ms, "model warmed up");
+        Ok(warmup)
+    }
+
+    /// Tokens `message` takes in the chat template, without its images.
+    pub fn count_tokens(&self, message: &Message) -> Result<usize> {
+        let templated = self.apply_template(vec![message.clone()], None, false)?;
+        templated.iter().try_fold(0, |n, t| match t {
+            Templated::Str(s) => Ok(n + self.model.str_to_token(s, AddBos::Never)?.len()),
+            Templated::Image(_) => Ok(n),
+        })
+    }
+
+    pub fn placement(&self) -> Placement {
+        let n_layer = self
+            .metadata()
+            .ok()
+            .and_then(|m| m.arch_value("block_count"))
+            .and_then(Value::as_u64)
+            .unwrap_or(u32::MAX as u64) as u32;
+        // llama.cpp offloads the last layers first, the output layer after all of them
+        let n_offloaded = match self.n_gpu_layers {
+            _ if !llama_cpp::gpu_offload_supported() => 0,
+            n if n < 0 => n_layer.saturating_add(1),
+            n => (n as u32).min(n_layer.saturating_add(1)),
+        };
+        let devices = if n_offloaded > 0 {
+            crate::devices::list_cached()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|d| d.library != "cpu")
+                .collect()
         } else {
-            self.template_stops(Some("chatml".to_string()))
+            vec![]
+        };
+        Placement {
+            variant: llama_cpp::loaded_variant(),
+            n_gpu_layers: self.n_gpu_layers,
+            n_offloaded,
+            devices,
+            fell_back: self.fell_back,
         }
     }
 
-    pub fn apply_template(
-        &self,
-        msgs: Vec<Message>,
-        template: Option<String>,
-        add_ass: bool,
-    ) -> Result<Vec<Templated>> {
-        let template: Cow<str> = if let Some(tt) = template {
-            tt.into()
-        } else if let Some(tt) = self.model.meta_val_str("tokenizer.chat_template")? {
-            tt.into()
+    /// The type most tensors are stored in, from `general.file_type`.
+    pub fn quant_type(&self) -> Result<Option<QuantType>> {
+        Ok(self
+            .metadata()?
+            .get("general.file_type")
+            .and_then(Value::as_u64)
+            .and_then(QuantType::from_file_type))
+    }
+
+    pub fn health(&self) -> Result<Health> {
+        let devices = crate::devices::list()?;
+        let (free_memory, total_memory) = if self.offloaded {
+            devices
+                .iter()
+                .filter(|d| d.library != "cpu")
+                .fold((0, 0), |(f, t), d| (f + d.free_memory, t + d.total_memory))
         } else {
-            "chatml".into()
+            let mem = crate::devices::system_memory()?;
+            (mem.free(), mem.total())
         };
-        let mut res = vec![];
-        if template == "chatml" || template.contains("<|im_start|>") {
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.images.is_empty() {
-                            writeln!(buf, "<|im_start|>{}\n{}<|im_end|>", msg.role, msg.content)?;
-                        } else {
-                            writeln!(buf, "<|im_start|>{}", msg.role)?;
-                            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                            msg.images.into_iter().for_each(|im| {
-                                res.push(Templated::Image(im.0));
-                            });
-                            buf = BufWriter::new(Vec::new());
-                            writeln!(buf, "{}<|im_end|>", msg.content)?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                writeln!(buf, "<|im_start|>assistant")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "llama2" || template == "mistral" || template.contains("[INST]") {
-            // llama2 template and its variants
-            // [variant] support system message
-            let support_system_message = template.contains("<<SYS>>") || template == "mistral";
-            // [variant] space before + after response
-            let space_around_response = template.contains("' ' + eos_token");
-            // [variant] add BOS inside history
-            let add_bos_inside_history = template.contains("bos_token + '[INST]");
-            // [variant] trim spaces from the input message
-            let strip_message = template.contains("content.strip()");
-            // construct the prompt
-            let mut is_inside_turn = true; // skip BOS at the beginning
-            let mut buf = BufWriter::new(Vec::new());
-            write!(buf, "[INST]")?;
-            let buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
-                let content = if strip_message {
-                    msg.content.trim().to_string()
-                } else {
-                    msg.content
-                };
-                if !is_inside_turn {
-                    is_inside_turn = true;
-                    if add_bos_inside_history {
-                        write!(buf, "<s>[INST]")?;
-                    } else {
-                        write!(buf, "[INST]")?;
-                    }
-                }
-                if msg.role == Role::System {
-                    if support_system_message {
-                        write!(buf, "<<SYS>>\n{}\n<</SYS>>\n\n", content)?;
-                    } else {
-                        // if the model does not support system message, we still include it in the first message, but without <<SYS>>
-                        writeln!(buf)?;
-                    }
-                } else if msg.role == Role::User {
-                    if !msg.images.is_empty() {
-                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                        msg.images.into_iter().for_each(|im| {
-                            res.push(Templated::Image(im.0));
-                        });
-                        buf = BufWriter::new(Vec::new());
-                    }
-                    write!(buf, "{} [/INST]", content)?;
-                } else {
-                    write!(
-                        buf,
-                        "{}{}{}</s>",
-                        if space_around_response { " " } else { "" },
-                        content,
-                        if space_around_response { " " } else { "" }
-                    )?;
-                    is_inside_turn = false;
-                }
-                Ok::<_, crate::error::Error>(buf)
-            })?;
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            // llama2 templates seem to not care about "add_generation_prompt"
-            Ok(res)
-        } else if template == "phi3"
-            || (template.contains("<|assistant|>") && template.contains("<|end|>"))
-        {
-            // Phi 3
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        writeln!(buf, "<|{}|>", msg.role)?;
-                        if !msg.images.is_empty() {
-                            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                            msg.images.into_iter().for_each(|im| {
-                                res.push(Templated::Image(im.0));
-                            });
-                            buf = BufWriter::new(Vec::new());
-                        }
-                        writeln!(buf, "{}<|end|>", msg.content)?;
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                writeln!(buf, "<|assistant|>")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "zephyr" || template.contains("<|user|>") {
-            // zephyr template
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        writeln!(buf, "<|{}|>", msg.role)?;
-                        if !msg.images.is_empty() {
-                            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                            msg.images.into_iter().for_each(|im| {
-                                res.push(Templated::Image(im.0));
-                            });
-                            buf = BufWriter::new(Vec::new());
-                        }
-                        writeln!(buf, "{}<|endoftext|>", msg.content)?;
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                writeln!(buf, "<|assistant|>")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "monarch" || template.contains("bos_token + message['role']") {
-            // mlabonne/AlphaMonarch-7B template (the <s> is included inside history)
-            let mut buf = msgs.into_iter().enumerate().try_fold(
-                BufWriter::new(Vec::new()),
-                |mut buf, (i, msg)| {
-                    writeln!(buf, "{}{}", if i == 0 { "" } else { "<s>" }, msg.role)?;
-                    if !msg.images.is_empty() {
-                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                        msg.images.into_iter().for_each(|im| {
-                            res.push(Templated::Image(im.0));
-                        });
-                        buf = BufWriter::new(Vec::new());
-                    }
-                    writeln!(buf, "{}</s>", msg.content)?;
-                    Ok::<_, crate::error::Error>(buf)
-                },
-            )?;
-            if add_ass {
-                writeln!(buf, "<s>assistant")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "gemma"
-            || template == "gemma2"
-            || template.contains("<start_of_turn>")
-        {
-            // google/gemma-7b-it
-            let mut system_prompt = "".to_string();
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            // there is no system message for gemma, but we will merge it with user prompt, so nothing is broken
-                            system_prompt = msg.content.trim().to_string();
-                        } else {
-                            writeln!(
-                                buf,
-                                "<start_of_turn>{}",
-                                if msg.role == Role::Assistant {
-                                    "model".to_string()
-                                } else {
-                                    msg.role.to_string()
-                                }
-                            )?;
-                            if !system_prompt.is_empty() && msg.role == Role::User {
-                                write!(buf, "{}\n\n", system_prompt)?;
-                                system_prompt = "".to_string();
-                            }
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            writeln!(buf, "{}<end_of_turn>", msg.content.trim())?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                writeln!(buf, "<start_of_turn>model")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "orion" || template.contains("'\\n\\nAssistant: ' + eos_token") {
-            // OrionStarAI/Orion-14B-Chat
-            let mut system_prompt = "".to_string();
-            let buf = msgs
-                .into_iter()
-                .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                    if msg.role == Role::System {
-                        // there is no system message support, we will merge it with user prompt
-                        system_prompt = msg.content;
-                    } else if msg.role == Role::User {
-                        write!(buf, "Human: ")?;
-                        if !system_prompt.is_empty() && msg.role == Role::User {
-                            write!(buf, "{}\n\n", system_prompt)?;
-                            system_prompt = "".to_string();
-                        }
-                        if !msg.images.is_empty() {
-                            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                            msg.images.into_iter().for_each(|im| {
-                                res.push(Templated::Image(im.0));
-                            });
-                            buf = BufWriter::new(Vec::new());
-                        }
-                        write!(buf, "{}\n\nAssistant: </s>", msg.content)?;
-                    } else {
-                        write!(buf, "{}</s>", msg.content)?;
-                    }
-                    Ok::<_, crate::error::Error>(buf)
-                })?;
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "openchat" || template.contains("GPT4 Correct ") {
-            // openchat/openchat-3.5-0106,
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            write!(buf, "{}<|end_of_turn|>", msg.content)?;
-                        } else {
-                            write!(
-                                buf,
-                                "GPT4 Correct {}: ",
-                                msg.role.to_string().to_uppercase()
-                            )?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            write!(buf, "{}<|end_of_turn|>", msg.content)?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "GPT4 Correct Assistant:")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "vicuna"
-            || template == "vicuna-orca"
-            || (template.contains("USER: ") && template.contains("ASSISTANT: "))
-        {
-            // eachadea/vicuna-13b-1.1 (and Orca variant)
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            // Orca-Vicuna variant uses a system prefix
-                            if template == "vicuna-orca" || template.contains("SYSTEM: ") {
-                                writeln!(buf, "SYSTEM: {}", msg.content)?;
-                            } else {
-                                write!(buf, "{}\n\n", msg.content)?;
-                            }
-                        } else if msg.role == Role::User {
-                            write!(buf, "USER: ")?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            writeln!(buf, "{}", msg.content)?;
-                        } else {
-                            writeln!(buf, "ASSISTANT: {}</s>", msg.content)?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "ASSISTANT:")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        // } else if false
-        //     && (template == "deepseek"
-        //         || (template.contains("### Instruction:") && template.contains("<|EOT|>")))
-        // {
-        //     // deepseek-ai/deepseek-coder-33b-instruct
-        //     let mut buf =
-        //         msgs.into_iter()
-        //             .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-        //                 if msg.role == Role::System {
-        //                     write!(buf, "{}", msg.content)?;
-        //                 } else if msg.role == Role::User {
-        //                     writeln!(buf, "### Instruction:")?;
-        //                     if !msg.images.is_empty() {
-        //                         res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-        //                         msg.images.into_iter().for_each(|im| {
-        //                             res.push(Templated::Image(im.0));
-        //                         });
-        //                         buf = BufWriter::new(Vec::new());
-        //                     }
-        //                     writeln!(buf, "{}", msg.content)?;
-        //                 } else {
-        //                     write!(buf, "### Response:\n{}\n<|EOT|>\n", msg.content)?;
-        //                 }
-        //                 Ok::<_, crate::error::Error>(buf)
-        //             })?;
-        //     if add_ass {
-        //         writeln!(buf, "### Response:")?;
-        //     }
-        //     res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-        //     Ok(res)
-        } else if template == "command-r"
-            || (template.contains("<|START_OF_TURN_TOKEN|>") && template.contains("<|USER_TOKEN|>"))
-        {
-            // CohereForAI/c4ai-command-r-plus
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            write!(
-                                buf,
-                                "<|START_OF_TURN_TOKEN|><|SYSTEM_TOKEN|>{}<|END_OF_TURN_TOKEN|>",
-                                msg.content.trim(),
-                            )?;
-                        } else if msg.role == Role::User {
-                            write!(buf, "<|START_OF_TURN_TOKEN|><|USER_TOKEN|>")?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            write!(buf, "{}<|END_OF_TURN_TOKEN|>", msg.content.trim())?;
-                        } else {
-                            write!(
-                                buf,
-                                "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>{}<|END_OF_TURN_TOKEN|>",
-                                msg.content
-                            )?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "llama3"
-            || (template.contains("<|start_header_id|>") && template.contains("<|end_header_id|>"))
-        {
-            // Llama 3
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::User {
-                            write!(buf, "<|start_header_id|>{}<|end_header_id|>\n\n", msg.role)?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            write!(buf, "{}<|eot_id|>", msg.content.trim())?;
-                        } else {
-                            write!(
-                                buf,
-                                "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
-                                msg.role, msg.content
-                            )?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "<|start_header_id|>assistant<|end_header_id|>\n\n")?;
+        Ok(Health {
+            model: self.name.clone(),
+            variant: llama_cpp::loaded_variant(),
+            devices,
+            offloaded: self.offloaded,
+            free_memory,
+            total_memory,
+            contexts: self.contexts.load(Ordering::SeqCst),
+            max_contexts: self.max_contexts,
+            warmed_up: self.warmed_up.load(Ordering::SeqCst),
+        })
+    }
+
+    /// Embeds `texts` packing as many as fit into `options.n_batch` tokens into one decode,
+    /// every text on its own sequence.
+    pub fn embed_batch(&self, texts: &[&str], options: &EmbeddingOptions) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let tokens = texts
+            .iter()
+            .map(|t| self.model.str_to_token(t, AddBos::Always))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let longest = tokens.iter().map(Vec::len).max().unwrap_or(0);
+        let n_batch = options.n_batch.max(longest).max(1);
+        let n_seq_max = texts.len().min(MAX_EMBED_SEQUENCES);
+        let _slot = ContextSlot::acquire(self)?;
+        let _span = tracing::debug_span!("embed_batch", texts = texts.len(), n_batch).entered();
+        // the whole batch has to be computed at once, non-causal models attend to all tokens
+        let params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(n_batch as u32))
+            .with_n_batch(n_batch as u32)
+            .with_n_ubatch(n_batch as u32)
+            .with_n_seq_max(n_seq_max as u32)
+            .with_n_threads(options.n_threads as i32)
+            .with_n_threads_batch(options.n_threads as i32)
+            .with_embeddings(true)
+            .with_pooling_type(options.pooling.into());
+        let mut ctx = self.model.new_context(&self.backend, params)?;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut res = Vec::with_capacity(texts.len());
+        let mut start = 0;
+        while start < tokens.len() {
+            let mut end = start;
+            let mut n_tokens = 0;
+            while end < tokens.len()
+                && end - start < n_seq_max
+                && n_tokens + tokens[end].len() <= n_batch
+            {
+                n_tokens += tokens[end].len();
+                end += 1;
             }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "chatglm3" || template.contains("[gMASK]sop") {
-            // chatglm3-6b
-            let mut buf = BufWriter::new(Vec::new());
-            write!(buf, "[gMASK]sop")?;
-            let mut buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
-                if msg.role == Role::User {
-                    write!(buf, "<|{}|>\n ", msg.role)?;
-                    if !msg.images.is_empty() {
-                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                        msg.images.into_iter().for_each(|im| {
-                            res.push(Templated::Image(im.0));
-                        });
-                        buf = BufWriter::new(Vec::new());
-                    }
-                    write!(buf, "{}", msg.content)?;
-                } else {
-                    write!(buf, "<|{}|>\n {}", msg.role, msg.content)?;
-                }
-                Ok::<_, crate::error::Error>(buf)
-            })?;
-            if add_ass {
-                write!(buf, "<|assistant|>")?;
+            batch.clear();
+            for (seq, t) in tokens[start..end].iter().enumerate() {
+                batch
+                    .add_sequence(t, seq as i32, true)
+                    .map_err(llama_cpp::LLamaCppError::from)?;
             }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "chatglm4" || template.contains("[gMASK]<sop>") {
-            // chatglm3-6b
-            let mut buf = BufWriter::new(Vec::new());
-            write!(buf, "[gMASK]<sop>")?;
-            let mut buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
-                if msg.role == Role::User {
-                    write!(buf, "<|{}|>\n ", msg.role)?;
-                    if !msg.images.is_empty() {
-                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                        msg.images.into_iter().for_each(|im| {
-                            res.push(Templated::Image(im.0));
-                        });
-                        buf = BufWriter::new(Vec::new());
+            ctx.clear_kv_cache();
+            crate::metrics::record_batches(1, n_tokens);
+            ctx.decode(&mut batch)?;
+            for seq in 0..end - start {
+                let mut embedding = ctx
+                    .embeddings_seq_ith(seq as i32)
+                    .map_err(llama_cpp::LLamaCppError::from)?
+                    .to_vec();
+                if options.normalize {
+                    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+                    if norm > 0.0 {
+                        embedding.iter_mut().for_each(|v| *v /= norm);
                     }
-                    write!(buf, "{}", msg.content)?;
-                } else {
-                    write!(buf, "<|{}|>\n {}", msg.role, msg.content)?;
                 }
-                Ok::<_, crate::error::Error>(buf)
-            })?;
-            if add_ass {
-                write!(buf, "<|assistant|>")?;
+                res.push(embedding);
             }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "minicpm" || template.contains("<用户>") {
-            // MiniCPM-3B-OpenHermes-2.5-v2-GGUF
-            let buf = msgs
-                .into_iter()
-                .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                    if msg.role == Role::User {
-                        write!(buf, "<用户>")?;
-                        if !msg.images.is_empty() {
-                            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                            msg.images.into_iter().for_each(|im| {
-                                res.push(Templated::Image(im.0));
-                            });
-                            buf = BufWriter::new(Vec::new());
-                        }
-                        write!(buf, "{}<AI>", msg.content.trim())?;
-                    } else {
-                        write!(buf, "{}", msg.content.trim())?;
-                    }
-                    Ok::<_, crate::error::Error>(buf)
-                })?;
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "deepseek2"
-            || template.contains("'Assistant: ' + message['content'] + eos_token")
-        {
-            // DeepSeek-V2
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            write!(buf, "{}\n\n", msg.content)?;
-                        } else if msg.role == Role::User {
-                            write!(buf, "User: :")?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            write!(buf, "{}\n\n", msg.content)?;
-                        } else {
-                            write!(buf, "Assistant: {}<｜end▁of▁sentence｜>", msg.content)?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "Assistant:")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else if template == "exaone3"
-            || (template.contains("[|system|]")
-                && template.contains("[|assistant|]")
-                && template.contains("[|endofturn|]"))
-        {
-            // ref: https://huggingface.co/LGAI-EXAONE/EXAONE-3.0-7.8B-Instruct/discussions/8#66bae61b1893d14ee8ed85bb
-            // EXAONE-3.0-7.8B-Instruct
-            let mut buf =
-                msgs.into_iter()
-                    .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
-                        if msg.role == Role::System {
-                            writeln!(buf, "[|system|]{}[|endofturn|]", msg.content.trim())?;
-                        } else if msg.role == Role::User {
-                            write!(buf, "[|user|]")?;
-                            if !msg.images.is_empty() {
-                                res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-                                msg.images.into_iter().for_each(|im| {
-                                    res.push(Templated::Image(im.0));
-                                });
-                                buf = BufWriter::new(Vec::new());
-                            }
-                            writeln!(buf, "{}", msg.content.trim())?;
-                        } else {
-                            writeln!(buf, "[|assistant|]{}[|endofturn|]", msg.content.trim())?;
-                        }
-                        Ok::<_, crate::error::Error>(buf)
-                    })?;
-            if add_ass {
-                write!(buf, "[|assistant|]")?;
-            }
-            res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
-            Ok(res)
-        } else {
-            //            log::info!("unsupported template {}, used chatml instead", template);
-            //            Err(crate::error::Error::UnsupportedTemplate(template.into()))
-            self.apply_template(msgs, Some("chatml".to_string()), add_ass)
+            start = end;
         }
+        Ok(res)
+    }
+
+    pub fn token_is_eog(&self, id: LlamaToken) -> Result<bool> {
+        Ok(self.model.token_is_eog(id))
+    }
+
+    pub fn template_stops(&self, template: Option<String>) -> Result<Vec<&'static str>> {
+        Ok(template::stops(&self.chat_template(template)?))
+    }
+
+    pub fn apply_template(
+        &self,
+        msgs: Vec<Message>,
+        template: Option<String>,
+        add_ass: bool,
+    ) -> Result<Vec<Templated>> {
+        template::render(msgs, &self.chat_template(template)?, add_ass)
+    }
+
+    /// The template to render with, `template` or else the one in the gguf metadata.
+    fn chat_template(&self, template: Option<String>) -> Result<String> {
+        Ok(match template {
+            Some(tt) => tt,
+            None => self
+                .model
+                .meta_val_str("tokenizer.chat_template")?
+                .unwrap_or_else(|| "chatml".to_string()),
+        })
     }
 }
 
@@ -695,13 +763,53 @@ impl Model for Llama {
         Ok(&self.name)
     }
     fn with_mmproj(&mut self, mmproj: PathBuf) -> Result<()> {
-        let clip_context = ClipContext::load(Path::new(&mmproj))?;
+        let clip_context = load_projector(&mmproj, &self.projector_options)?;
         self.mmproj = Some(clip_context);
         Ok(())
     }
+    fn with_projector(&mut self, projector: ClipContext) {
+        self.mmproj = Some(projector);
+    }
     fn new_context(&self, options: ContextOptions) -> Result<Pin<Box<Mutex<dyn Context>>>> {
         Ok(Box::pin(Mutex::new(LlamaContext::new(self, options)?)))
     }
+    fn embed_batch(&self, texts: &[&str], options: &EmbeddingOptions) -> Result<Vec<Vec<f32>>> {
+        Llama::embed_batch(self, texts, options)
+    }
+    fn warmup(&self) -> Result<Warmup> {
+        Llama::warmup(self)
+    }
+    fn architecture(&self) -> Result<Architecture> {
+        Llama::architecture(self)
+    }
+    fn capabilities(&self) -> Result<Capabilities> {
+        Llama::capabilities(self)
+    }
+    fn placement(&self) -> Placement {
+        Llama::placement(self)
+    }
+    fn n_ctx_train(&self) -> usize {
+        self.model.n_ctx_train() as usize
+    }
+    fn n_embd(&self) -> usize {
+        self.model.n_embd().max(0) as usize
+    }
+    fn n_vocab(&self) -> usize {
+        self.model.n_vocab().max(0) as usize
+    }
+    fn quant_type(&self) -> Result<Option<QuantType>> {
+        Llama::quant_type(self)
+    }
+    fn count_tokens(&self, message: &Message) -> Result<usize> {
+        Llama::count_tokens(self, message)
+    }
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>> {
+        let tokens = self.model.str_to_token(text, AddBos::Never)?;
+        Ok(tokens.into_iter().map(|t| t.0).collect())
+    }
+    fn health(&self) -> Result<Health> {
+        Llama::health(self)
+    }
 }
 
 pub struct LlamaContext {
@@ -711,188 +819,937 @@ pub struct LlamaContext {
     ctx: Pin<Box<llama_cpp::context::LlamaContext>>,
     //    sampler: Pin<Box<Sampler>>,
     model: Arc<Llama>,
+    // last prompt token held back by token healing and its text
+    healing: Option<(LlamaToken, String)>,
+    // last token decoded into the main sequence, used to restore its logits
+    last_token: Option<LlamaToken>,
+    // token counts of the current exchange, reset by the first eval after a prediction
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    // token counts since the context was created, counted against `token_quota`, shared with
+    // its forks so forking doesn't start a new quota
+    total_prompt_tokens: Arc<AtomicUsize>,
+    total_completion_tokens: Arc<AtomicUsize>,
+    predicted: bool,
+    // used cells of the llama.cpp context last added to the kv cache gauge in crate::metrics,
+    // shared with its forks so shared cells are counted once
+    kv_reported: Arc<AtomicI32>,
+    // forks share the kv cache of the context they come from and take no slot
+    _slot: Option<ContextSlot>,
+    // projector attached to this context, used instead of the model's
+    projector: Option<ClipContext>,
+    // token at every kv cache position for prompt lookup, NO_TOKEN where unknown
+    history: Vec<LlamaToken>,
+    // self-extend: positions below ga_i are grouped, n_grouped tokens more than positions
+    ga_i: i32,
+    n_grouped: i32,
+    // sequence of the negative prompt, see `ContextOptions::with_negative_prompt`
+    guidance: Option<Box<Guidance>>,
+    prompt_progress: Option<Arc<Box<PromptProgressCallback>>>,
+    // why the last generation ended
+    finish_reason: Option<FinishReason>,
+}
+
+// history entry of positions whose token is not known, like image embeddings
+const NO_TOKEN: LlamaToken = LlamaToken(-1);
+
+/// The negative prompt of classifier-free guidance on a fork of the context it guides.
+struct Guidance {
+    ctx: LlamaContext,
+    scale: f32,
+    // tokens of the negative prompt at the start of the fork's history
+    n_negative: usize,
+    // tokens of the guided context replaced by the negative prompt, set at its first prediction
+    start: Option<usize>,
+}
+
+impl Guidance {
+    fn new(main: &LlamaContext, text: &str) -> Result<Self> {
+        let mut ctx = main.fork()?;
+        ctx.eval_str(text, true, false)?;
+        if ctx.history.is_empty() {
+            return Err(crate::error::Error::Unsupported(
+                "the negative prompt has no tokens",
+            ));
+        }
+        Ok(Self {
+            n_negative: ctx.history.len(),
+            ctx,
+            scale: main.options.guidance_scale,
+            start: None,
+        })
+    }
+
+    fn fork(&self) -> Result<Self> {
+        Ok(Self {
+            ctx: self.ctx.fork()?,
+            scale: self.scale,
+            n_negative: self.n_negative,
+            start: self.start,
+        })
+    }
+
+    /// Logits after the negative prompt followed by what the guided context evaluated since
+    /// its first prediction, `history` being its tokens. Tokens the fork has from before the
+    /// guided context went back are dropped, and the last one is decoded again if nothing is
+    /// new, its logits were replaced by the guided context's decodes.
+    fn logits(&mut self, history: &[LlamaToken]) -> Result<&[f32]> {
+        let start = (*self.start.get_or_insert(history.len())).min(history.len());
+        let mut tokens = self.ctx.history[..self.n_negative].to_vec();
+        tokens.extend(history[start..].iter().filter(|t| **t != NO_TOKEN));
+        let evaluated = self.ctx.history.iter();
+        let n_same = evaluated.zip(&tokens).take_while(|(a, b)| a == b).count();
+        let n_keep = n_same.min(tokens.len() - 1);
+        self.ctx.rewind(n_keep as i32, None)?;
+        self.ctx.eval_tokens(tokens.split_off(n_keep))?;
+        self.ctx.report_kv_cache();
+        Ok(self.ctx.ctx.get_logits_ith(self.ctx.logit))
+    }
 }
 
 impl<'a> LlamaContext {
-    pub fn new(model: &'a Llama, options: ContextOptions) -> Result<Self> {
+    pub fn new(model: &'a Llama, mut options: ContextOptions) -> Result<Self> {
+        let (ga_n, ga_w) = (options.grp_attn_n, options.grp_attn_w);
+        if ga_n > 1 && (ga_n > u8::MAX as usize || ga_w == 0 || ga_w % ga_n != 0) {
+            return Err(crate::error::Error::Unsupported(
+                "grp_attn_w has to be a multiple of grp_attn_n, which is at most 255",
+            ));
+        }
+        let slot = ContextSlot::acquire(model)?;
+        if options.auto_tune {
+            auto_tune(&mut options, model.offloaded);
+        }
         let ctx_params: LlamaContextParams = (&options).into();
-        let ctx = Self {
+        let llama_ctx = model
+            .model
+            .new_context(&model.backend, ctx_params)
+            .map_err(|source| crate::error::Error::ContextCreate {
+                n_ctx: options.n_ctx,
+                n_batch: options.n_batch,
+                source,
+            })?;
+        let mut ctx = Self {
             options,
             logit: 0,
             n_curr: 0,
-            ctx: Box::pin(model.model.new_context(&LLAMA_BACKEND, ctx_params)?),
+            ctx: Box::pin(llama_ctx),
             model: Arc::new(model.clone()),
+            healing: None,
+            last_token: None,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_prompt_tokens: Arc::new(AtomicUsize::new(0)),
+            total_completion_tokens: Arc::new(AtomicUsize::new(0)),
+            predicted: false,
+            kv_reported: Arc::new(AtomicI32::new(0)),
+            _slot: Some(slot),
+            projector: None,
+            history: vec![],
+            ga_i: 0,
+            n_grouped: 0,
+            guidance: None,
+            prompt_progress: None,
+            finish_reason: None,
         };
+        if let Some(text) = ctx.options.negative_prompt.clone() {
+            ctx.guidance = Some(Box::new(Guidance::new(&ctx, &text)?));
+        }
         Ok(ctx)
     }
 
-    fn eval_str(&mut self, prompt: &str, add_bos: bool) -> Result<()> {
-        self.logit = self.ctx.eval_string(
-            prompt,
-            2048,
-            if add_bos {
-                AddBos::Always
-            } else {
-                AddBos::Never
-            },
-            &mut self.n_curr,
-        )?;
+    /// A context continuing from the same tokens on its own kv cache sequence.
+    pub fn fork(&self) -> Result<Self> {
+        let guidance = match &self.guidance {
+            Some(g) => Some(Box::new(g.fork()?)),
+            None => None,
+        };
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut fork = Self {
+            options: self.options.clone(),
+            logit: self.logit,
+            n_curr: self.n_curr,
+            ctx: Box::pin(self.ctx.fork()?),
+            model: self.model.clone(),
+            healing: self.healing.clone(),
+            last_token: self.last_token,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_prompt_tokens: self.total_prompt_tokens.clone(),
+            total_completion_tokens: self.total_completion_tokens.clone(),
+            predicted: false,
+            kv_reported: self.kv_reported.clone(),
+            _slot: None,
+            projector: self.projector.clone(),
+            history: self.history.clone(),
+            ga_i: self.ga_i,
+            n_grouped: self.n_grouped,
+            guidance,
+            prompt_progress: None,
+            finish_reason: None,
+        };
+        fork.report_kv_cache();
+        Ok(fork)
+    }
+
+    /// Decodes the last token again if a fork decoded since, the logits llama.cpp holds are
+    /// those of the last decoded sequence.
+    fn restore_logits(&mut self) -> Result<()> {
+        if !self.ctx.logits_current() && self.n_curr > 0 {
+            self.rewind(self.n_curr, self.last_token)?;
+        }
         Ok(())
     }
 
-    fn eval_image(&mut self, image: &[u8]) -> Result<()> {
-        let embedded_image = if let Some(clip_context) = &self.model.mmproj {
-            clip_context.embed_image(self.options.n_threads, image)?
+    /// Marks the content of `messages` as plain text if special tokens are escaped.
+    fn escape_messages(&self, messages: Vec<Message>) -> Vec<Message> {
+        if !self.options.escape_special_tokens {
+            return messages;
+        }
+        messages
+            .into_iter()
+            .map(|mut m| {
+                m.content = mark_content(&m.content);
+                m
+            })
+            .collect()
+    }
+
+    /// Tokenizes `text`, parts marked by [`mark_content`] without parsing special tokens.
+    fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<LlamaToken>> {
+        let add_bos = if add_bos {
+            AddBos::Always
         } else {
-            return Err(crate::error::Error::MmprojNotDefined);
+            AddBos::Never
         };
-        log::debug!("image embedding created: {} tokens", embedded_image.len());
-        self.ctx
-            .eval_embed_image(embedded_image, 2048, &mut self.n_curr)?;
-        Ok(())
+        if !self.options.escape_special_tokens {
+            return Ok(self.model.model.str_to_token(text, add_bos)?);
+        }
+        let mut parts = text.split(CONTENT_START);
+        // the first part is tokenized even if empty, for the bos token
+        let mut tokens = self.model.model.str_to_token_with_special(
+            parts.next().unwrap_or_default(),
+            add_bos,
+            true,
+        )?;
+        for part in parts {
+            let (content, template) = part.split_once(CONTENT_END).unwrap_or((part, ""));
+            for (text, special) in [(content, false), (template, true)] {
+                if !text.is_empty() {
+                    tokens.extend(self.model.model.str_to_token_with_special(
+                        text,
+                        AddBos::Never,
+                        special,
+                    )?);
+                }
+            }
+        }
+        Ok(tokens)
     }
 
-    fn find_partial_stop_pos(&self, stop: &str, text: &str) -> Result<Option<usize>> {
-        if !text.is_empty() && !stop.is_empty() {
-            let text_last_char = text.chars().last().unwrap();
-            Ok(stop.char_indices().rev().find_map(|(i, ch)| {
-                if ch == text_last_char {
-                    let current_partial = stop[..i + 1].to_string();
-                    if text.ends_with(&current_partial) {
-                        return Some(text.len() - i - 1);
+    fn eval_str(&mut self, prompt: &str, add_bos: bool, heal: bool) -> Result<()> {
+        self.flush_healing()?;
+        let mut tokens = self.tokenize(prompt, add_bos)?;
+        if heal && tokens.len() > 1 {
+            let last = tokens[tokens.len() - 1];
+            if self.model.model.token_type(&last) != LlamaTokenType::Control {
+                match self.model.model.token_to_str(&last) {
+                    Ok(piece) if !piece.is_empty() => {
+                        tokens.pop();
+                        self.healing = Some((last, piece));
                     }
+                    _ => {}
                 }
-                None
-            }))
-        } else {
-            Ok(None)
+            }
         }
+        self.eval_tokens(tokens)
     }
 
-    fn find_stopping_string(
-        &self,
-        text: &str,
-        last_token_size: usize,
-        is_stop_type_full: bool,
-    ) -> Result<(bool, Option<usize>)> {
-        let mut has_stop_token = true;
-        let mut stop_pos = None;
-        for w in self.model.template_stops(None)? {
-            let mut pos = None;
-            if is_stop_type_full {
-                let tmp = w.len() + last_token_size;
-                let from_pos = if text.len() > tmp {
-                    text.len() - tmp
-                } else {
-                    0
-                };
-                if let Some(p) = text[from_pos..].find(w) {
-                    pos = Some(from_pos + p);
-                }
-            } else {
-                pos = self.find_partial_stop_pos(w, text)?;
+    /// Evaluates `tokens` in batches of `n_batch`, with self-extend in windows of
+    /// `grp_attn_w`.
+    fn eval_tokens(&mut self, tokens: Vec<LlamaToken>) -> Result<()> {
+        self.ensure_space(tokens.len())?;
+        if let Some(last) = tokens.last() {
+            self.last_token = Some(*last);
+        }
+        self.history.extend(&tokens);
+        let n_batch = self.ctx.n_batch() as usize;
+        crate::metrics::record_batches(tokens.len().div_ceil(n_batch), tokens.len());
+        tracing::trace!(
+            n_batch,
+            n_ubatch = self.ctx.n_ubatch(),
+            tokens = tokens.len(),
+            "evaluating prompt"
+        );
+        let total = tokens.len();
+        let progress = self.prompt_progress.clone();
+        let report = move |done: usize| {
+            tracing::trace!(done, total, "prompt chunk decoded");
+            if let Some(cb) = &progress {
+                cb(done, total);
             }
-            if pos != stop_pos && (stop_pos.is_none() || pos < stop_pos) {
-                if is_stop_type_full {
-                    has_stop_token = false;
-                }
-                stop_pos = pos;
+        };
+        if self.options.grp_attn_n <= 1 {
+            let decode_error = self.decode_error(tokens.len());
+            self.logit = self
+                .ctx
+                .eval_tokens_with_progress(tokens, n_batch, &mut self.n_curr, |done, _| {
+                    report(done)
+                })
+                .map_err(decode_error)?;
+            return Ok(());
+        }
+        // self-extend groups the context between windows
+        for (i, window) in tokens.chunks(self.options.grp_attn_w).enumerate() {
+            self.self_extend()?;
+            let n_done = i * self.options.grp_attn_w;
+            let decode_error = self.decode_error(window.len());
+            self.logit = self
+                .ctx
+                .eval_tokens_with_progress(window.to_vec(), n_batch, &mut self.n_curr, |done, _| {
+                    report(n_done + done)
+                })
+                .map_err(decode_error)?;
+        }
+        Ok(())
+    }
+
+    /// Self-extend: while the tokens after the grouped part fill more than `grp_attn_w`
+    /// positions, the oldest window of them is merged into groups of `grp_attn_n` tokens
+    /// sharing a position, the way llama.cpp's main example does it.
+    fn self_extend(&mut self) -> Result<()> {
+        let (ga_n, ga_w) = (
+            self.options.grp_attn_n as i32,
+            self.options.grp_attn_w as i32,
+        );
+        // recurrent models have no positions to group
+        let Some(d) = NonZeroU8::new(ga_n as u8).filter(|_| ga_n > 1 && !self.is_recurrent())
+        else {
+            return Ok(());
+        };
+        if self.n_curr < self.ga_i + ga_w {
+            return Ok(());
+        }
+        // positions of shared cells would move for every sequence
+        if self.ctx.is_forked() {
+            return Err(crate::error::Error::Unsupported(
+                "self-extend can't group the context of a fork",
+            ));
+        }
+        let seq = self.ctx.seq_id();
+        while self.n_curr >= self.ga_i + ga_w {
+            let ib = (ga_n * self.ga_i) / ga_w;
+            let bd = (ga_w / ga_n) * (ga_n - 1);
+            let dd = (ga_w / ga_n) - ib * bd - ga_w;
+            let start = self.ga_i + ib * bd;
+            self.ctx.kv_cache_seq_add(
+                seq,
+                Some(self.ga_i as u32),
+                Some(self.n_curr as u32),
+                ib * bd,
+            );
+            self.ctx
+                .kv_cache_seq_div(seq, Some(start as u32), Some((start + ga_w) as u32), d);
+            self.ctx.kv_cache_seq_add(
+                seq,
+                Some((start + ga_w) as u32),
+                Some((self.n_curr + ib * bd) as u32),
+                dd,
+            );
+            self.n_curr -= bd;
+            self.n_grouped += bd;
+            self.ga_i += ga_w / ga_n;
+            // prompt lookup only needs the order of the tokens, keep one per position
+            let n_drop = (bd as usize).min(self.history.len());
+            self.history.drain(..n_drop);
+        }
+        tracing::trace!(
+            n_past = self.n_curr,
+            grouped = self.n_grouped,
+            "context grouped by self-extend"
+        );
+        Ok(())
+    }
+
+    /// Tokens in the kv cache, more than positions once self-extend grouped some.
+    fn n_cells(&self) -> i32 {
+        self.n_curr + self.n_grouped
+    }
+
+    /// Whether the model keeps a fixed size state per sequence instead of a kv cache.
+    fn is_recurrent(&self) -> bool {
+        self.model.recurrent
+    }
+
+    /// Fails if `n_tokens` more tokens do not fit into the context next to the cells its
+    /// forks use. The state of recurrent models doesn't grow, they fit any number.
+    fn ensure_space(&self, n_tokens: usize) -> Result<()> {
+        if self.is_recurrent() {
+            return Ok(());
+        }
+        let used = self.ctx.get_kv_cache_used_cells().max(self.n_cells());
+        let needed = used.max(0) as usize + n_tokens;
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if needed > n_ctx {
+            return Err(crate::error::Error::KVCacheNotBigEnough(needed, n_ctx));
+        }
+        Ok(())
+    }
+
+    /// Adds the batch about to be decoded at the current position to a decode error.
+    fn decode_error(&self, n_tokens: usize) -> impl FnOnce(DecodeError) -> crate::error::Error {
+        let n_past = self.n_curr;
+        move |source| crate::error::Error::Decode {
+            n_tokens,
+            n_past,
+            source,
+        }
+    }
+
+    /// Tokens the context may still evaluate or generate under `token_quota`.
+    fn quota_left(&self) -> Option<usize> {
+        let used = self.total_prompt_tokens.load(Ordering::SeqCst)
+            + self.total_completion_tokens.load(Ordering::SeqCst);
+        self.options.token_quota.map(|q| q.saturating_sub(used))
+    }
+
+    /// Fails with [`crate::error::Error::QuotaExceeded`] once `token_quota` is used up.
+    fn check_quota(&self) -> Result<()> {
+        match self.options.token_quota {
+            Some(quota) if self.quota_left() == Some(0) => {
+                Err(crate::error::Error::QuotaExceeded(quota))
             }
+            _ => Ok(()),
         }
-        Ok((has_stop_token, stop_pos))
     }
 
-    fn process_token(
-        &self,
-        mut n_sent_text: usize,
-        mut generated_string: String,
-        token: LlamaToken,
-        callback: std::sync::Arc<Box<dyn Fn(String) -> bool + Send + Sync + 'static>>,
-    ) -> Result<(bool, String, usize)> {
-        let mut text_to_send = "".to_string();
-        let token_str = self.ctx.token_to_piece(&token)?;
-        if !self.model.token_is_eog(token)? {
-            generated_string += &token_str;
-        }
-        let mut has_next_token = true;
-        let mut incomplete = false;
-        for i in 1..std::cmp::min(5, generated_string.len() + 1) {
-            let c = generated_string.as_bytes()[generated_string.len() - i];
-            if (c & 0xC0) == 0x80 {
-                // continuation byte: 10xxxxxx
-                continue;
+    fn eval_id(&mut self, token: LlamaToken) -> Result<()> {
+        self.ensure_space(1)?;
+        crate::metrics::record_batches(1, 1);
+        let decode_error = self.decode_error(1);
+        self.logit = self
+            .ctx
+            .eval_id(token, &mut self.n_curr)
+            .map_err(decode_error)?;
+        self.last_token = Some(token);
+        self.history.push(token);
+        Ok(())
+    }
+
+    /// Evaluates `tokens` in one batch with logits for each, `self.logit` points at the last.
+    fn eval_draft(&mut self, tokens: &[LlamaToken]) -> Result<()> {
+        self.ensure_space(tokens.len())?;
+        crate::metrics::record_batches(1, tokens.len());
+        let decode_error = self.decode_error(tokens.len());
+        self.ctx
+            .eval_with_logits(tokens, &mut self.n_curr)
+            .map_err(decode_error)?;
+        self.logit = tokens.len() as i32 - 1;
+        self.last_token = tokens.last().copied();
+        self.history.extend(tokens);
+        Ok(())
+    }
+
+    /// Drops the kv cache from position `n_curr` on without decoding anything, the logits of
+    /// the last token kept are at batch index `logit`.
+    fn drop_tail(&mut self, n_curr: i32, logit: i32) {
+        let seq = self.ctx.seq_id();
+        self.ctx.clear_kv_cache_seq(seq, Some(n_curr as u32), None);
+        self.n_curr = n_curr;
+        self.history.truncate(n_curr.max(0) as usize);
+        self.last_token = self.history.last().copied().filter(|t| *t != NO_TOKEN);
+        self.logit = logit;
+    }
+
+    /// Tokens that followed the last `ngram - 1` tokens of the context and `token` the last
+    /// time they appeared, at most `max` of them.
+    fn lookup_draft(&self, token: LlamaToken, ngram: usize, max: usize) -> Vec<LlamaToken> {
+        let history = &self.history;
+        if ngram == 0
+            || max == 0
+            || history.len() + 1 < ngram
+            || history.len() != self.n_curr.max(0) as usize
+        {
+            return vec![];
+        }
+        let mut key = history[history.len() + 1 - ngram..].to_vec();
+        key.push(token);
+        (0..history.len().saturating_sub(ngram))
+            .rev()
+            .find(|i| history[*i..*i + ngram] == key[..])
+            .map(|i| {
+                history[i + ngram..]
+                    .iter()
+                    .take(max)
+                    .take_while(|t| **t != NO_TOKEN)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drops the kv cache from position `n_curr` on. The state of a recurrent model can only
+    /// be cleared as a whole, it is rebuilt from the tokens before `n_curr` instead.
+    fn clear_from(&mut self, n_curr: i32) -> Result<()> {
+        let seq = self.ctx.seq_id();
+        let n_curr = n_curr.max(0) as usize;
+        if self.ctx.clear_kv_cache_seq(seq, Some(n_curr as u32), None) {
+            return Ok(());
+        }
+        let kept = &self.history[..n_curr.min(self.history.len())];
+        if kept.len() < n_curr || kept.contains(&NO_TOKEN) {
+            return Err(crate::error::Error::Unsupported(
+                "the state of a recurrent model can't go back to before an image",
+            ));
+        }
+        let tokens = kept.to_vec();
+        tracing::debug!(
+            n_tokens = tokens.len(),
+            "recurrent state evaluated again to go back"
+        );
+        self.ctx.clear_kv_cache_seq(seq, None, None);
+        self.n_curr = 0;
+        self.history.clear();
+        self.eval_tokens(tokens)
+    }
+
+    /// Evaluates a token held back by token healing when the prompt continues instead.
+    fn flush_healing(&mut self) -> Result<()> {
+        if let Some((token, _)) = self.healing.take() {
+            self.eval_id(token)?;
+        }
+        Ok(())
+    }
+
+    /// Drops everything evaluated after position `n_curr` and decodes the token before it
+    /// again, so its logits are current for the next prediction.
+    fn rewind(&mut self, n_curr: i32, last_token: Option<LlamaToken>) -> Result<()> {
+        let from = if last_token.is_some() {
+            n_curr - 1
+        } else {
+            n_curr
+        };
+        if from < self.ga_i {
+            return Err(crate::error::Error::Unsupported(
+                "can't go back into the part of the context grouped by self-extend",
+            ));
+        }
+        match last_token {
+            Some(token) if n_curr > 0 => {
+                self.clear_from(n_curr - 1)?;
+                self.n_curr = n_curr - 1;
+                self.history.truncate(self.n_curr as usize);
+                self.eval_id(token)?;
             }
-            if (c & 0xE0) == 0xC0 {
-                // 2-byte character: 110xxxxx ...
-                incomplete = i < 2;
-            } else if (c & 0xF0) == 0xE0 {
-                // 3-byte character: 1110xxxx ...
-                incomplete = i < 3;
-            } else if (c & 0xF8) == 0xF0 {
-                // 4-byte character: 11110xxx ...
-                incomplete = i < 4;
+            _ => {
+                self.clear_from(n_curr)?;
+                self.n_curr = n_curr;
+                self.history.truncate(n_curr.max(0) as usize);
+                self.last_token = last_token;
             }
-            // else 1-byte character or invalid byte
-            break;
-        }
-        if !incomplete {
-            let mut pos = std::cmp::min(n_sent_text, generated_string.len());
-            if !self.model.token_is_eog(token)? {
-                let str_test = generated_string[pos..].to_string();
-                let is_stop_full;
-                let (h, mut stop_pos) =
-                    self.find_stopping_string(&str_test, token_str.len(), true)?;
-                has_next_token = h;
-                if let Some(sp) = &stop_pos {
-                    is_stop_full = true;
-                    generated_string = generated_string[pos + sp..].to_string();
-                    pos = std::cmp::min(n_sent_text, generated_string.len());
-                } else {
-                    is_stop_full = false;
-                    (has_next_token, stop_pos) =
-                        self.find_stopping_string(&str_test, token_str.len(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Sampler which only allows tokens starting with the held back prompt text.
+    fn healing_sampler(&self, params: &SamplingParams, prefix: &str) -> Result<Sampler> {
+        let mut params = params.clone();
+        for (token, piece) in self.model.model.tokens() {
+            match piece {
+                Ok(p) if p.starts_with(prefix) => {}
+                _ => params.add_logit_bias(token, f32::NEG_INFINITY),
+            }
+        }
+        Ok(Sampler::new(&self.model.model, params)?)
+    }
+
+    fn eval_image(&mut self, image: &[u8]) -> Result<()> {
+        if self.ctx.seq_id() != 0 {
+            return Err(crate::error::Error::Unsupported(
+                "images can't be evaluated in a forked context",
+            ));
+        }
+        self.flush_healing()?;
+        self.self_extend()?;
+        self.last_token = None;
+        let embedded_image =
+            if let Some(clip_context) = self.projector.as_ref().or(self.model.mmproj.as_ref()) {
+                clip_context.embed_image(self.options.n_threads, image)?
+            } else {
+                return Err(crate::error::Error::MmprojNotDefined);
+            };
+        log::debug!("image embedding created: {} tokens", embedded_image.len());
+        self.ensure_space(embedded_image.len())?;
+        let n_batch = self.ctx.n_batch() as usize;
+        crate::metrics::record_batches(
+            embedded_image.len().div_ceil(n_batch),
+            embedded_image.len(),
+        );
+        let decode_error = self.decode_error(embedded_image.len());
+        self.ctx
+            .eval_embed_image(embedded_image, n_batch, &mut self.n_curr)
+            .map_err(decode_error)?;
+        self.history.resize(self.n_curr.max(0) as usize, NO_TOKEN);
+        Ok(())
+    }
+
+    /// Samples and evaluates tokens until a stop condition, sending the text to `token_callback`.
+    fn generate(
+        &mut self,
+        sampler: &mut Sampler,
+        mut healing_sampler: Option<Sampler>,
+        params: &PredictOptions,
+        n_sent_text: usize,
+        token_callback: std::sync::Arc<Box<dyn Fn(String) -> bool + Send + Sync + 'static>>,
+    ) -> Result<()> {
+        let max_len = if let Some(mm) = params.max_len {
+            mm as usize
+        } else {
+            usize::MAX
+        };
+        let quota_left = self.quota_left();
+        let stops = self
+            .model
+            .template_stops(None)?
+            .into_iter()
+            .map(String::from)
+            .chain(params.stop.iter().cloned())
+            .collect();
+        let mut seq = SequenceDecoder::new(
+            stops,
+            params.reverse_prompts.clone(),
+            max_len.min(quota_left.unwrap_or(usize::MAX)),
+            n_sent_text,
+            token_callback,
+        )
+        .with_repetition_abort(params.repetition_abort)
+        .with_token_id_callback(params.token_id_callback.clone());
+        // whether a stop condition ended the generation before the length limits
+        let mut stopped = false;
+        let custom = params.custom_sampler.as_deref();
+        // token sampled while verifying a draft, not evaluated yet
+        let mut next = None;
+        let (mut n_drafted, mut n_accepted) = (0, 0);
+        'generate: while seq.remaining() > 0 {
+            let token_id = match (next.take(), healing_sampler.take()) {
+                (Some(token), _) => token,
+                (None, Some(mut hs)) => hs.sample(&self.ctx, -1, false)?,
+                (None, None) => self.sample(sampler, custom, -1, self.history.len())?,
+            };
+            sampler.accept(token_id, true)?;
+            self.self_extend()?;
+            let room = (self.ctx.n_ctx() as i32 - self.n_cells() - 1).max(0) as usize;
+            // the negative prompt's decode replaces the logits of a draft, recurrent models
+            // can't drop the rejected part of one, the token and its draft are decoded in one
+            // batch
+            let n_batch = self.ctx.n_batch() as usize;
+            let max_draft = if self.guidance.is_some() || self.is_recurrent() {
+                0
+            } else {
+                params
+                    .lookup_draft
+                    .min(seq.remaining() - 1)
+                    .min(room)
+                    .min(n_batch.saturating_sub(1))
+            };
+            let draft = self.lookup_draft(token_id, params.lookup_ngram, max_draft);
+            let mut tokens = vec![token_id];
+            if draft.is_empty() {
+                self.eval_id(token_id)?;
+            } else {
+                let n_batch_start = self.n_curr;
+                tokens.extend(&draft);
+                self.eval_draft(&tokens)?;
+                tokens.truncate(1);
+                // the logits after each kept token decide on the next draft token
+                for (i, d) in draft.iter().enumerate() {
+                    let n_history = n_batch_start as usize + i + 1;
+                    let sampled = self.sample(sampler, custom, i as i32, n_history)?;
+                    if sampled != *d {
+                        next = Some(sampled);
+                        break;
+                    }
+                    sampler.accept(sampled, true)?;
+                    tokens.push(sampled);
                 }
-                if stop_pos.is_none()
-                    || (!has_next_token
-                        && !is_stop_full
-                        && stop_pos.is_some()
-                        && stop_pos.unwrap() > 0)
-                {
-                    text_to_send = generated_string[pos..].to_string();
-                    n_sent_text += text_to_send.len();
+                n_drafted += draft.len();
+                n_accepted += tokens.len() - 1;
+                if tokens.len() <= draft.len() {
+                    let n_kept = tokens.len() as i32;
+                    self.drop_tail(n_batch_start + n_kept, n_kept - 1);
                 }
-            } else {
-                text_to_send = generated_string[pos..].to_string();
-                n_sent_text += text_to_send.len();
             }
-            if !callback(text_to_send) {
-                return Ok((false, generated_string, n_sent_text));
+            for (i, token_id) in tokens.iter().copied().enumerate() {
+                self.completion_tokens += 1;
+                self.total_completion_tokens.fetch_add(1, Ordering::SeqCst);
+                let bytes = self
+                    .model
+                    .model
+                    .token_to_bytes(&token_id, params.decode_special_tokens)?;
+                if !seq.push(token_id.0, &bytes, self.model.token_is_eog(token_id)?) {
+                    // accepted draft tokens after the stop are not part of the answer, the
+                    // stop token is decoded again so its logits are the last ones
+                    let n_extra = (tokens.len() - i - 1) as i32;
+                    if n_extra > 0 {
+                        self.rewind(self.n_curr - n_extra, Some(token_id))?;
+                    }
+                    stopped = true;
+                    break 'generate;
+                }
             }
         }
-        if incomplete {
-            has_next_token = true;
+        if n_drafted > 0 {
+            tracing::debug!(
+                drafted = n_drafted,
+                accepted = n_accepted,
+                "prompt lookup drafts verified"
+            );
         }
-        if self.model.token_is_eog(token)? {
-            has_next_token = false;
+        // a character cut off by max_len
+        seq.finish();
+        self.finish_reason = Some(seq.finish_reason());
+        if self.finish_reason == Some(FinishReason::DegenerateOutput) {
+            tracing::warn!(
+                generated_tokens = seq.n_generated(),
+                "prediction aborted, the output repeats itself"
+            );
         }
-        Ok((has_next_token, generated_string, n_sent_text))
+        let n_generated = seq.n_generated();
+        if !stopped && quota_left.is_some_and(|q| n_generated >= q && n_generated < max_len) {
+            return Err(crate::error::Error::QuotaExceeded(
+                self.options.token_quota.unwrap_or_default(),
+            ));
+        }
+        Ok(())
     }
-}
 
-impl Context for LlamaContext {
-    fn eval(&mut self, messages: Vec<Message>) -> Result<()> {
+    /// Picks a token from the logits at `index` with `custom`, or `sampler` without one.
+    /// The custom sampler sees the first `n_history` tokens of the context. With a negative
+    /// prompt the logits are guided away from its sequence first.
+    fn sample(
+        &mut self,
+        sampler: &mut Sampler,
+        custom: Option<&Mutex<Box<dyn CustomSampler>>>,
+        index: i32,
+        n_history: usize,
+    ) -> Result<LlamaToken> {
+        if self.guidance.is_none() && custom.is_none() {
+            return Ok(sampler.sample(&self.ctx, index, false)?);
+        }
+        let mut logits = self.ctx.get_logits_ith(index).to_vec();
+        if let Some(guidance) = self.guidance.as_deref_mut() {
+            let scale = guidance.scale;
+            let negative = guidance.logits(&self.history[..n_history])?;
+            apply_guidance(&mut logits, negative, scale);
+        }
+        let Some(custom) = custom else {
+            return Ok(sampler.sample_logits(&logits, false)?);
+        };
+        let history: Vec<Token> = self.history[..n_history].iter().map(|t| t.0).collect();
+        let token = custom
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sample(&mut logits, &history);
+        if !(0..logits.len() as i32).contains(&token) {
+            return Err(crate::error::Error::InvalidToken(token, logits.len()));
+        }
+        Ok(LlamaToken(token))
+    }
+
+    /// Brings the kv cache gauge in [`crate::metrics`] up to date with the cells used by this
+    /// context and its forks.
+    fn report_kv_cache(&mut self) {
+        let used = self.ctx.get_kv_cache_used_cells();
+        let reported = self.kv_reported.swap(used, Ordering::SeqCst);
+        crate::metrics::add_kv_cache_tokens((used - reported) as i64);
+    }
+
+    /// Evaluates `messages` in the chat template, callers hold the shared lock.
+    fn eval_messages(&mut self, messages: Vec<Message>) -> Result<()> {
+        self.check_quota()?;
+        if std::mem::take(&mut self.predicted) {
+            self.prompt_tokens = 0;
+            self.completion_tokens = 0;
+        }
+        let n_start = self.n_curr;
+        let span = tracing::debug_span!(
+            "eval",
+            n_past = n_start,
+            prompt_tokens = tracing::field::Empty
+        );
+        let _span = span.enter();
+        let start = std::time::Instant::now();
+        let messages = self.escape_messages(messages);
         let templated_message = self.model.apply_template(messages, None, true)?;
-        templated_message
+        // the prompt ends in the generation prompt of the template, there is nothing to heal
+        let res = templated_message
             .into_iter()
             .enumerate()
             .try_for_each(|(i, m)| {
                 match m {
-                    Templated::Str(st) => self.eval_str(&st, i == 0)?,
+                    Templated::Str(st) => self.eval_str(&st, i == 0, false)?,
                     Templated::Image(st) => self.eval_image(&st)?,
                 }
                 Ok::<_, crate::error::Error>(())
-            })?;
-        Ok(())
+            });
+        let n_prompt = (self.n_curr - n_start).max(0) as usize;
+        span.record("prompt_tokens", n_prompt);
+        self.prompt_tokens += n_prompt;
+        self.total_prompt_tokens
+            .fetch_add(n_prompt, Ordering::SeqCst);
+        crate::metrics::record_prompt(n_prompt, start.elapsed());
+        self.report_kv_cache();
+        tracing::debug!(
+            elapsed_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "prompt evaluated"
+        );
+        res
+    }
+
+    /// Log-probability of each label as the answer to `prompt`.
+    ///
+    /// The prompt and label tokens count against `token_quota` as prompt tokens, though they
+    /// are dropped again.
+    fn score_labels(&mut self, prompt: Message, labels: &[&str]) -> Result<Vec<f32>> {
+        self.check_quota()?;
+        let n_start = self.n_curr;
+        let prompt = self.escape_messages(vec![prompt]);
+        let templated = self.model.apply_template(prompt, None, true)?;
+        let res = templated.into_iter().enumerate().try_for_each(|(i, t)| {
+            match t {
+                Templated::Str(st) => self.eval_str(&st, i == 0 && self.n_curr == 0, false)?,
+                Templated::Image(img) => self.eval_image(&img)?,
+            }
+            Ok::<_, crate::error::Error>(())
+        });
+        self.total_prompt_tokens
+            .fetch_add((self.n_curr - n_start).max(0) as usize, Ordering::SeqCst);
+        res?;
+        let n_prompt = self.n_curr;
+        let prompt_logits = self.ctx.get_logits_ith(self.logit).to_vec();
+        let mut scores = Vec::with_capacity(labels.len());
+        for label in labels {
+            self.check_quota()?;
+            let tokens = self.model.model.str_to_token(label, AddBos::Never)?;
+            let mut score = 0.0;
+            for (i, token) in tokens.iter().enumerate() {
+                score += if i == 0 {
+                    log_softmax(&prompt_logits, *token)
+                } else {
+                    log_softmax(self.ctx.get_logits_ith(self.logit), *token)
+                };
+                if i + 1 < tokens.len() {
+                    self.eval_id(*token)?;
+                    self.total_prompt_tokens.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            self.clear_from(n_prompt)?;
+            self.n_curr = n_prompt;
+            self.history.truncate(n_prompt as usize);
+            scores.push(score);
+        }
+        Ok(scores)
+    }
+
+    /// Evaluates the tokens of one of `choices`, walking down the prefix tree of their tokens
+    /// with the most likely allowed token at each step. The end of generation token stands
+    /// for ending the answer where a choice is complete and a longer one goes on.
+    fn choose_tokens(&mut self, choices: &[Vec<LlamaToken>]) -> Result<(usize, f32)> {
+        let eos = self.model.model.token_eos();
+        let mut candidates: Vec<usize> = (0..choices.len()).collect();
+        let mut log_p = 0.0;
+        for depth in 0.. {
+            let complete = candidates
+                .iter()
+                .copied()
+                .find(|&c| choices[c].len() == depth);
+            candidates.retain(|&c| choices[c].len() > depth);
+            let mut next: Vec<LlamaToken> = candidates.iter().map(|&c| choices[c][depth]).collect();
+            next.sort_by_key(|t| t.0);
+            next.dedup();
+            if let (Some(c), []) = (complete, next.as_slice()) {
+                return Ok((c, log_p.exp()));
+            }
+            if let (None, [c]) = (complete, candidates.as_slice()) {
+                // the only choice left follows without sampling
+                self.eval_tokens(choices[*c][depth..].to_vec())?;
+                return Ok((*c, log_p.exp()));
+            }
+            let logits = self.ctx.get_logits_ith(self.logit);
+            let mut allowed: Vec<(Option<LlamaToken>, f32)> = next
+                .iter()
+                .map(|t| (Some(*t), logits[t.0 as usize]))
+                .collect();
+            if complete.is_some() {
+                allowed.push((None, logits[eos.0 as usize]));
+            }
+            let scores: Vec<f32> = allowed.iter().map(|a| a.1).collect();
+            let (token, score) = allowed
+                .iter()
+                .copied()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("a choice continues or is complete");
+            log_p += score - log_sum_exp(&scores);
+            let Some(token) = token else {
+                return Ok((
+                    complete.expect("only complete choices can end"),
+                    log_p.exp(),
+                ));
+            };
+            candidates.retain(|&c| choices[c][depth] == token);
+            self.eval_id(token)?;
+        }
+        unreachable!("the choices have a finite length")
+    }
+}
+
+impl Drop for LlamaContext {
+    fn drop(&mut self) {
+        if self.ctx.is_forked() {
+            // the other contexts keep their cells, only this sequence's are released
+            let lock = self.ctx.shared_lock();
+            let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+            let seq = self.ctx.seq_id();
+            self.ctx.clear_kv_cache_seq(seq, None, None);
+            self.report_kv_cache();
+        } else {
+            let reported = self.kv_reported.swap(0, Ordering::SeqCst);
+            crate::metrics::add_kv_cache_tokens(-(reported as i64));
+        }
+    }
+}
+
+impl Context for LlamaContext {
+    fn eval(&mut self, messages: Vec<Message>) -> Result<()> {
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.eval_messages(messages)
+    }
+
+    fn eval_text(&mut self, text: &str) -> Result<()> {
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.check_quota()?;
+        if std::mem::take(&mut self.predicted) {
+            self.prompt_tokens = 0;
+            self.completion_tokens = 0;
+        }
+        let n_start = self.n_curr;
+        let _span = tracing::debug_span!("eval_text", n_past = n_start).entered();
+        let start = std::time::Instant::now();
+        let heal = self.options.token_healing;
+        let res = if self.options.escape_special_tokens {
+            self.eval_str(&mark_content(text), self.n_curr == 0, heal)
+        } else {
+            self.eval_str(text, self.n_curr == 0, heal)
+        };
+        let n_prompt = (self.n_curr - n_start).max(0) as usize;
+        self.prompt_tokens += n_prompt;
+        self.total_prompt_tokens
+            .fetch_add(n_prompt, Ordering::SeqCst);
+        crate::metrics::record_prompt(n_prompt, start.elapsed());
+        self.report_kv_cache();
+        res
     }
 
     fn predict(&mut self, params: &PredictOptions) -> Result<String> {
@@ -914,32 +1771,283 @@ impl Context for LlamaContext {
         params: &PredictOptions,
         token_callback: std::sync::Arc<Box<dyn Fn(String) -> bool + Send + Sync + 'static>>,
     ) -> Result<()> {
-        let mut generated_text = "".to_string();
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.restore_logits()?;
+        self.check_quota()?;
         let mut n_sent_text = 0;
-        let mut sampler = Sampler::new(&self.model.model, params.clone().into())?;
-        let stop = if let Some(mm) = params.max_len {
-            mm as usize
-        } else {
-            usize::MAX
-        };
-        for _ in 0..stop {
-            let token_id = sampler.sample(&self.ctx, -1, false)?;
-            sampler.accept(token_id, true)?;
-            self.ctx.eval_id(token_id, &mut self.n_curr)?;
-            let (has_next_token, g, n) = self.process_token(
-                n_sent_text,
-                generated_text,
-                token_id,
-                token_callback.clone(),
-            )?;
-            generated_text = g;
-            n_sent_text = n;
-            if !has_next_token {
-                break;
+        let mut sampling_params: SamplingParams = params.clone().into();
+        if self.options.deterministic && sampling_params.seed == DEFAULT_SEED {
+            sampling_params.seed = self.options.seed;
+        }
+        // the first token regenerates the held back prompt text, which is not sent again
+        let healing_sampler = match self.healing.take() {
+            Some((_, prefix)) => {
+                n_sent_text = prefix.len();
+                Some(self.healing_sampler(&sampling_params, &prefix)?)
             }
-            //            let token_str = self.ctx.token_to_piece(&token_id)?;
-            //            token_callback(token_str);
+            None => None,
+        };
+        let mut sampler = Sampler::new(&self.model.model, sampling_params)?;
+        self.predicted = true;
+        let _span = tracing::debug_span!("predict", n_past = self.n_curr).entered();
+        let start = std::time::Instant::now();
+        let n_start = self.completion_tokens;
+        let res = self.generate(
+            &mut sampler,
+            healing_sampler,
+            params,
+            n_sent_text,
+            token_callback,
+        );
+        let n_generated = self.completion_tokens - n_start;
+        crate::metrics::record_generation(n_generated, start.elapsed());
+        self.report_kv_cache();
+        tracing::debug!(
+            generated_tokens = n_generated,
+            elapsed_ms = start.elapsed().as_secs_f64() * 1000.0,
+            "prediction finished"
+        );
+        res
+    }
+
+    fn classify(&mut self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>> {
+        if labels.is_empty() {
+            return Ok(vec![]);
+        }
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.flush_healing()?;
+        let (n_start, last_start) = (self.n_curr, self.last_token);
+        let prompt = Message {
+            content: format!(
+                "Classify the text into exactly one of these labels: {}.\nAnswer with the label only.\n\nText: {text}",
+                labels.join(", ")
+            ),
+            role: Role::User,
+            images: vec![],
+        };
+        let scores = self.score_labels(prompt, labels);
+        self.rewind(n_start, last_start)?;
+        let scores = scores?;
+        // softmax over the label log-probabilities
+        let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = scores.iter().map(|s| (s - max).exp()).sum();
+        let mut res: Vec<(String, f32)> = labels
+            .iter()
+            .zip(scores)
+            .map(|(l, s)| (l.to_string(), (s - max).exp() / sum))
+            .collect();
+        res.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(res)
+    }
+
+    fn choose(&mut self, prompt: Message, choices: &[&str]) -> Result<(usize, f32)> {
+        if choices.is_empty() {
+            return Err(crate::error::Error::Unsupported(
+                "there is nothing to choose from",
+            ));
+        }
+        // the sequence must not move on between the prompt and the answer
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.eval_messages(vec![prompt])?;
+        self.flush_healing()?;
+        self.restore_logits()?;
+        let choices = choices
+            .iter()
+            .map(|c| Ok(self.model.model.str_to_token(c, AddBos::Never)?))
+            .collect::<Result<Vec<_>>>()?;
+        // the prompt may have used up the quota
+        self.check_quota()?;
+        let n_start = self.n_curr;
+        let res = self.choose_tokens(&choices);
+        let n_answer = (self.n_curr - n_start).max(0) as usize;
+        self.completion_tokens += n_answer;
+        self.total_completion_tokens
+            .fetch_add(n_answer, Ordering::SeqCst);
+        self.predicted = true;
+        self.report_kv_cache();
+        res
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            total_prompt_tokens: self.total_prompt_tokens.load(Ordering::SeqCst),
+            total_completion_tokens: self.total_completion_tokens.load(Ordering::SeqCst),
+            context_used: self.n_cells().max(0) as usize,
+            context_size: self.ctx.n_ctx() as usize,
+            finish_reason: self.finish_reason,
+        }
+    }
+
+    fn state_bytes(&self) -> Result<Vec<u8>> {
+        // the llama.cpp state holds every sequence of the shared kv cache
+        if self.ctx.seq_id() != 0 {
+            return Err(crate::error::Error::Unsupported(
+                "the state of a forked context can't be saved",
+            ));
+        }
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        if self.n_grouped > 0 {
+            return Err(crate::error::Error::Unsupported(
+                "the state of a context grouped by self-extend can't be saved",
+            ));
+        }
+        let mut out = STATE_MAGIC.to_vec();
+        out.extend(STATE_VERSION.to_le_bytes());
+        let (healing_token, healing_text) = match &self.healing {
+            Some((t, s)) => (t.0, s.as_str()),
+            None => (-1, ""),
+        };
+        for v in [
+            self.model.model.n_vocab(),
+            self.model.model.n_embd(),
+            self.ctx.n_ctx() as i32,
+            self.n_curr,
+            self.logit,
+            self.last_token.map_or(-1, |t| t.0),
+            healing_token,
+        ] {
+            out.extend(v.to_le_bytes());
+        }
+        out.extend((healing_text.len() as u64).to_le_bytes());
+        out.extend(healing_text.as_bytes());
+        let state = self.ctx.state_bytes();
+        out.extend((state.len() as u64).to_le_bytes());
+        out.extend(state);
+        out.extend(crc32(&out).to_le_bytes());
+        Ok(out)
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        if self.ctx.seq_id() != 0 || self.ctx.is_forked() {
+            return Err(crate::error::Error::Unsupported(
+                "a state can't be restored into a context with forks",
+            ));
         }
+        let invalid = |msg: &str| crate::error::Error::InvalidState(msg.to_string());
+        let mut r = StateReader(state);
+        if r.take(4)? != STATE_MAGIC {
+            return Err(invalid("not a context state"));
+        }
+        if r.i32()? as u32 != STATE_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let (payload, sum) = state.split_at(state.len().saturating_sub(4));
+        if sum.len() < 4 || crc32(payload).to_le_bytes() != sum {
+            return Err(invalid("checksum mismatch"));
+        }
+        r.0 = &r.0[..r.0.len().saturating_sub(4)];
+        let header = [r.i32()?, r.i32()?, r.i32()?];
+        if header
+            != [
+                self.model.model.n_vocab(),
+                self.model.model.n_embd(),
+                self.ctx.n_ctx() as i32,
+            ]
+        {
+            return Err(invalid("saved from a different model or context size"));
+        }
+        let (n_curr, logit, last_token, healing_token) = (r.i32()?, r.i32()?, r.i32()?, r.i32()?);
+        let len = r.u64()? as usize;
+        let healing_text = String::from_utf8(r.take(len)?.to_vec())?;
+        let len = r.u64()? as usize;
+        let llama_state = r.take(len)?;
+        if !r.0.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        // the checksum matches what state_bytes wrote and the header matches this context,
+        // llama.cpp reads no further than the length
+        unsafe { self.ctx.restore_state(llama_state) }
+            .map_err(|e| crate::error::Error::InvalidState(e.to_string()))?;
+        self.n_curr = n_curr;
+        self.logit = logit;
+        self.history = vec![NO_TOKEN; n_curr.max(0) as usize];
+        (self.ga_i, self.n_grouped) = (0, 0);
+        self.last_token = (last_token >= 0).then_some(LlamaToken(last_token));
+        self.healing = (healing_token >= 0).then(|| (LlamaToken(healing_token), healing_text));
+        self.prompt_tokens = 0;
+        self.completion_tokens = 0;
+        self.predicted = false;
+        self.report_kv_cache();
         Ok(())
     }
+
+    fn set_projector(&mut self, projector: Option<ClipContext>) {
+        self.projector = projector;
+    }
+
+    fn n_past(&self) -> usize {
+        self.n_curr.max(0) as usize
+    }
+
+    fn n_ctx(&self) -> usize {
+        self.ctx.n_ctx() as usize
+    }
+
+    fn n_batch(&self) -> usize {
+        self.ctx.n_batch() as usize
+    }
+
+    fn n_ubatch(&self) -> usize {
+        self.ctx.n_ubatch() as usize
+    }
+
+    fn truncate(&mut self, n_past: usize) -> Result<()> {
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        if n_past >= self.n_past() {
+            return Ok(());
+        }
+        self.healing = None;
+        self.rewind(n_past as i32, None)?;
+        self.report_kv_cache();
+        Ok(())
+    }
+
+    fn discard(&mut self, start: usize, end: usize) -> Result<()> {
+        let lock = self.ctx.shared_lock();
+        let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let end = end.min(self.n_past());
+        if start >= end {
+            return Ok(());
+        }
+        if (start as i32) < self.ga_i {
+            return Err(crate::error::Error::Unsupported(
+                "can't discard from the part of the context grouped by self-extend",
+            ));
+        }
+        if self.is_recurrent() {
+            return Err(crate::error::Error::Unsupported(
+                "a recurrent model can't drop tokens from the middle of its state",
+            ));
+        }
+        let seq = self.ctx.seq_id();
+        self.ctx
+            .clear_kv_cache_seq(seq, Some(start as u32), Some(end as u32));
+        // the tokens after the gap move up, their logits stay valid
+        self.ctx
+            .kv_cache_seq_add(seq, Some(end as u32), None, start as i32 - end as i32);
+        self.n_curr -= (end - start) as i32;
+        self.history
+            .drain(start.min(self.history.len())..end.min(self.history.len()));
+        self.report_kv_cache();
+        Ok(())
+    }
+
+    fn fork(&mut self) -> Result<Pin<Box<Mutex<dyn Context>>>> {
+        Ok(Box::pin(Mutex::new(LlamaContext::fork(self)?)))
+    }
+
+    fn state_size(&self) -> usize {
+        self.ctx.get_state_size()
+    }
+
+    fn set_prompt_progress(&mut self, callback: Option<Arc<Box<PromptProgressCallback>>>) {
+        self.prompt_progress = callback;
+    }
 }
@@ -2,7 +2,7 @@
 #[cfg(feature = "llama")]
 use std::{path::PathBuf, pin::Pin, sync::Mutex};
 
-use crate::options::{Message, PredictOptions};
+use crate::options::{Message, PredictOptions, Usage};
 #[cfg(feature = "whisper")]
 use crate::{options::AutomaticSpeechRecognitionOptions, Result};
 #[cfg(feature = "whisper")]
@@ -11,9 +11,14 @@ use std::path::PathBuf;
 #[cfg(feature = "tts")]
 use crate::options::TTSOptions;
 
+#[cfg(feature = "llama")]
+use llama_cpp::clip::ClipContext;
+
 #[cfg(feature = "llama")]
 use crate::{
-    options::{ContextOptions, ModelOptions},
+    gguf::{Architecture, Capabilities},
+    health::{Health, Placement, Warmup},
+    options::{ContextOptions, EmbeddingOptions, ModelOptions, QuantType, Token},
     Result,
 };
 
@@ -25,6 +30,8 @@ use crate::{
 
 #[cfg(feature = "llama")]
 pub mod llama;
+#[cfg(feature = "llama")]
+mod sequence;
 
 #[cfg(feature = "whisper")]
 pub mod whisper;
@@ -38,19 +45,54 @@ pub mod tts;
 #[cfg(feature = "llama")]
 pub trait Context: Send {
     fn eval(&mut self, msg: Vec<Message>) -> Result<()>;
+    fn eval_text(&mut self, text: &str) -> Result<()>;
     fn predict(&mut self, params: &PredictOptions) -> Result<String>;
     fn predict_with_callback(
         &mut self,
         params: &PredictOptions,
         token_callback: std::sync::Arc<Box<dyn Fn(String) -> bool + Send + Sync + Sync + 'static>>,
     ) -> Result<()>;
+    fn classify(&mut self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>>;
+    fn choose(&mut self, prompt: Message, choices: &[&str]) -> Result<(usize, f32)>;
+    fn usage(&self) -> Usage;
+    fn state_bytes(&self) -> Result<Vec<u8>>;
+    fn restore_state(&mut self, state: &[u8]) -> Result<()>;
+    fn set_projector(&mut self, projector: Option<ClipContext>);
+    fn n_past(&self) -> usize;
+    fn n_ctx(&self) -> usize;
+    fn n_batch(&self) -> usize;
+    fn n_ubatch(&self) -> usize;
+    /// Drops everything evaluated from position `n_past` on.
+    fn truncate(&mut self, n_past: usize) -> Result<()>;
+    /// Drops the tokens at positions `start..end` and moves the ones after them up.
+    fn discard(&mut self, start: usize, end: usize) -> Result<()>;
+    fn fork(&mut self) -> Result<Pin<Box<Mutex<dyn Context>>>>;
+    /// Bytes of the llama.cpp state of the context, forks included.
+    fn state_size(&self) -> usize;
+    fn set_prompt_progress(
+        &mut self,
+        callback: Option<std::sync::Arc<Box<crate::options::PromptProgressCallback>>>,
+    );
 }
 
 #[cfg(feature = "llama")]
 pub trait Model: Send + Sync {
     fn name(&self) -> Result<&str>;
     fn with_mmproj(&mut self, mmproj: PathBuf) -> Result<()>;
+    fn with_projector(&mut self, projector: ClipContext);
     fn new_context(&self, opions: ContextOptions) -> Result<Pin<Box<Mutex<dyn Context>>>>;
+    fn embed_batch(&self, texts: &[&str], options: &EmbeddingOptions) -> Result<Vec<Vec<f32>>>;
+    fn warmup(&self) -> Result<Warmup>;
+    fn architecture(&self) -> Result<Architecture>;
+    fn capabilities(&self) -> Result<Capabilities>;
+    fn placement(&self) -> Placement;
+    fn n_ctx_train(&self) -> usize;
+    fn n_embd(&self) -> usize;
+    fn n_vocab(&self) -> usize;
+    fn quant_type(&self) -> Result<Option<QuantType>>;
+    fn count_tokens(&self, message: &Message) -> Result<usize>;
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>>;
+    fn health(&self) -> Result<Health>;
 }
 
 #[cfg(feature = "llama")]
@@ -59,6 +101,7 @@ pub fn init(
     options: ModelOptions,
     callback: Option<impl FnMut(f32) -> bool + 'static>,
 ) -> Result<impl Model> {
+    crate::paths::init_dependencies()?;
     llama::Llama::new(model, options, callback)
 }
 
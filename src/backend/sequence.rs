@@ -0,0 +1,368 @@
+//! Stop strings, streaming and length limits of a generated sequence, apart from sampling
+//! and decoding so every sequence of a batch can keep its own.
+use std::sync::Arc;
+
+use llama_cpp::token::utf8::Utf8Decoder;
+
+use crate::options::{FinishReason, RepetitionAbort, Token, TokenCallback, TokenIdCallback};
+
+/// The state of one sequence being generated.
+///
+/// Text is sent to the callback as soon as it can't be the start of a stop string anymore,
+/// stop strings themselves are never sent.
+pub struct SequenceDecoder {
+    stops: Vec<String>,
+    reverse_prompts: Vec<String>,
+    limit: usize,
+    callback: Arc<Box<TokenCallback>>,
+    token_id_callback: Option<Arc<Box<TokenIdCallback>>>,
+    // pieces can end in the middle of a character, text is only sent once it is complete
+    decoder: Utf8Decoder,
+    // the generated text, cut at a stop string
+    text: String,
+    // everything generated
+    full_text: String,
+    n_sent: usize,
+    n_generated: usize,
+    repetition_abort: Option<RepetitionAbort>,
+    // the last generated tokens, as many as the repetition check looks at
+    tokens: Vec<Token>,
+    // a token ending in the middle of a character, sent to the token id callback with the next
+    held_token: Option<(Token, String)>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl SequenceDecoder {
+    /// A sequence ending at `stops`, after `reverse_prompts` or `limit` tokens. The first
+    /// `n_sent` bytes of the text, like a healed prompt, are not sent to `callback` again.
+    pub fn new(
+        stops: Vec<String>,
+        reverse_prompts: Vec<String>,
+        limit: usize,
+        n_sent: usize,
+        callback: Arc<Box<TokenCallback>>,
+    ) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            reverse_prompts,
+            limit,
+            callback,
+            token_id_callback: None,
+            decoder: Utf8Decoder::new(),
+            text: String::new(),
+            full_text: String::new(),
+            n_sent,
+            n_generated: 0,
+            repetition_abort: None,
+            tokens: vec![],
+            held_token: None,
+            finish_reason: None,
+        }
+    }
+
+    /// Ends the sequence once it repeats itself, see [`RepetitionAbort`].
+    pub fn with_repetition_abort(mut self, repetition_abort: Option<RepetitionAbort>) -> Self {
+        self.repetition_abort = repetition_abort;
+        self
+    }
+
+    /// Sends every token with its text to `callback` too, before stop strings are looked for.
+    pub fn with_token_id_callback(mut self, callback: Option<Arc<Box<TokenIdCallback>>>) -> Self {
+        self.token_id_callback = callback;
+        self
+    }
+
+    /// Tokens that can still be generated.
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.n_generated)
+    }
+
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// Why the sequence ended, [`FinishReason::Length`] while it can go on.
+    pub fn finish_reason(&self) -> FinishReason {
+        self.finish_reason.unwrap_or(FinishReason::Length)
+    }
+
+    /// Adds the bytes of a generated token and sends the text that became final.
+    ///
+    /// Returns whether the sequence continues, it ends at the end of generation, a stop
+    /// string, a reverse prompt, a repetition or when the callback returns `false`.
+    pub fn push(&mut self, token: Token, bytes: &[u8], is_eog: bool) -> bool {
+        self.n_generated += 1;
+        let piece = self.decoder.push(bytes);
+        let pending = self.decoder.is_pending();
+        let wanted = match &self.token_id_callback {
+            None => true,
+            Some(cb) => {
+                let wanted = match self.held_token.take() {
+                    Some((held, text)) => cb(held, text),
+                    None => true,
+                };
+                if pending {
+                    self.held_token = Some((token, piece.clone()));
+                    wanted
+                } else {
+                    cb(token, piece.clone()) && wanted
+                }
+            }
+        };
+        let has_next = self.process(&piece, is_eog, pending) && wanted;
+        self.full_text.push_str(&piece);
+        let tail = self.full_text.trim_end();
+        if !has_next
+            || self
+                .reverse_prompts
+                .iter()
+                .any(|p| !p.trim_end().is_empty() && tail.ends_with(p.trim_end()))
+        {
+            self.finish_reason = Some(FinishReason::Stop);
+            return false;
+        }
+        if self.is_repeating(token) {
+            self.finish_reason = Some(FinishReason::DegenerateOutput);
+            return false;
+        }
+        true
+    }
+
+    /// Adds `token` to the tokens checked for repetitions, whether the last `ngram` of them
+    /// occurred often enough.
+    fn is_repeating(&mut self, token: Token) -> bool {
+        let Some(abort) = self.repetition_abort else {
+            return false;
+        };
+        let (ngram, window) = (abort.ngram.max(1), abort.window.max(1));
+        self.tokens.push(token);
+        if self.tokens.len() > 2 * window {
+            self.tokens.drain(..self.tokens.len() - window);
+        }
+        let recent = &self.tokens[self.tokens.len().saturating_sub(window)..];
+        if recent.len() < ngram {
+            return false;
+        }
+        let last = &recent[recent.len() - ngram..];
+        let n_repeats = recent.windows(ngram).filter(|w| w == &last).count();
+        n_repeats >= abort.max_repeats.max(2)
+    }
+
+    /// Sends a character cut off by the end of the sequence, with the token it ends in.
+    pub fn finish(&mut self) {
+        let rest = self.decoder.finish();
+        if let (Some(cb), Some((token, text))) = (&self.token_id_callback, self.held_token.take()) {
+            cb(token, text + &rest);
+        }
+        if !rest.is_empty() {
+            (self.callback)(rest);
+        }
+    }
+
+    fn process(&mut self, piece: &str, is_eog: bool, pending: bool) -> bool {
+        if !is_eog {
+            self.text.push_str(piece);
+        }
+        if pending {
+            return !is_eog;
+        }
+        let pos = self.n_sent.min(self.text.len());
+        let mut has_next = !is_eog;
+        let mut send = true;
+        if !is_eog {
+            let unsent = &self.text[pos..];
+            if let Some(stop_pos) = full_stop_pos(&self.stops, unsent, piece.len()) {
+                self.text.truncate(pos + stop_pos);
+                has_next = false;
+            } else if partial_stop_pos(&self.stops, unsent).is_some() {
+                // held back until the next tokens tell whether it's a stop string
+                send = false;
+            }
+        }
+        let text = if send {
+            self.text[pos..].to_string()
+        } else {
+            String::new()
+        };
+        self.n_sent += text.len();
+        (self.callback)(text) && has_next
+    }
+}
+
+/// Earliest position of a stop string in `text`, only the end the last token can have
+/// completed is searched.
+fn full_stop_pos(stops: &[String], text: &str, last_token_size: usize) -> Option<usize> {
+    stops
+        .iter()
+        .filter_map(|stop| {
+            let mut from = text.len().saturating_sub(stop.len() + last_token_size);
+            while !text.is_char_boundary(from) {
+                from -= 1;
+            }
+            text[from..].find(stop.as_str()).map(|p| from + p)
+        })
+        .min()
+}
+
+/// Earliest position where `text` ends with the beginning of a stop string.
+fn partial_stop_pos(stops: &[String], text: &str) -> Option<usize> {
+    let last = text.chars().last()?;
+    stops
+        .iter()
+        .filter_map(|stop| {
+            stop.char_indices().rev().find_map(|(i, ch)| {
+                let partial = &stop[..i + ch.len_utf8()];
+                if ch == last && text.ends_with(partial) {
+                    Some(text.len() - partial.len())
+                } else {
+                    None
+                }
+            })
+        })
+        .min()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::SequenceDecoder;
+    use crate::options::{FinishReason, RepetitionAbort};
+
+    fn sequence(
+        stops: &[&str],
+        reverse_prompts: &[&str],
+        limit: usize,
+    ) -> (SequenceDecoder, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sink = sent.clone();
+        let seq = SequenceDecoder::new(
+            stops.iter().map(|s| s.to_string()).collect(),
+            reverse_prompts.iter().map(|s| s.to_string()).collect(),
+            limit,
+            0,
+            Arc::new(Box::new(move |text| {
+                sink.lock().unwrap().push(text);
+                true
+            })),
+        );
+        (seq, sent)
+    }
+
+    #[test]
+    fn stop_string_test() {
+        let (mut seq, sent) = sequence(&["<|im_end|>"], &[], 16);
+        assert!(seq.push(0, b"Hello", false));
+        // the start of the stop string is held back
+        assert!(seq.push(0, b" world<|im", false));
+        assert!(!seq.push(0, b"_end|>", false));
+        // the text before the stop string is still sent
+        assert_eq!(sent.lock().unwrap().concat(), "Hello world");
+        assert_eq!(seq.n_generated(), 3);
+        assert_eq!(seq.remaining(), 13);
+        assert_eq!(seq.finish_reason(), FinishReason::Stop);
+    }
+
+    #[test]
+    fn held_back_text_test() {
+        let (mut seq, sent) = sequence(&["<|im_end|>"], &[], 16);
+        assert!(seq.push(0, b"a <", false));
+        assert_eq!(sent.lock().unwrap().concat(), "");
+        // not a stop string after all
+        assert!(seq.push(0, b"b", false));
+        assert_eq!(sent.lock().unwrap().concat(), "a <b");
+        // held back text is sent at the end of generation
+        assert!(seq.push(0, b"<|im", false));
+        assert!(!seq.push(0, b"", true));
+        assert_eq!(sent.lock().unwrap().concat(), "a <b<|im");
+    }
+
+    #[test]
+    fn reverse_prompt_and_utf8_test() {
+        let (mut seq, sent) = sequence(&[], &["User:"], 16);
+        let heart = "❤".as_bytes();
+        assert!(seq.push(0, &heart[..1], false));
+        assert!(seq.push(0, &heart[1..], false));
+        assert!(!seq.push(0, b" User: ", false));
+        // reverse prompts are part of the text
+        assert_eq!(sent.lock().unwrap().concat(), "❤ User: ");
+
+        let (mut seq, sent) = sequence(&[], &[], 16);
+        assert!(seq.push(0, &heart[..2], false));
+        seq.finish();
+        assert_eq!(sent.lock().unwrap().concat(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn repetition_abort_test() {
+        let (seq, _) = sequence(&[], &[], 64);
+        let mut seq = seq.with_repetition_abort(Some(RepetitionAbort {
+            ngram: 4,
+            window: 32,
+            max_repeats: 3,
+        }));
+        // varied text goes on
+        for token in 0..16 {
+            assert!(seq.push(token, b"x", false));
+        }
+        // a phrase of 2 tokens, the 4-gram of it repeats every 2 tokens
+        let mut n_loop = 0;
+        while seq.push(100 + n_loop % 2, b"y", false) {
+            n_loop += 1;
+        }
+        assert_eq!(n_loop, 7);
+        assert_eq!(seq.finish_reason(), FinishReason::DegenerateOutput);
+
+        let (mut seq, _) = sequence(&[], &[], 2);
+        assert!(seq.push(1, b"a", false));
+        assert!(seq.push(1, b"a", false));
+        assert_eq!(seq.remaining(), 0);
+        assert_eq!(seq.finish_reason(), FinishReason::Length);
+    }
+
+    #[test]
+    fn token_id_callback_test() {
+        let (seq, sent) = sequence(&["</s>"], &[], 16);
+        let tokens = Arc::new(Mutex::new(vec![]));
+        let sink = tokens.clone();
+        let mut seq = seq.with_token_id_callback(Some(Arc::new(Box::new(move |id, text| {
+            sink.lock().unwrap().push((id, text));
+            true
+        }))));
+        let heart = "❤".as_bytes();
+        assert!(seq.push(1, b"a", false));
+        assert!(seq.push(2, &heart[..1], false));
+        assert!(seq.push(3, &heart[1..], false));
+        assert!(!seq.push(4, b"</s>", false));
+        // the stop string reaches only the token id callback
+        assert_eq!(sent.lock().unwrap().concat(), "a❤");
+        assert_eq!(
+            *tokens.lock().unwrap(),
+            [(1, "a"), (2, ""), (3, "❤"), (4, "</s>")].map(|(id, t)| (id, t.to_string()))
+        );
+
+        // a cut off character comes with the token it ends in, every token is sent once
+        let (seq, sent) = sequence(&[], &[], 16);
+        let tokens = Arc::new(Mutex::new(vec![]));
+        let sink = tokens.clone();
+        let mut seq = seq.with_token_id_callback(Some(Arc::new(Box::new(move |id, text| {
+            sink.lock().unwrap().push((id, text));
+            true
+        }))));
+        assert!(seq.push(1, b"a", false));
+        assert!(seq.push(2, &heart[..1], false));
+        assert!(seq.push(3, &heart[1..2], false));
+        seq.finish();
+        assert_eq!(sent.lock().unwrap().concat(), "a\u{FFFD}");
+        assert_eq!(
+            *tokens.lock().unwrap(),
+            [(1, "a"), (2, ""), (3, "\u{FFFD}")].map(|(id, t)| (id, t.to_string()))
+        );
+
+        let (seq, _) = sequence(&[], &[], 16);
+        let mut seq = seq.with_token_id_callback(Some(Arc::new(Box::new(|id, _| id != 2))));
+        assert!(seq.push(1, b"a", false));
+        assert!(!seq.push(2, b"b", false));
+        assert_eq!(seq.finish_reason(), FinishReason::Stop);
+    }
+}
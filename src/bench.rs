@@ -0,0 +1,179 @@
+//! Throughput measurements of a model across configurations.
+//!
+//! [`run`] loads the model once per library variant and gpu layer count and measures prompt
+//! processing and generation speed for every `n_batch` and `n_threads` combination of
+//! [`BenchOptions`]. The [`Report`] serializes to JSON, so results of different machines can be
+//! compared, and [`Report::fastest_variant`] can be passed to
+//! [`crate::devices::set_backend_preference`].
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    devices::{self, Device},
+    options::{BenchOptions, ContextOptions, Message, ModelOptions, PredictOptions, Role},
+    Model, Result,
+};
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct BenchResult {
+    pub variant: String,
+    pub n_gpu_layers: i32,
+    pub n_batch: usize,
+    pub n_threads: usize,
+    pub load_ms: f64,
+    pub prompt_tokens: usize,
+    pub prompt_ms: f64,
+    pub generated_tokens: usize,
+    pub generation_ms: f64,
+}
+
+impl BenchResult {
+    /// Prompt processing throughput in tokens per second.
+    pub fn prompt_tokens_per_second(&self) -> f64 {
+        per_second(self.prompt_tokens, self.prompt_ms)
+    }
+
+    /// Generation throughput in tokens per second.
+    pub fn generation_tokens_per_second(&self) -> f64 {
+        per_second(self.generated_tokens, self.generation_ms)
+    }
+}
+
+fn per_second(tokens: usize, ms: f64) -> f64 {
+    if ms > 0.0 {
+        tokens as f64 * 1000.0 / ms
+    } else {
+        0.0
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Report {
+    pub model: PathBuf,
+    pub devices: Vec<Device>,
+    pub results: Vec<BenchResult>,
+}
+
+impl Report {
+    /// The run with the highest generation throughput.
+    pub fn fastest(&self) -> Option<&BenchResult> {
+        self.results.iter().max_by(|a, b| {
+            a.generation_tokens_per_second()
+                .total_cmp(&b.generation_tokens_per_second())
+        })
+    }
+
+    pub fn fastest_variant(&self) -> Option<&str> {
+        self.fastest().map(|r| r.variant.as_str())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Measures `model` in every configuration of `options`.
+///
+/// Measuring more than one variant reloads the native libraries, so no other [`Model`] may be
+/// alive during the call. The preferred variant is restored afterwards.
+pub fn run(model: impl Into<PathBuf>, options: &BenchOptions) -> Result<Report> {
+    let model = model.into();
+    let variants: Vec<Option<String>> = if options.variants.is_empty() {
+        vec![None]
+    } else {
+        options.variants.iter().cloned().map(Some).collect()
+    };
+    let previous = llama_cpp::preferred_variant();
+    let mut results = vec![];
+    let res = variants.into_iter().try_for_each(|variant| {
+        if variant.is_some() {
+            devices::set_preferred_variant(variant);
+            crate::shutdown()?;
+        }
+        for &n_gpu_layers in &options.n_gpu_layers {
+            run_model(&model, n_gpu_layers, options, &mut results)?;
+        }
+        Ok(())
+    });
+    let restored = if options.variants.is_empty() {
+        Ok(())
+    } else {
+        devices::set_preferred_variant(previous);
+        crate::shutdown()
+    };
+    // a failed measurement is reported rather than a failed restore after it
+    res.and(restored)?;
+    Ok(Report {
+        model,
+        devices: devices::list()?,
+        results,
+    })
+}
+
+fn run_model(
+    path: &Path,
+    n_gpu_layers: i32,
+    options: &BenchOptions,
+    results: &mut Vec<BenchResult>,
+) -> Result<()> {
+    let start = Instant::now();
+    let model = Model::new(
+        path.to_path_buf(),
+        ModelOptions::builder()
+            .cpu(n_gpu_layers == 0)
+            .n_gpu_layers(n_gpu_layers)
+            .build(),
+    )?;
+    let load_ms = ms(start);
+    let variant = devices::loaded_variant().unwrap_or_default();
+    // roughly one token per word, the template adds a few more
+    let prompt = "the ".repeat(options.n_prompt);
+    for &n_batch in &options.n_batch {
+        for &n_threads in &options.n_threads {
+            let mut ctx = model.context(
+                ContextOptions::builder()
+                    .n_ctx(options.n_prompt + options.n_gen.max(0) as usize + 64)
+                    .n_batch(n_batch)
+                    .n_threads(n_threads)
+                    .build(),
+            )?;
+            let start = Instant::now();
+            ctx.eval(vec![Message {
+                role: Role::User,
+                content: prompt.clone(),
+                images: vec![],
+            }])?;
+            let prompt_ms = ms(start);
+            let start = Instant::now();
+            ctx.predict(
+                PredictOptions::builder()
+                    .ignore_eos(true)
+                    .max_len(options.n_gen)
+                    .build(),
+            )
+            .predict()?;
+            let generation_ms = ms(start);
+            let usage = ctx.usage();
+            let result = BenchResult {
+                variant: variant.clone(),
+                n_gpu_layers,
+                n_batch,
+                n_threads,
+                load_ms,
+                prompt_tokens: usage.prompt_tokens,
+                prompt_ms,
+                generated_tokens: usage.completion_tokens,
+                generation_ms,
+            };
+            log::debug!("{result:?}");
+            results.push(result);
+        }
+    }
+    Ok(())
+}
+
+fn ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
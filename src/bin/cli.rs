@@ -0,0 +1,329 @@
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use clap::{Args, Parser, Subcommand};
+use nebula::{
+    options::{
+        BenchOptions, ContextOptions, Message, ModelOptions, PredictOptions, Role, TokenCallback,
+    },
+    Context, Model,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "nebula",
+    version,
+    about = "Run and inspect GGUF models with nebula"
+)]
+struct Cli {
+    /// Directory the native llama.cpp libraries are loaded from.
+    #[arg(long, global = true, env = "NEBULA_DEPENDENCIES_DIR")]
+    dependencies: Option<PathBuf>,
+    /// Print debug logs, repeat for llama.cpp's own log.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Interactive chat.
+    Run {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// System prompt.
+        #[arg(long)]
+        system: Option<String>,
+    },
+    /// Answer a single prompt.
+    Generate {
+        #[command(flatten)]
+        model: ModelArgs,
+        prompt: String,
+        /// Maximum number of tokens to generate.
+        #[arg(short = 'n', long)]
+        max_len: Option<i32>,
+    },
+    /// Measure prompt processing and generation speed.
+    Bench {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[arg(long, default_value_t = 512)]
+        prompt_tokens: usize,
+        #[arg(long, default_value_t = 128)]
+        gen_tokens: i32,
+        /// Batch sizes to measure.
+        #[arg(long, value_delimiter = ',', default_value = "2048")]
+        n_batch: Vec<usize>,
+        /// Thread counts to measure, all cores if not given.
+        #[arg(long, value_delimiter = ',')]
+        threads: Vec<usize>,
+        /// Gpu layer counts to measure, 0 runs on the cpu.
+        #[arg(long, value_delimiter = ',', allow_negative_numbers = true)]
+        ngl: Vec<i32>,
+        /// Library variants to measure, e.g. `cpu_avx2,cuda_v12`.
+        #[arg(long, value_delimiter = ',')]
+        variants: Vec<String>,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List detected devices and available library variants.
+    Devices,
+    /// Print the metadata of a GGUF file.
+    Inspect {
+        file: PathBuf,
+        /// Print full arrays instead of a summary.
+        #[arg(long)]
+        full: bool,
+    },
+}
+
+#[derive(Args)]
+struct ModelArgs {
+    /// GGUF model file.
+    model: PathBuf,
+    #[arg(long, default_value_t = 4096)]
+    n_ctx: usize,
+    /// Number of layers offloaded to the gpu, -1 for all.
+    #[arg(long, default_value_t = -1)]
+    n_gpu_layers: i32,
+    /// Run on the cpu only.
+    #[arg(long)]
+    cpu: bool,
+    #[arg(long, default_value_t = 0.8)]
+    temp: f32,
+}
+
+impl ModelArgs {
+    fn load(&self) -> nebula::Result<Model> {
+        self.load_with(self.cpu)
+    }
+
+    fn load_with(&self, cpu: bool) -> nebula::Result<Model> {
+        eprint!("loading {} ...", self.model.display());
+        let options = ModelOptions::builder()
+            .cpu(cpu)
+            .n_gpu_layers(self.n_gpu_layers)
+            .build()
+            .with_load_progress(|p| {
+                eprint!("\rloading {:3.0}%", p * 100.0);
+                true
+            });
+        let model = Model::new(self.model.clone(), options)?;
+        eprintln!();
+        Ok(model)
+    }
+
+    fn context(&self, model: &Model) -> nebula::Result<Context> {
+        model.context(ContextOptions::builder().n_ctx(self.n_ctx).build())
+    }
+
+    fn predict_options(&self) -> PredictOptions {
+        PredictOptions::builder().temp(self.temp).build()
+    }
+}
+
+fn message(role: Role, content: impl Into<String>) -> Message {
+    Message {
+        role,
+        content: content.into(),
+        images: vec![],
+    }
+}
+
+fn print_tokens(options: PredictOptions) -> PredictOptions {
+    let callback: Box<TokenCallback> = Box::new(|token| {
+        print!("{token}");
+        std::io::stdout().flush().is_ok()
+    });
+    PredictOptions {
+        token_callback: Some(Arc::new(callback)),
+        ..options
+    }
+}
+
+fn run(model: ModelArgs, system: Option<String>) -> nebula::Result<()> {
+    let m = model.load()?;
+    let mut ctx = model.context(&m)?;
+    if let Some(system) = system {
+        ctx.eval(vec![message(Role::System, system)])?;
+    }
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "/exit" || line == "/quit" {
+            break;
+        }
+        ctx.eval(vec![message(Role::User, line)])?;
+        ctx.predict(print_tokens(model.predict_options()))
+            .predict()?;
+        let usage = ctx.usage();
+        println!(
+            "\n[{} tokens, context {:.0}% full]",
+            usage.completion_tokens,
+            usage.context_fraction() * 100.0
+        );
+    }
+    Ok(())
+}
+
+fn generate(model: ModelArgs, prompt: String, max_len: Option<i32>) -> nebula::Result<()> {
+    let m = model.load()?;
+    let mut ctx = model.context(&m)?;
+    ctx.eval(vec![message(Role::User, prompt)])?;
+    let options = PredictOptions {
+        max_len,
+        ..model.predict_options()
+    };
+    ctx.predict(print_tokens(options)).predict()?;
+    println!();
+    Ok(())
+}
+
+fn bench(model: ModelArgs, options: BenchOptions, json: bool) -> nebula::Result<()> {
+    let report = nebula::bench::run(model.model, &options)?;
+    if json {
+        println!("{}", report.to_json()?);
+        return Ok(());
+    }
+    println!(
+        "{:<12} {:>6} {:>7} {:>7} {:>12} {:>12}",
+        "variant", "ngl", "batch", "threads", "prompt t/s", "gen t/s"
+    );
+    for r in &report.results {
+        println!(
+            "{:<12} {:>6} {:>7} {:>7} {:>12.2} {:>12.2}",
+            r.variant,
+            r.n_gpu_layers,
+            r.n_batch,
+            r.n_threads,
+            r.prompt_tokens_per_second(),
+            r.generation_tokens_per_second()
+        );
+    }
+    if let Some(v) = report.fastest_variant() {
+        println!("fastest variant: {v}");
+    }
+    Ok(())
+}
+
+fn devices() -> nebula::Result<()> {
+    for d in nebula::devices::list()? {
+        println!(
+            "{} {} {} {} compute {} memory {}/{} MiB free",
+            d.library,
+            d.variant,
+            d.id,
+            d.name,
+            d.compute,
+            d.free_memory >> 20,
+            d.total_memory >> 20
+        );
+    }
+    let variants = nebula::devices::variants()?;
+    if variants.is_empty() {
+        println!(
+            "no library variants in {}",
+            nebula::paths::dependencies_dir().display()
+        );
+    } else {
+        println!("variants: {}", variants.join(", "));
+    }
+    Ok(())
+}
+
+fn inspect(file: PathBuf, full: bool) -> nebula::Result<()> {
+    let gguf = nebula::gguf::read(&file)?;
+    println!("version: {}", gguf.version);
+    println!("tensors: {}", gguf.tensor_count);
+    for (k, v) in &gguf.metadata {
+        if full {
+            println!("{k}: {v:?}");
+        } else {
+            println!("{k}: {v}");
+        }
+    }
+    Ok(())
+}
+
+fn try_main(cli: Cli) -> nebula::Result<()> {
+    if let Some(deps) = cli.dependencies {
+        nebula::init(deps)?;
+    }
+    if cli.verbose > 1 {
+        nebula::logging::route_native_logs(log::LevelFilter::Debug)?;
+    } else if !matches!(cli.command, Command::Devices | Command::Inspect { .. }) {
+        nebula::logging::silence_native_logs()?;
+    }
+    match cli.command {
+        Command::Run { model, system } => run(model, system),
+        Command::Generate {
+            model,
+            prompt,
+            max_len,
+        } => generate(model, prompt, max_len),
+        Command::Bench {
+            model,
+            prompt_tokens,
+            gen_tokens,
+            n_batch,
+            threads,
+            ngl,
+            variants,
+            json,
+        } => {
+            let ngl = if !ngl.is_empty() {
+                ngl
+            } else if model.cpu {
+                vec![0]
+            } else {
+                vec![model.n_gpu_layers, 0]
+            };
+            let options = BenchOptions::builder()
+                .n_prompt(prompt_tokens)
+                .n_gen(gen_tokens)
+                .n_batch(n_batch)
+                .n_threads(if threads.is_empty() {
+                    vec![num_cpus::get()]
+                } else {
+                    threads
+                })
+                .n_gpu_layers(ngl)
+                .variants(variants)
+                .build();
+            bench(model, options, json)
+        }
+        Command::Devices => devices(),
+        Command::Inspect { file, full } => inspect(file, full),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => log::LevelFilter::Warn,
+        _ => log::LevelFilter::Debug,
+    };
+    simple_logger::SimpleLogger::new()
+        .with_level(level)
+        .init()
+        .unwrap();
+    if let Err(e) = try_main(cli) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
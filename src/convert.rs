@@ -0,0 +1,793 @@
+//! Conversion of Hugging Face checkpoints to GGUF.
+//!
+//! [`hf_to_gguf`] reads `config.json`, `tokenizer.json`, `tokenizer_config.json` and the
+//! `*.safetensors` weights of a model directory and writes a GGUF file the llama backend
+//! loads, no Python tooling needed. Llama family models (`LlamaForCausalLM`,
+//! `MistralForCausalLM`) with a BPE tokenizer are supported.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value as Json;
+
+use crate::{
+    error::Error,
+    gguf::{self, Value},
+    options::{ConvertOptions, ConvertType, QuantizeProgressCallback},
+    Result,
+};
+
+const ARCHITECTURES: &[&str] = &["LlamaForCausalLM", "MistralForCausalLM"];
+const ALIGNMENT: u64 = 32;
+// guards against allocating for corrupt safetensors headers
+const MAX_HEADER_LEN: u64 = 100 << 20;
+// and for token ids of corrupt tokenizers
+const MAX_VOCAB: usize = 1 << 24;
+// elements converted at once for tensors that need no permutation
+const CHUNK: u64 = 1 << 20;
+
+// ggml tensor types
+const GGML_TYPE_F32: u32 = 0;
+const GGML_TYPE_F16: u32 = 1;
+const GGML_TYPE_BF16: u32 = 30;
+
+// llama.cpp token types
+const TOKEN_NORMAL: i32 = 1;
+const TOKEN_CONTROL: i32 = 3;
+const TOKEN_USER_DEFINED: i32 = 4;
+const TOKEN_UNUSED: i32 = 5;
+const TOKEN_BYTE: i32 = 6;
+
+/// Converts the Hugging Face model in `dir` to a GGUF file at `output`.
+///
+/// With [`ConvertOptions::quant_type`] the converted model is written next to `output`
+/// first and quantized from there.
+pub fn hf_to_gguf(
+    dir: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: ConvertOptions,
+) -> Result<()> {
+    let (dir, output) = (dir.as_ref(), output.as_ref());
+    let span = tracing::info_span!("convert", ?dir, ?output);
+    let _enter = span.enter();
+    let config = read_json(&dir.join("config.json"))?;
+    let hparams = HParams::from_config(&config)?;
+    let name = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut metadata = hparams.metadata(name, options.out_type);
+    metadata.extend(vocab(dir, &config)?);
+    let tensors = tensors(dir, &hparams)?;
+    let progress = options.progress.clone();
+    let Some(quant_type) = options.quant_type else {
+        return write_gguf(output, &metadata, &tensors, options.out_type, |p| {
+            if let Some(cb) = &progress {
+                cb(p)
+            }
+        });
+    };
+    let converted = output.with_extension("convert.tmp");
+    let res = write_gguf(&converted, &metadata, &tensors, options.out_type, |p| {
+        if let Some(cb) = &progress {
+            cb(p / 2.0)
+        }
+    })
+    .and_then(|_| {
+        let mut quantize = options.quantize.clone();
+        if let Some(cb) = progress.clone() {
+            let halved: Box<QuantizeProgressCallback> = Box::new(move |p| cb(0.5 + p / 2.0));
+            quantize.progress = Some(std::sync::Arc::new(halved));
+        }
+        crate::quantize(&converted, output, quant_type, quantize)
+    });
+    let _ = std::fs::remove_file(&converted);
+    res
+}
+
+fn read_json(path: &Path) -> Result<Json> {
+    let file = File::open(path)
+        .map_err(|e| Error::Convert(format!("can't open {}: {e}", path.display())))?;
+    Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+}
+
+/// Hyperparameters from `config.json`.
+struct HParams {
+    n_layer: u64,
+    n_embd: u64,
+    n_ff: u64,
+    n_head: u64,
+    n_head_kv: u64,
+    n_ctx: u64,
+    rms_norm_eps: f64,
+    rope_theta: f64,
+}
+
+impl HParams {
+    fn from_config(config: &Json) -> Result<Self> {
+        let arch = config["architectures"][0].as_str().unwrap_or_default();
+        if !ARCHITECTURES.contains(&arch) {
+            return Err(Error::Convert(format!("unsupported architecture {arch:?}")));
+        }
+        let get = |key: &str| {
+            config[key]
+                .as_u64()
+                .ok_or_else(|| Error::Convert(format!("config.json has no {key}")))
+        };
+        let n_head = get("num_attention_heads")?;
+        let n_head_kv = config["num_key_value_heads"].as_u64().unwrap_or(n_head);
+        if n_head == 0 || n_head_kv == 0 {
+            return Err(Error::Convert(
+                "config.json has no attention heads".to_string(),
+            ));
+        }
+        Ok(Self {
+            n_layer: get("num_hidden_layers")?,
+            n_embd: get("hidden_size")?,
+            n_ff: get("intermediate_size")?,
+            n_head,
+            n_head_kv,
+            n_ctx: get("max_position_embeddings")?,
+            rms_norm_eps: config["rms_norm_eps"].as_f64().unwrap_or(1e-5),
+            rope_theta: config["rope_theta"].as_f64().unwrap_or(10000.0),
+        })
+    }
+
+    fn metadata(&self, name: String, out_type: ConvertType) -> Vec<(String, Value)> {
+        // llama_ftype of the converted file
+        let file_type = match out_type {
+            ConvertType::F32 => 0,
+            ConvertType::F16 => 1,
+            ConvertType::BF16 => 32,
+        };
+        [
+            ("general.architecture", Value::String("llama".to_string())),
+            ("general.name", Value::String(name)),
+            ("general.file_type", Value::U32(file_type)),
+            ("llama.context_length", Value::U32(self.n_ctx as u32)),
+            ("llama.embedding_length", Value::U32(self.n_embd as u32)),
+            ("llama.block_count", Value::U32(self.n_layer as u32)),
+            ("llama.feed_forward_length", Value::U32(self.n_ff as u32)),
+            (
+                "llama.rope.dimension_count",
+                Value::U32((self.n_embd / self.n_head) as u32),
+            ),
+            ("llama.rope.freq_base", Value::F32(self.rope_theta as f32)),
+            ("llama.attention.head_count", Value::U32(self.n_head as u32)),
+            (
+                "llama.attention.head_count_kv",
+                Value::U32(self.n_head_kv as u32),
+            ),
+            (
+                "llama.attention.layer_norm_rms_epsilon",
+                Value::F32(self.rms_norm_eps as f32),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+}
+
+/// Tokenizer metadata from `tokenizer.json` and `tokenizer_config.json`.
+///
+/// Tokenizers with byte fallback (Llama 2, Mistral) become sentencepiece vocabularies scored
+/// by merge rank, byte level ones (Llama 3) keep their merges.
+fn vocab(dir: &Path, config: &Json) -> Result<Vec<(String, Value)>> {
+    let tokenizer = read_json(&dir.join("tokenizer.json"))?;
+    let tokenizer_config = read_json(&dir.join("tokenizer_config.json")).unwrap_or(Json::Null);
+    let model = &tokenizer["model"];
+    if model["type"].as_str() != Some("BPE") {
+        return Err(Error::Convert(format!(
+            "unsupported tokenizer {}",
+            model["type"]
+        )));
+    }
+    let mut ids: HashMap<String, usize> = model["vocab"]
+        .as_object()
+        .ok_or_else(|| Error::Convert("tokenizer.json has no vocab".to_string()))?
+        .iter()
+        .filter_map(|(t, id)| Some((t.clone(), id.as_u64()? as usize)))
+        .collect();
+    let added: Vec<(usize, String, bool)> = tokenizer["added_tokens"]
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|t| {
+                    let id = t["id"].as_u64()? as usize;
+                    let special = t["special"].as_bool().unwrap_or(false);
+                    Some((id, t["content"].as_str()?.to_string(), special))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ids.extend(added.iter().map(|(id, t, _)| (t.clone(), *id)));
+    // an added token whose text is in the vocabulary twice is not in `ids` anymore
+    let n_vocab = ids
+        .values()
+        .chain(added.iter().map(|(id, _, _)| id))
+        .map(|id| id + 1)
+        .max()
+        .unwrap_or_default()
+        .max(config["vocab_size"].as_u64().unwrap_or_default() as usize);
+    if n_vocab > MAX_VOCAB {
+        return Err(Error::Convert(format!(
+            "a vocabulary of {n_vocab} tokens is too large"
+        )));
+    }
+    let mut tokens: Vec<String> = (0..n_vocab).map(|i| format!("[PAD{i}]")).collect();
+    let mut types = vec![TOKEN_UNUSED; n_vocab];
+    for (token, id) in &ids {
+        tokens[*id] = token.clone();
+        types[*id] = TOKEN_NORMAL;
+    }
+    for (id, _, special) in &added {
+        types[*id] = if *special {
+            TOKEN_CONTROL
+        } else {
+            TOKEN_USER_DEFINED
+        };
+    }
+    // merges are "a b" strings or, in newer files, pairs
+    let merges: Vec<(String, String)> = model["merges"]
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .map(|m| {
+                    let pair = match m {
+                        Json::String(s) => s.split_once(' '),
+                        Json::Array(p) => match p.as_slice() {
+                            [a, b] => a.as_str().zip(b.as_str()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    pair.map(|(a, b)| (a.to_string(), b.to_string()))
+                        .ok_or_else(|| Error::Convert(format!("invalid merge {m}")))
+                })
+                .collect::<Result<_>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let mut metadata = vec![];
+    if model["byte_fallback"].as_bool().unwrap_or(false) {
+        // the earlier a merge, the higher the score of the token it produces
+        let mut scores = vec![0.0; n_vocab];
+        for (rank, (a, b)) in merges.iter().enumerate().rev() {
+            if let Some(id) = ids.get(&format!("{a}{b}")) {
+                scores[*id] = -(rank as f32);
+            }
+        }
+        for (i, t) in tokens.iter().enumerate() {
+            if t.len() == 6 && t.starts_with("<0x") && t.ends_with('>') {
+                types[i] = TOKEN_BYTE;
+            }
+        }
+        metadata.push(("tokenizer.ggml.model", Value::String("llama".to_string())));
+        metadata.push((
+            "tokenizer.ggml.scores",
+            Value::Array(scores.into_iter().map(Value::F32).collect()),
+        ));
+    } else {
+        let pre = if tokenizer["pre_tokenizer"]
+            .to_string()
+            .contains("(?i:'s|'t|'re|'ve|'m|'ll|'d)")
+        {
+            "llama-bpe"
+        } else {
+            "default"
+        };
+        metadata.push(("tokenizer.ggml.model", Value::String("gpt2".to_string())));
+        metadata.push(("tokenizer.ggml.pre", Value::String(pre.to_string())));
+        metadata.push((
+            "tokenizer.ggml.merges",
+            Value::Array(
+                merges
+                    .into_iter()
+                    .map(|(a, b)| Value::String(format!("{a} {b}")))
+                    .collect(),
+            ),
+        ));
+    }
+    metadata.push((
+        "tokenizer.ggml.tokens",
+        Value::Array(tokens.into_iter().map(Value::String).collect()),
+    ));
+    metadata.push((
+        "tokenizer.ggml.token_type",
+        Value::Array(types.into_iter().map(Value::I32).collect()),
+    ));
+    // special tokens are named in tokenizer_config.json, as a string or an added token
+    let special_id = |key: &str| {
+        let token = &tokenizer_config[format!("{key}_token")];
+        token
+            .as_str()
+            .or_else(|| token["content"].as_str())
+            .and_then(|t| ids.get(t).map(|id| *id as u64))
+            .or_else(|| config[format!("{key}_token_id")].as_u64())
+    };
+    for key in ["bos", "eos", "unk", "pad"] {
+        if let Some(id) = special_id(key) {
+            metadata.push((
+                match key {
+                    "bos" => "tokenizer.ggml.bos_token_id",
+                    "eos" => "tokenizer.ggml.eos_token_id",
+                    "unk" => "tokenizer.ggml.unknown_token_id",
+                    _ => "tokenizer.ggml.padding_token_id",
+                },
+                Value::U32(id as u32),
+            ));
+        }
+    }
+    if let Some(add_bos) = tokenizer_config["add_bos_token"].as_bool() {
+        metadata.push(("tokenizer.ggml.add_bos_token", Value::Bool(add_bos)));
+    }
+    if let Some(template) = tokenizer_config["chat_template"].as_str() {
+        metadata.push((
+            "tokenizer.chat_template",
+            Value::String(template.to_string()),
+        ));
+    }
+    Ok(metadata
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dtype {
+    F32,
+    F16,
+    BF16,
+}
+
+impl Dtype {
+    fn size(self) -> u64 {
+        match self {
+            Self::F32 => 4,
+            Self::F16 | Self::BF16 => 2,
+        }
+    }
+}
+
+/// A tensor of the checkpoint under its GGUF name.
+struct Tensor {
+    name: String,
+    path: PathBuf,
+    offset: u64,
+    dtype: Dtype,
+    // row major as in the checkpoint, GGUF lists the dimensions the other way round
+    shape: Vec<u64>,
+    // heads of q and k, whose rows are interleaved again for llama.cpp's rope
+    permute: Option<u64>,
+}
+
+impl Tensor {
+    fn n_elements(&self) -> u64 {
+        self.shape.iter().product()
+    }
+
+    fn out_type(&self, out_type: ConvertType) -> u32 {
+        match out_type {
+            _ if self.shape.len() < 2 => GGML_TYPE_F32,
+            ConvertType::F32 => GGML_TYPE_F32,
+            ConvertType::F16 => GGML_TYPE_F16,
+            ConvertType::BF16 => GGML_TYPE_BF16,
+        }
+    }
+
+    fn out_size(&self, out_type: ConvertType) -> u64 {
+        let size = match self.out_type(out_type) {
+            GGML_TYPE_F32 => 4,
+            _ => 2,
+        };
+        self.n_elements() * size
+    }
+}
+
+/// The tensors of all `*.safetensors` files in `dir`.
+fn tensors(dir: &Path, hparams: &HParams) -> Result<Vec<Tensor>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| Some(e.ok()?.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "safetensors"))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        return Err(Error::Convert(format!(
+            "no .safetensors files in {}",
+            dir.display()
+        )));
+    }
+    let mut tensors = vec![];
+    for path in files {
+        let mut file = File::open(&path)?;
+        let mut len = [0; 8];
+        file.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > MAX_HEADER_LEN {
+            return Err(Error::Convert(format!(
+                "{} has a header of {len} bytes",
+                path.display()
+            )));
+        }
+        let mut header = vec![0; len as usize];
+        file.read_exact(&mut header)?;
+        let header: serde_json::Map<String, Json> = serde_json::from_slice(&header)?;
+        for (name, info) in header {
+            if name == "__metadata__" || name.ends_with("rotary_emb.inv_freq") {
+                continue;
+            }
+            let dtype = match info["dtype"].as_str() {
+                Some("F32") => Dtype::F32,
+                Some("F16") => Dtype::F16,
+                Some("BF16") => Dtype::BF16,
+                other => {
+                    return Err(Error::Convert(format!(
+                        "{name} has the unsupported type {other:?}"
+                    )))
+                }
+            };
+            let shape: Vec<u64> = info["shape"]
+                .as_array()
+                .map(|s| s.iter().filter_map(Json::as_u64).collect())
+                .unwrap_or_default();
+            let start = info["data_offsets"][0].as_u64().unwrap_or_default();
+            let Some((gguf_name, permute)) = tensor_name(&name, hparams) else {
+                return Err(Error::Convert(format!("unknown tensor {name}")));
+            };
+            // the rows of every head are split into two halves
+            if let Some(n_head) = permute {
+                if !matches!(shape.first(), Some(rows) if *rows > 0 && rows % (2 * n_head) == 0) {
+                    return Err(Error::Convert(format!(
+                        "{name} of shape {shape:?} can't be split into {n_head} heads"
+                    )));
+                }
+            }
+            tensors.push(Tensor {
+                name: gguf_name,
+                path: path.clone(),
+                offset: 8 + len + start,
+                dtype,
+                shape,
+                permute,
+            });
+        }
+    }
+    Ok(tensors)
+}
+
+/// GGUF name of the checkpoint tensor `name` and the heads to permute its rows by.
+fn tensor_name(name: &str, hparams: &HParams) -> Option<(String, Option<u64>)> {
+    let (base, suffix) = name.rsplit_once('.')?;
+    let (gguf_name, permute) = match base {
+        "model.embed_tokens" => ("token_embd".to_string(), None),
+        "model.norm" => ("output_norm".to_string(), None),
+        "lm_head" => ("output".to_string(), None),
+        _ => {
+            let (n, rest) = base.strip_prefix("model.layers.")?.split_once('.')?;
+            let n: u64 = n.parse().ok()?;
+            let (part, permute) = match rest {
+                "input_layernorm" => ("attn_norm", None),
+                "self_attn.q_proj" => ("attn_q", Some(hparams.n_head)),
+                "self_attn.k_proj" => ("attn_k", Some(hparams.n_head_kv)),
+                "self_attn.v_proj" => ("attn_v", None),
+                "self_attn.o_proj" => ("attn_output", None),
+                "post_attention_layernorm" => ("ffn_norm", None),
+                "mlp.gate_proj" => ("ffn_gate", None),
+                "mlp.up_proj" => ("ffn_up", None),
+                "mlp.down_proj" => ("ffn_down", None),
+                _ => return None,
+            };
+            (format!("blk.{n}.{part}"), permute)
+        }
+    };
+    Some((format!("{gguf_name}.{suffix}"), permute))
+}
+
+fn write_gguf(
+    path: &Path,
+    metadata: &[(String, Value)],
+    tensors: &[Tensor],
+    out_type: ConvertType,
+    progress: impl Fn(f32),
+) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(gguf::MAGIC)?;
+    w.write_all(&3u32.to_le_bytes())?;
+    w.write_all(&(tensors.len() as u64).to_le_bytes())?;
+    w.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    for (key, value) in metadata {
+        gguf::write_kv(&mut w, key, value)?;
+    }
+    let mut offset = 0u64;
+    for t in tensors {
+        gguf::write_string(&mut w, &t.name)?;
+        w.write_all(&(t.shape.len() as u32).to_le_bytes())?;
+        for d in t.shape.iter().rev() {
+            w.write_all(&d.to_le_bytes())?;
+        }
+        w.write_all(&t.out_type(out_type).to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        offset += t.out_size(out_type).next_multiple_of(ALIGNMENT);
+    }
+    pad(&mut w)?;
+    let total: u64 = tensors.iter().map(Tensor::n_elements).sum();
+    let mut done = 0;
+    for t in tensors {
+        write_tensor(&mut w, t, out_type)?;
+        pad(&mut w)?;
+        done += t.n_elements();
+        tracing::debug!(tensor = t.name, "tensor converted");
+        progress(done as f32 / total.max(1) as f32);
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn pad(w: &mut (impl Write + Seek)) -> Result<()> {
+    let pos = w.stream_position()?;
+    w.write_all(&vec![0; (pos.next_multiple_of(ALIGNMENT) - pos) as usize])?;
+    Ok(())
+}
+
+fn write_tensor(w: &mut impl Write, t: &Tensor, out_type: ConvertType) -> Result<()> {
+    let mut file = File::open(&t.path)?;
+    file.seek(SeekFrom::Start(t.offset))?;
+    let out_type = t.out_type(out_type);
+    if let Some(n_head) = t.permute {
+        let data = read_f32(&mut file, t.dtype, t.n_elements())?;
+        return write_f32(w, &permute(&data, t.shape[0], n_head), out_type);
+    }
+    let mut left = t.n_elements();
+    while left > 0 {
+        let n = left.min(CHUNK);
+        write_f32(w, &read_f32(&mut file, t.dtype, n)?, out_type)?;
+        left -= n;
+    }
+    Ok(())
+}
+
+fn read_f32(r: &mut impl Read, dtype: Dtype, n: u64) -> Result<Vec<f32>> {
+    let mut buf = vec![0; (n * dtype.size()) as usize];
+    r.read_exact(&mut buf)?;
+    Ok(match dtype {
+        Dtype::F32 => buf
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        Dtype::F16 => buf
+            .chunks_exact(2)
+            .map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]])))
+            .collect(),
+        Dtype::BF16 => buf
+            .chunks_exact(2)
+            .map(|b| f32::from_bits((u16::from_le_bytes([b[0], b[1]]) as u32) << 16))
+            .collect(),
+    })
+}
+
+fn write_f32(w: &mut impl Write, data: &[f32], ggml_type: u32) -> Result<()> {
+    let bytes: Vec<u8> = match ggml_type {
+        GGML_TYPE_F16 => data
+            .iter()
+            .flat_map(|v| f32_to_f16(*v).to_le_bytes())
+            .collect(),
+        GGML_TYPE_BF16 => data
+            .iter()
+            .flat_map(|v| f32_to_bf16(*v).to_le_bytes())
+            .collect(),
+        _ => data.iter().flat_map(|v| v.to_le_bytes()).collect(),
+    };
+    w.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Undoes the rotary permutation of the rows of q and k in Hugging Face checkpoints, which
+/// put the two halves of every head one after the other instead of interleaved.
+fn permute(data: &[f32], rows: u64, n_head: u64) -> Vec<f32> {
+    let (rows, n_head) = (rows as usize, n_head as usize);
+    let cols = data.len() / rows;
+    let half = rows / n_head / 2;
+    let mut out = vec![0.0; data.len()];
+    for h in 0..n_head {
+        for j in 0..2 {
+            for i in 0..half {
+                let src = ((h * 2 + j) * half + i) * cols;
+                let dst = ((h * half + i) * 2 + j) * cols;
+                out[dst..dst + cols].copy_from_slice(&data[src..src + cols]);
+            }
+        }
+    }
+    out
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mant = (h & 0x3ff) as u32;
+    let bits = match (exp, mant) {
+        (0, 0) => sign,
+        // subnormal, normalized for f32
+        (0, _) => {
+            let shift = mant.leading_zeros() - 21;
+            sign | ((113 - shift) << 23) | (((mant << shift) & 0x3ff) << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mant << 13),
+        _ => sign | ((exp + 112) << 23) | (mant << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// Rounds to the nearest f16, ties to even.
+fn f32_to_f16(v: f32) -> u16 {
+    let bits = v.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let exp = exp - 112;
+    if exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (value, shift) = if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        (mant | 0x80_0000, (14 - exp) as u32)
+    } else {
+        (((exp as u32) << 23) | mant, 13)
+    };
+    let rest = value & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    let mut h = value >> shift;
+    if rest > half || (rest == half && h & 1 == 1) {
+        // a carry into the exponent rounds up to the next power of two or infinity
+        h += 1;
+    }
+    sign | h as u16
+}
+
+/// Rounds to the nearest bf16, ties to even.
+fn f32_to_bf16(v: f32) -> u16 {
+    let bits = v.to_bits();
+    if v.is_nan() {
+        return ((bits >> 16) | 0x40) as u16;
+    }
+    ((bits + 0x7fff + ((bits >> 16) & 1)) >> 16) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn permute_test() {
+        // two heads of four rows, each row of two columns holding its index
+        let data: Vec<f32> = (0..8).flat_map(|r| [r as f32, r as f32]).collect();
+        let rows: Vec<f32> = permute(&data, 8, 2)
+            .chunks_exact(2)
+            .map(|row| {
+                assert_eq!(row[0], row[1]);
+                row[0]
+            })
+            .collect();
+        // the halves of every head are interleaved
+        assert_eq!(rows, [0.0, 2.0, 1.0, 3.0, 4.0, 6.0, 5.0, 7.0]);
+        // heads of two rows stay as they are
+        assert_eq!(permute(&data, 8, 4), data);
+    }
+
+    #[test]
+    fn f16_test() {
+        for (h, v) in [
+            (0x0000, 0.0),
+            (0x3c00, 1.0),
+            (0xc000, -2.0),
+            (0x3555, 0.333_251_95),
+            (0x7bff, 65504.0),
+            (0x0400, 6.103_515_6e-5),
+            // subnormals
+            (0x0001, 5.960_464_5e-8),
+            (0x03ff, 6.097_555e-5),
+            (0x7c00, f32::INFINITY),
+            (0xfc00, f32::NEG_INFINITY),
+        ] {
+            assert_eq!(f16_to_f32(h), v, "{h:#06x}");
+            assert_eq!(f32_to_f16(v), h, "{v}");
+        }
+        assert!(f16_to_f32(0x8000).is_sign_negative());
+        assert!(f16_to_f32(0x7e00).is_nan());
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        // rounding to nearest, ties to even
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_f16(1.5 * 2f32.powi(-25)), 0x0001);
+        assert_eq!(f32_to_f16(1e-10), 0x0000);
+        for h in 0..=u16::MAX {
+            if !f16_to_f32(h).is_nan() {
+                assert_eq!(f32_to_f16(f16_to_f32(h)), h, "{h:#06x}");
+            }
+        }
+    }
+
+    #[test]
+    fn bf16_test() {
+        let bytes: Vec<u8> = [0x3f80u16, 0xc049, 0x7f80, 0x0001]
+            .iter()
+            .flat_map(|b| b.to_le_bytes())
+            .collect();
+        let values = read_f32(&mut bytes.as_slice(), Dtype::BF16, 4).unwrap();
+        assert_eq!(
+            values,
+            [1.0, -3.140_625, f32::INFINITY, f32::from_bits(0x0001_0000)]
+        );
+        assert_eq!(f32_to_bf16(1.0), 0x3f80);
+        assert_eq!(f32_to_bf16(std::f32::consts::PI), 0x4049);
+        // rounding to nearest, ties to even
+        assert_eq!(f32_to_bf16(f32::from_bits(0x3f80_8000)), 0x3f80);
+        assert_eq!(f32_to_bf16(f32::from_bits(0x3f81_8000)), 0x3f82);
+        assert_eq!(f32_to_bf16(f32::from_bits(0x3f80_8001)), 0x3f81);
+        assert!(f32::from_bits((f32_to_bf16(f32::NAN) as u32) << 16).is_nan());
+
+        let bytes: Vec<u8> = [0x3c00u16, 0xc000]
+            .iter()
+            .flat_map(|b| b.to_le_bytes())
+            .collect();
+        let values = read_f32(&mut bytes.as_slice(), Dtype::F16, 2).unwrap();
+        assert_eq!(values, [1.0, -2.0]);
+    }
+
+    #[test]
+    fn malformed_test() {
+        let config = serde_json::json!({
+            "architectures": ["LlamaForCausalLM"],
+            "num_hidden_layers": 1,
+            "hidden_size": 8,
+            "intermediate_size": 16,
+            "num_attention_heads": 2,
+            "max_position_embeddings": 64,
+        });
+        let hparams = HParams::from_config(&config).unwrap();
+        let mut headless = config.clone();
+        headless["num_attention_heads"] = 0.into();
+        assert!(HParams::from_config(&headless).is_err());
+
+        let dir = std::env::temp_dir().join("nebula_convert_malformed_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let tokenizer = |merges: Json, added: Json| {
+            let tokenizer = serde_json::json!({
+                "model": {"type": "BPE", "vocab": {"a": 0, "b": 1, "ab": 2}, "merges": merges},
+                "added_tokens": added,
+            });
+            std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+            vocab(&dir, &config)
+        };
+        assert!(tokenizer(serde_json::json!([["a", "b"]]), Json::Null).is_ok());
+        assert!(tokenizer(serde_json::json!([["a"]]), Json::Null).is_err());
+        assert!(tokenizer(serde_json::json!(["ab"]), Json::Null).is_err());
+        // an added token repeating a vocabulary entry under an id past it
+        let added = serde_json::json!([{"id": 7, "content": "a"}, {"id": 8, "content": "a"}]);
+        assert!(tokenizer(Json::Array(vec![]), added).is_ok());
+        let added = serde_json::json!([{"id": 1u64 << 40, "content": "<s>"}]);
+        assert!(tokenizer(Json::Array(vec![]), added).is_err());
+
+        // a query projection without rows to split into heads
+        let header = serde_json::json!({
+            "model.layers.0.self_attn.q_proj.weight":
+                {"dtype": "F32", "shape": [], "data_offsets": [0, 0]},
+        })
+        .to_string();
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend(header.as_bytes());
+        std::fs::write(dir.join("model.safetensors"), file).unwrap();
+        assert!(tensors(&dir, &hparams).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
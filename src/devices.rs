@@ -0,0 +1,170 @@
+//! Hardware llama.cpp can run on and the library variants shipped for it.
+use std::sync::RwLock;
+
+use crate::Result;
+
+pub use llama_cpp::{CpuTopology, MemInfo};
+
+#[cfg(target_os = "android")]
+pub use llama_cpp::AndroidMemoryInfo;
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Device {
+    /// `cpu`, `cuda`, `rocm`, `metal` or `vulkan`.
+    pub library: String,
+    /// Instruction set extension of a cpu, e.g. `avx2`.
+    pub variant: String,
+    pub id: String,
+    pub name: String,
+    /// Compute capability of a gpu.
+    pub compute: String,
+    /// Newest cuda version the driver of a gpu supports, empty if unknown.
+    pub driver: String,
+    /// Release of the gpu driver, e.g. `550.54.14`, empty if unknown.
+    pub driver_release: String,
+    pub total_memory: u64,
+    pub free_memory: u64,
+}
+
+impl From<llama_cpp::DeviceInfo> for Device {
+    fn from(d: llama_cpp::DeviceInfo) -> Self {
+        let variant = match d.variant {
+            llama_cpp::CPUCapability::None => "",
+            llama_cpp::CPUCapability::Avx => "avx",
+            llama_cpp::CPUCapability::Avx2 => "avx2",
+        };
+        Self {
+            library: d.library.to_string(),
+            variant: if d.library == "cpu" {
+                variant.to_string()
+            } else {
+                String::new()
+            },
+            id: d.id,
+            name: d.name,
+            compute: d.compute,
+            driver: if d.driver_version == llama_cpp::DriverVersion::default() {
+                String::new()
+            } else {
+                d.driver_version.to_string()
+            },
+            driver_release: d.driver_release,
+            total_memory: d.memInfo.total(),
+            free_memory: d.memInfo.free(),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DEVICES: RwLock<Option<Vec<Device>>> = RwLock::new(None);
+}
+
+/// Devices found on this machine, the cpu if there is no supported gpu.
+///
+/// Queried on every call, for the free memory now. Other processes sharing a gpu change it
+/// at any time.
+pub fn list() -> Result<Vec<Device>> {
+    crate::paths::init_dependencies()?;
+    let devices: Vec<Device> = llama_cpp::devices()?
+        .into_iter()
+        .map(Device::from)
+        .collect();
+    *DEVICES.write().unwrap_or_else(|e| e.into_inner()) = Some(devices.clone());
+    Ok(devices)
+}
+
+/// The devices of the last [`list`], queried on first use. Cheaper where the free memory
+/// may be out of date.
+pub fn list_cached() -> Result<Vec<Device>> {
+    if let Some(devices) = &*DEVICES.read().unwrap_or_else(|e| e.into_inner()) {
+        return Ok(devices.clone());
+    }
+    list()
+}
+
+/// Total and available system memory.
+pub fn system_memory() -> Result<MemInfo> {
+    Ok(llama_cpp::system_memory()?)
+}
+
+/// Takes the memory available to the app from `ActivityManager.getMemoryInfo`, which the
+/// app has to query through the Android sdk, instead of `/proc`. The free memory [`list`] and
+/// [`system_memory`] report then stays clear of the low memory killer.
+#[cfg(target_os = "android")]
+pub fn set_android_memory_info(info: Option<AndroidMemoryInfo>) {
+    llama_cpp::set_android_memory_info(info);
+}
+
+/// Library variants in [`crate::paths::dependencies_dir`], e.g. `cpu_avx2` or `cuda_v12`.
+pub fn variants() -> Result<Vec<String>> {
+    crate::paths::init_dependencies()?;
+    Ok(llama_cpp::available_variants()?)
+}
+
+/// Checks that the devices of this machine can run `variant`, e.g. that the gpu driver is
+/// recent enough for `cuda_v12`, failing with [`crate::error::Error::Requirement`] otherwise.
+///
+/// Variants whose requirements aren't met are skipped when the libraries are loaded.
+pub fn check_requirements(variant: &str) -> Result<()> {
+    crate::paths::init_dependencies()?;
+    match llama_cpp::check_requirements(variant) {
+        Err(llama_cpp::LLamaCppError::Requirement(e)) => Err(e.into()),
+        res => Ok(res?),
+    }
+}
+
+/// Loads the libraries from `variant` instead of the best one for this machine, `None`
+/// restores the automatic choice. Used by [`crate::bench`], which restores the previous one.
+pub(crate) fn set_preferred_variant(variant: Option<String>) {
+    llama_cpp::set_preferred_variant(variant);
+}
+
+/// Loads only `variants`, e.g. `["cuda_v12", "cpu_avx2"]`, in this order instead of the
+/// best one for this machine, or `None` for the automatic choice.
+///
+/// Without a preference set here or in [`crate::options::ModelOptions::backend_preference`],
+/// the comma separated variants of the `NEBULA_BACKEND_PREFERENCE` environment variable are
+/// used. Fails with [`crate::error::Error::VariantUnavailable`] for a variant that is not in
+/// [`variants`]. Takes effect when the libraries are loaded next, after [`crate::shutdown`] if
+/// a model was created already.
+pub fn set_backend_preference(variants: Option<&[&str]>) -> Result<()> {
+    let variants: Option<Vec<String>> = variants.map(|v| v.iter().map(|v| v.to_string()).collect());
+    if let Some(variants) = &variants {
+        check_backend_preference(variants)?;
+    }
+    llama_cpp::set_variant_preference(variants);
+    Ok(())
+}
+
+/// The variants the libraries are loaded from, see [`set_backend_preference`].
+pub fn backend_preference() -> Option<Vec<String>> {
+    llama_cpp::variant_preference()
+}
+
+/// Checks that every one of `variants` is available, and that the libraries aren't loaded
+/// from another one already.
+pub(crate) fn check_backend_preference(variants: &[String]) -> Result<()> {
+    let available = variants()?;
+    if let Some(requested) = variants.iter().find(|v| !available.contains(v)) {
+        return Err(crate::error::Error::VariantUnavailable {
+            requested: requested.clone(),
+            available,
+        });
+    }
+    match loaded_variant() {
+        Some(loaded) if !variants.contains(&loaded) => Err(crate::error::Error::Unsupported(
+            "the libraries are loaded from another variant already, call shutdown first",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The variant the libraries are currently loaded from.
+pub fn loaded_variant() -> Option<String> {
+    llama_cpp::loaded_variant()
+}
+
+/// Core counts of this machine's cpu, the basis of [`crate::options::ContextOptions::auto_tune`].
+pub fn cpu_topology() -> CpuTopology {
+    llama_cpp::cpu_topology()
+}
@@ -1,10 +1,39 @@
 use std::{
     io::{BufWriter, IntoInnerError},
+    path::PathBuf,
     string::FromUtf8Error,
 };
 
 use thiserror::Error;
 
+/// What failed, see [`Error::kind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The gpus or the memory of the machine could not be queried.
+    DeviceDiscovery,
+    /// The llama.cpp libraries or their dependencies could not be loaded.
+    LibraryLoad,
+    ModelLoad,
+    ContextCreate,
+    Tokenize,
+    Decode,
+    Sampling,
+    /// Images and the projector embedding them.
+    Multimodal,
+    Template,
+    /// Saved context states.
+    State,
+    /// Quantization and conversion of models.
+    Conversion,
+    /// Limits on models and contexts alive at once.
+    Resources,
+    InvalidInput,
+    Unsupported,
+    Io,
+    Other,
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[cfg(feature = "llama")]
@@ -28,6 +57,9 @@ pub enum Error {
     #[cfg(feature = "llama")]
     #[error("{0}")]
     Predict(#[from] llama_cpp::PredictError),
+    #[cfg(feature = "llama")]
+    #[error("{0}")]
+    LlamaQuantize(#[from] llama_cpp::QuantizeError),
     #[error("not mmproj models not support images")]
     ModelNotMmproj,
     #[error("{0} > {1}: the required kv cache size is not big enough either reduce n_len or increase n_ctx")]
@@ -48,7 +80,328 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("{0}")]
     Json(#[from] serde_json::Error),
+    #[error("{0} models or contexts are still alive, drop them before shutdown")]
+    ResourcesInUse(usize),
+    #[error("the model already has {0} contexts, the configured maximum")]
+    TooManyContexts(usize),
+    #[error("model loading was cancelled by the progress callback")]
+    ModelLoadCancelled,
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("invalid gguf file: {0}")]
+    InvalidGguf(String),
+    #[error("invalid context state: {0}")]
+    InvalidState(String),
+    #[error("invalid prompt template: {0}")]
+    PromptTemplate(String),
+    #[error("conversion failed: {0}")]
+    Convert(String),
+    #[error("the custom sampler picked token {0}, the vocabulary has {1}")]
+    InvalidToken(i32, usize),
+    #[error("the token quota of {0} is used up")]
+    QuotaExceeded(usize),
+    #[error("the embedding has {1} dimensions, the store holds embeddings of {0}")]
+    EmbeddingSize(usize, usize),
+    #[error("the library variant {requested} is not available, found {available:?}")]
+    VariantUnavailable {
+        requested: String,
+        available: Vec<String>,
+    },
+    #[cfg(feature = "llama")]
+    #[error("{0}")]
+    Requirement(#[from] llama_cpp::RequirementError),
+    #[cfg(feature = "llama")]
+    #[error("loading the model {} failed: {source}", .path.display())]
+    ModelLoad {
+        path: PathBuf,
+        source: llama_cpp::LlamaModelLoadError,
+    },
+    #[cfg(feature = "llama")]
+    #[error("creating a context with n_ctx {n_ctx} and n_batch {n_batch} failed: {source}")]
+    ContextCreate {
+        n_ctx: usize,
+        n_batch: usize,
+        source: llama_cpp::LLamaCppError,
+    },
+    #[cfg(feature = "llama")]
+    #[error("decoding {n_tokens} tokens at position {n_past} failed: {source}")]
+    Decode {
+        n_tokens: usize,
+        n_past: i32,
+        source: llama_cpp::DecodeError,
+    },
+}
+
+// kind, code and hint of an error
+type Class = (ErrorKind, &'static str, Option<&'static str>);
+
+const HINT_CONTEXT_FULL: &str =
+    "increase ContextOptions::n_ctx, or truncate or discard tokens of the context";
+#[cfg(feature = "llama")]
+const CONTEXT_CREATE: Class = (
+    ErrorKind::ContextCreate,
+    "context_create.failed",
+    Some("lower n_ctx, n_batch, n_ubatch or max_forks, or offload fewer layers to the gpu"),
+);
+#[cfg(feature = "llama")]
+const REQUIREMENT: Class = (
+    ErrorKind::LibraryLoad,
+    "library_load.requirement",
+    Some("update the gpu driver, or load a variant this machine can run"),
+);
+const NO_PROJECTOR: Class = (
+    ErrorKind::Multimodal,
+    "multimodal.no_projector",
+    Some("load the model with the multimodal projector of its images"),
+);
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.class().0
+    }
+
+    /// Identifies the error independent of its message, `kind.detail` in snake case. Codes
+    /// stay the same across releases, match on them rather than on messages.
+    pub fn code(&self) -> &'static str {
+        self.class().1
+    }
+
+    /// What the user can do about the error, if anything.
+    pub fn hint(&self) -> Option<&'static str> {
+        self.class().2
+    }
+
+    fn class(&self) -> Class {
+        use ErrorKind::*;
+        match self {
+            #[cfg(feature = "llama")]
+            Error::LLamaCpp(e) => llama_cpp_class(e),
+            #[cfg(feature = "llama")]
+            Error::LLamaModelLoad(e) | Error::ModelLoad { source: e, .. } => model_load_class(e),
+            #[cfg(feature = "llama")]
+            Error::LlamaContextLoad(_) | Error::ContextCreate { .. } => CONTEXT_CREATE,
+            #[cfg(feature = "llama")]
+            Error::LlamaStringToToken(_) => (Tokenize, "tokenize.invalid_text", None),
+            #[cfg(feature = "llama")]
+            Error::LlamaDecode(e) | Error::Decode { source: e, .. } => decode_class(e),
+            #[cfg(feature = "llama")]
+            Error::LlamaClip(e) => clip_class(e),
+            #[cfg(feature = "llama")]
+            Error::Predict(e) => predict_class(e),
+            #[cfg(feature = "llama")]
+            Error::LlamaQuantize(_) => (Conversion, "conversion.quantize", None),
+            #[cfg(feature = "llama")]
+            Error::Requirement(_) => REQUIREMENT,
+            Error::ModelNotMmproj | Error::MmprojNotDefined => NO_PROJECTOR,
+            Error::KVCacheNotBigEnough(..) => {
+                (Decode, "decode.context_full", Some(HINT_CONTEXT_FULL))
+            }
+            Error::PromtTooLong => (Decode, "decode.prompt_too_long", Some(HINT_CONTEXT_FULL)),
+            Error::Unknown(_) => (Other, "other", None),
+            Error::UnsupportedTemplate(_) => (
+                Template,
+                "template.unsupported",
+                Some("pass a chat template the model's tokenizer supports"),
+            ),
+            Error::PromptTemplate(_) => (Template, "template.invalid", None),
+            Error::FromUtf8(_) => (Tokenize, "tokenize.invalid_utf8", None),
+            Error::IntoInner(_) => (Io, "io.buffer", None),
+            Error::Io(_) => (Io, "io", None),
+            Error::Json(_) => (InvalidInput, "invalid_input.json", None),
+            Error::ResourcesInUse(_) => (
+                Resources,
+                "resources.in_use",
+                Some("drop every model and context before shutting down"),
+            ),
+            Error::TooManyContexts(_) => (
+                Resources,
+                "resources.too_many_contexts",
+                Some("drop a context of the model or raise ModelOptions::max_contexts"),
+            ),
+            Error::ModelLoadCancelled => (ModelLoad, "model_load.cancelled", None),
+            Error::Unsupported(_) => (Unsupported, "unsupported", None),
+            Error::InvalidGguf(_) => (
+                ModelLoad,
+                "model_load.invalid_gguf",
+                Some("check that the file is a complete GGUF model"),
+            ),
+            Error::InvalidState(_) => (
+                State,
+                "state.invalid",
+                Some("restore states into a context of the same model and n_ctx"),
+            ),
+            Error::Convert(_) => (Conversion, "conversion.failed", None),
+            Error::QuotaExceeded(_) => (
+                Resources,
+                "resources.quota_exceeded",
+                Some("raise ContextOptions::token_quota or continue in a new context"),
+            ),
+            Error::InvalidToken(..) => (
+                Sampling,
+                "sampling.invalid_token",
+                Some("return an index into the logits from the custom sampler"),
+            ),
+            Error::EmbeddingSize(..) => (
+                InvalidInput,
+                "invalid_input.embedding_size",
+                Some("index documents and queries with the same embedding model"),
+            ),
+            Error::VariantUnavailable { .. } => (
+                LibraryLoad,
+                "library_load.variant_unavailable",
+                Some("prefer one of the variants found, or install its libraries"),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "llama")]
+fn llama_cpp_class(e: &llama_cpp::LLamaCppError) -> Class {
+    use llama_cpp::LLamaCppError as E;
+    use ErrorKind::*;
+    match e {
+        E::BackendAlreadyInitialized => (Other, "other.backend_initialized", None),
+        E::Nul(_) => (InvalidInput, "invalid_input.nul_byte", None),
+        E::FromUtf8(_) => (Tokenize, "tokenize.invalid_utf8", None),
+        E::InsufficientBufferSpace(..) => (Tokenize, "tokenize.buffer_too_small", None),
+        E::TokenToString(_) => (Tokenize, "tokenize.token_to_piece", None),
+        E::DecodeError(e) => decode_class(e),
+        E::LlamaModelLoadError(e) => model_load_class(e),
+        E::LlamaContextLoadError(_) => CONTEXT_CREATE,
+        E::BatchAddError(_) => (Decode, "decode.batch_full", None),
+        E::EmbeddingError(_) => (
+            Decode,
+            "decode.embeddings",
+            Some("embed with a model and pooling type that support embeddings"),
+        ),
+        E::ClipError(e) => clip_class(e),
+        E::Predict(e) => predict_class(e),
+        E::Sys(e) => sys_class(e),
+        E::Quantize(_) => (Conversion, "conversion.quantize", None),
+        E::NoFreeSequence(_) => (
+            Resources,
+            "resources.no_free_sequence",
+            Some("drop a fork or raise ContextOptions::max_forks"),
+        ),
+        E::Requirement(_) => REQUIREMENT,
+        E::SamplerInitGramar => (
+            Sampling,
+            "sampling.grammar",
+            Some("check the grammar, llama.cpp's log has the position it rejected"),
+        ),
+        E::SamplerInitChain => (Sampling, "sampling.init", None),
+    }
+}
+
+#[cfg(feature = "llama")]
+fn model_load_class(e: &llama_cpp::LlamaModelLoadError) -> Class {
+    use llama_cpp::LlamaModelLoadError as E;
+    use ErrorKind::*;
+    match e {
+        E::NullResult => (
+            ModelLoad,
+            "model_load.failed",
+            Some(
+                "check that the file is a GGUF model of an architecture this llama.cpp supports \
+                 and that its memory estimate fits, see nebula::estimate_memory",
+            ),
+        ),
+        E::NullError(_) => (InvalidInput, "invalid_input.nul_byte", None),
+        E::PathToStrError(_) => (InvalidInput, "invalid_input.path_encoding", None),
+        E::Sys(e) => sys_class(e),
+        E::Clip(e) => clip_class(e),
+    }
+}
+
+#[cfg(feature = "llama")]
+fn decode_class(e: &llama_cpp::DecodeError) -> Class {
+    use llama_cpp::DecodeError as E;
+    use ErrorKind::*;
+    match e {
+        E::NoKvCacheSlot => (Decode, "decode.no_kv_cache_slot", Some(HINT_CONTEXT_FULL)),
+        E::NTokensZero => (Decode, "decode.empty_batch", None),
+        E::Unknown(_) => (Decode, "decode.failed", None),
+        E::StringToToken(_) => (Tokenize, "tokenize.invalid_text", None),
+        E::BatcAdd(_) => (Decode, "decode.batch_full", None),
+        E::EvalEmbedImage => (Multimodal, "multimodal.image_eval", None),
+    }
+}
+
+#[cfg(feature = "llama")]
+fn clip_class(e: &llama_cpp::ClipError) -> Class {
+    use llama_cpp::ClipError as E;
+    use ErrorKind::*;
+    match e {
+        E::Sys(e) => sys_class(e),
+        E::PathToStrError(_) => (InvalidInput, "invalid_input.path_encoding", None),
+        E::NullError(_) => (InvalidInput, "invalid_input.nul_byte", None),
+        E::NullReturn => (
+            Multimodal,
+            "multimodal.projector_load",
+            Some("check that the projector file belongs to the model"),
+        ),
+        E::ConvertFailed => (
+            Conversion,
+            "conversion.projector",
+            Some("convert a projector stored in F32 or F16"),
+        ),
+    }
+}
+
+#[cfg(feature = "llama")]
+fn predict_class(e: &llama_cpp::PredictError) -> Class {
+    use llama_cpp::PredictError as E;
+    use ErrorKind::*;
+    match e {
+        E::ClipImageU8Init => (Multimodal, "multimodal.image_alloc", None),
+        E::LoadImageFromBytes => (
+            Multimodal,
+            "multimodal.image_decode",
+            Some("pass the bytes of a png, jpeg, bmp or gif image"),
+        ),
+        E::ImageEmbed => (Multimodal, "multimodal.image_embed", None),
+        E::ImagesWithoutMMProj => NO_PROJECTOR,
+        E::Decode(e) => decode_class(e),
+        E::TokenToString(_) => (Tokenize, "tokenize.token_to_piece", None),
+        E::BatchAdd(_) => (Decode, "decode.batch_full", None),
+    }
+}
+
+#[cfg(feature = "llama")]
+fn sys_class(e: &llama_cpp::SysError) -> Class {
+    let kind = match e.kind() {
+        llama_cpp::SysErrorKind::LibraryLoad => ErrorKind::LibraryLoad,
+        llama_cpp::SysErrorKind::DeviceDiscovery => ErrorKind::DeviceDiscovery,
+        llama_cpp::SysErrorKind::InvalidInput => ErrorKind::InvalidInput,
+        llama_cpp::SysErrorKind::Unsupported => ErrorKind::Unsupported,
+    };
+    (kind, e.code(), e.hint())
 }
 
 #[cfg(feature = "llama-http")]
-impl actix_web::error::ResponseError for Error {}
+impl actix_web::error::ResponseError for Error {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        if let Error::QuotaExceeded(_) = self {
+            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        match self.kind() {
+            ErrorKind::InvalidInput | ErrorKind::Template | ErrorKind::Tokenize => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorKind::Resources => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        actix_web::HttpResponse::build(self.status_code()).json(serde_json::json!({
+            "error": {
+                "message": self.to_string(),
+                "type": self.kind(),
+                "code": self.code(),
+                "hint": self.hint(),
+            }
+        }))
+    }
+}
@@ -0,0 +1,652 @@
+//! Reader for the metadata of GGUF model files.
+//!
+//! Only the header is read, tensor data is never touched, so inspecting a model is cheap
+//! regardless of its size. Versions 2 and 3 of the format are supported. Header values can
+//! be written too, for the model converter, and part of a file copied to another.
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{error::Error, Result};
+
+pub(crate) const MAGIC: &[u8; 4] = b"GGUF";
+// guards against allocating for corrupt lengths
+const MAX_STRING_LEN: u64 = 1 << 30;
+const MAX_DIMS: u32 = 4;
+// arrays of arrays nested deeper than this are refused instead of overflowing the stack
+const MAX_ARRAY_DEPTH: usize = 8;
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as an unsigned integer, if it is a non negative integer of any width.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as u64),
+            Self::U16(v) => Some(v as u64),
+            Self::U32(v) => Some(v as u64),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(v) => Some(v as f64),
+            Self::F64(v) => Some(v),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Type id of the value in a GGUF file.
+    fn type_id(&self) -> u32 {
+        match self {
+            Self::U8(_) => 0,
+            Self::I8(_) => 1,
+            Self::U16(_) => 2,
+            Self::I16(_) => 3,
+            Self::U32(_) => 4,
+            Self::I32(_) => 5,
+            Self::F32(_) => 6,
+            Self::Bool(_) => 7,
+            Self::String(_) => 8,
+            Self::Array(_) => 9,
+            Self::U64(_) => 10,
+            Self::I64(_) => 11,
+            Self::F64(_) => 12,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::U8(v) => write!(f, "{v}"),
+            Self::I8(v) => write!(f, "{v}"),
+            Self::U16(v) => write!(f, "{v}"),
+            Self::I16(v) => write!(f, "{v}"),
+            Self::U32(v) => write!(f, "{v}"),
+            Self::I32(v) => write!(f, "{v}"),
+            Self::F32(v) => write!(f, "{v}"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::String(v) => write!(f, "{v:?}"),
+            Self::U64(v) => write!(f, "{v}"),
+            Self::I64(v) => write!(f, "{v}"),
+            Self::F64(v) => write!(f, "{v}"),
+            Self::Array(a) if a.len() > 8 => {
+                write!(f, "[")?;
+                for v in &a[..4] {
+                    write!(f, "{v}, ")?;
+                }
+                write!(f, "... {} more]", a.len() - 4)
+            }
+            Self::Array(a) => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Header of a GGUF file.
+#[derive(Clone, Debug)]
+pub struct Gguf {
+    pub version: u32,
+    pub tensor_count: u64,
+    /// Key value pairs in file order.
+    pub metadata: Vec<(String, Value)>,
+}
+
+impl Gguf {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.metadata
+            .iter()
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+
+    /// `general.architecture`, e.g. `llama`.
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture").and_then(Value::as_str)
+    }
+
+    /// A key of the model's architecture, e.g. `context_length` for `llama.context_length`.
+    pub fn arch_value(&self, key: &str) -> Option<&Value> {
+        self.get(&format!("{}.{key}", self.architecture()?))
+    }
+}
+
+/// Model architecture, `general.architecture` of a GGUF file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Architecture {
+    Llama,
+    Falcon,
+    Gpt2,
+    GptNeoX,
+    Mpt,
+    Baichuan,
+    Starcoder,
+    Starcoder2,
+    Bert,
+    NomicBert,
+    JinaBertV2,
+    Bloom,
+    StableLm,
+    Qwen,
+    Qwen2,
+    Qwen2Moe,
+    Phi2,
+    Phi3,
+    InternLm2,
+    MiniCpm,
+    Gemma,
+    Gemma2,
+    Mamba,
+    Rwkv6,
+    CommandR,
+    Dbrx,
+    Olmo,
+    Deepseek2,
+    ChatGlm,
+    T5,
+    T5Encoder,
+    Nemotron,
+    Exaone,
+    Granite,
+    /// A multimodal projector, loaded with [`crate::Model::new_with_mmproj`].
+    Clip,
+    /// Any architecture not listed, by name.
+    Other(String),
+}
+
+const ARCHITECTURES: &[(&str, Architecture)] = &[
+    ("llama", Architecture::Llama),
+    ("falcon", Architecture::Falcon),
+    ("gpt2", Architecture::Gpt2),
+    ("gptneox", Architecture::GptNeoX),
+    ("mpt", Architecture::Mpt),
+    ("baichuan", Architecture::Baichuan),
+    ("starcoder", Architecture::Starcoder),
+    ("starcoder2", Architecture::Starcoder2),
+    ("bert", Architecture::Bert),
+    ("nomic-bert", Architecture::NomicBert),
+    ("jina-bert-v2", Architecture::JinaBertV2),
+    ("bloom", Architecture::Bloom),
+    ("stablelm", Architecture::StableLm),
+    ("qwen", Architecture::Qwen),
+    ("qwen2", Architecture::Qwen2),
+    ("qwen2moe", Architecture::Qwen2Moe),
+    ("phi2", Architecture::Phi2),
+    ("phi3", Architecture::Phi3),
+    ("internlm2", Architecture::InternLm2),
+    ("minicpm", Architecture::MiniCpm),
+    ("gemma", Architecture::Gemma),
+    ("gemma2", Architecture::Gemma2),
+    ("mamba", Architecture::Mamba),
+    ("rwkv6", Architecture::Rwkv6),
+    ("command-r", Architecture::CommandR),
+    ("dbrx", Architecture::Dbrx),
+    ("olmo", Architecture::Olmo),
+    ("deepseek2", Architecture::Deepseek2),
+    ("chatglm", Architecture::ChatGlm),
+    ("t5", Architecture::T5),
+    ("t5encoder", Architecture::T5Encoder),
+    ("nemotron", Architecture::Nemotron),
+    ("exaone", Architecture::Exaone),
+    ("granite", Architecture::Granite),
+    ("clip", Architecture::Clip),
+];
+
+impl Architecture {
+    pub fn from_name(name: &str) -> Self {
+        ARCHITECTURES
+            .iter()
+            .find_map(|(n, a)| (*n == name).then(|| a.clone()))
+            .unwrap_or_else(|| Self::Other(name.to_string()))
+    }
+
+    /// The name llama.cpp uses, e.g. `qwen2`.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Other(name) => name,
+            arch => ARCHITECTURES
+                .iter()
+                .find_map(|(n, a)| (a == arch).then_some(*n))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Recurrent architectures, they keep a fixed size state instead of a kv cache.
+    pub fn is_recurrent(&self) -> bool {
+        matches!(self, Self::Mamba | Self::Rwkv6)
+    }
+
+    /// Encoder-only architectures, they produce embeddings but can't generate text.
+    pub fn is_encoder_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Bert | Self::NomicBert | Self::JinaBertV2 | Self::T5Encoder
+        )
+    }
+}
+
+impl Display for Architecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl serde::Serialize for Architecture {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(self.name())
+    }
+}
+
+/// What a model can do, derived from its metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Capabilities {
+    /// Images can be evaluated. A GGUF file only supports them if it is or embeds a projector,
+    /// a model does once one is attached.
+    pub supports_vision: bool,
+    /// The chat template accepts a system message.
+    pub supports_system_prompt: bool,
+    /// The model is meant for [`crate::Model::embed_batch`], not for generating text.
+    pub is_embedding_model: bool,
+    /// The vocabulary has fill-in-the-middle tokens, for code completion between a prefix and
+    /// a suffix.
+    pub fim_tokens_present: bool,
+    /// The model is recurrent, like Mamba and RWKV: its state has a fixed size, so `n_ctx`
+    /// doesn't limit how much it can read and [`crate::options::Usage::context_size`] is no
+    /// measure of what is left. Going back in a context evaluates it again from the start.
+    pub is_recurrent: bool,
+}
+
+// fim tokens of models that predate the tokenizer.ggml.fim_* keys
+const FIM_PIECES: &[&str] = &[
+    "<|fim_prefix|>",
+    "<fim_prefix>",
+    "<fim-prefix>",
+    "<｜fim▁begin｜>",
+    "<PRE>",
+];
+
+impl Gguf {
+    /// `general.architecture` as an [`Architecture`].
+    pub fn typed_architecture(&self) -> Option<Architecture> {
+        self.architecture().map(Architecture::from_name)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        let arch = self.typed_architecture();
+        let template = self.get("tokenizer.chat_template").and_then(Value::as_str);
+        let fim_keys = [
+            "tokenizer.ggml.fim_pre_token_id",
+            "tokenizer.ggml.prefix_token_id",
+        ];
+        let fim_tokens_present = fim_keys.iter().any(|k| self.get(k).is_some())
+            || self
+                .get("tokenizer.ggml.tokens")
+                .and_then(Value::as_array)
+                .is_some_and(|tokens| {
+                    tokens
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .any(|t| FIM_PIECES.contains(&t))
+                });
+        Capabilities {
+            // projectors and models that embed one
+            supports_vision: self
+                .get("clip.has_vision_encoder")
+                .map_or(arch == Some(Architecture::Clip), |v| {
+                    *v == Value::Bool(true)
+                }),
+            // templates without a system role raise an error for it, e.g. gemma's
+            supports_system_prompt: arch != Some(Architecture::Clip)
+                && template.map_or(true, |t| {
+                    !t.to_lowercase().contains("system role not supported")
+                }),
+            is_embedding_model: arch.as_ref().is_some_and(Architecture::is_encoder_only)
+                || self.arch_value("pooling_type").is_some(),
+            fim_tokens_present,
+            is_recurrent: arch.as_ref().is_some_and(Architecture::is_recurrent),
+        }
+    }
+}
+
+/// Reads the header of the GGUF file at `path`.
+pub fn read(path: impl AsRef<Path>) -> Result<Gguf> {
+    read_from(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn read_from(r: &mut impl Read) -> Result<Gguf> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::InvalidGguf("not a GGUF file".to_string()));
+    }
+    let version = read_u32(r)?;
+    if !(2..=3).contains(&version) {
+        return Err(Error::InvalidGguf(format!(
+            "unsupported GGUF version {version}"
+        )));
+    }
+    let tensor_count = read_u64(r)?;
+    let kv_count = read_u64(r)?;
+    let mut metadata = vec![];
+    for _ in 0..kv_count {
+        let key = read_string(r)?;
+        let ty = read_u32(r)?;
+        metadata.push((key, read_value(r, ty, 0)?));
+    }
+    Ok(Gguf {
+        version,
+        tensor_count,
+        metadata,
+    })
+}
+
+/// A tensor of a GGUF file, without its data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dims: Vec<u64>,
+    /// `ggml_type` of the data, e.g. 1 for F16.
+    pub ggml_type: u32,
+    /// Position of the data within the data section.
+    pub offset: u64,
+    /// Bytes of the data, up to the next tensor's, so alignment padding included.
+    pub size: u64,
+}
+
+impl TensorInfo {
+    /// The repeating layer the tensor is part of, from `blk.<n>.` names.
+    pub fn layer(&self) -> Option<u64> {
+        self.name
+            .strip_prefix("blk.")?
+            .split('.')
+            .next()?
+            .parse()
+            .ok()
+    }
+}
+
+/// Reads the header and the tensor infos of the GGUF file at `path`.
+pub fn read_tensors(path: impl AsRef<Path>) -> Result<(Gguf, Vec<TensorInfo>)> {
+    let (gguf, tensors, _) = read_layout(&mut BufReader::new(File::open(path)?))?;
+    Ok((gguf, tensors))
+}
+
+/// The header, the tensor infos and where the data section starts.
+fn read_layout(r: &mut BufReader<File>) -> Result<(Gguf, Vec<TensorInfo>, u64)> {
+    let gguf = read_from(r)?;
+    let mut tensors = vec![];
+    for _ in 0..gguf.tensor_count {
+        let name = read_string(r)?;
+        let n_dims = read_u32(r)?;
+        if n_dims > MAX_DIMS {
+            return Err(Error::InvalidGguf(format!(
+                "tensor {name} of {n_dims} dimensions"
+            )));
+        }
+        let dims = (0..n_dims).map(|_| read_u64(r)).collect::<Result<_>>()?;
+        tensors.push(TensorInfo {
+            name,
+            dims,
+            ggml_type: read_u32(r)?,
+            offset: read_u64(r)?,
+            size: 0,
+        });
+    }
+    let alignment = gguf
+        .get("general.alignment")
+        .and_then(Value::as_u64)
+        .filter(|a| *a > 0)
+        .unwrap_or(DEFAULT_ALIGNMENT);
+    let data_start = r.stream_position()?.next_multiple_of(alignment);
+    let data_len = r.get_ref().metadata()?.len().saturating_sub(data_start);
+    let mut order: Vec<usize> = (0..tensors.len()).collect();
+    order.sort_by_key(|i| tensors[*i].offset);
+    for (n, i) in order.iter().enumerate() {
+        let end = order.get(n + 1).map_or(data_len, |j| tensors[*j].offset);
+        tensors[*i].size = end.saturating_sub(tensors[*i].offset);
+    }
+    Ok((gguf, tensors, data_start))
+}
+
+/// Copies the keys and tensors of the GGUF file at `path` that `keep_key` and `keep_tensor`
+/// select to a new file at `output`, the tensor data as is.
+pub(crate) fn write_subset(
+    path: &Path,
+    output: &Path,
+    keep_key: impl Fn(&str) -> bool,
+    keep_tensor: impl Fn(&str) -> bool,
+) -> Result<()> {
+    let mut r = BufReader::new(File::open(path)?);
+    let (gguf, tensors, data_start) = read_layout(&mut r)?;
+    let alignment = gguf
+        .get("general.alignment")
+        .and_then(Value::as_u64)
+        .filter(|a| *a > 0)
+        .unwrap_or(DEFAULT_ALIGNMENT);
+    let metadata: Vec<_> = gguf
+        .metadata
+        .iter()
+        .filter(|(k, _)| k == "general.alignment" || keep_key(k))
+        .collect();
+    let tensors: Vec<_> = tensors
+        .into_iter()
+        .filter(|t| keep_tensor(&t.name))
+        .collect();
+    let mut w = BufWriter::new(File::create(output)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&3u32.to_le_bytes())?;
+    w.write_all(&(tensors.len() as u64).to_le_bytes())?;
+    w.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    for (key, value) in metadata {
+        write_kv(&mut w, key, value)?;
+    }
+    let mut offset = 0u64;
+    for t in &tensors {
+        write_string(&mut w, &t.name)?;
+        w.write_all(&(t.dims.len() as u32).to_le_bytes())?;
+        for d in &t.dims {
+            w.write_all(&d.to_le_bytes())?;
+        }
+        w.write_all(&t.ggml_type.to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        offset += t.size.next_multiple_of(alignment);
+    }
+    pad(&mut w, alignment)?;
+    for t in &tensors {
+        r.seek(SeekFrom::Start(data_start + t.offset))?;
+        if std::io::copy(&mut (&mut r).take(t.size), &mut w)? != t.size {
+            return Err(Error::InvalidGguf(format!(
+                "data of tensor {} truncated",
+                t.name
+            )));
+        }
+        pad(&mut w, alignment)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn pad(w: &mut (impl Write + Seek), alignment: u64) -> Result<()> {
+    let pos = w.stream_position()?;
+    w.write_all(&vec![0; (pos.next_multiple_of(alignment) - pos) as usize])?;
+    Ok(())
+}
+
+fn read_bytes<const N: usize>(r: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(r)?))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(r)?))
+}
+
+fn read_string(r: &mut impl Read) -> Result<String> {
+    let len = read_u64(r)?;
+    if len > MAX_STRING_LEN {
+        return Err(Error::InvalidGguf(format!("string of {len} bytes")));
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+pub(crate) fn write_string(w: &mut impl Write, s: &str) -> Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+/// Writes a key value pair of the header.
+pub(crate) fn write_kv(w: &mut impl Write, key: &str, value: &Value) -> Result<()> {
+    write_string(w, key)?;
+    w.write_all(&value.type_id().to_le_bytes())?;
+    write_value(w, value)
+}
+
+fn write_value(w: &mut impl Write, value: &Value) -> Result<()> {
+    match value {
+        Value::U8(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I8(v) => w.write_all(&v.to_le_bytes())?,
+        Value::U16(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I16(v) => w.write_all(&v.to_le_bytes())?,
+        Value::U32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::F32(v) => w.write_all(&v.to_le_bytes())?,
+        Value::Bool(v) => w.write_all(&[*v as u8])?,
+        Value::String(v) => write_string(w, v)?,
+        Value::Array(a) => {
+            // arrays are homogeneous, an empty one is written as u8
+            let ty = a.first().map_or(0, Value::type_id);
+            if a.iter().any(|v| v.type_id() != ty) {
+                return Err(Error::InvalidGguf("mixed array".to_string()));
+            }
+            w.write_all(&ty.to_le_bytes())?;
+            w.write_all(&(a.len() as u64).to_le_bytes())?;
+            for v in a {
+                write_value(w, v)?;
+            }
+        }
+        Value::U64(v) => w.write_all(&v.to_le_bytes())?,
+        Value::I64(v) => w.write_all(&v.to_le_bytes())?,
+        Value::F64(v) => w.write_all(&v.to_le_bytes())?,
+    }
+    Ok(())
+}
+
+/// Reads a value of type `ty` inside `depth` arrays.
+fn read_value(r: &mut impl Read, ty: u32, depth: usize) -> Result<Value> {
+    Ok(match ty {
+        0 => Value::U8(u8::from_le_bytes(read_bytes(r)?)),
+        1 => Value::I8(i8::from_le_bytes(read_bytes(r)?)),
+        2 => Value::U16(u16::from_le_bytes(read_bytes(r)?)),
+        3 => Value::I16(i16::from_le_bytes(read_bytes(r)?)),
+        4 => Value::U32(read_u32(r)?),
+        5 => Value::I32(i32::from_le_bytes(read_bytes(r)?)),
+        6 => Value::F32(f32::from_le_bytes(read_bytes(r)?)),
+        7 => Value::Bool(read_bytes::<1>(r)?[0] != 0),
+        8 => Value::String(read_string(r)?),
+        9 => {
+            if depth >= MAX_ARRAY_DEPTH {
+                return Err(Error::InvalidGguf(format!(
+                    "arrays nested deeper than {MAX_ARRAY_DEPTH}"
+                )));
+            }
+            let ty = read_u32(r)?;
+            let len = read_u64(r)?;
+            let mut values = Vec::with_capacity(len.min(1 << 20) as usize);
+            for _ in 0..len {
+                values.push(read_value(r, ty, depth + 1)?);
+            }
+            Value::Array(values)
+        }
+        10 => Value::U64(read_u64(r)?),
+        11 => Value::I64(i64::from_le_bytes(read_bytes(r)?)),
+        12 => Value::F64(f64::from_le_bytes(read_bytes(r)?)),
+        ty => return Err(Error::InvalidGguf(format!("unknown value type {ty}"))),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gguf_nested_array_test() {
+        // a header with one key holding `depth` arrays of one element around a u8
+        let header = |depth: usize| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(0u64.to_le_bytes());
+            bytes.extend(1u64.to_le_bytes());
+            write_string(&mut bytes, "nested").unwrap();
+            bytes.extend(9u32.to_le_bytes());
+            for i in 1..=depth {
+                bytes.extend((if i == depth { 0u32 } else { 9 }).to_le_bytes());
+                bytes.extend(1u64.to_le_bytes());
+            }
+            bytes.push(7);
+            bytes
+        };
+        let gguf = read_from(&mut header(2).as_slice()).unwrap();
+        assert_eq!(
+            gguf.metadata[0].1,
+            Value::Array(vec![Value::Array(vec![Value::U8(7)])])
+        );
+        // deep nesting fails instead of overflowing the stack
+        let err = read_from(&mut header(100_000).as_slice()).unwrap_err();
+        assert_eq!(err.code(), "model_load.invalid_gguf");
+    }
+}
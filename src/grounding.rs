@@ -0,0 +1,272 @@
+//! Bounding boxes in the answers of grounding vision models.
+//!
+//! [`parse`] understands the coordinate markup of
+//! - Qwen-VL and Qwen2-VL: `<ref>dog</ref><box>(x1,y1),(x2,y2)</box>` in thousandths,
+//! - PaliGemma: `<loc0256><loc0128><loc0768><loc0896> dog ; ...` as y, x, y, x in 1024ths,
+//! - Kosmos-2: `<phrase>dog</phrase><object><patch_index_0044><patch_index_0863></object>`,
+//!   the top left and bottom right cells of a 32 by 32 grid.
+
+/// A box in coordinates relative to the image, `0.0` to `1.0` from the top left corner.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub struct BoundingBox {
+    pub x_min: f32,
+    pub y_min: f32,
+    pub x_max: f32,
+    pub y_max: f32,
+}
+
+impl BoundingBox {
+    fn new(x_min: f32, y_min: f32, x_max: f32, y_max: f32) -> Self {
+        let clamp = |v: f32| v.clamp(0.0, 1.0);
+        Self {
+            x_min: clamp(x_min.min(x_max)),
+            y_min: clamp(y_min.min(y_max)),
+            x_max: clamp(x_min.max(x_max)),
+            y_max: clamp(y_min.max(y_max)),
+        }
+    }
+
+    /// The box in pixels of an image of `width` by `height`, as `(x_min, y_min, x_max, y_max)`.
+    pub fn to_pixels(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let (w, h) = (width as f32, height as f32);
+        (
+            (self.x_min * w).round() as u32,
+            (self.y_min * h).round() as u32,
+            (self.x_max * w).round() as u32,
+            (self.y_max * h).round() as u32,
+        )
+    }
+}
+
+/// An object the model located, `label` is the text it referred to it with.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Detection {
+    pub label: String,
+    pub bbox: BoundingBox,
+}
+
+// label and box tags of the formats marking up both the same way
+const QWEN_VL: ((&str, &str), (&str, &str)) = (("<ref>", "</ref>"), ("<box>", "</box>"));
+const QWEN2_VL: ((&str, &str), (&str, &str)) = (
+    ("<|object_ref_start|>", "<|object_ref_end|>"),
+    ("<|box_start|>", "<|box_end|>"),
+);
+const KOSMOS_2: ((&str, &str), (&str, &str)) =
+    (("<phrase>", "</phrase>"), ("<object>", "</object>"));
+
+// cells per side of the Kosmos-2 grid
+const KOSMOS_GRID: u32 = 32;
+// PaliGemma locations are in 1024ths of the image
+const PALIGEMMA_LOCATIONS: f32 = 1024.0;
+
+/// The boxes in `text`, in the order they appear. Markup that can't be read is skipped.
+pub fn parse(text: &str) -> Vec<Detection> {
+    let mut res = parse_tagged(text, QWEN_VL, qwen_box);
+    res.extend(parse_tagged(text, QWEN2_VL, qwen_box));
+    res.extend(parse_tagged(text, KOSMOS_2, kosmos_boxes));
+    res.extend(parse_paligemma(text));
+    // each format is read on its own, a stable sort puts mixed ones back in text order
+    res.sort_by_key(|(at, _)| *at);
+    res.into_iter().map(|(_, detection)| detection).collect()
+}
+
+/// Walks through the labels and boxes of `text`, a box belongs to the label before it.
+/// Detections come with the byte offset of their box.
+fn parse_tagged(
+    text: &str,
+    ((label_open, label_close), (box_open, box_close)): ((&str, &str), (&str, &str)),
+    boxes: fn(&str) -> Vec<BoundingBox>,
+) -> Vec<(usize, Detection)> {
+    let mut res = vec![];
+    let mut label = "";
+    let mut rest = text;
+    loop {
+        let (open, close, at) = match (rest.find(label_open), rest.find(box_open)) {
+            (Some(l), Some(b)) if l < b => (label_open, label_close, l),
+            (_, Some(b)) => (box_open, box_close, b),
+            (Some(l), None) => (label_open, label_close, l),
+            (None, None) => break,
+        };
+        let inner = &rest[at + open.len()..];
+        let Some(end) = inner.find(close) else {
+            break;
+        };
+        if open == label_open {
+            label = inner[..end].trim();
+        } else {
+            let offset = text.len() - rest.len() + at;
+            res.extend(boxes(&inner[..end]).into_iter().map(|bbox| {
+                let label = label.to_string();
+                (offset, Detection { label, bbox })
+            }));
+        }
+        rest = &inner[end + close.len()..];
+    }
+    res
+}
+
+/// The numbers in `text`, e.g. `(12,34),(56,78)`.
+fn numbers(text: &str) -> Vec<f32> {
+    text.split(|c: char| !c.is_ascii_digit() && c != '.')
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
+
+fn qwen_box(text: &str) -> Vec<BoundingBox> {
+    match numbers(text)[..] {
+        [x1, y1, x2, y2] => vec![BoundingBox::new(
+            x1 / 1000.0,
+            y1 / 1000.0,
+            x2 / 1000.0,
+            y2 / 1000.0,
+        )],
+        _ => vec![],
+    }
+}
+
+fn kosmos_boxes(text: &str) -> Vec<BoundingBox> {
+    let grid = KOSMOS_GRID as f32;
+    text.split("</delimiter_of_multi_objects/>")
+        .filter_map(|part| match numbers(part)[..] {
+            [top_left, bottom_right] => {
+                let cell = |i: f32| {
+                    (
+                        (i as u32 % KOSMOS_GRID) as f32,
+                        (i as u32 / KOSMOS_GRID) as f32,
+                    )
+                };
+                let ((x1, y1), (x2, y2)) = (cell(top_left), cell(bottom_right));
+                Some(BoundingBox::new(
+                    x1 / grid,
+                    y1 / grid,
+                    (x2 + 1.0) / grid,
+                    (y2 + 1.0) / grid,
+                ))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// PaliGemma's `; ` separated detections, four `<locNNNN>` tokens and the label, segmentation
+/// tokens after the locations are skipped. Detections come with the byte offset of their part.
+fn parse_paligemma(text: &str) -> Vec<(usize, Detection)> {
+    let mut offset = 0;
+    text.split(';')
+        .filter_map(|part| {
+            let at = offset;
+            offset += part.len() + 1;
+            let mut rest = part.trim_start();
+            let mut locations = vec![];
+            while let Some(token) = rest
+                .strip_prefix("<loc")
+                .or_else(|| rest.strip_prefix("<seg"))
+            {
+                let end = token.find('>')?;
+                let value: u32 = token[..end].parse().ok()?;
+                if rest.starts_with("<loc") {
+                    locations.push(value as f32 / PALIGEMMA_LOCATIONS);
+                }
+                rest = token[end + 1..].trim_start();
+            }
+            match locations[..] {
+                [y1, x1, y2, x2] => Some((
+                    at,
+                    Detection {
+                        label: rest.trim().to_string(),
+                        bbox: BoundingBox::new(x1, y1, x2, y2),
+                    },
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, BoundingBox, Detection};
+
+    fn detection(label: &str, bbox: (f32, f32, f32, f32)) -> Detection {
+        Detection {
+            label: label.to_string(),
+            bbox: BoundingBox {
+                x_min: bbox.0,
+                y_min: bbox.1,
+                x_max: bbox.2,
+                y_max: bbox.3,
+            },
+        }
+    }
+
+    #[test]
+    fn qwen_vl_test() {
+        let text = "There are <ref>two dogs</ref><box>(100,200),(300,400)</box>\
+                    <box>(500,500),(1000,1000)</box> and <ref>a cat</ref><box>(0,0),(250,125)</box>.";
+        assert_eq!(
+            parse(text),
+            vec![
+                detection("two dogs", (0.1, 0.2, 0.3, 0.4)),
+                detection("two dogs", (0.5, 0.5, 1.0, 1.0)),
+                detection("a cat", (0.0, 0.0, 0.25, 0.125)),
+            ]
+        );
+        let text =
+            "<|object_ref_start|>car<|object_ref_end|><|box_start|>(10,20),(30,40)<|box_end|>";
+        assert_eq!(
+            parse(text),
+            vec![detection("car", (0.01, 0.02, 0.03, 0.04))]
+        );
+    }
+
+    #[test]
+    fn paligemma_test() {
+        let text = "<loc0256><loc0128><loc0768><loc0896> dog ; \
+                    <loc0000><loc0000><loc0512><loc0512><seg012><seg100> cat";
+        assert_eq!(
+            parse(text),
+            vec![
+                detection("dog", (0.125, 0.25, 0.875, 0.75)),
+                detection("cat", (0.0, 0.0, 0.5, 0.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn kosmos_2_test() {
+        // cells 33 and 66 are the second and third of the second and third rows
+        let text = "<phrase>a snowman</phrase><object><patch_index_0033><patch_index_0066>\
+                    </delimiter_of_multi_objects/><patch_index_0000><patch_index_1023></object>";
+        assert_eq!(
+            parse(text),
+            vec![
+                detection("a snowman", (0.03125, 0.03125, 0.09375, 0.09375)),
+                detection("a snowman", (0.0, 0.0, 1.0, 1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn mixed_formats_test() {
+        let text = "<loc0000><loc0000><loc0512><loc0512> cat ; \
+                    <|object_ref_start|>car<|object_ref_end|><|box_start|>(10,20),(30,40)<|box_end|>\
+                    <ref>dog</ref><box>(0,0),(1000,1000)</box>";
+        assert_eq!(
+            parse(text),
+            vec![
+                detection("cat", (0.0, 0.0, 0.5, 0.5)),
+                detection("car", (0.01, 0.02, 0.03, 0.04)),
+                detection("dog", (0.0, 0.0, 1.0, 1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_test() {
+        assert!(parse("a dog (100,200),(300,400)").is_empty());
+        assert!(parse("<ref>dog</ref><box>(100,200)</box><box>(1,2),(3,4)").is_empty());
+        assert!(parse("<loc0256><loc0128> dog").is_empty());
+        let bbox = parse("<box>(300,400),(100,200)</box>")[0].bbox;
+        assert_eq!(bbox.to_pixels(1000, 500), (100, 100, 300, 200));
+    }
+}
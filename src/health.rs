@@ -0,0 +1,71 @@
+//! Readiness of a loaded model.
+//!
+//! Servers call [`crate::Model::warmup`] once after loading, so the first request doesn't pay
+//! for paging in the weights and compiling gpu kernels, and report [`crate::Model::health`]
+//! before routing traffic to the model.
+use crate::devices::Device;
+
+/// Result of [`crate::Model::warmup`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Warmup {
+    /// Tokens decoded.
+    pub tokens: usize,
+    /// Time of the warm-up decode, context creation included.
+    pub elapsed_ms: f64,
+}
+
+/// Where a model runs after loading, see [`crate::Model::placement`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Placement {
+    /// Library variant llama.cpp runs on, e.g. `cuda_v12`.
+    pub variant: Option<String>,
+    /// Layers offloaded to the gpu, 0 on the cpu and negative for all of them.
+    pub n_gpu_layers: i32,
+    /// Layers actually on the gpu, `n_gpu_layers` capped at the layers of the model plus the
+    /// output layer, 0 if the variant can't offload.
+    pub n_offloaded: u32,
+    /// The gpus the offloaded layers run on, empty on the cpu.
+    pub devices: Vec<Device>,
+    /// The configured offload failed and [`crate::options::ModelOptions::fallback`] loaded
+    /// the model with `n_gpu_layers` instead.
+    pub fell_back: bool,
+}
+
+impl Placement {
+    /// Some layers run on the gpu.
+    pub fn on_gpu(&self) -> bool {
+        self.n_offloaded != 0
+    }
+}
+
+/// State of a model, see [`crate::Model::health`].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Health {
+    pub model: String,
+    /// Library variant llama.cpp runs on, e.g. `cuda_v12`.
+    pub variant: Option<String>,
+    pub devices: Vec<Device>,
+    /// Layers are offloaded to the gpus in `devices`.
+    pub offloaded: bool,
+    /// Free memory of the devices the model runs on, the gpus if layers are offloaded and
+    /// system memory otherwise.
+    pub free_memory: u64,
+    pub total_memory: u64,
+    /// Contexts of the model alive now.
+    pub contexts: usize,
+    /// [`crate::options::ModelOptions::max_contexts`].
+    pub max_contexts: Option<usize>,
+    /// [`crate::Model::warmup`] ran successfully.
+    pub warmed_up: bool,
+}
+
+impl Health {
+    /// Warmed up and able to create another context.
+    pub fn ready(&self) -> bool {
+        self.warmed_up && self.max_contexts.map_or(true, |max| self.contexts < max)
+    }
+
+    pub fn to_json(&self) -> crate::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
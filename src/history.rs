@@ -0,0 +1,307 @@
+//! A conversation that stays within the context window of the model.
+//!
+//! [`ChatHistory`] counts the tokens of every message and, when the next turn doesn't fit
+//! anymore, drops or summarizes the oldest ones as set by [`Overflow`]. Messages that are
+//! already in the kv cache are not evaluated again, dropped ones are cut out of it and the
+//! tokens after them moved up.
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    error::Error,
+    options::{
+        ChatHistoryOptions, ContextOptions, Message, Overflow, PredictOptions, RetrievalOptions,
+        Role, TokenCallback,
+    },
+    rag::{self, Answer, DocumentStore},
+    Context, Model, Result,
+};
+
+struct Entry {
+    message: Message,
+    tokens: usize,
+    pinned: bool,
+    // kv cache positions of the eval call the message was part of, none until evaluated
+    span: Option<(usize, usize)>,
+}
+
+pub struct ChatHistory {
+    model: Model,
+    context: Context,
+    context_options: ContextOptions,
+    options: ChatHistoryOptions,
+    entries: Vec<Entry>,
+}
+
+impl ChatHistory {
+    pub fn new(
+        model: &Model,
+        context_options: ContextOptions,
+        options: ChatHistoryOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            model: model.clone(),
+            context: model.context(context_options.clone())?,
+            context_options,
+            options,
+            entries: vec![],
+        })
+    }
+
+    /// Appends `message`, making room for it first if the history would not fit anymore.
+    pub fn push(&mut self, message: Message) -> Result<()> {
+        let tokens = self.model.count_tokens(&message)?;
+        let pinned = self.options.pin_system && message.role == Role::System;
+        self.entries.push(Entry {
+            message,
+            tokens,
+            pinned,
+            span: None,
+        });
+        let res = self.fit();
+        if res.is_err() {
+            self.entries.pop();
+        }
+        res
+    }
+
+    /// Keeps the message at `index` whatever the overflow strategy, returns `false` if there
+    /// is none.
+    pub fn pin(&mut self, index: usize) -> bool {
+        match self.entries.get_mut(index) {
+            Some(e) => {
+                e.pinned = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.entries.iter().map(|e| &e.message)
+    }
+
+    /// Tokens of all messages in the history.
+    pub fn tokens(&self) -> usize {
+        self.entries.iter().map(|e| e.tokens).sum()
+    }
+
+    /// Evaluates the messages pushed since the last answer and generates the next one, which
+    /// becomes part of the history.
+    pub fn predict(&mut self, options: PredictOptions) -> Result<String> {
+        let pending: Vec<Message> = self
+            .entries
+            .iter()
+            .filter(|e| e.span.is_none())
+            .map(|e| e.message.clone())
+            .collect();
+        let start = self.n_past();
+        if !pending.is_empty() {
+            self.context.eval(pending)?;
+        }
+        let end = self.n_past();
+        self.entries
+            .iter_mut()
+            .filter(|e| e.span.is_none())
+            .for_each(|e| e.span = Some((start, end)));
+        let answer = match options.token_callback.clone() {
+            // predictions with a callback return no text, collect it on the way
+            Some(callback) => {
+                let collected = Arc::new(Mutex::new(String::new()));
+                let sink = collected.clone();
+                let tee: Box<TokenCallback> = Box::new(move |token| {
+                    sink.lock().unwrap().push_str(&token);
+                    callback(token)
+                });
+                let options = PredictOptions {
+                    token_callback: Some(Arc::new(tee)),
+                    ..options
+                };
+                self.context.predict(options).predict()?;
+                let answer = collected.lock().unwrap().clone();
+                answer
+            }
+            None => self.context.predict(options).predict()?,
+        };
+        let message = Message {
+            content: answer.clone(),
+            role: Role::Assistant,
+            images: vec![],
+        };
+        self.entries.push(Entry {
+            tokens: self.model.count_tokens(&message)?,
+            message,
+            pinned: false,
+            span: Some((end, self.n_past())),
+        });
+        self.fit()?;
+        Ok(answer)
+    }
+
+    /// Answers `query` from the chunks of `store` most similar to it, see [`crate::rag`].
+    ///
+    /// The chunks go into the user message with their ids and the model is asked to cite
+    /// them. The message stays in the history like any other, so follow-up questions can
+    /// refer to the sources.
+    pub fn ask_with_context(
+        &mut self,
+        query: &str,
+        store: &dyn DocumentStore,
+        retrieval: &RetrievalOptions,
+        options: PredictOptions,
+    ) -> Result<Answer> {
+        let sources: Vec<_> = store
+            .retrieve(query, retrieval.top_k)?
+            .into_iter()
+            .filter(|r| retrieval.min_score.map_or(true, |min| r.score >= min))
+            .collect();
+        self.push(Message {
+            content: rag::prompt(query, &sources),
+            role: Role::User,
+            images: vec![],
+        })?;
+        let text = self.predict(options)?;
+        Ok(Answer {
+            citations: rag::citations(&text, &sources),
+            text,
+            sources,
+        })
+    }
+
+    fn n_past(&self) -> usize {
+        self.context.backend.lock().unwrap().n_past()
+    }
+
+    /// Drops or summarizes the oldest messages that are not pinned until the history leaves
+    /// `options.reserve` tokens of the context free.
+    fn fit(&mut self) -> Result<()> {
+        let n_ctx = self.context_options.n_ctx;
+        let budget = n_ctx.saturating_sub(self.options.reserve);
+        let total = self.tokens();
+        if total <= budget {
+            return Ok(());
+        }
+        let mut excess = total - budget;
+        let mut victims = vec![];
+        for (i, e) in self.entries.iter().enumerate() {
+            if excess == 0 {
+                break;
+            }
+            if !e.pinned {
+                victims.push(i);
+                excess = excess.saturating_sub(e.tokens);
+            }
+        }
+        if excess > 0 {
+            return Err(Error::KVCacheNotBigEnough(total, budget));
+        }
+        let summary = match self.options.overflow {
+            Overflow::DropOldest => None,
+            Overflow::Summarize => match self.summarize(&victims) {
+                Ok(summary) => {
+                    let dropped: usize = victims.iter().map(|i| self.entries[*i].tokens).sum();
+                    (total - dropped + summary.tokens <= budget).then_some(summary)
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "summarizing the chat history failed");
+                    None
+                }
+            },
+        };
+        self.evict(&victims, summary.is_some())?;
+        let first = victims[0];
+        let mut i = 0;
+        self.entries.retain(|_| {
+            i += 1;
+            !victims.contains(&(i - 1))
+        });
+        if let Some(summary) = summary {
+            self.entries.insert(first, summary);
+        }
+        Ok(())
+    }
+
+    /// Removes the tokens of `victims` from the kv cache. If only parts of an eval call go or
+    /// something is inserted in their place, everything from there on is evaluated again.
+    fn evict(&mut self, victims: &[usize], insert: bool) -> Result<()> {
+        let mut spans: Vec<(usize, usize)> = victims
+            .iter()
+            .filter_map(|i| self.entries[*i].span)
+            .collect();
+        spans.dedup();
+        let Some(cut) = spans.iter().map(|s| s.0).min() else {
+            return Ok(());
+        };
+        let whole = self
+            .entries
+            .iter()
+            .enumerate()
+            .all(|(i, e)| e.span.map_or(true, |s| !spans.contains(&s)) || victims.contains(&i));
+        let contiguous = spans.windows(2).all(|w| w[0].1 == w[1].0);
+        let mut backend = self.context.backend.lock().unwrap();
+        if whole && contiguous && !insert {
+            let end = spans[spans.len() - 1].1;
+            backend.discard(cut, end)?;
+            for e in &mut self.entries {
+                if let Some((s, t)) = e.span.as_mut().filter(|s| s.0 >= end) {
+                    *s -= end - cut;
+                    *t -= end - cut;
+                }
+            }
+        } else {
+            backend.truncate(cut)?;
+            for e in &mut self.entries {
+                if e.span.is_some_and(|s| s.0 >= cut) {
+                    e.span = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A system message summing up the messages at `victims`, written by the model in a
+    /// context of its own.
+    fn summarize(&self, victims: &[usize]) -> Result<Entry> {
+        let transcript = victims
+            .iter()
+            .map(|i| {
+                let m = &self.entries[*i].message;
+                format!("{}: {}", m.role, m.content)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut ctx = self.model.context(self.context_options.clone())?;
+        ctx.eval(vec![
+            Message {
+                content: "Summarize the following conversation in a few sentences. Keep names, \
+                          facts and decisions."
+                    .to_string(),
+                role: Role::System,
+                images: vec![],
+            },
+            Message {
+                content: transcript,
+                role: Role::User,
+                images: vec![],
+            },
+        ])?;
+        let summary = ctx
+            .predict(
+                PredictOptions::builder()
+                    .temp(0.0)
+                    .max_len(self.options.summary_tokens as i32)
+                    .build(),
+            )
+            .predict()?;
+        let message = Message {
+            content: format!("Summary of the earlier conversation: {}", summary.trim()),
+            role: Role::System,
+            images: vec![],
+        };
+        Ok(Entry {
+            tokens: self.model.count_tokens(&message)?,
+            message,
+            pinned: false,
+            span: None,
+        })
+    }
+}
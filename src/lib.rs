@@ -18,17 +18,132 @@ use std::{path::PathBuf, pin::Pin, sync::Mutex};
 #[cfg(feature = "whisper")]
 use std::path::PathBuf;
 
+#[cfg(feature = "llama")]
+pub mod bench;
+#[cfg(feature = "convert")]
+pub mod convert;
+#[cfg(feature = "llama")]
+pub mod devices;
 pub mod error;
+pub mod gguf;
+pub mod grounding;
+#[cfg(feature = "llama")]
+pub mod health;
+#[cfg(feature = "llama")]
+pub mod history;
+#[cfg(feature = "llama")]
+pub mod logging;
+#[cfg(feature = "llama")]
+pub mod memory;
+#[cfg(feature = "llama")]
+pub mod metrics;
 pub mod options;
+pub mod paths;
+pub mod prompt;
+#[cfg(feature = "llama")]
+pub mod rag;
+#[cfg(feature = "llama")]
+pub mod session;
+pub mod template;
 pub type Result<T> = std::result::Result<T, error::Error>;
 pub mod utils;
 
 mod backend;
 
+/// Sets the directory native dependencies are loaded from, see [`paths::set_dependencies_dir`].
 pub fn init(resource_path: std::path::PathBuf) -> Result<()> {
-    resource_path::set(resource_path).map_err(error::Error::Unknown)
+    paths::set_dependencies_dir(resource_path)
+}
+
+/// Frees the llama.cpp backend and unloads the native libraries.
+///
+/// Host applications that get unloaded themselves (plugins, DLLs) should call it before exit,
+/// so nothing is left for static destructors. Every [`Model`] and [`Context`] has to be
+/// dropped first, otherwise [`error::Error::ResourcesInUse`] is returned and nothing is freed.
+/// The libraries are loaded again by the next [`Model::new`].
+#[cfg(feature = "llama")]
+pub fn shutdown() -> Result<()> {
+    backend::llama::shutdown(std::time::Duration::ZERO)
+}
+
+/// Like [`shutdown`], but waits up to `timeout` for the models and contexts still alive to be
+/// dropped, e.g. by requests that are finishing on other threads.
+///
+/// Loading a model waits until the shutdown is over, then loads the libraries again, so a
+/// server can reload with another [`devices::set_backend_preference`] without restarting.
+/// After the timeout nothing is freed and [`error::Error::ResourcesInUse`] is returned.
+#[cfg(feature = "llama")]
+pub fn shutdown_timeout(timeout: std::time::Duration) -> Result<()> {
+    backend::llama::shutdown(timeout)
+}
+
+/// Quantizes the GGUF model at `input` to `quant_type` and writes it to `output`.
+///
+/// Usually run on a F16 or F32 model, quantizing a quantized one needs
+/// [`options::QuantizeOptions::allow_requantize`].
+#[cfg(feature = "llama")]
+pub fn quantize(
+    input: impl AsRef<std::path::Path>,
+    output: impl AsRef<std::path::Path>,
+    quant_type: options::QuantType,
+    options: options::QuantizeOptions,
+) -> Result<()> {
+    backend::llama::quantize(input.as_ref(), output.as_ref(), quant_type, &options)
+}
+
+/// Estimates the memory loading the GGUF model at `path` with `model_options` and creating a
+/// context with `context_options` would need, next to the free memory of this machine.
+///
+/// Only the header of the file is read, so it is cheap for any model size.
+#[cfg(feature = "llama")]
+pub fn estimate_memory(
+    path: impl AsRef<std::path::Path>,
+    model_options: &options::ModelOptions,
+    context_options: &ContextOptions,
+) -> Result<memory::MemoryEstimate> {
+    memory::estimate(path.as_ref(), model_options, context_options)
+}
+
+/// Queries the devices of this machine again, see [`devices::list`].
+///
+/// Models query them before fitting layers into the free memory of the gpus, see
+/// [`options::ModelOptions::vram_reserve`].
+#[cfg(feature = "llama")]
+pub fn refresh_devices() -> Result<Vec<devices::Device>> {
+    devices::list()
+}
+
+/// Sets how stderr output of llama.cpp is handled during every later load.
+///
+/// Call it next to [`init`], the default is [`options::StdioPolicy::Silence`]. Redirecting
+/// stderr affects the whole process, so the host application's own stderr is swallowed as well
+/// while a load runs.
+#[cfg(feature = "llama")]
+pub fn set_stdio_policy(policy: options::StdioPolicy) {
+    llama_cpp::stdio::set_policy(policy);
+}
+
+/// Sets how stdout output of llava is handled while images are embedded.
+///
+/// The default silences stdout on windows, where llava prints to it, and leaves it alone
+/// everywhere else.
+#[cfg(feature = "llama")]
+pub fn set_stdout_policy(policy: options::StdioPolicy) {
+    llama_cpp::stdio::set_stdout_policy(policy);
+}
+
+/// Returns and clears the output collected under [`options::StdioPolicy::Capture`].
+#[cfg(feature = "llama")]
+pub fn captured_stdio() -> String {
+    llama_cpp::stdio::take_captured()
 }
 
+/// A loaded model.
+///
+/// Cloning is cheap and clones share the weights. A model can be used from any number of
+/// threads, each creating its own [`Context`]s; creating and dropping contexts is serialized
+/// internally, decoding on distinct contexts runs in parallel. The number of live contexts can
+/// be bounded with [`options::ModelOptions::max_contexts`].
 #[cfg(feature = "llama")]
 #[derive(Clone)]
 pub struct Model {
@@ -91,21 +206,131 @@ impl Model {
         })
     }
 
+    /// Loads the model and uses `projector` for its images, the projector can be shared with
+    /// other models built on the same language model.
+    pub fn new_with_projector(
+        model: impl Into<PathBuf> + 'static,
+        projector: &Projector,
+        options: options::ModelOptions,
+    ) -> Result<Self> {
+        let mut backend = backend::init(
+            model,
+            options,
+            None::<Box<dyn FnMut(f32) -> bool + 'static>>,
+        )?;
+        backend.with_projector(projector.clip.clone());
+        Ok(Self {
+            backend: Arc::new(Box::pin(backend)),
+        })
+    }
+
+    /// Tokens `message` takes in the chat template of the model, without its images.
+    pub fn count_tokens(&self, message: &Message) -> Result<usize> {
+        self.backend.count_tokens(message)
+    }
+
+    /// Tokens of `text` as is, without a chat template or a bos token.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<options::Token>> {
+        self.backend.tokenize(text)
+    }
+
+    /// Where the model runs, after [`options::ModelOptions::fallback`] if the configured gpu
+    /// offload failed.
+    pub fn placement(&self) -> health::Placement {
+        self.backend.placement()
+    }
+
+    /// The context length the model was trained with.
+    pub fn n_ctx_train(&self) -> usize {
+        self.backend.n_ctx_train()
+    }
+
+    /// Size of the model's embeddings.
+    pub fn n_embd(&self) -> usize {
+        self.backend.n_embd()
+    }
+
+    /// Tokens in the model's vocabulary.
+    pub fn n_vocab(&self) -> usize {
+        self.backend.n_vocab()
+    }
+
+    /// The type most weights are quantized to, `None` for one [`quantize`] can't produce.
+    pub fn quant_type(&self) -> Result<Option<options::QuantType>> {
+        self.backend.quant_type()
+    }
+
+    /// The architecture of the model, from the file's metadata.
+    pub fn architecture(&self) -> Result<gguf::Architecture> {
+        self.backend.architecture()
+    }
+
+    /// What the model supports, so applications can adapt to it without matching file names.
+    pub fn capabilities(&self) -> Result<gguf::Capabilities> {
+        self.backend.capabilities()
+    }
+
+    /// Decodes a few tokens, so weights are paged in and gpu kernels compiled before the
+    /// first real request.
+    pub fn warmup(&self) -> Result<health::Warmup> {
+        self.backend.warmup()
+    }
+
+    /// Devices, memory headroom and context slots of the model, see [`health::Health::ready`].
+    pub fn health(&self) -> Result<health::Health> {
+        self.backend.health()
+    }
+
+    /// Embeddings of `texts`, in the same order.
+    ///
+    /// Many texts are evaluated per decode call, each on its own sequence, so indexing
+    /// thousands of documents doesn't take a decode per document.
+    pub fn embed_batch(
+        &self,
+        texts: &[&str],
+        options: options::EmbeddingOptions,
+    ) -> Result<Vec<Vec<f32>>> {
+        self.backend.embed_batch(texts, &options)
+    }
+
     pub fn context(&self, options: options::ContextOptions) -> Result<Context> {
-        let ctx = Context {
-            _options: options.clone(),
-            backend: self.backend.new_context(options)?,
-        };
-        Ok(ctx)
+        let backend = self.backend.new_context(options.clone())?;
+        Ok(Context::new(options, backend))
     }
 }
 
+/// An inference session with its own kv cache.
+///
+/// A context can be moved to and shared between threads, calls on it are serialized.
+/// Run one context per thread to decode in parallel, contexts of the same model share its
+/// weights read-only and keep their kv cache and sampler to themselves.
+///
+/// The lock around each context stays even though contexts don't share state: a llama.cpp
+/// context must not be used by two threads at once, forks and background prefills work
+/// on the same one, and a prediction mutates the sampler and kv cache through `&self`.
 #[cfg(feature = "llama")]
 pub struct Context {
     _options: options::ContextOptions,
-    backend: Pin<Box<Mutex<dyn backend::Context>>>,
+    // n_ctx, n_batch and n_ubatch, fixed at creation so reading them doesn't wait for a
+    // running prediction
+    sizes: (usize, usize, usize),
+    // applied in order to text before tokenization and after detokenization
+    input_hooks: Vec<Arc<Box<options::TextHook>>>,
+    output_hooks: Vec<Arc<Box<options::TextHook>>>,
+    // shared with the thread of a running prefill
+    backend: Arc<Pin<Box<Mutex<dyn backend::Context>>>>,
+    // how the last prefill failed, until its handle or the next call on the context returns it
+    prefill_error: Arc<Mutex<Option<error::Error>>>,
 }
 
+// models and contexts are shared between threads, this stops compiling if that breaks
+#[cfg(feature = "llama")]
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<Model>;
+    let _ = assert_send_sync::<Context>;
+};
+
 #[cfg(feature = "llama")]
 #[derive(bon::Builder)]
 pub struct Predict<'a> {
@@ -131,30 +356,545 @@ impl<'a> Predict<'a> {
         self
     }
 
+    /// Predicts with `sampler` picking the tokens, see [`options::CustomSampler`].
+    pub fn with_custom_sampler(mut self, sampler: impl options::CustomSampler + 'static) -> Self {
+        self.options = self.options.with_custom_sampler(sampler);
+        self
+    }
+
+    /// Predicts with the sampling settings in `overrides`, see
+    /// [`options::PredictOptions::with_overrides`].
+    pub fn with_overrides(mut self, overrides: &options::SamplingOptions) -> Self {
+        self.options = self.options.with_overrides(overrides);
+        self
+    }
+
+    /// Sends the id of every generated token with its text to `callback`, see
+    /// [`options::PredictOptions::token_id_callback`]. Works next to the token callback,
+    /// e.g. for exact token accounting or caching by token.
+    pub fn with_token_id_callback(
+        mut self,
+        callback: impl Fn(options::Token, String) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.options.token_id_callback = Some(Arc::new(Box::new(callback)));
+        self
+    }
+
+    /// Predicts with the token callback called as `delivery` says, see [`options::Delivery`].
+    pub fn with_delivery(mut self, delivery: options::Delivery) -> Self {
+        self.options.delivery = delivery;
+        self
+    }
+
     pub fn predict(&mut self) -> Result<String> {
+        let hooks = self.context.output_hooks.clone();
         if let Some(callback) = self.options.token_callback.clone() {
-            self.context
-                .backend
-                .lock()
-                .unwrap()
-                .predict_with_callback(&self.options, callback)?;
+            let callback: Arc<Box<TokenCallback>> = if hooks.is_empty() {
+                callback
+            } else {
+                Arc::new(Box::new(move |text| callback(apply_hooks(&hooks, &text))))
+            };
+            if self.options.delivery == options::Delivery::EveryToken {
+                self.context
+                    .backend()?
+                    .predict_with_callback(&self.options, callback)?;
+                return Ok("".to_string());
+            }
+            let chunks = Arc::new(Mutex::new(Chunks::new(self.options.delivery)));
+            let collect = chunks.clone();
+            let deliver = callback.clone();
+            let chunked: Box<TokenCallback> = Box::new(move |text| {
+                let ready = collect.lock().unwrap().push(text);
+                ready.map_or(true, |text| deliver(text))
+            });
+            let res = self
+                .context
+                .backend()
+                .and_then(|mut b| b.predict_with_callback(&self.options, Arc::new(chunked)));
+            // the rest is delivered even if the prediction failed midway
+            let rest = std::mem::take(&mut chunks.lock().unwrap().text);
+            if !rest.is_empty() {
+                callback(rest);
+            }
+            res?;
             Ok("".to_string())
         } else {
-            self.context.backend.lock().unwrap().predict(&self.options)
+            let text = self.context.backend()?.predict(&self.options)?;
+            Ok(apply_hooks(&hooks, &text))
+        }
+    }
+}
+
+/// `text` passed through `hooks` in order.
+#[cfg(feature = "llama")]
+fn apply_hooks(hooks: &[Arc<Box<options::TextHook>>], text: &str) -> String {
+    hooks
+        .iter()
+        .fold(text.to_string(), |text, hook| hook(&text))
+}
+
+/// Text collected for the token callback until the [`options::Delivery`] lets it through.
+#[cfg(feature = "llama")]
+struct Chunks {
+    delivery: options::Delivery,
+    text: String,
+    n_tokens: usize,
+    last: std::time::Instant,
+}
+
+#[cfg(feature = "llama")]
+impl Chunks {
+    fn new(delivery: options::Delivery) -> Self {
+        Self {
+            delivery,
+            text: String::new(),
+            n_tokens: 0,
+            last: std::time::Instant::now(),
+        }
+    }
+
+    /// Adds the text of a token, returns what is to be delivered now.
+    fn push(&mut self, text: String) -> Option<String> {
+        self.text.push_str(&text);
+        self.n_tokens += 1;
+        let end = match self.delivery {
+            options::Delivery::EveryToken => self.text.len(),
+            options::Delivery::Tokens(n) if self.n_tokens >= n => self.text.len(),
+            options::Delivery::Interval(ms) if self.last.elapsed().as_millis() >= ms as u128 => {
+                self.text.len()
+            }
+            options::Delivery::Words => self
+                .text
+                .char_indices()
+                .rev()
+                .find(|(_, c)| c.is_whitespace())
+                .map_or(0, |(i, c)| i + c.len_utf8()),
+            _ => 0,
+        };
+        if end == 0 {
+            return None;
         }
+        self.n_tokens = 0;
+        self.last = std::time::Instant::now();
+        let rest = self.text.split_off(end);
+        Some(std::mem::replace(&mut self.text, rest))
+    }
+}
+
+/// llama.cpp's interactive mode: the model writes until it produces one of the reverse
+/// prompts, then the caller appends the next input to the same conversation.
+///
+/// ```no_run
+/// # fn run(ctx: &mut nebula::Context) -> nebula::Result<()> {
+/// let options = nebula::options::PredictOptions::builder()
+///     .reverse_prompts(vec!["User:".to_string()])
+///     .build();
+/// let mut chat = ctx.interactive(options);
+/// chat.input("Transcript of a dialog with a helpful assistant.\nUser: Hi!\nAssistant:")?;
+/// let answer = chat.generate()?;
+/// if chat.awaiting_input() {
+///     chat.input(" What is the capital of France?\nAssistant:")?;
+///     let answer = chat.generate()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "llama")]
+pub struct Interactive<'a> {
+    context: &'a mut Context,
+    options: options::PredictOptions,
+    awaiting_input: bool,
+}
+
+#[cfg(feature = "llama")]
+impl<'a> Interactive<'a> {
+    /// Evaluates `text` as is, without a chat template, and keeps it in the kv cache.
+    pub fn input(&mut self, text: &str) -> Result<()> {
+        self.awaiting_input = false;
+        self.context.eval_text(text)
+    }
+
+    /// Generates until a reverse prompt, the end of generation or `max_len`.
+    ///
+    /// The text ends with the reverse prompt if it was produced.
+    pub fn generate(&mut self) -> Result<String> {
+        self.generate_with(&options::SamplingOptions::default())
+    }
+
+    /// [`Interactive::generate`] with the sampling settings in `overrides` for this turn only.
+    pub fn generate_with(&mut self, overrides: &options::SamplingOptions) -> Result<String> {
+        let turn_options = self.options.clone().with_overrides(overrides);
+        let text = match turn_options.token_callback.clone() {
+            // predictions with a callback return no text, collect it on the way
+            Some(callback) => {
+                let collected = Arc::new(Mutex::new(String::new()));
+                let sink = collected.clone();
+                let tee: Box<TokenCallback> = Box::new(move |token| {
+                    sink.lock().unwrap().push_str(&token);
+                    callback(token)
+                });
+                let options = options::PredictOptions {
+                    token_callback: Some(Arc::new(tee)),
+                    ..turn_options
+                };
+                self.context.predict(options).predict()?;
+                let text = collected.lock().unwrap().clone();
+                text
+            }
+            None => self.context.predict(turn_options).predict()?,
+        };
+        let tail = text.trim_end();
+        self.awaiting_input = self
+            .options
+            .reverse_prompts
+            .iter()
+            .any(|p| !p.trim_end().is_empty() && tail.ends_with(p.trim_end()));
+        Ok(text)
+    }
+
+    /// Whether the last [`Interactive::generate`] stopped at a reverse prompt.
+    pub fn awaiting_input(&self) -> bool {
+        self.awaiting_input
+    }
+
+    pub fn context(&mut self) -> &mut Context {
+        self.context
     }
 }
 
 #[cfg(feature = "llama")]
 impl Context {
+    fn new(
+        options: options::ContextOptions,
+        backend: Pin<Box<Mutex<dyn backend::Context>>>,
+    ) -> Self {
+        let sizes = {
+            let backend = backend.lock().unwrap();
+            (backend.n_ctx(), backend.n_batch(), backend.n_ubatch())
+        };
+        Self {
+            _options: options,
+            sizes,
+            input_hooks: vec![],
+            output_hooks: vec![],
+            backend: Arc::new(backend),
+            prefill_error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The backend once a running prefill is done, or the error of a failed prefill that
+    /// wasn't returned by [`Prefill::wait`] yet.
+    fn backend(&self) -> Result<std::sync::MutexGuard<'_, dyn backend::Context + 'static>> {
+        let backend = self.backend.lock().unwrap();
+        match self.prefill_error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(backend),
+        }
+    }
+
+    /// Passes the text of following prompts through `hook` before it is tokenized, after the
+    /// input hooks added before, e.g. to normalize unicode or redact personal data.
+    ///
+    /// Hooks see the content of every message, not the chat template around it, and the
+    /// text of [`Context::eval_text`], [`Context::classify`] and [`Context::choose`].
+    pub fn add_input_hook(&mut self, hook: impl Fn(&str) -> String + Send + Sync + 'static) {
+        self.input_hooks.push(Arc::new(Box::new(hook)));
+    }
+
+    /// Passes generated text through `hook` before it is returned or sent to the token
+    /// callback, after the output hooks added before, e.g. to strip ANSI escapes or filter
+    /// words.
+    ///
+    /// Streamed text reaches the hook in the pieces of [`options::PredictOptions::delivery`],
+    /// deliver [`options::Delivery::Words`] for hooks matching whole words. The token id
+    /// callback gets the text unchanged.
+    pub fn add_output_hook(&mut self, hook: impl Fn(&str) -> String + Send + Sync + 'static) {
+        self.output_hooks.push(Arc::new(Box::new(hook)));
+    }
+
+    /// Removes the input and output hooks.
+    pub fn clear_hooks(&mut self) {
+        self.input_hooks.clear();
+        self.output_hooks.clear();
+    }
+
+    fn hooked_input(&self, text: &str) -> String {
+        apply_hooks(&self.input_hooks, text)
+    }
+
+    fn hooked_messages(&self, msgs: Vec<Message>) -> Vec<Message> {
+        if self.input_hooks.is_empty() {
+            return msgs;
+        }
+        msgs.into_iter()
+            .map(|m| Message {
+                content: self.hooked_input(&m.content),
+                ..m
+            })
+            .collect()
+    }
+
     pub fn eval(&mut self, msgs: Vec<Message>) -> Result<()> {
-        self.backend.lock().unwrap().eval(msgs)?;
+        let msgs = self.hooked_messages(msgs);
+        self.backend()?.eval(msgs)?;
         Ok(())
     }
 
+    /// Evaluates `text` as is, without applying the chat template.
+    pub fn eval_text(&mut self, text: &str) -> Result<()> {
+        let text = self.hooked_input(text);
+        self.backend()?.eval_text(&text)
+    }
+
+    /// Starts evaluating `msgs` on a background thread and returns right away.
+    ///
+    /// The context is busy until the prompt is evaluated, a prediction started meanwhile
+    /// waits for the rest of it, e.g. a chat UI prefills while the user is still typing and
+    /// the answer starts as soon as the last part is in. Failures, like a prompt not fitting
+    /// into the context, are returned by [`Prefill::wait`], or by the next call on the context
+    /// returning a result if that comes first or the [`Prefill`] is dropped.
+    pub fn prefill(&mut self, msgs: Vec<Message>) -> Result<Prefill> {
+        let msgs = self.hooked_messages(msgs);
+        let backend = self.backend.clone();
+        let error = self.prefill_error.clone();
+        let (locked, is_locked) = std::sync::mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("nebula-prefill".to_string())
+            .spawn(move || {
+                let mut backend = backend.lock().unwrap();
+                // the caller returns once the context is taken, calls after it wait for this
+                let _ = locked.send(());
+                // a failed prefill before this one is reported instead of evaluating after it
+                if error.lock().unwrap().is_none() {
+                    let res = backend.eval(msgs);
+                    *error.lock().unwrap() = res.err();
+                }
+            })?;
+        let _ = is_locked.recv();
+        Ok(Prefill {
+            handle,
+            error: self.prefill_error.clone(),
+        })
+    }
+
+    /// Evaluates `template` with its placeholders filled from `vars`, see [`prompt::format`].
+    pub fn eval_template(
+        &mut self,
+        template: &str,
+        vars: &[(&str, &str)],
+        format: prompt::PromptFormat,
+    ) -> Result<()> {
+        self.eval_text(&prompt::format(template, vars, format)?)
+    }
+
+    /// Alternates generating until one of `options.reverse_prompts` and evaluating user input,
+    /// see [`Interactive`].
+    pub fn interactive(&mut self, options: options::PredictOptions) -> Interactive {
+        Interactive {
+            context: self,
+            options,
+            awaiting_input: false,
+        }
+    }
+
     pub fn predict(&mut self, options: options::PredictOptions) -> Predict {
         Predict::new(self, options)
     }
+
+    /// Asks a grounding vision model about the image in `message`, e.g. "Detect every dog",
+    /// and returns the boxes of its answer, see [`grounding::parse`].
+    ///
+    /// The answer is collected whole, a token callback in `options` is not called. Special
+    /// tokens are decoded, Qwen2-VL marks up its boxes with them.
+    pub fn detect(
+        &mut self,
+        message: Message,
+        options: options::PredictOptions,
+    ) -> Result<Vec<grounding::Detection>> {
+        self.eval(vec![message])?;
+        let options = options::PredictOptions {
+            token_callback: None,
+            decode_special_tokens: true,
+            ..options
+        };
+        let answer = self.predict(options).predict()?;
+        Ok(grounding::parse(&answer))
+    }
+
+    /// Zero-shot classification of `text`.
+    ///
+    /// Every label is scored by the probability the model assigns to its tokens as the answer,
+    /// the result holds all labels with their normalized probability, most likely first.
+    /// The context is left as it was before the call.
+    pub fn classify(&mut self, text: &str, labels: &[&str]) -> Result<Vec<(String, f32)>> {
+        let text = self.hooked_input(text);
+        self.backend()?.classify(&text, labels)
+    }
+
+    /// Answers `prompt` with exactly one of `choices` and returns its index and probability.
+    ///
+    /// Generation is restricted to the tokens continuing one of the choices, so the answer
+    /// needs no parsing. The most likely token is taken at each step, the probability is the
+    /// product of the probabilities of the answer's tokens among the allowed ones. A choice
+    /// that is the start of another one ends where the model is likelier to end the answer.
+    /// The prompt and the answer stay in the context, like after a prediction.
+    pub fn choose(&mut self, prompt: Message, choices: &[&str]) -> Result<(usize, f32)> {
+        let prompt = self.hooked_messages(vec![prompt]).remove(0);
+        self.backend()?.choose(prompt, choices)
+    }
+
+    /// Token counts of the last exchange and how full the context is, waits for a running
+    /// call on the context.
+    pub fn usage(&self) -> options::Usage {
+        self.backend.lock().unwrap().usage()
+    }
+
+    /// Positions of the context in use, the tokens and images evaluated so far.
+    pub fn n_past(&self) -> usize {
+        self.backend.lock().unwrap().n_past()
+    }
+
+    /// Size of the context in tokens, forks and negative prompts included.
+    pub fn n_ctx(&self) -> usize {
+        self.sizes.0
+    }
+
+    /// Tokens passed to one decode call, see [`options::ContextOptions::n_batch`].
+    pub fn n_batch(&self) -> usize {
+        self.sizes.1
+    }
+
+    /// Tokens computed at once, see [`options::ContextOptions::n_ubatch`].
+    pub fn n_ubatch(&self) -> usize {
+        self.sizes.2
+    }
+
+    /// Reports the progress of evaluating prompts to `callback`, see
+    /// [`options::PromptProgressCallback`], e.g. for a progress bar during the prefill of a
+    /// long prompt.
+    ///
+    /// The count starts over for every text between images, images are not reported.
+    pub fn set_prompt_progress(&mut self, callback: impl Fn(usize, usize) + Send + Sync + 'static) {
+        self.backend
+            .lock()
+            .unwrap()
+            .set_prompt_progress(Some(Arc::new(Box::new(callback))));
+    }
+
+    pub fn clear_prompt_progress(&mut self) {
+        self.backend.lock().unwrap().set_prompt_progress(None);
+    }
+
+    /// Bytes the state of the context takes in llama.cpp, forks included.
+    ///
+    /// The kv cache of a transformer grows with every token up to `n_ctx`, recurrent models
+    /// like Mamba and RWKV keep a state of a fixed size, see
+    /// [`gguf::Capabilities::is_recurrent`].
+    pub fn state_size(&self) -> usize {
+        self.backend.lock().unwrap().state_size()
+    }
+
+    /// The evaluated conversation, kv cache included, as bytes.
+    ///
+    /// The bytes can be stored anywhere and restored with [`Context::restore_state`] in
+    /// another context of the same model and `n_ctx`, also in another process.
+    pub fn state_bytes(&self) -> Result<Vec<u8>> {
+        self.backend()?.state_bytes()
+    }
+
+    /// Replaces the context's state with one returned by [`Context::state_bytes`].
+    ///
+    /// Fails with [`error::Error::InvalidState`] if the bytes are damaged or come from a
+    /// different model or context size.
+    pub fn restore_state(&mut self, state: &[u8]) -> Result<()> {
+        self.backend()?.restore_state(state)
+    }
+
+    /// A new context continuing from everything evaluated so far, without evaluating it again.
+    ///
+    /// The fork shares the kv cache of the prefix with this context, both continue
+    /// independently afterwards, e.g. to explore several answers to the same conversation.
+    /// At most [`options::ContextOptions::max_forks`] forks can be alive at once, they work on
+    /// the same llama.cpp context and take turns decoding.
+    pub fn fork(&self) -> Result<Context> {
+        let backend = self.backend()?.fork()?;
+        let mut fork = Context::new(self._options.clone(), backend);
+        fork.input_hooks = self.input_hooks.clone();
+        fork.output_hooks = self.output_hooks.clone();
+        Ok(fork)
+    }
+
+    /// Embeds the images of following messages with `projector` instead of the model's.
+    pub fn attach_projector(&mut self, projector: &Projector) {
+        self.backend
+            .lock()
+            .unwrap()
+            .set_projector(Some(projector.clip.clone()));
+    }
+
+    /// Goes back to the projector of the model, if it has one.
+    pub fn detach_projector(&mut self) {
+        self.backend.lock().unwrap().set_projector(None);
+    }
+}
+
+/// A prompt evaluated in the background, see [`Context::prefill`].
+#[cfg(feature = "llama")]
+pub struct Prefill {
+    handle: std::thread::JoinHandle<()>,
+    error: Arc<Mutex<Option<error::Error>>>,
+}
+
+#[cfg(feature = "llama")]
+impl Prefill {
+    /// Whether the prompt is evaluated, [`Prefill::wait`] returns without blocking then.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Blocks until the prompt is evaluated and returns how that went, `Ok` if a call on the
+    /// context returned the error already.
+    pub fn wait(self) -> Result<()> {
+        if let Err(e) = self.handle.join() {
+            std::panic::resume_unwind(e);
+        }
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A vision projector (mmproj) turning images into embeddings for a language model.
+///
+/// It is loaded once and can be shared by several models and contexts, cloning it is cheap.
+/// Files that embed their projector don't need one, [`Model::new`] loads it with the model
+/// from a copy of its tensors in [`paths::projectors_dir`], made on first load.
+#[cfg(feature = "llama")]
+#[derive(Clone)]
+pub struct Projector {
+    clip: llama_cpp::clip::ClipContext,
+}
+
+#[cfg(feature = "llama")]
+impl Projector {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        crate::paths::init_dependencies()?;
+        Ok(Self {
+            clip: llama_cpp::clip::ClipContext::load(path.into())?,
+        })
+    }
+
+    /// Loads the projector with its precision and threads set by `options`.
+    pub fn load_with_options(
+        path: impl Into<PathBuf>,
+        options: options::ProjectorOptions,
+    ) -> Result<Self> {
+        crate::paths::init_dependencies()?;
+        Ok(Self {
+            clip: backend::llama::load_projector(&path.into(), &options)?,
+        })
+    }
 }
 
 #[cfg(feature = "llama")]
@@ -184,6 +924,27 @@ pub struct CompletionRequest {
     #[serde(alias = "model")]
     _model: String,
     messages: Vec<Message>,
+    #[serde(default)]
+    stop: Option<Stop>,
+}
+
+/// `stop` of a completion request, one string or a list.
+#[cfg(feature = "llama-http")]
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[cfg(feature = "llama-http")]
+impl From<Stop> for Vec<String> {
+    fn from(val: Stop) -> Self {
+        match val {
+            Stop::One(s) => vec![s],
+            Stop::Many(v) => v,
+        }
+    }
 }
 
 #[cfg(feature = "llama-http")]
@@ -200,6 +961,7 @@ async fn complitions(
         .await
         .context(state.context_options.clone())?;
     ctx.eval(data.messages)?;
+    let stop: Vec<String> = data.stop.map(Into::into).unwrap_or_default();
     if data.stream{
         let (tx, mut reciever) = tokio::sync::mpsc::channel(100);
         let mut predict_options = PredictOptions::builder()
@@ -207,6 +969,7 @@ async fn complitions(
             .penalty_present(data.presence_penalty)
             .temp(data.temperature)
             .top_p(data.top_p)
+            .stop(stop)
             .token_callback(Arc::new(Box::new(move |token| {
                 tx.blocking_send(token).is_ok()
             })))
@@ -264,11 +1027,14 @@ async fn complitions(
             .penalty_present(data.presence_penalty)
             .temp(data.temperature)
             .top_p(data.top_p)
+            .stop(stop)
             .build();
         if let Some(ss) = data.seed {
             predict_options.seed = ss;
         }
         predict_options.max_len = data.max_completion_tokens;
+        let content = ctx.predict(predict_options).predict()?;
+        let usage = ctx.usage();
         Ok(HttpResponse::Ok().json(serde_json::json!({
             "id": "chatcmpl",
             "object": "chat.completion",
@@ -279,15 +1045,18 @@ async fn complitions(
                 "index": 0,
                 "message": {
                     "role": "assistant",
-                    "content": ctx.predict(predict_options).predict()?
+                    "content": content
                 },
                 "logprobs": null,
-                "finish_reason": "stop"
+                "finish_reason": match usage.finish_reason {
+                    Some(options::FinishReason::Length) => "length",
+                    _ => "stop",
+                }
             }],
             "usage": {
-                "prompt_tokens": 0,
-                "completion_tokens": 0,
-                "total_tokens": 0,
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+                "total_tokens": usage.total_tokens(),
                 "completion_tokens_details": {
                     "reasoning_tokens": 0
                 }
@@ -296,6 +1065,20 @@ async fn complitions(
     }
 }
 
+#[cfg(feature = "prometheus")]
+#[actix_web::get("/metrics")]
+async fn prometheus_metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render_prometheus())
+}
+
+#[cfg(feature = "llama-http")]
+fn metrics_service(_cfg: &mut actix_web::web::ServiceConfig) {
+    #[cfg(feature = "prometheus")]
+    _cfg.service(prometheus_metrics);
+}
+
 #[cfg(feature = "llama-http")]
 struct AppState {
     context_options: ContextOptions,
@@ -339,6 +1122,7 @@ impl Server {
                     model: mm.clone(),
                 }))
                 .service(complitions)
+                .configure(metrics_service)
         })
             .bind((self.host, self.port))?
             .run();
@@ -360,6 +1144,8 @@ impl Server {
 mod test {
     use std::{io::Write, path::PathBuf};
 
+    use super::options::Message;
+
     struct TestModel {
         pub _repo: String,
         pub filename: PathBuf,
@@ -400,15 +1186,44 @@ mod test {
         }
     }
 
-    fn main_with_model(model_repo: &str, model_file_name: &str) {
-        simple_logger::SimpleLogger::new()
-            .with_level(log::LevelFilter::Debug)
-            .init()
+    /// The file of the model most tests run, from the hf-hub cache. It is downloaded again
+    /// if a [`TestModel`] of it removed it.
+    fn test_model_path() -> PathBuf {
+        let api = hf_hub::api::sync::Api::new().unwrap();
+        api.model("TheBloke/evolvedSeeker_1_3-GGUF".to_string())
+            .get("evolvedseeker_1_3.Q2_K.gguf")
+            .unwrap()
+    }
+
+    /// The model most tests run, loaded with the default options.
+    fn test_model() -> super::Model {
+        super::Model::new(test_model_path(), super::options::ModelOptions::default()).unwrap()
+    }
+
+    static INIT: std::sync::Once = std::sync::Once::new();
+    // model tests share downloaded files which `TestModel` removes on drop
+    static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn serial() -> std::sync::MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn init() {
+        INIT.call_once(|| {
+            simple_logger::SimpleLogger::new()
+                .with_level(log::LevelFilter::Debug)
+                .init()
+                .unwrap();
+            super::init(std::path::PathBuf::from(
+                "backends/llama_cpp/llama-cpp-sys/dist",
+            ))
             .unwrap();
-        super::init(std::path::PathBuf::from(
-            "backends/llama_cpp/llama-cpp-sys/dist",
-        ))
-        .unwrap();
+        });
+    }
+
+    fn main_with_model(model_repo: &str, model_file_name: &str) {
+        let _serial = serial();
+        init();
         let test_model = TestModel::new(model_repo, model_file_name);
         eprintln!("{}", test_model.filename.display());
         let model_options = super::options::ModelOptions::default();
@@ -449,6 +1264,2114 @@ mod test {
         }
     }
 
+    #[test]
+    fn deterministic_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        let run = |seed: u32| {
+            let ctx = model.context(
+                super::options::ContextOptions::builder()
+                    .deterministic(true)
+                    .seed(seed)
+                    .build(),
+            );
+            assert!(ctx.is_ok());
+            let mut ctx = ctx.unwrap();
+            assert!(ctx.eval(vec![prompt.try_into().unwrap()]).is_ok());
+            let answer = ctx
+                .predict(
+                    super::options::PredictOptions::builder()
+                        .seed(llama_cpp::sample::DEFAULT_SEED)
+                        .max_len(64)
+                        .build(),
+                )
+                .predict();
+            assert!(answer.is_ok());
+            answer.unwrap()
+        };
+        let first = run(42);
+        assert!(!first.is_empty());
+        for _ in 0..2 {
+            assert_eq!(first, run(42));
+        }
+    }
+
+    #[test]
+    fn shutdown_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx = model.context(super::options::ContextOptions::default());
+        assert!(ctx.is_ok());
+        let ctx = ctx.unwrap();
+        assert!(matches!(
+            super::shutdown(),
+            Err(super::error::Error::ResourcesInUse(_))
+        ));
+        drop(ctx);
+        drop(model);
+        assert!(super::shutdown().is_ok());
+        let model = super::Model::new(test_model_path(), super::options::ModelOptions::default());
+        assert!(model.is_ok());
+        drop(model);
+        assert!(super::shutdown().is_ok());
+    }
+
+    #[test]
+    fn shutdown_timeout_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        assert!(matches!(
+            super::shutdown_timeout(std::time::Duration::from_millis(50)),
+            Err(super::error::Error::ResourcesInUse(_))
+        ));
+        // a request finishing on another thread
+        let worker = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            drop(ctx);
+            drop(model);
+        });
+        assert!(super::shutdown_timeout(std::time::Duration::from_secs(30)).is_ok());
+        assert_eq!(super::devices::loaded_variant(), None);
+        worker.join().unwrap();
+        let model = super::Model::new(test_model_path(), super::options::ModelOptions::default());
+        assert!(model.is_ok());
+        drop(model);
+        assert!(super::shutdown().is_ok());
+    }
+
+    #[test]
+    fn stdio_capture_test() {
+        let _serial = serial();
+        init();
+        super::set_stdio_policy(super::options::StdioPolicy::Capture);
+        let _ = super::captured_stdio();
+        let model = super::Model::new(test_model_path(), super::options::ModelOptions::default());
+        super::set_stdio_policy(super::options::StdioPolicy::default());
+        assert!(model.is_ok());
+        assert!(super::captured_stdio().contains("llama_model_loader"));
+        assert!(super::captured_stdio().is_empty());
+    }
+
+    #[test]
+    fn parallel_contexts_test() {
+        let _serial = serial();
+        init();
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::builder()
+                .max_contexts(4)
+                .build(),
+        );
+        assert!(model.is_ok());
+        let model = model.unwrap();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        let handles = (0..4)
+            .map(|_| {
+                let model = model.clone();
+                std::thread::spawn(move || {
+                    let mut ctx = model.context(super::options::ContextOptions::default())?;
+                    ctx.eval(vec![prompt.try_into().unwrap()])?;
+                    ctx.predict(
+                        super::options::PredictOptions::builder()
+                            .max_len(32)
+                            .build(),
+                    )
+                    .predict()
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            assert!(h.join().unwrap().is_ok());
+        }
+        let contexts = (0..4)
+            .map(|_| model.context(super::options::ContextOptions::default()))
+            .collect::<super::Result<Vec<_>>>();
+        assert!(contexts.is_ok());
+        assert!(matches!(
+            model.context(super::options::ContextOptions::default()),
+            Err(super::error::Error::TooManyContexts(4))
+        ));
+        drop(contexts);
+        assert!(model
+            .context(super::options::ContextOptions::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn rope_scaling_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx = model.context(
+            super::options::ContextOptions::builder()
+                .n_ctx(8192)
+                .rope_scaling_type(super::options::RopeScalingType::Yarn)
+                .rope_freq_scale(0.5)
+                .yarn_orig_ctx(4096)
+                .build(),
+        );
+        assert!(ctx.is_ok());
+        let mut ctx = ctx.unwrap();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        assert!(ctx.eval(vec![prompt.try_into().unwrap()]).is_ok());
+        let answer = ctx
+            .predict(
+                super::options::PredictOptions::builder()
+                    .max_len(16)
+                    .build(),
+            )
+            .predict();
+        assert!(answer.is_ok());
+    }
+
+    #[test]
+    fn usage_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx = model.context(super::options::ContextOptions::default());
+        assert!(ctx.is_ok());
+        let mut ctx = ctx.unwrap();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        let predict = |ctx: &mut super::Context| {
+            assert!(ctx.eval(vec![prompt.try_into().unwrap()]).is_ok());
+            let answer = ctx
+                .predict(super::options::PredictOptions::builder().max_len(8).build())
+                .predict();
+            assert!(answer.is_ok());
+            ctx.usage()
+        };
+        let before = super::metrics::snapshot();
+        let first = predict(&mut ctx);
+        assert!(first.prompt_tokens > 0);
+        assert!(first.completion_tokens > 0 && first.completion_tokens <= 8);
+        assert_eq!(first.context_used, first.total_tokens());
+        assert_eq!(first.context_size, 2048);
+        let second = predict(&mut ctx);
+        assert_eq!(
+            second.context_used,
+            first.context_used + second.total_tokens()
+        );
+        assert!(second.context_fraction() > first.context_fraction());
+        let after = super::metrics::snapshot();
+        assert_eq!(
+            after.prompt_tokens - before.prompt_tokens,
+            (first.prompt_tokens + second.prompt_tokens) as u64
+        );
+        assert_eq!(
+            after.generated_tokens - before.generated_tokens,
+            (first.completion_tokens + second.completion_tokens) as u64
+        );
+        assert!(after.kv_cache_tokens >= second.context_used as u64);
+        drop(ctx);
+        assert!(super::metrics::snapshot().kv_cache_tokens < after.kv_cache_tokens);
+    }
+
+    #[test]
+    fn load_progress_test() {
+        let _serial = serial();
+        init();
+        let progress = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let p = progress.clone();
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::default().with_load_progress(move |x| {
+                p.lock().unwrap().push(x);
+                true
+            }),
+        );
+        assert!(model.is_ok());
+        let progress = progress.lock().unwrap();
+        assert!(!progress.is_empty());
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(progress.last(), Some(&1.0));
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::default().with_load_progress(|x| x < 0.5),
+        );
+        assert!(matches!(
+            model,
+            Err(super::error::Error::ModelLoadCancelled)
+        ));
+    }
+
+    #[test]
+    fn no_mmap_test() {
+        let _serial = serial();
+        init();
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::builder()
+                .use_mmap(false)
+                .build(),
+        );
+        assert!(model.is_ok());
+        let ctx = model
+            .unwrap()
+            .context(super::options::ContextOptions::default());
+        assert!(ctx.is_ok());
+    }
+
+    #[test]
+    fn native_logs_test() {
+        let _serial = serial();
+        init();
+        assert!(super::logging::route_native_logs(log::LevelFilter::Warn).is_ok());
+        assert_eq!(
+            super::logging::native_log_level(),
+            Some(log::LevelFilter::Warn)
+        );
+        let model = super::Model::new(test_model_path(), super::options::ModelOptions::default());
+        assert!(model.is_ok());
+        drop(model);
+        assert!(super::logging::native_logs_to_stderr().is_ok());
+        assert_eq!(super::logging::native_log_level(), None);
+    }
+
+    #[test]
+    fn long_prompt_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let prompt = super::options::Message {
+            role: super::options::Role::User,
+            content: "Repeat after me: ".to_string() + &"apple banana cherry ".repeat(3500),
+            images: vec![],
+        };
+        let ctx = model.context(
+            super::options::ContextOptions::builder()
+                .n_ctx(12288)
+                .n_batch(256)
+                .build(),
+        );
+        assert!(ctx.is_ok());
+        let mut ctx = ctx.unwrap();
+        assert!(ctx.eval(vec![prompt.clone()]).is_ok());
+        let usage = ctx.usage();
+        assert!(usage.prompt_tokens > 10000);
+        assert_eq!(usage.context_used, usage.prompt_tokens);
+        let answer = ctx
+            .predict(super::options::PredictOptions::builder().max_len(8).build())
+            .predict();
+        assert!(answer.is_ok());
+        let ctx = model.context(
+            super::options::ContextOptions::builder()
+                .n_ctx(2048)
+                .n_batch(256)
+                .build(),
+        );
+        assert!(ctx.is_ok());
+        let mut ctx = ctx.unwrap();
+        assert!(matches!(
+            ctx.eval(vec![prompt]),
+            Err(super::error::Error::KVCacheNotBigEnough(_, 2048))
+        ));
+    }
+
+    #[test]
+    fn gguf_inspect_test() {
+        let _serial = serial();
+        init();
+        let gguf = super::gguf::read(test_model_path());
+        assert!(gguf.is_ok());
+        let gguf = gguf.unwrap();
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert!(gguf.tensor_count > 0);
+        assert!(gguf
+            .arch_value("context_length")
+            .and_then(super::gguf::Value::as_u64)
+            .is_some());
+        let devices = super::devices::list();
+        assert!(devices.is_ok());
+        assert!(!devices.unwrap().is_empty());
+    }
+
+    #[test]
+    fn estimate_memory_test() {
+        let _serial = serial();
+        init();
+        let (gguf, tensors) = super::gguf::read_tensors(test_model_path()).unwrap();
+        assert_eq!(tensors.len() as u64, gguf.tensor_count);
+        let file_size = std::fs::metadata(test_model_path()).unwrap().len();
+        let weights: u64 = tensors.iter().map(|t| t.size).sum();
+        assert!(weights < file_size && weights > file_size / 10 * 9);
+
+        let cpu = super::options::ModelOptions::builder().cpu(true).build();
+        let context = |n_ctx| {
+            super::options::ContextOptions::builder()
+                .n_ctx(n_ctx)
+                .build()
+        };
+        let small = super::estimate_memory(test_model_path(), &cpu, &context(1024)).unwrap();
+        assert_eq!(small.weights(), weights);
+        assert_eq!(small.vram(), 0);
+        assert_eq!(small.n_gpu_layers, 0);
+        let n_layer = gguf
+            .arch_value("block_count")
+            .and_then(super::gguf::Value::as_u64)
+            .unwrap();
+        let n_embd = gguf
+            .arch_value("embedding_length")
+            .and_then(super::gguf::Value::as_u64)
+            .unwrap();
+        // no grouped query attention, f16 keys and values
+        assert_eq!(small.kv_cache(), 1024 * n_layer * n_embd * 2 * 2);
+        let large = super::estimate_memory(test_model_path(), &cpu, &context(2048)).unwrap();
+        assert_eq!(large.kv_cache(), 2 * small.kv_cache());
+        assert!(large.compute() > small.compute());
+        assert!(large.total() > small.total());
+    }
+
+    #[test]
+    fn estimate_memory_corrupt_test() {
+        // a header claiming more layers than it has tensors
+        let path = std::env::temp_dir().join("nebula_estimate_memory_corrupt_test.gguf");
+        let mut file = super::gguf::MAGIC.to_vec();
+        file.extend(3u32.to_le_bytes());
+        file.extend(0u64.to_le_bytes());
+        file.extend(2u64.to_le_bytes());
+        let arch = super::gguf::Value::String("llama".to_string());
+        super::gguf::write_kv(&mut file, "general.architecture", &arch).unwrap();
+        let n_layer = super::gguf::Value::U64(u64::MAX);
+        super::gguf::write_kv(&mut file, "llama.block_count", &n_layer).unwrap();
+        std::fs::write(&path, file).unwrap();
+        let res = super::estimate_memory(
+            &path,
+            &super::options::ModelOptions::default(),
+            &super::options::ContextOptions::default(),
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap_err().code(), "model_load.invalid_gguf");
+    }
+
+    #[test]
+    fn vram_reserve_test() {
+        let _serial = serial();
+        init();
+        let devices = super::refresh_devices().unwrap();
+        assert_eq!(devices, super::devices::list_cached().unwrap());
+        // nothing fits when the whole gpu is reserved
+        let options = super::options::ModelOptions::builder()
+            .vram_reserve(u64::MAX)
+            .build();
+        let estimate = super::estimate_memory(
+            test_model_path(),
+            &options,
+            &super::options::ContextOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(estimate.n_gpu_layers, 0);
+        assert_eq!(estimate.vram(), 0);
+        let model = super::Model::new(test_model_path(), options).unwrap();
+        if devices.iter().any(|d| d.library != "cpu") {
+            assert_eq!(model.placement().n_gpu_layers, 0);
+        }
+    }
+
+    #[test]
+    fn bench_test() {
+        let _serial = serial();
+        init();
+        let options = super::options::BenchOptions::builder()
+            .n_prompt(64)
+            .n_gen(8)
+            .n_batch(vec![16, 64])
+            .n_gpu_layers(vec![0])
+            .build();
+        let report = super::bench::run(test_model_path(), &options);
+        assert!(report.is_ok());
+        let report = report.unwrap();
+        assert_eq!(report.results.len(), 2);
+        for r in &report.results {
+            assert!(r.prompt_tokens >= 64);
+            assert!(r.generated_tokens > 0 && r.generated_tokens <= 8);
+            assert!(r.prompt_tokens_per_second() > 0.0);
+        }
+        assert!(report.fastest_variant().is_some());
+        assert!(report.to_json().is_ok());
+    }
+
+    #[test]
+    fn auto_tune_test() {
+        let _serial = serial();
+        init();
+        let cpu = super::devices::cpu_topology();
+        assert!(cpu.performance >= 1);
+        assert!(cpu.performance <= cpu.physical);
+        assert!(cpu.physical <= cpu.logical);
+        let model = test_model();
+        let ctx = model.context(
+            super::options::ContextOptions::builder()
+                .auto_tune(true)
+                .build(),
+        );
+        assert!(ctx.is_ok());
+        let mut ctx = ctx.unwrap();
+        let msg = super::options::Message {
+            role: super::options::Role::User,
+            content: "Write a hello world program in C.".to_string(),
+            images: vec![],
+        };
+        assert!(ctx.eval(vec![msg]).is_ok());
+        let answer = ctx
+            .predict(
+                super::options::PredictOptions::builder()
+                    .max_len(16)
+                    .build(),
+            )
+            .predict();
+        assert!(answer.is_ok());
+        assert!(!answer.unwrap().is_empty());
+    }
+
+    #[test]
+    fn quantize_test() {
+        let _serial = serial();
+        init();
+        let output = std::env::temp_dir().join("nebula_quantize_test.gguf");
+        let progress = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let p = progress.clone();
+        let res = super::quantize(
+            test_model_path(),
+            &output,
+            super::options::QuantType::Q4_0,
+            super::options::QuantizeOptions::builder()
+                .allow_requantize(true)
+                .build()
+                .with_progress(move |v| p.lock().unwrap().push(v)),
+        );
+        assert!(res.is_ok());
+        let progress = progress.lock().unwrap();
+        assert!(progress.len() > 1);
+        assert_eq!(progress.last(), Some(&1.0));
+        let gguf = super::gguf::read(&output);
+        assert!(gguf.is_ok());
+        assert_eq!(
+            gguf.unwrap()
+                .get("general.file_type")
+                .and_then(super::gguf::Value::as_u64),
+            Some(2)
+        );
+        let model = super::Model::new(output.clone(), super::options::ModelOptions::default());
+        assert!(model.is_ok());
+        drop(model);
+        let _ = std::fs::remove_file(&output);
+        assert!(matches!(
+            super::quantize(
+                test_model_path(),
+                test_model_path(),
+                super::options::QuantType::Q4_0,
+                super::options::QuantizeOptions::default(),
+            ),
+            Err(super::error::Error::LlamaQuantize(_))
+        ));
+    }
+
+    #[test]
+    fn state_bytes_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx_options = super::options::ContextOptions::builder()
+            .deterministic(true)
+            .seed(42)
+            .build();
+        let predict_options = super::options::PredictOptions::builder()
+            .seed(llama_cpp::sample::DEFAULT_SEED)
+            .max_len(32)
+            .build();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        let mut ctx = model.context(ctx_options.clone()).unwrap();
+        assert!(ctx.eval(vec![prompt.try_into().unwrap()]).is_ok());
+        let state = ctx.state_bytes();
+        assert!(state.is_ok());
+        let state = state.unwrap();
+        let first = ctx.predict(predict_options.clone()).predict();
+        assert!(first.is_ok());
+
+        let mut restored = model.context(ctx_options).unwrap();
+        assert!(restored.restore_state(&state).is_ok());
+        assert_eq!(restored.usage().context_used, ctx.usage().prompt_tokens);
+        let second = restored.predict(predict_options).predict();
+        assert!(second.is_ok());
+        assert_eq!(first.unwrap(), second.unwrap());
+
+        assert!(matches!(
+            restored.restore_state(&state[..state.len() - 1]),
+            Err(super::error::Error::InvalidState(_))
+        ));
+        let mut corrupted = state.clone();
+        let last = corrupted.len() - 8;
+        corrupted[last] ^= 1;
+        assert!(matches!(
+            restored.restore_state(&corrupted),
+            Err(super::error::Error::InvalidState(_))
+        ));
+        let mut other = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .n_ctx(1024)
+                    .build(),
+            )
+            .unwrap();
+        assert!(matches!(
+            other.restore_state(&state),
+            Err(super::error::Error::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn fork_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx_options = super::options::ContextOptions::builder()
+            .deterministic(true)
+            .seed(42)
+            .max_forks(1)
+            .build();
+        let predict_options = super::options::PredictOptions::builder()
+            .seed(llama_cpp::sample::DEFAULT_SEED)
+            .max_len(32)
+            .build();
+        let prompt = r###"{"role": "user", "content": "Write simple Rust programm."}"###;
+        let mut ctx = model.context(ctx_options).unwrap();
+        assert!(ctx.eval(vec![prompt.try_into().unwrap()]).is_ok());
+        let fork = ctx.fork();
+        assert!(fork.is_ok());
+        let mut fork = fork.unwrap();
+        assert_eq!(fork.usage().context_used, ctx.usage().context_used);
+        assert!(matches!(
+            ctx.fork(),
+            Err(super::error::Error::LLamaCpp(
+                llama_cpp::LLamaCppError::NoFreeSequence(_)
+            ))
+        ));
+
+        let first = ctx.predict(predict_options.clone()).predict();
+        assert!(first.is_ok());
+        let second = fork.predict(predict_options.clone()).predict();
+        assert!(second.is_ok());
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert!(fork.usage().prompt_tokens == 0);
+
+        drop(fork);
+        assert!(ctx.fork().is_ok());
+    }
+
+    #[test]
+    fn fork_kv_cache_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        // text of at least `n` tokens
+        let filler = |n: usize| {
+            let mut text = String::new();
+            while model.tokenize(&text).unwrap().len() < n {
+                text.push_str(" x");
+            }
+            text
+        };
+        let kv_cache_tokens = || super::metrics::snapshot().kv_cache_tokens;
+        let before = kv_cache_tokens();
+        let ctx_options = super::options::ContextOptions::builder()
+            .n_ctx(128)
+            .max_forks(1)
+            .build();
+        let mut ctx = model.context(ctx_options).unwrap();
+        ctx.eval_text("fn main() {").unwrap();
+        let prefix = ctx.n_past();
+        let mut fork = ctx.fork().unwrap();
+        // the cells of the prefix are shared and counted once
+        assert_eq!(kv_cache_tokens() - before, prefix as u64);
+        fork.eval_text(&filler(90)).unwrap();
+        assert_eq!(kv_cache_tokens() - before, fork.n_past() as u64);
+
+        // the text fits next to the context's own tokens, not next to the fork's as well
+        let text = filler(40);
+        assert!(matches!(
+            ctx.eval_text(&text),
+            Err(super::error::Error::KVCacheNotBigEnough(_, 128))
+        ));
+        drop(fork);
+        assert_eq!(kv_cache_tokens() - before, prefix as u64);
+        ctx.eval_text(&text).unwrap();
+        drop(ctx);
+        assert_eq!(kv_cache_tokens(), before);
+    }
+
+    #[test]
+    fn utf8_stream_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let ctx_options = super::options::ContextOptions::builder()
+            .deterministic(true)
+            .seed(42)
+            .build();
+        for content in [
+            "Translate to Chinese and Japanese: good morning, how are you?",
+            "Reply only with emoji: 🦀🔥👍🏽 what do you think of Rust?",
+        ] {
+            let prompt = super::options::Message {
+                role: super::options::Role::User,
+                content: content.to_string(),
+                images: vec![],
+            };
+            let chunks = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+            let cchunks = chunks.clone();
+            let mut ctx = model.context(ctx_options.clone()).unwrap();
+            assert!(ctx.eval(vec![prompt.clone()]).is_ok());
+            assert!(ctx
+                .predict(
+                    super::options::PredictOptions::builder()
+                        .seed(llama_cpp::sample::DEFAULT_SEED)
+                        .max_len(48)
+                        .token_callback(std::sync::Arc::new(Box::new(move |token| {
+                            cchunks.lock().unwrap().push(token);
+                            true
+                        })))
+                        .build(),
+                )
+                .predict()
+                .is_ok());
+
+            let mut ctx = model.context(ctx_options.clone()).unwrap();
+            assert!(ctx.eval(vec![prompt]).is_ok());
+            let answer = ctx
+                .predict(
+                    super::options::PredictOptions::builder()
+                        .seed(llama_cpp::sample::DEFAULT_SEED)
+                        .max_len(48)
+                        .build(),
+                )
+                .predict();
+            assert!(answer.is_ok());
+            let chunks = chunks.lock().unwrap();
+            assert!(chunks.iter().all(|c| !c.contains('\u{fffd}')));
+            assert_eq!(chunks.concat(), answer.unwrap());
+        }
+    }
+
+    #[test]
+    fn interactive_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .deterministic(true)
+                    .seed(42)
+                    .build(),
+            )
+            .unwrap();
+        let options = super::options::PredictOptions::builder()
+            .seed(llama_cpp::sample::DEFAULT_SEED)
+            .max_len(64)
+            .reverse_prompts(vec!["User:".to_string()])
+            .build();
+        let mut chat = ctx.interactive(options);
+        assert!(chat
+            .input("A dialog between a user and a helpful assistant.\nUser: Hi!\nAssistant:")
+            .is_ok());
+        let answer = chat.generate();
+        assert!(answer.is_ok());
+        let answer = answer.unwrap();
+        assert!(answer.matches("User:").count() <= 1);
+        assert_eq!(chat.awaiting_input(), answer.trim_end().ends_with("User:"));
+
+        let used = chat.context().usage().context_used;
+        assert!(chat
+            .input(" Name a programming language.\nAssistant:")
+            .is_ok());
+        assert!(!chat.awaiting_input());
+        assert!(chat.context().usage().context_used > used);
+        assert!(chat.generate().is_ok());
+    }
+
+    #[test]
+    fn embed_batch_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let texts = [
+            "fn main() { println!(\"hello\"); }",
+            "The quick brown fox jumps over the lazy dog.",
+            "def main(): print('hello')",
+            "A completely different sentence about the weather.",
+            "fn main() { println!(\"hello\"); }",
+        ];
+        // small batches, so the texts are spread over several decode calls
+        let embeddings = model.embed_batch(
+            &texts,
+            super::options::EmbeddingOptions::builder()
+                .n_batch(32)
+                .build(),
+        );
+        assert!(embeddings.is_ok());
+        let embeddings = embeddings.unwrap();
+        assert_eq!(embeddings.len(), texts.len());
+        assert!(embeddings.iter().all(|e| e.len() == embeddings[0].len()));
+        for e in &embeddings {
+            let norm = e.iter().map(|v| v * v).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-3);
+        }
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        assert!((dot(&embeddings[0], &embeddings[4]) - 1.0).abs() < 1e-3);
+
+        for pooling in [super::options::Pooling::Cls, super::options::Pooling::Last] {
+            let embeddings = model.embed_batch(
+                &texts[..2],
+                super::options::EmbeddingOptions::builder()
+                    .pooling(pooling)
+                    .normalize(false)
+                    .build(),
+            );
+            assert!(embeddings.is_ok());
+            assert_eq!(embeddings.unwrap().len(), 2);
+        }
+        assert!(model
+            .embed_batch(&[], super::options::EmbeddingOptions::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn health_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let health = model.health();
+        assert!(health.is_ok());
+        let health = health.unwrap();
+        assert!(!health.warmed_up);
+        assert!(!health.ready());
+        assert!(!health.devices.is_empty());
+        assert!(health.total_memory >= health.free_memory);
+
+        let warmup = model.warmup();
+        assert!(warmup.is_ok());
+        assert!(warmup.unwrap().tokens > 0);
+        let _ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        let health = model.health().unwrap();
+        assert!(health.warmed_up);
+        assert!(health.ready());
+        assert_eq!(health.contexts, 1);
+        assert!(health.to_json().is_ok());
+    }
+
+    #[test]
+    fn architecture_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        assert_eq!(
+            model.architecture().unwrap(),
+            super::gguf::Architecture::Llama
+        );
+        let capabilities = model.capabilities().unwrap();
+        assert!(!capabilities.supports_vision);
+        assert!(!capabilities.is_embedding_model);
+        assert!(!capabilities.is_recurrent);
+        let ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        assert!(ctx.state_size() > 0);
+
+        use super::gguf::Value;
+        let header = |metadata: Vec<(&str, Value)>| super::gguf::Gguf {
+            version: 3,
+            tensor_count: 0,
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        };
+        let bert = header(vec![(
+            "general.architecture",
+            Value::String("bert".to_string()),
+        )]);
+        assert!(bert.capabilities().is_embedding_model);
+        let mamba = header(vec![(
+            "general.architecture",
+            Value::String("mamba".to_string()),
+        )]);
+        assert!(mamba.capabilities().is_recurrent);
+        assert!(!mamba.capabilities().is_embedding_model);
+        let gemma = header(vec![
+            ("general.architecture", Value::String("gemma".to_string())),
+            (
+                "tokenizer.chat_template",
+                Value::String("{{ raise_exception('System role not supported') }}".to_string()),
+            ),
+        ]);
+        assert_eq!(
+            gemma.typed_architecture(),
+            Some(super::gguf::Architecture::Gemma)
+        );
+        assert!(!gemma.capabilities().supports_system_prompt);
+        let coder = header(vec![
+            ("general.architecture", Value::String("qwen2".to_string())),
+            (
+                "tokenizer.ggml.tokens",
+                Value::Array(vec![Value::String("<|fim_prefix|>".to_string())]),
+            ),
+        ]);
+        assert!(coder.capabilities().fim_tokens_present);
+        assert!(coder.capabilities().supports_system_prompt);
+    }
+
+    #[test]
+    fn escape_special_tokens_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let text = "hello <\u{ff5c}end\u{2581}of\u{2581}sentence\u{ff5c}> world";
+        let prompt_tokens = |escape_special_tokens: bool| {
+            let mut ctx = model
+                .context(
+                    super::options::ContextOptions::builder()
+                        .escape_special_tokens(escape_special_tokens)
+                        .build(),
+                )
+                .unwrap();
+            ctx.eval_text(text).unwrap();
+            ctx.usage().prompt_tokens
+        };
+        // the eos token is a single token only when special tokens are parsed
+        assert!(prompt_tokens(true) > prompt_tokens(false));
+    }
+
+    #[test]
+    fn projector_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.detach_projector();
+        let res = ctx.eval(vec![super::options::Message {
+            role: super::options::Role::User,
+            content: "What is in the image?".to_string(),
+            images: vec![super::options::Image(vec![0; 16])],
+        }]);
+        assert!(matches!(res, Err(super::error::Error::MmprojNotDefined)));
+
+        let header = super::gguf::Gguf {
+            version: 3,
+            tensor_count: 0,
+            metadata: vec![
+                (
+                    "general.architecture".to_string(),
+                    super::gguf::Value::String("llama".to_string()),
+                ),
+                (
+                    "clip.has_vision_encoder".to_string(),
+                    super::gguf::Value::Bool(true),
+                ),
+            ],
+        };
+        assert!(header.capabilities().supports_vision);
+
+        // an embedded projector is copied out of the model file on its own
+        let subset = std::env::temp_dir().join("nebula-subset-test.gguf");
+        super::gguf::write_subset(
+            &test_model_path(),
+            &subset,
+            |key| key.starts_with("general."),
+            |tensor| tensor == "token_embd.weight",
+        )
+        .unwrap();
+        let (source, source_tensors) = super::gguf::read_tensors(test_model_path()).unwrap();
+        let (copy, tensors) = super::gguf::read_tensors(&subset).unwrap();
+        std::fs::remove_file(&subset).unwrap();
+        assert_eq!(copy.architecture(), source.architecture());
+        assert!(copy.metadata.iter().all(|(k, _)| k.starts_with("general.")));
+        assert_eq!(tensors.len(), 1);
+        let embd = source_tensors
+            .iter()
+            .find(|t| t.name == "token_embd.weight")
+            .unwrap();
+        assert_eq!(
+            (&tensors[0].dims, tensors[0].ggml_type),
+            (&embd.dims, embd.ggml_type)
+        );
+        assert!(tensors[0].size >= embd.size);
+    }
+
+    #[test]
+    fn projector_options_test() {
+        let _serial = serial();
+        init();
+        let options: super::options::ModelOptions = serde_json::from_str(
+            r#"{"projector": {"n_threads": 2, "precision": "F16", "require_cpu": true}}"#,
+        )
+        .unwrap();
+        assert_eq!(options.projector.n_threads, Some(2));
+        assert_eq!(
+            options.projector.precision,
+            super::options::ProjectorPrecision::F16
+        );
+        let defaults = super::options::ProjectorOptions::default();
+        assert_eq!(defaults.precision, super::options::ProjectorPrecision::File);
+        assert!(!defaults.require_cpu);
+
+        let cpu = super::options::ProjectorOptions::builder()
+            .require_cpu(true)
+            .build();
+        if !llama_cpp::gpu_offload_supported() {
+            return;
+        }
+        // a gpu build can't move the encoder to the cpu
+        let res = super::Projector::load_with_options(test_model_path(), cpu);
+        assert!(matches!(res, Err(super::error::Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn projector_precision_test() {
+        use super::options::{ProjectorOptions, ProjectorPrecision};
+        use llama_cpp::clip::ClipType;
+
+        let _serial = serial();
+        init();
+        let mmproj = TestModel::new("mys/ggml_llava-v1.5-7b", "mmproj-model-f16.gguf");
+        // llava converts the matrices, norms, biases and convolutions keep their type
+        let matrix_types = |tensors: &[super::gguf::TensorInfo]| {
+            tensors
+                .iter()
+                .filter(|t| t.dims.len() == 2 && t.name.contains("weight"))
+                .map(|t| t.ggml_type)
+                .collect::<Vec<_>>()
+        };
+        let (_, source) = super::gguf::read_tensors(&mmproj.filename).unwrap();
+        assert!(matrix_types(&source).contains(&(ClipType::F16 as u32)));
+        for (precision, typ) in [
+            (ProjectorPrecision::F32, ClipType::F32),
+            (ProjectorPrecision::F16, ClipType::F16),
+        ] {
+            let options = ProjectorOptions::builder().precision(precision).build();
+            super::Projector::load_with_options(mmproj.filename.clone(), options).unwrap();
+            // the copy made by the load is reused
+            let converted =
+                super::backend::llama::convert_projector(&mmproj.filename, typ).unwrap();
+            let (_, tensors) = super::gguf::read_tensors(&converted).unwrap();
+            std::fs::remove_file(&converted).unwrap();
+            assert_eq!(tensors.len(), source.len());
+            let types = matrix_types(&tensors);
+            assert!(!types.is_empty());
+            assert!(types.iter().all(|t| *t == typ as u32), "{precision:?}");
+        }
+    }
+
+    #[test]
+    fn placement_test() {
+        let _serial = serial();
+        init();
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::builder()
+                .fallback(super::options::LoadFallback::FewerLayers)
+                .build(),
+        )
+        .unwrap();
+        let placement = model.placement();
+        assert!(placement.variant.is_some());
+        assert!(!placement.fell_back);
+        assert_eq!(placement.n_gpu_layers, -1);
+
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::builder()
+                .cpu(true)
+                .fallback(super::options::LoadFallback::Cpu)
+                .build(),
+        )
+        .unwrap();
+        assert!(!model.placement().on_gpu());
+        assert!(!model.health().unwrap().offloaded);
+    }
+
+    #[test]
+    fn chat_history_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = |role, content: &str| Message {
+            content: content.to_string(),
+            role,
+            images: vec![],
+        };
+        let mut history = super::history::ChatHistory::new(
+            &model,
+            super::options::ContextOptions::builder().n_ctx(512).build(),
+            super::options::ChatHistoryOptions::builder()
+                .reserve(128)
+                .build(),
+        )
+        .unwrap();
+        history
+            .push(message(
+                super::options::Role::System,
+                "You are a helpful assistant.",
+            ))
+            .unwrap();
+        let predict_options = || {
+            super::options::PredictOptions::builder()
+                .max_len(16)
+                .temp(0.0)
+                .build()
+        };
+        let question = "Write a function adding two numbers in Rust. ".repeat(8);
+        for _ in 0..6 {
+            history
+                .push(message(super::options::Role::User, &question))
+                .unwrap();
+            history.predict(predict_options()).unwrap();
+            assert!(history.tokens() <= 512 - 128);
+        }
+        let first = history.messages().next().unwrap();
+        assert_eq!(first.role, super::options::Role::System);
+        assert!(history.messages().count() < 13);
+
+        let too_long = "word ".repeat(600);
+        assert!(history
+            .push(message(super::options::Role::User, &too_long))
+            .is_err());
+    }
+
+    #[test]
+    fn rag_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut store =
+            super::rag::MemoryStore::new(&model, super::options::EmbeddingOptions::default());
+        let text =
+            "The lighthouse keeper is called Ada. She lives on the island of Skerry. ".repeat(8);
+        let options = super::options::ChunkOptions::builder()
+            .tokens(32)
+            .overlap(8)
+            .build();
+        let chunks = store.add_document("keeper", &text, &options).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[1].id, "keeper#1");
+        for chunk in &chunks {
+            assert!(model.tokenize(&chunk.text).unwrap().len() <= 32 + 4);
+        }
+        store
+            .add_document("rust", "Rust is a systems programming language.", &options)
+            .unwrap();
+        assert_eq!(store.len(), chunks.len() + 1);
+        let wrong_size = store.insert(chunks[0].clone(), vec![1.0; 3]);
+        assert!(matches!(
+            wrong_size,
+            Err(super::error::Error::EmbeddingSize(_, 3))
+        ));
+
+        let mut history = super::history::ChatHistory::new(
+            &model,
+            super::options::ContextOptions::builder()
+                .n_ctx(1024)
+                .build(),
+            super::options::ChatHistoryOptions::default(),
+        )
+        .unwrap();
+        let retrieval = super::options::RetrievalOptions::builder().top_k(2).build();
+        let answer = history
+            .ask_with_context(
+                "Who keeps the lighthouse?",
+                &store,
+                &retrieval,
+                super::options::PredictOptions::builder()
+                    .max_len(16)
+                    .temp(0.0)
+                    .build(),
+            )
+            .unwrap();
+        assert_eq!(answer.sources.len(), 2);
+        assert!(answer.sources[0].score >= answer.sources[1].score);
+        assert!(answer
+            .citations
+            .iter()
+            .all(|id| answer.sources.iter().any(|s| &s.chunk.id == id)));
+        assert!(history
+            .messages()
+            .any(|m| m.content.contains("[keeper#") || m.content.contains("[rust#0]")));
+
+        assert_eq!(store.remove_document("rust"), 1);
+    }
+
+    #[test]
+    fn chat_session_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let context_options = super::options::ContextOptions::builder().n_ctx(512).build();
+        let mut session = super::session::ChatSession::new(
+            &model,
+            super::options::PredictOptions::builder()
+                .max_len(16)
+                .temp(0.0)
+                .build(),
+        )
+        .unwrap();
+        session.messages.push(Message {
+            content: "Write a function adding two numbers in Rust.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        });
+        let mut ctx = session.resume(&model, context_options.clone()).unwrap();
+        let answer = ctx
+            .predict(session.predict_options.clone())
+            .predict()
+            .unwrap();
+        session.messages.push(Message {
+            content: answer,
+            role: super::options::Role::Assistant,
+            images: vec![],
+        });
+        session.save_state(&ctx).unwrap();
+        let used = ctx.usage().context_used;
+        drop(ctx);
+
+        let path = std::env::temp_dir().join("nebula-session-test.json");
+        session.save(&path).unwrap();
+        let loaded = super::session::ChatSession::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.has_state());
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.model, session.model);
+        assert_eq!(loaded.predict_options.max_len, Some(16));
+
+        // the state is restored as is
+        let ctx = loaded.resume(&model, context_options).unwrap();
+        assert_eq!(ctx.usage().context_used, used);
+        drop(ctx);
+        // a context of another size evaluates the messages again
+        let ctx = loaded
+            .resume(
+                &model,
+                super::options::ContextOptions::builder()
+                    .n_ctx(1024)
+                    .build(),
+            )
+            .unwrap();
+        assert!(ctx.usage().context_used > 0);
+        drop(ctx);
+        // a state inflating past the size of the context is dropped and the messages are
+        // evaluated again
+        let limit = model.context(context_options.clone()).unwrap().state_size();
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::fast());
+        encoder.write_all(&vec![0; limit + (1 << 20)]).unwrap();
+        let mut json = serde_json::to_value(&loaded).unwrap();
+        json["state"]["data"] =
+            base64::Engine::encode(&base64::prelude::BASE64_STANDARD, encoder.finish().unwrap())
+                .into();
+        let bomb: super::session::ChatSession = serde_json::from_value(json).unwrap();
+        let ctx = bomb.resume(&model, context_options).unwrap();
+        assert!(ctx.usage().context_used > 0);
+    }
+
+    #[test]
+    fn sampling_overrides_test() {
+        let _serial = serial();
+        init();
+        let base = super::options::PredictOptions::builder()
+            .temp(0.8)
+            .max_len(64)
+            .build();
+        let overrides = super::options::SamplingOptions::builder()
+            .temp(0.0)
+            .stop(vec!["\n".to_string()])
+            .build();
+        let options = base.clone().with_overrides(&overrides);
+        assert_eq!(options.temp, 0.0);
+        assert_eq!(options.max_len, Some(64));
+        assert_eq!(options.top_k, base.top_k);
+
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![Message {
+            content: "Write a Rust program printing the numbers from 1 to 10.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        }])
+        .unwrap();
+        // the same context predicts with the settings of each call
+        let mut fork = ctx.fork().unwrap();
+        let first_line = ctx
+            .predict(base)
+            .with_overrides(&overrides)
+            .predict()
+            .unwrap();
+        assert!(!first_line.contains('\n'));
+        let longer = fork
+            .predict(options)
+            .with_overrides(
+                &super::options::SamplingOptions::builder()
+                    .stop(vec![])
+                    .build(),
+            )
+            .predict()
+            .unwrap();
+        assert!(longer.starts_with(&first_line));
+    }
+
+    struct Greedy {
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl super::options::CustomSampler for Greedy {
+        fn sample(&mut self, logits: &mut [f32], history: &[super::options::Token]) -> i32 {
+            assert!(!history.is_empty());
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            logits
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap()
+                .0 as i32
+        }
+    }
+
+    #[test]
+    fn custom_sampler_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![Message {
+            content: "Write a Rust program printing the numbers from 1 to 10.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        }])
+        .unwrap();
+        let mut fork = ctx.fork().unwrap();
+        let options = super::options::PredictOptions::builder()
+            .temp(0.0)
+            .max_len(8)
+            .build();
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let custom = ctx
+            .predict(options.clone())
+            .with_custom_sampler(Greedy {
+                calls: calls.clone(),
+            })
+            .predict()
+            .unwrap();
+        assert!(calls.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        // picking the most likely token is what greedy sampling does
+        let greedy = fork.predict(options).predict().unwrap();
+        assert_eq!(custom, greedy);
+    }
+
+    /// Picks the tokens of a fixed answer one after the other.
+    struct Script {
+        tokens: Vec<super::options::Token>,
+    }
+
+    impl super::options::CustomSampler for Script {
+        fn sample(&mut self, _logits: &mut [f32], _history: &[super::options::Token]) -> i32 {
+            self.tokens.remove(0)
+        }
+    }
+
+    #[test]
+    fn detect_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = |content: &str| Message {
+            content: content.to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let scripted = |text: &str| {
+            let tokens = model.tokenize(text).unwrap();
+            super::options::PredictOptions::builder()
+                .max_len(tokens.len() as i32)
+                .build()
+                .with_custom_sampler(Script { tokens })
+        };
+
+        // a special token of the model's vocabulary is part of the answer
+        let special = "<\u{ff5c}fim\u{2581}hole\u{ff5c}>";
+        assert_eq!(model.tokenize(special).unwrap().len(), 1);
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![message("Fill in the hole.")]).unwrap();
+        let options = super::options::PredictOptions {
+            decode_special_tokens: true,
+            ..scripted(special)
+        };
+        assert_eq!(ctx.predict(options).predict().unwrap(), special);
+
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        let answer = "<ref>dog</ref><box>(100,200),(300,400)</box>";
+        let detections = ctx
+            .detect(message("Detect every dog"), scripted(answer))
+            .unwrap();
+        assert_eq!(detections, super::grounding::parse(answer));
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].label, "dog");
+    }
+
+    #[test]
+    fn negative_prompt_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = Message {
+            content: "Write a Rust program printing the numbers from 1 to 10.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let options = super::options::PredictOptions::builder()
+            .temp(0.0)
+            .max_len(16)
+            .build();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![message.clone()]).unwrap();
+        let plain = ctx.predict(options.clone()).predict().unwrap();
+
+        // a scale of 1 keeps the distribution, greedy sampling picks the same tokens
+        let mut guided = model
+            .context(
+                super::options::ContextOptions::default()
+                    .with_negative_prompt("Write a Python program.", 1.0),
+            )
+            .unwrap();
+        guided.eval(vec![message.clone()]).unwrap();
+        let mut fork = guided.fork().unwrap();
+        assert_eq!(guided.predict(options.clone()).predict().unwrap(), plain);
+        assert_eq!(fork.predict(options.clone()).predict().unwrap(), plain);
+
+        let mut steered = model
+            .context(
+                super::options::ContextOptions::default()
+                    .with_negative_prompt("Write a Python program.", 3.0),
+            )
+            .unwrap();
+        steered.eval(vec![message]).unwrap();
+        assert!(!steered.predict(options).predict().unwrap().is_empty());
+    }
+
+    #[test]
+    fn error_codes_test() {
+        let _serial = serial();
+        init();
+        let decode = super::error::Error::Decode {
+            n_tokens: 4,
+            n_past: 12,
+            source: llama_cpp::DecodeError::NoKvCacheSlot,
+        };
+        assert_eq!(decode.kind(), super::error::ErrorKind::Decode);
+        assert_eq!(decode.code(), "decode.no_kv_cache_slot");
+        assert!(decode.to_string().contains("4 tokens at position 12"));
+        assert!(decode.hint().is_some());
+
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::builder().n_ctx(16).build())
+            .unwrap();
+        let err = ctx
+            .eval(vec![Message {
+                content: "Write a Rust program printing the numbers from 1 to 10.".repeat(32),
+                role: super::options::Role::User,
+                images: vec![],
+            }])
+            .unwrap_err();
+        assert_eq!(err.code(), "decode.context_full");
+        assert!(err.hint().unwrap().contains("n_ctx"));
+    }
+
+    #[test]
+    fn token_healing_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let options = super::options::PredictOptions::builder()
+            .temp(0.0)
+            .max_len(4)
+            .build();
+        let mut ctx = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .token_healing(true)
+                    .build(),
+            )
+            .unwrap();
+        ctx.eval_text("The capital of Fran").unwrap();
+        // the first token completes the word, its held back part is not sent again
+        let answer = ctx.predict(options).predict().unwrap();
+        assert!(answer.starts_with("ce"), "{answer:?}");
+    }
+
+    #[test]
+    fn prefill_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = Message {
+            content: "Write a Rust program printing the numbers from 1 to 10.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let options = super::options::PredictOptions::builder()
+            .temp(0.0)
+            .max_len(16)
+            .build();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![message.clone()]).unwrap();
+        let expected = ctx.predict(options.clone()).predict().unwrap();
+
+        // the prediction waits for the prefill instead of running on a partial prompt
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        let prefill = ctx.prefill(vec![message.clone()]).unwrap();
+        assert_eq!(ctx.predict(options.clone()).predict().unwrap(), expected);
+        prefill.wait().unwrap();
+
+        let mut small = model
+            .context(super::options::ContextOptions::builder().n_ctx(16).build())
+            .unwrap();
+        let long = Message {
+            content: message.content.repeat(32),
+            ..message
+        };
+        let prefill = small.prefill(vec![long.clone()]).unwrap();
+        assert_eq!(prefill.wait().unwrap_err().code(), "decode.context_full");
+
+        // without waiting, the next call on the context fails instead, once
+        let mut small = model
+            .context(super::options::ContextOptions::builder().n_ctx(16).build())
+            .unwrap();
+        drop(small.prefill(vec![long]).unwrap());
+        let err = small.predict(options).predict().unwrap_err();
+        assert_eq!(err.code(), "decode.context_full");
+        assert!(small.state_bytes().is_ok());
+    }
+
+    #[test]
+    fn token_quota_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = Message {
+            content: "Write a Rust program printing the numbers from 1 to 10.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let options = super::options::PredictOptions::builder()
+            .max_len(16)
+            .build();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![message.clone()]).unwrap();
+        let n_prompt = ctx.usage().total_prompt_tokens;
+        assert!(n_prompt > 0);
+
+        let mut ctx = model
+            .context(super::options::ContextOptions {
+                token_quota: Some(n_prompt + 4),
+                ..Default::default()
+            })
+            .unwrap();
+        ctx.eval(vec![message.clone()]).unwrap();
+        let err = ctx.predict(options.clone()).predict().unwrap_err();
+        assert!(matches!(err, super::error::Error::QuotaExceeded(_)));
+        let usage = ctx.usage();
+        assert_eq!(usage.total_completion_tokens, 4);
+        assert_eq!(usage.completion_tokens, 4);
+        // prompts are refused too once the quota is used up
+        let err = ctx.eval(vec![message]).unwrap_err();
+        assert!(matches!(err, super::error::Error::QuotaExceeded(_)));
+        assert!(ctx.eval_text("fn main() {").is_err());
+        assert_eq!(ctx.usage().total_prompt_tokens, n_prompt);
+        assert!(ctx.predict(options).predict().is_err());
+        let err = ctx.classify("fn main() {}", &["Rust", "C"]).unwrap_err();
+        assert!(matches!(err, super::error::Error::QuotaExceeded(_)));
+        // forks count against the same quota
+        let mut fork = ctx.fork().unwrap();
+        assert!(fork.eval_text("fn main() {").is_err());
+        assert_eq!(fork.usage().total_completion_tokens, 4);
+    }
+
+    #[test]
+    fn classify_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval_text("fn main() {").unwrap();
+        let n_past = ctx.n_past();
+        let scores = ctx
+            .classify(
+                "def greet(name):\n    print(f\"Hello, {name}!\")",
+                &["Rust", "Python"],
+            )
+            .unwrap();
+        assert_eq!(scores.len(), 2);
+        let sum: f32 = scores.iter().map(|(_, p)| p).sum();
+        assert!((sum - 1.0).abs() < 1e-4);
+        assert_eq!(scores[0].0, "Python");
+        // the context is rewound to where it was
+        assert_eq!(ctx.n_past(), n_past);
+        assert!(ctx.classify("text", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn choose_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let question = |content: &str| Message {
+            content: content.to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        let choices = ["Rust", "Python", "JavaScript"];
+        let (index, probability) = ctx
+            .choose(
+                question("Which language has a borrow checker? Answer with its name."),
+                &choices,
+            )
+            .unwrap();
+        assert!(index < choices.len());
+        assert!(probability > 0.0 && probability <= 1.0);
+        let usage = ctx.usage();
+        assert!(usage.completion_tokens > 0);
+
+        // one choice being the start of another can still be picked
+        let (index, _) = ctx
+            .choose(
+                question("How much is 5 + 5? Answer with the number."),
+                &["1", "10"],
+            )
+            .unwrap();
+        assert!(index < 2);
+        let (index, probability) = ctx.choose(question("Say yes."), &["yes"]).unwrap();
+        assert_eq!((index, probability), (0, 1.0));
+        assert!(ctx.choose(question("Say yes."), &[]).is_err());
+    }
+
+    #[test]
+    fn delivery_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let message = Message {
+            content: "Explain in a few sentences what a linked list is.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        let predict = |delivery| {
+            let mut ctx = model
+                .context(super::options::ContextOptions::default())
+                .unwrap();
+            ctx.eval(vec![message.clone()]).unwrap();
+            let chunks = std::sync::Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+            let sink = chunks.clone();
+            let options = super::options::PredictOptions::builder()
+                .max_len(32)
+                .token_callback(std::sync::Arc::new(Box::new(move |text| {
+                    sink.lock().unwrap().push(text);
+                    true
+                })))
+                .build();
+            ctx.predict(options)
+                .with_delivery(delivery)
+                .predict()
+                .unwrap();
+            let chunks = chunks.lock().unwrap().clone();
+            (chunks, ctx.usage().completion_tokens)
+        };
+
+        let (chunks, n_tokens) = predict(super::options::Delivery::Tokens(4));
+        assert!(!chunks.is_empty());
+        assert!(chunks.len() <= n_tokens.div_ceil(4) + 1);
+
+        let (chunks, _) = predict(super::options::Delivery::Words);
+        assert!(!chunks.concat().is_empty());
+        let (_, words) = chunks.split_last().unwrap();
+        for word in words {
+            assert!(word.ends_with(char::is_whitespace), "{word:?}");
+        }
+    }
+
+    #[test]
+    fn prompt_progress_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::builder().n_batch(8).build())
+            .unwrap();
+        let progress = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = progress.clone();
+        ctx.set_prompt_progress(move |done, total| sink.lock().unwrap().push((done, total)));
+        let message = Message {
+            content: "Summarize the history of the Rust programming language in a paragraph."
+                .to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        ctx.eval(vec![message.clone()]).unwrap();
+        let reports = progress.lock().unwrap().clone();
+        // one report per batch of 8 tokens
+        assert!(reports.len() > 1);
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        let (done, total) = *reports.last().unwrap();
+        assert_eq!(done, total);
+
+        ctx.clear_prompt_progress();
+        progress.lock().unwrap().clear();
+        ctx.eval(vec![message]).unwrap();
+        assert!(progress.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ubatch_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .n_batch(16)
+                    .n_ubatch(4)
+                    .build(),
+            )
+            .unwrap();
+        let progress = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = progress.clone();
+        ctx.set_prompt_progress(move |done, _| sink.lock().unwrap().push(done));
+        let message = Message {
+            content: "Write a function that returns the sum of a list of numbers.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        };
+        ctx.eval(vec![message]).unwrap();
+        // decode calls of n_batch tokens, split into micro batches by llama.cpp
+        let reports = progress.lock().unwrap().clone();
+        assert!(reports.len() > 1);
+        assert!(reports[0] == 16 && reports.windows(2).all(|w| w[1] - w[0] <= 16));
+        // drafts longer than a batch are cut to fit
+        let answer = ctx
+            .predict(
+                super::options::PredictOptions::builder()
+                    .max_len(32)
+                    .lookup_draft(64)
+                    .build(),
+            )
+            .predict()
+            .unwrap();
+        assert!(!answer.is_empty());
+    }
+
+    #[test]
+    fn backend_preference_test() {
+        let _serial = serial();
+        init();
+        let err = super::devices::set_backend_preference(Some(&["no_such_variant"])).unwrap_err();
+        assert_eq!(err.code(), "library_load.variant_unavailable");
+        let available = super::devices::variants().unwrap();
+        assert!(err.to_string().contains(&available[0]));
+
+        let model = test_model();
+        let loaded = super::devices::loaded_variant().unwrap();
+        drop(model);
+        let options = super::options::ModelOptions::default().with_backend_preference(&["nope"]);
+        let err = super::Model::new(test_model_path(), options).unwrap_err();
+        assert_eq!(err.code(), "library_load.variant_unavailable");
+        // the variant loaded already satisfies a preference including it
+        let options =
+            super::options::ModelOptions::default().with_backend_preference(&[loaded.as_str()]);
+        assert!(super::Model::new(test_model_path(), options).is_ok());
+        // the preference of a model is not kept for later loads
+        assert_eq!(super::devices::backend_preference(), None);
+    }
+
+    #[test]
+    fn introspection_test() {
+        let _serial = serial();
+        init();
+        let gguf = super::gguf::read(test_model_path()).unwrap();
+        let arch_value = |key| {
+            gguf.arch_value(key)
+                .and_then(super::gguf::Value::as_u64)
+                .unwrap() as usize
+        };
+        let model = super::Model::new(
+            test_model_path(),
+            super::options::ModelOptions::builder().cpu(true).build(),
+        )
+        .unwrap();
+        assert_eq!(model.n_ctx_train(), arch_value("context_length"));
+        assert_eq!(model.n_embd(), arch_value("embedding_length"));
+        assert!(model.n_vocab() > 30000);
+        assert_eq!(
+            model.quant_type().unwrap(),
+            Some(super::options::QuantType::Q2_K)
+        );
+        let placement = model.placement();
+        assert_eq!(placement.n_offloaded, 0);
+        assert!(placement.devices.is_empty());
+
+        let ctx = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .n_ctx(512)
+                    .n_batch(256)
+                    .n_ubatch(64)
+                    .build(),
+            )
+            .unwrap();
+        assert_eq!(
+            (ctx.n_ctx(), ctx.n_batch(), ctx.n_ubatch(), ctx.n_past()),
+            (512, 256, 64, 0)
+        );
+    }
+
+    #[test]
+    fn token_id_callback_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        ctx.eval(vec![Message {
+            content: "Write a haiku about the sea.".to_string(),
+            role: super::options::Role::User,
+            images: vec![],
+        }])
+        .unwrap();
+        let tokens = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = tokens.clone();
+        let answer = ctx
+            .predict(
+                super::options::PredictOptions::builder()
+                    .max_len(24)
+                    .build(),
+            )
+            .with_token_id_callback(move |id, text| {
+                sink.lock().unwrap().push((id, text));
+                true
+            })
+            .predict()
+            .unwrap();
+        let tokens = tokens.lock().unwrap();
+        // one call per generated token, the text is the raw output
+        assert_eq!(tokens.len(), ctx.usage().completion_tokens);
+        assert!(tokens.iter().all(|(id, _)| *id >= 0));
+        let raw: String = tokens.iter().map(|(_, text)| text.as_str()).collect();
+        assert!(raw.starts_with(&answer));
+    }
+
+    #[test]
+    fn concurrent_predictions_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let generate = |model: &super::Model| {
+            let mut ctx = model
+                .context(
+                    super::options::ContextOptions::builder()
+                        .n_ctx(256)
+                        .n_threads(2)
+                        .build(),
+                )
+                .unwrap();
+            ctx.eval(vec![Message {
+                role: super::options::Role::User,
+                content: "Write a function reversing a string in Rust.".to_string(),
+                images: vec![],
+            }])
+            .unwrap();
+            let options = super::options::PredictOptions::builder()
+                .max_len(24)
+                .temp(0.0)
+                .build();
+            ctx.predict(options).predict().unwrap()
+        };
+        let expected = generate(&model);
+        assert!(!expected.is_empty());
+        // greedy answers of contexts decoding at the same time match the one decoded alone
+        let answers: Vec<String> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let model = model.clone();
+                    s.spawn(move || generate(&model))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for answer in answers {
+            assert_eq!(answer, expected);
+        }
+
+        // sizes can be read while another thread predicts on the context
+        let mut ctx = model
+            .context(super::options::ContextOptions::builder().n_ctx(256).build())
+            .unwrap();
+        ctx.eval_text("fn main() {").unwrap();
+        let options = super::options::PredictOptions::builder()
+            .max_len(24)
+            .temp(0.0)
+            .build();
+        std::thread::scope(|s| {
+            let running = s.spawn(|| super::Predict::new(&ctx, options).predict());
+            while !running.is_finished() {
+                assert!(ctx.n_ctx() >= 256);
+                assert!(ctx.n_ubatch() <= ctx.n_batch());
+            }
+            running.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn text_hooks_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let mut ctx = model
+            .context(super::options::ContextOptions::default())
+            .unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = seen.clone();
+        ctx.add_input_hook(|text| text.replace("alice@example.com", "[email]"));
+        ctx.add_input_hook(move |text| {
+            sink.lock().unwrap().push(text.to_string());
+            text.to_string()
+        });
+        ctx.add_output_hook(|text| text.to_uppercase());
+        ctx.add_output_hook(|text| text.replace('\x1b', ""));
+        ctx.eval(vec![Message {
+            role: super::options::Role::User,
+            content: "Write to alice@example.com about Rust.".to_string(),
+            images: vec![],
+        }])
+        .unwrap();
+        // hooks run in the order they were added
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["Write to [email] about Rust.".to_string()]
+        );
+        let options = || {
+            super::options::PredictOptions::builder()
+                .max_len(16)
+                .temp(0.0)
+                .build()
+        };
+        let mut fork = ctx.fork().unwrap();
+        let answer = ctx.predict(options()).predict().unwrap();
+        assert!(!answer.is_empty());
+        assert_eq!(answer, answer.to_uppercase());
+
+        // streamed text goes through the hooks too, forks keep them
+        let streamed = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+        let sink = streamed.clone();
+        let streaming = super::options::PredictOptions {
+            token_callback: Some(std::sync::Arc::new(Box::new(move |text| {
+                sink.lock().unwrap().push_str(&text);
+                true
+            }))),
+            ..options()
+        };
+        fork.predict(streaming)
+            .with_delivery(super::options::Delivery::Words)
+            .predict()
+            .unwrap();
+        assert_eq!(*streamed.lock().unwrap(), answer);
+
+        ctx.clear_hooks();
+        ctx.eval_text(" alice@example.com").unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lookup_decoding_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        let code = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n";
+        let answer = |lookup_draft| {
+            let mut ctx = model
+                .context(super::options::ContextOptions::default())
+                .unwrap();
+            ctx.eval(vec![Message {
+                content: format!("Repeat this code exactly:\n{code}"),
+                role: super::options::Role::User,
+                images: vec![],
+            }])
+            .unwrap();
+            ctx.predict(
+                super::options::PredictOptions::builder()
+                    .max_len(48)
+                    .temp(0.0)
+                    .lookup_draft(lookup_draft)
+                    .build(),
+            )
+            .predict()
+            .unwrap()
+        };
+        let plain = answer(0);
+        assert!(!plain.is_empty());
+        assert_eq!(answer(8), plain);
+    }
+
+    #[test]
+    #[cfg(feature = "convert")]
+    fn convert_test() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join("nebula-convert-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{"architectures": ["LlamaForCausalLM"], "hidden_size": 8,
+                "intermediate_size": 16, "num_hidden_layers": 1, "num_attention_heads": 2,
+                "num_key_value_heads": 1, "max_position_embeddings": 64, "vocab_size": 4}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("tokenizer.json"),
+            r#"{"model": {"type": "BPE", "vocab": {"a": 0, "b": 1, "ab": 2},
+                "merges": ["a b"]},
+                "added_tokens": [{"id": 3, "content": "</s>", "special": true}]}"#,
+        )
+        .unwrap();
+        let shapes: &[(&str, &[u64])] = &[
+            ("model.embed_tokens.weight", &[4, 8]),
+            ("model.norm.weight", &[8]),
+            ("model.layers.0.self_attn.q_proj.weight", &[8, 8]),
+            ("model.layers.0.self_attn.k_proj.weight", &[4, 8]),
+            ("model.layers.0.self_attn.rotary_emb.inv_freq", &[2]),
+        ];
+        let mut header = serde_json::Map::new();
+        let mut data = vec![];
+        for (name, shape) in shapes {
+            let start = data.len();
+            for i in 0..shape.iter().product::<u64>() {
+                data.extend((i as f32).to_le_bytes());
+            }
+            header.insert(
+                name.to_string(),
+                serde_json::json!({"dtype": "F32", "shape": shape, "data_offsets": [start, data.len()]}),
+            );
+        }
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut file = std::fs::File::create(dir.join("model.safetensors")).unwrap();
+        file.write_all(&(header.len() as u64).to_le_bytes())
+            .unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&data).unwrap();
+        drop(file);
+
+        let progress = std::sync::Arc::new(std::sync::Mutex::new(0.0));
+        let reported = progress.clone();
+        let output = dir.join("model.gguf");
+        super::convert::hf_to_gguf(
+            &dir,
+            &output,
+            super::options::ConvertOptions::default()
+                .with_progress(move |p| *reported.lock().unwrap() = p),
+        )
+        .unwrap();
+        assert_eq!(*progress.lock().unwrap(), 1.0);
+        let gguf = super::gguf::read(&output).unwrap();
+        assert_eq!(gguf.architecture(), Some("llama"));
+        assert_eq!(gguf.tensor_count, 4);
+        assert_eq!(
+            gguf.arch_value("attention.head_count_kv")
+                .and_then(super::gguf::Value::as_u64),
+            Some(1)
+        );
+        assert_eq!(
+            gguf.get("tokenizer.ggml.tokens")
+                .and_then(super::gguf::Value::as_array)
+                .map(|t| t.len()),
+            Some(4)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn self_extend_test() {
+        let _serial = serial();
+        init();
+        let model = test_model();
+        assert!(model
+            .context(
+                super::options::ContextOptions::builder()
+                    .grp_attn_n(3)
+                    .grp_attn_w(128)
+                    .build()
+            )
+            .is_err());
+
+        let mut ctx = model
+            .context(
+                super::options::ContextOptions::builder()
+                    .n_ctx(2048)
+                    .grp_attn_n(4)
+                    .grp_attn_w(128)
+                    .build(),
+            )
+            .unwrap();
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(60);
+        ctx.eval(vec![Message {
+            content: format!("{text}\nWhat animal jumps?"),
+            role: super::options::Role::User,
+            images: vec![],
+        }])
+        .unwrap();
+        let answer = ctx
+            .predict(
+                super::options::PredictOptions::builder()
+                    .max_len(16)
+                    .temp(0.0)
+                    .build(),
+            )
+            .predict()
+            .unwrap();
+        assert!(!answer.is_empty());
+        // every token stays in the kv cache, only the positions are grouped
+        assert!(ctx.usage().context_used > 600);
+        assert!(ctx.state_bytes().is_err());
+    }
+
     models_tests! {
     //        model_test_llava_1_6_mistral_7b_gguf:
     //        ("cjpais/llava-1.6-mistral-7b-gguf",
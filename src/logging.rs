@@ -0,0 +1,44 @@
+//! Where the log output of llama.cpp goes.
+//!
+//! By default llama.cpp prints its log straight to stderr, at load time as well as while
+//! decoding. [`route_native_logs`] hands every line to the [`log`] crate instead, under the
+//! target [`NATIVE_TARGET`], so it is filtered and formatted like the host application's own
+//! records (and reaches `tracing` subscribers through `tracing-log`). Unlike redirecting stderr
+//! with [`crate::options::StdioPolicy`] this does not touch the process wide file descriptor.
+use log::LevelFilter;
+
+use crate::Result;
+
+pub const NATIVE_TARGET: &str = llama_cpp::logging::TARGET;
+
+fn load() -> Result<()> {
+    crate::paths::init_dependencies()?;
+    llama_cpp::load_libraries()?;
+    Ok(())
+}
+
+/// Sends llama.cpp log lines up to `max_level` to the [`log`] crate.
+///
+/// The setting survives [`crate::shutdown`], it is applied again when the libraries are loaded.
+pub fn route_native_logs(max_level: LevelFilter) -> Result<()> {
+    load()?;
+    llama_cpp::logging::set_max_level(max_level);
+    Ok(())
+}
+
+/// Drops all llama.cpp log output.
+pub fn silence_native_logs() -> Result<()> {
+    route_native_logs(LevelFilter::Off)
+}
+
+/// Lets llama.cpp print to stderr again, the default.
+pub fn native_logs_to_stderr() -> Result<()> {
+    load()?;
+    llama_cpp::logging::reset();
+    Ok(())
+}
+
+/// The level set with [`route_native_logs`], `None` while llama.cpp prints to stderr.
+pub fn native_log_level() -> Option<LevelFilter> {
+    llama_cpp::logging::max_level()
+}
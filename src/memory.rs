@@ -0,0 +1,242 @@
+//! Memory a model needs, estimated from its GGUF header before loading it.
+//!
+//! Weights are the sizes of the tensors in the file, placed on the cpu or the gpus the way
+//! llama.cpp offloads layers: the last `n_gpu_layers` repeating layers, then the output layer.
+//! With [`ModelOptions::vram_reserve`] only the layers fitting into the free memory of the
+//! gpus are. The kv cache is f16 for every token of `n_ctx`. Compute buffers are approximated
+//! from the largest activations of a batch, llama.cpp's actual graph may need somewhat more or
+//! less.
+use std::path::Path;
+
+use crate::{
+    devices::Device,
+    error::Error,
+    gguf::{self, TensorInfo, Value},
+    options::{ContextOptions, ModelOptions},
+    Result,
+};
+
+// llama.cpp pads the kv cache to a multiple of this without flash attention
+const KV_PADDING: u64 = 32;
+const F16_BYTES: u64 = 2;
+const F32_BYTES: u64 = 4;
+
+/// What loading a model with a context needs, see [`crate::estimate_memory`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct MemoryEstimate {
+    /// Weights kept in system memory, mapped from the file with `use_mmap`.
+    pub weights_ram: u64,
+    /// Weights of the layers offloaded to the gpus.
+    pub weights_vram: u64,
+    pub kv_cache_ram: u64,
+    pub kv_cache_vram: u64,
+    /// Logits of every sequence and, without offloading, the compute buffers.
+    pub compute_ram: u64,
+    pub compute_vram: u64,
+    /// Layers placed on the gpus, the output layer counts as one more. 0 if there is no gpu.
+    pub n_gpu_layers: u32,
+    /// Free system memory, `None` where the system doesn't tell.
+    pub free_ram: Option<u64>,
+    /// Free memory of all gpus, `None` without a gpu.
+    pub free_vram: Option<u64>,
+}
+
+impl MemoryEstimate {
+    pub fn weights(&self) -> u64 {
+        self.weights_ram + self.weights_vram
+    }
+
+    pub fn kv_cache(&self) -> u64 {
+        self.kv_cache_ram + self.kv_cache_vram
+    }
+
+    pub fn compute(&self) -> u64 {
+        self.compute_ram + self.compute_vram
+    }
+
+    pub fn ram(&self) -> u64 {
+        self.weights_ram + self.kv_cache_ram + self.compute_ram
+    }
+
+    pub fn vram(&self) -> u64 {
+        self.weights_vram + self.kv_cache_vram + self.compute_vram
+    }
+
+    pub fn total(&self) -> u64 {
+        self.ram() + self.vram()
+    }
+
+    /// Both parts fit into the free memory, unknown free memory counts as enough.
+    pub fn fits(&self) -> bool {
+        self.ram() <= self.free_ram.unwrap_or(u64::MAX)
+            && self.vram() <= self.free_vram.unwrap_or(u64::MAX)
+    }
+}
+
+pub(crate) fn estimate(
+    path: &Path,
+    model_options: &ModelOptions,
+    context_options: &ContextOptions,
+) -> Result<MemoryEstimate> {
+    let (header, tensors) = gguf::read_tensors(path)?;
+    let hparam = |key: &str| header.arch_value(key).and_then(Value::as_u64);
+    let n_layer = hparam("block_count").unwrap_or(0);
+    // every layer has tensors, a larger count is corrupt and would be allocated for below
+    if n_layer > tensors.len() as u64 {
+        return Err(Error::InvalidGguf(format!(
+            "{n_layer} layers with {} tensors",
+            tensors.len()
+        )));
+    }
+    let n_embd = hparam("embedding_length").unwrap_or(0);
+    let n_head = per_layer(header.arch_value("attention.head_count"), n_layer);
+    let n_head_kv = match header.arch_value("attention.head_count_kv") {
+        Some(v) => per_layer(Some(v), n_layer),
+        None => n_head.clone(),
+    };
+    let n_head_max = n_head.iter().copied().max().unwrap_or(0);
+    let head_dim = n_embd.checked_div(n_head_max).unwrap_or(0);
+    let n_embd_k = hparam("attention.key_length").unwrap_or(head_dim);
+    let n_embd_v = hparam("attention.value_length").unwrap_or(head_dim);
+    let n_ff = per_layer(header.arch_value("feed_forward_length"), n_layer)
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+    let n_vocab = hparam("vocab_size")
+        .or_else(|| {
+            header
+                .get("tokenizer.ggml.tokens")
+                .and_then(Value::as_array)
+                .map(|t| t.len() as u64)
+        })
+        .unwrap_or(0);
+
+    let free_vram = free_vram(&crate::devices::list()?);
+    let free_ram = Some(crate::devices::system_memory()?.free()).filter(|f| *f > 0);
+
+    let n_gpu = match free_vram {
+        _ if model_options.cpu => 0,
+        None => 0,
+        Some(free) if model_options.n_gpu_layers < 0 => match model_options.vram_reserve {
+            Some(reserve) => fit(
+                &layer_weights(&tensors, n_layer),
+                free.saturating_sub(reserve),
+            ),
+            None => n_layer + 1,
+        },
+        Some(_) => (model_options.n_gpu_layers as u64).min(n_layer + 1),
+    };
+    let first_gpu_layer = n_layer.saturating_sub(n_gpu);
+    let on_gpu = |layer: Option<u64>, name: &str| match layer {
+        Some(l) => n_gpu > 0 && l >= first_gpu_layer,
+        // the input embeddings always stay on the cpu
+        None => n_gpu > n_layer && name.starts_with("output"),
+    };
+
+    let mut estimate = MemoryEstimate {
+        weights_ram: 0,
+        weights_vram: 0,
+        kv_cache_ram: 0,
+        kv_cache_vram: 0,
+        compute_ram: 0,
+        compute_vram: 0,
+        n_gpu_layers: n_gpu as u32,
+        free_ram,
+        free_vram,
+    };
+    for t in &tensors {
+        if on_gpu(t.layer(), &t.name) {
+            estimate.weights_vram += t.size;
+        } else {
+            estimate.weights_ram += t.size;
+        }
+    }
+
+    let n_ctx = (context_options.n_ctx as u64).next_multiple_of(KV_PADDING);
+    for (layer, n_kv) in n_head_kv.iter().enumerate() {
+        let bytes = n_ctx * (n_embd_k + n_embd_v) * n_kv * F16_BYTES;
+        if on_gpu(Some(layer as u64), "") {
+            estimate.kv_cache_vram += bytes;
+        } else {
+            estimate.kv_cache_ram += bytes;
+        }
+    }
+
+    // the most tokens one graph computes at once
+    let n_ubatch = context_options.n_ubatch() as u64;
+    // logits, the widest activations and the attention scores of one batch
+    let graph = F32_BYTES * n_ubatch * (n_vocab + n_ff + 4 * n_embd + n_ctx * n_head_max);
+    let outputs = F32_BYTES * n_vocab * context_options.n_sequences() as u64;
+    estimate.compute_ram = outputs;
+    if n_gpu > 0 {
+        estimate.compute_vram = graph;
+    } else {
+        estimate.compute_ram += graph;
+    }
+    Ok(estimate)
+}
+
+/// The most layers whose weights fit into the free memory of the gpus minus `reserve`, the
+/// output layer counting as one more. `None` without a gpu.
+pub(crate) fn fit_gpu_layers(path: &Path, reserve: u64) -> Result<Option<u32>> {
+    let Some(free) = free_vram(&crate::devices::list()?) else {
+        return Ok(None);
+    };
+    let (header, tensors) = gguf::read_tensors(path)?;
+    let n_layer = header
+        .arch_value("block_count")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let n = fit(
+        &layer_weights(&tensors, n_layer),
+        free.saturating_sub(reserve),
+    );
+    Ok(Some(n as u32))
+}
+
+fn free_vram(devices: &[Device]) -> Option<u64> {
+    let mut gpus = devices.iter().filter(|d| d.library != "cpu").peekable();
+    gpus.peek()
+        .is_some()
+        .then(|| gpus.map(|d| d.free_memory).sum())
+}
+
+/// Weights of every repeating layer, then of the output layer.
+fn layer_weights(tensors: &[TensorInfo], n_layer: u64) -> Vec<u64> {
+    let mut weights = vec![0; n_layer as usize + 1];
+    for t in tensors {
+        match t.layer() {
+            Some(l) if l < n_layer => weights[l as usize] += t.size,
+            None if t.name.starts_with("output") => weights[n_layer as usize] += t.size,
+            _ => {}
+        }
+    }
+    weights
+}
+
+/// How many layers of `weights` fit into `budget`, offloaded from the last repeating layer
+/// backwards and the output layer after all of them.
+fn fit(weights: &[u64], budget: u64) -> u64 {
+    let Some((output, layers)) = weights.split_last() else {
+        return 0;
+    };
+    let mut used = 0;
+    let mut n = 0;
+    for w in layers.iter().rev().chain([output]) {
+        used += w;
+        if used > budget {
+            break;
+        }
+        n += 1;
+    }
+    n
+}
+
+/// A hyperparameter given once for all layers or as an array with a value per layer.
+fn per_layer(value: Option<&Value>, n_layer: u64) -> Vec<u64> {
+    match value {
+        Some(Value::Array(values)) => values.iter().map(|v| v.as_u64().unwrap_or(0)).collect(),
+        Some(v) => vec![v.as_u64().unwrap_or(0); n_layer as usize],
+        None => vec![0; n_layer as usize],
+    }
+}
@@ -0,0 +1,123 @@
+//! Process wide counters of the llama backend's inference work.
+//!
+//! The counters are cumulative over all models and contexts, [`snapshot`] reads them. With the
+//! `prometheus` feature [`render_prometheus`] formats them in the Prometheus text format, and
+//! the HTTP server serves them on `/metrics`.
+use std::{
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+static PROMPT_TOKENS: AtomicU64 = AtomicU64::new(0);
+static PROMPT_EVAL_US: AtomicU64 = AtomicU64::new(0);
+static GENERATED_TOKENS: AtomicU64 = AtomicU64::new(0);
+static EVAL_US: AtomicU64 = AtomicU64::new(0);
+static DECODE_BATCHES: AtomicU64 = AtomicU64::new(0);
+static DECODE_BATCH_TOKENS: AtomicU64 = AtomicU64::new(0);
+static KV_CACHE_TOKENS: AtomicI64 = AtomicI64::new(0);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Metrics {
+    /// Prompt tokens evaluated.
+    pub prompt_tokens: u64,
+    /// Time spent evaluating prompts.
+    pub prompt_eval_ms: f64,
+    /// Tokens generated by predictions.
+    pub generated_tokens: u64,
+    /// Time spent generating, sampling included.
+    pub eval_ms: f64,
+    /// Batches passed to `llama_decode`.
+    pub decode_batches: u64,
+    /// Tokens in those batches, divided by `decode_batches` it is the mean batch size.
+    pub decode_batch_tokens: u64,
+    /// Tokens currently held in the kv caches of all live contexts.
+    pub kv_cache_tokens: u64,
+}
+
+pub fn snapshot() -> Metrics {
+    Metrics {
+        prompt_tokens: PROMPT_TOKENS.load(Ordering::Relaxed),
+        prompt_eval_ms: PROMPT_EVAL_US.load(Ordering::Relaxed) as f64 / 1000.0,
+        generated_tokens: GENERATED_TOKENS.load(Ordering::Relaxed),
+        eval_ms: EVAL_US.load(Ordering::Relaxed) as f64 / 1000.0,
+        decode_batches: DECODE_BATCHES.load(Ordering::Relaxed),
+        decode_batch_tokens: DECODE_BATCH_TOKENS.load(Ordering::Relaxed),
+        kv_cache_tokens: KV_CACHE_TOKENS.load(Ordering::Relaxed).max(0) as u64,
+    }
+}
+
+pub(crate) fn record_prompt(tokens: usize, elapsed: Duration) {
+    PROMPT_TOKENS.fetch_add(tokens as u64, Ordering::Relaxed);
+    PROMPT_EVAL_US.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn record_generation(tokens: usize, elapsed: Duration) {
+    GENERATED_TOKENS.fetch_add(tokens as u64, Ordering::Relaxed);
+    EVAL_US.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn record_batches(batches: usize, tokens: usize) {
+    DECODE_BATCHES.fetch_add(batches as u64, Ordering::Relaxed);
+    DECODE_BATCH_TOKENS.fetch_add(tokens as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn add_kv_cache_tokens(delta: i64) {
+    KV_CACHE_TOKENS.fetch_add(delta, Ordering::Relaxed);
+}
+
+/// The metrics in the Prometheus text exposition format.
+#[cfg(feature = "prometheus")]
+pub fn render_prometheus() -> String {
+    use std::fmt::Write;
+
+    let m = snapshot();
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP nebula_{name} {help}");
+        let _ = writeln!(out, "# TYPE nebula_{name} {kind}");
+        let _ = writeln!(out, "nebula_{name} {value}");
+    };
+    metric(
+        "prompt_tokens_total",
+        "counter",
+        "Prompt tokens evaluated.",
+        m.prompt_tokens as f64,
+    );
+    metric(
+        "prompt_eval_seconds_total",
+        "counter",
+        "Time spent evaluating prompts.",
+        m.prompt_eval_ms / 1000.0,
+    );
+    metric(
+        "generated_tokens_total",
+        "counter",
+        "Tokens generated.",
+        m.generated_tokens as f64,
+    );
+    metric(
+        "eval_seconds_total",
+        "counter",
+        "Time spent generating tokens.",
+        m.eval_ms / 1000.0,
+    );
+    metric(
+        "decode_batches_total",
+        "counter",
+        "Batches decoded.",
+        m.decode_batches as f64,
+    );
+    metric(
+        "decode_batch_tokens_total",
+        "counter",
+        "Tokens in decoded batches.",
+        m.decode_batch_tokens as f64,
+    );
+    metric(
+        "kv_cache_tokens",
+        "gauge",
+        "Tokens held in the kv caches of all live contexts.",
+        m.kv_cache_tokens as f64,
+    );
+    out
+}
@@ -1,6 +1,7 @@
 use base64::prelude::*;
 use llama_cpp::sample::SamplingParams;
-use serde::{de::Visitor, Deserialize, Deserializer};
+pub use llama_cpp::stdio::StdioPolicy;
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use std::{fmt::Display, io::Read};
 
@@ -44,6 +45,21 @@ fn default_usize_2048() -> usize {
     2048
 }
 
+// llama.cpp's micro batch size when none is set
+const DEFAULT_N_UBATCH: usize = 512;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_f32_minus_1() -> f32 {
+    -1.0
+}
+
+fn default_f32_32_0() -> f32 {
+    32.0
+}
+
 fn default_samplers() -> Vec<SamplerType> {
     vec![
         SamplerType::TopK,
@@ -55,6 +71,35 @@ fn default_samplers() -> Vec<SamplerType> {
     ]
 }
 
+/// How llama.cpp places threads and memory on NUMA systems, only supported on Linux.
+///
+/// NUMA is set up once per process, by the first model loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum NumaStrategy {
+    #[default]
+    Disabled,
+    /// Spread execution evenly over all nodes.
+    Distribute,
+    /// Only spawn threads on CPUs on the node that execution started on.
+    Isolate,
+    /// Use the CPU map provided by numactl.
+    Numactl,
+}
+
+impl From<NumaStrategy> for llama_cpp::llama_backend::NumaStrategy {
+    fn from(val: NumaStrategy) -> Self {
+        match val {
+            NumaStrategy::Disabled => llama_cpp::llama_backend::NumaStrategy::DISABLED,
+            NumaStrategy::Distribute => llama_cpp::llama_backend::NumaStrategy::DISTRIBUTE,
+            NumaStrategy::Isolate => llama_cpp::llama_backend::NumaStrategy::ISOLATE,
+            NumaStrategy::Numactl => llama_cpp::llama_backend::NumaStrategy::NUMACTL,
+        }
+    }
+}
+
+/// Receives the load progress between 0 and 1, returning `false` cancels the load.
+pub type LoadProgressCallback = dyn Fn(f32) -> bool + Send + Sync + 'static;
+
 #[derive(bon::Builder, serde::Deserialize)]
 pub struct ModelOptions {
     #[builder(default)]
@@ -63,6 +108,48 @@ pub struct ModelOptions {
     #[builder(default = -1)]
     #[serde(default = "default_i32_minus_1")]
     pub n_gpu_layers: i32,
+    /// Maximum number of contexts alive at once for this model, unlimited if unset.
+    ///
+    /// Every context allocates its own kv cache, use it to bound memory when contexts are
+    /// created on demand, e.g. one per request.
+    #[serde(default)]
+    pub max_contexts: Option<usize>,
+    /// Map the model file instead of reading it, pages are loaded on demand.
+    ///
+    /// Unset maps it where the platform supports mmap and reads it with a warning elsewhere,
+    /// `true` fails to load where mmap is not supported.
+    #[serde(default)]
+    pub use_mmap: Option<bool>,
+    /// Lock the model in RAM so it is never swapped out.
+    #[builder(default)]
+    #[serde(default)]
+    pub use_mlock: bool,
+    #[builder(default)]
+    #[serde(default)]
+    pub numa_strategy: NumaStrategy,
+    /// Fit the layers offloaded with `n_gpu_layers` -1 into the free memory of the gpus at load
+    /// time, leaving this many bytes to other processes and the kv caches and compute buffers
+    /// of this model's contexts. Unset offloads every layer whatever the free memory.
+    #[serde(default)]
+    pub vram_reserve: Option<u64>,
+    /// What to try when the model doesn't load with `n_gpu_layers`, see
+    /// [`crate::Model::placement`] for where it ended up.
+    #[builder(default)]
+    #[serde(default)]
+    pub fallback: LoadFallback,
+    /// Library variants to load, e.g. `cuda_v12` or `cpu_avx2`, only these and in this order.
+    /// Empty keeps the global choice, see [`crate::devices::set_backend_preference`], which this
+    /// doesn't change. The libraries are shared by every model, the preference of the one
+    /// loading them applies, later models fail unless theirs includes the loaded variant.
+    #[builder(default)]
+    #[serde(default)]
+    pub backend_preference: Vec<String>,
+    /// How the vision projector of the model is loaded and run, see [`ProjectorOptions`].
+    #[builder(default)]
+    #[serde(default)]
+    pub projector: ProjectorOptions,
+    #[serde(skip_deserializing)]
+    pub load_progress: Option<std::sync::Arc<Box<LoadProgressCallback>>>,
 }
 
 impl Default for ModelOptions {
@@ -71,7 +158,82 @@ impl Default for ModelOptions {
     }
 }
 
-#[derive(Clone, Debug, serde::Deserialize, PartialEq)]
+impl ModelOptions {
+    /// Reports the load progress to `callback`, see [`LoadProgressCallback`].
+    /// A cancelled load fails with [`crate::error::Error::ModelLoadCancelled`].
+    pub fn with_load_progress(
+        mut self,
+        callback: impl Fn(f32) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.load_progress = Some(std::sync::Arc::new(Box::new(callback)));
+        self
+    }
+
+    /// Loads the libraries only from `variants`, see [`ModelOptions::backend_preference`].
+    pub fn with_backend_preference(mut self, variants: &[&str]) -> Self {
+        self.backend_preference = variants.iter().map(|v| v.to_string()).collect();
+        self
+    }
+}
+
+/// How a vision projector (mmproj) is loaded and run, see [`crate::Projector::load_with_options`].
+///
+/// llava has no choice of device, it runs the vision encoder on the gpu the library variant
+/// was built for. Load a cpu variant to run it on the cpu, see
+/// [`crate::devices::set_backend_preference`].
+///
+/// Embedding an image is a large part of the latency of a multimodal prompt on laptops, the
+/// right settings depend on the machine.
+#[derive(Clone, Debug, bon::Builder, serde::Deserialize)]
+pub struct ProjectorOptions {
+    /// Threads embedding an image, `n_threads` of the context if unset.
+    #[serde(default)]
+    pub n_threads: Option<usize>,
+    #[builder(default)]
+    #[serde(default)]
+    pub precision: ProjectorPrecision,
+    /// Refuse to load the projector unless the vision encoder runs on the cpu, that is a cpu
+    /// library variant is loaded. It does not move the encoder.
+    #[builder(default)]
+    #[serde(default)]
+    pub require_cpu: bool,
+}
+
+impl Default for ProjectorOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Type the weight matrices of a projector are computed in.
+///
+/// Other than the file's own, the projector is converted once into
+/// [`crate::paths::projectors_dir`] and the copy is loaded from then on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum ProjectorPrecision {
+    /// As stored in the file.
+    #[default]
+    File,
+    /// Half the memory and faster on most gpus and recent cpus.
+    F16,
+    /// Exact, for cpus without fast f16 arithmetic.
+    F32,
+}
+
+/// Retries of a model load that failed with the configured gpu offload, e.g. because the
+/// layers don't fit into the memory of the gpu.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum LoadFallback {
+    /// Fail with the error of the load.
+    #[default]
+    None,
+    /// Load again with all layers on the cpu.
+    Cpu,
+    /// Halve the offloaded layers until the model loads, the cpu last.
+    FewerLayers,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
 pub enum Role {
     #[serde(alias = "system")]
     System,
@@ -148,6 +310,16 @@ impl<'de> Visitor<'de> for ImageVisitor {
     }
 }
 
+/// Written as base64, which reading accepts.
+impl Serialize for Image {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&BASE64_STANDARD.encode(&self.0))
+    }
+}
+
 impl<'de> Deserialize<'de> for Image {
     fn deserialize<D>(deserializer: D) -> Result<Image, D::Error>
     where
@@ -157,7 +329,7 @@ impl<'de> Deserialize<'de> for Image {
     }
 }
 
-#[derive(Clone, Debug, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Message {
     pub content: String,
     pub role: Role,
@@ -179,7 +351,54 @@ impl TryFrom<Value> for Message {
     }
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+/// Token accounting of the last exchange with a context.
+///
+/// An exchange is everything evaluated since the previous prediction plus the prediction
+/// that answered it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Usage {
+    /// Prompt tokens added to the context during the exchange.
+    pub prompt_tokens: usize,
+    /// Tokens generated during the exchange.
+    pub completion_tokens: usize,
+    /// Prompt tokens added since the context was created, by it and its forks.
+    pub total_prompt_tokens: usize,
+    /// Tokens generated since the context was created, by it and its forks.
+    pub total_completion_tokens: usize,
+    /// Tokens held by the context, including all earlier exchanges.
+    pub context_used: usize,
+    pub context_size: usize,
+    /// Why the last prediction ended, `None` before the first one.
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The end of generation, a stop string or reverse prompt, or the token callback.
+    Stop,
+    /// `max_len` tokens were generated.
+    Length,
+    /// The model was repeating itself, see [`PredictOptions::repetition_abort`].
+    DegenerateOutput,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Share of the context in use, between 0 and 1.
+    pub fn context_fraction(&self) -> f32 {
+        if self.context_size == 0 {
+            0.0
+        } else {
+            self.context_used as f32 / self.context_size as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub enum SamplerType {
     None = 0,
     TopK = 1,
@@ -204,9 +423,77 @@ impl From<SamplerType> for llama_cpp::sample::SamplerType {
     }
 }
 
+/// How rotary position embeddings are scaled to positions beyond the training context.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum RopeScalingType {
+    /// Whatever the model file specifies.
+    #[default]
+    Unspecified,
+    None,
+    Linear,
+    Yarn,
+}
+
+impl From<RopeScalingType> for llama_cpp::context::params::RopeScalingType {
+    fn from(val: RopeScalingType) -> Self {
+        match val {
+            RopeScalingType::Unspecified => {
+                llama_cpp::context::params::RopeScalingType::Unspecified
+            }
+            RopeScalingType::None => llama_cpp::context::params::RopeScalingType::None,
+            RopeScalingType::Linear => llama_cpp::context::params::RopeScalingType::Linear,
+            RopeScalingType::Yarn => llama_cpp::context::params::RopeScalingType::Yarn,
+        }
+    }
+}
+
 pub type TokenCallback = dyn Fn(String) -> bool + Send + Sync + 'static;
 
-#[derive(Clone, bon::Builder, serde::Deserialize)]
+/// Receives each generated token with its text, see [`PredictOptions::token_id_callback`].
+/// Returning `false` stops the prediction.
+pub type TokenIdCallback = dyn Fn(Token, String) -> bool + Send + Sync + 'static;
+
+/// Transforms text on its way into or out of a context, see [`crate::Context::add_input_hook`]
+/// and [`crate::Context::add_output_hook`].
+pub type TextHook = dyn Fn(&str) -> String + Send + Sync + 'static;
+
+/// Receives the prompt tokens decoded so far and the number of tokens being evaluated, after
+/// every batch, see [`crate::Context::set_prompt_progress`].
+pub type PromptProgressCallback = dyn Fn(usize, usize) + Send + Sync + 'static;
+
+/// Id of a token in the vocabulary of a model.
+pub type Token = i32;
+
+/// Picks the next token from the raw logits of the model, in place of the sampler chain
+/// llama.cpp builds from the [`PredictOptions`], e.g. for contrastive decoding.
+///
+/// `logits` has an entry per token of the vocabulary and may be modified freely. `history`
+/// holds the tokens of the context so far, prompt and generated ones, with -1 where an image
+/// was evaluated. Penalties, grammars and the other sampling options don't apply, the token of
+/// a healed prompt is still picked by llama.cpp.
+pub trait CustomSampler: Send {
+    fn sample(&mut self, logits: &mut [f32], history: &[Token]) -> Token;
+}
+
+/// How often the token callback is called. Text is collected in between and whatever is
+/// left is delivered when the prediction ends, so the callback still sees all of it.
+///
+/// Returning `false` from the callback stops the prediction at the next delivery.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// Every piece of text as soon as it is decoded.
+    #[default]
+    EveryToken,
+    /// Every this many tokens.
+    Tokens(usize),
+    /// What was collected once this many milliseconds passed since the last delivery.
+    Interval(u64),
+    /// Up to the last whitespace, so words arrive whole.
+    Words,
+}
+
+#[derive(Clone, bon::Builder, serde::Deserialize, serde::Serialize)]
 pub struct PredictOptions {
     #[builder(default)]
     #[serde(default)]
@@ -271,15 +558,87 @@ pub struct PredictOptions {
     #[builder(default)]
     #[serde(default)]
     pub ignore_eos: bool,
+    /// Order of the truncation samplers. The full chain is always
+    /// logit bias -> penalties -> `samplers` in this order -> softmax -> seeded pick,
+    /// mirostat replaces `samplers` when enabled and `temp <= 0` switches to greedy.
     #[builder(default = default_samplers())]
     #[serde(default = "default_samplers")]
     pub samplers: Vec<SamplerType>,
     #[builder(default)]
     #[serde(default)]
     pub grammar: String,
-    #[serde(skip_deserializing)]
+    /// Generation stops once the text ends with one of these, e.g. `"User:"`, and the caller
+    /// can append the next input, see [`crate::Interactive`]. The reverse prompt stays part
+    /// of the generated text and the kv cache.
+    #[builder(default)]
+    #[serde(default)]
+    pub reverse_prompts: Vec<String>,
+    /// Generation stops before any of these, which are not part of the text, like the stop
+    /// strings of the chat template.
+    #[builder(default)]
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Prompt lookup decoding: with every sampled token, up to this many tokens that
+    /// followed the same `lookup_ngram` tokens earlier in the context are evaluated as a
+    /// draft and kept as far as the sampler agrees with them. Speeds up answers repeating
+    /// the prompt, like code edits, without changing them. `0` turns it off. Drafts are cut
+    /// to fit into one batch of `n_batch` tokens.
+    #[builder(default)]
+    #[serde(default)]
+    pub lookup_draft: usize,
+    #[builder(default = default_usize_3())]
+    #[serde(default = "default_usize_3")]
+    pub lookup_ngram: usize,
+    /// Writes special tokens like `<|box_start|>` into the generated text instead of leaving
+    /// them out. The end of generation token is never part of the text.
+    #[builder(default)]
+    #[serde(default)]
+    pub decode_special_tokens: bool,
+    #[serde(skip)]
     pub token_callback: Option<std::sync::Arc<Box<TokenCallback>>>,
+    /// When the text reaches `token_callback`, see [`Delivery`].
+    #[builder(default)]
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Receives every generated token as it is sampled, with the text it completes.
+    ///
+    /// Unlike `token_callback` it sees the raw output: stop strings and the end of generation
+    /// token are included. A character split over several tokens comes with the last of them,
+    /// the others have empty text, so the texts add up to everything generated. A token
+    /// ending in the middle of a character is sent with the next one, or at the end of the
+    /// prediction with the cut off character, so every token is sent once.
+    #[serde(skip)]
+    pub token_id_callback: Option<std::sync::Arc<Box<TokenIdCallback>>>,
+    /// Picks the tokens instead of the sampling options, see [`CustomSampler`].
+    #[serde(skip)]
+    pub custom_sampler: Option<std::sync::Arc<std::sync::Mutex<Box<dyn CustomSampler>>>>,
     pub max_len: Option<i32>,
+    /// Stops the prediction once the model repeats itself, instead of generating the same
+    /// phrase until `max_len`, see [`RepetitionAbort`].
+    pub repetition_abort: Option<RepetitionAbort>,
+}
+
+/// When a prediction counts as stuck in a loop: the last `ngram` generated tokens occurred
+/// `max_repeats` times within the last `window` ones. It then ends with
+/// [`FinishReason::DegenerateOutput`].
+///
+/// A long `ngram` still catches short phrases, their repetitions repeat it as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RepetitionAbort {
+    pub ngram: usize,
+    pub window: usize,
+    pub max_repeats: usize,
+}
+
+impl Default for RepetitionAbort {
+    fn default() -> Self {
+        Self {
+            ngram: 16,
+            window: 512,
+            max_repeats: 4,
+        }
+    }
 }
 
 impl Default for PredictOptions {
@@ -288,6 +647,52 @@ impl Default for PredictOptions {
     }
 }
 
+impl PredictOptions {
+    /// Samples with `sampler`, see [`CustomSampler`].
+    pub fn with_custom_sampler(mut self, sampler: impl CustomSampler + 'static) -> Self {
+        self.custom_sampler = Some(std::sync::Arc::new(std::sync::Mutex::new(Box::new(
+            sampler,
+        ))));
+        self
+    }
+
+    /// These options with the fields set in `overrides` replaced.
+    pub fn with_overrides(mut self, overrides: &SamplingOptions) -> Self {
+        let o = overrides.clone();
+        self.seed = o.seed.unwrap_or(self.seed);
+        self.temp = o.temp.unwrap_or(self.temp);
+        self.top_k = o.top_k.unwrap_or(self.top_k);
+        self.top_p = o.top_p.unwrap_or(self.top_p);
+        self.min_p = o.min_p.unwrap_or(self.min_p);
+        self.penalty_repeat = o.penalty_repeat.unwrap_or(self.penalty_repeat);
+        self.penalty_freq = o.penalty_freq.unwrap_or(self.penalty_freq);
+        self.penalty_present = o.penalty_present.unwrap_or(self.penalty_present);
+        self.grammar = o.grammar.unwrap_or(self.grammar);
+        self.stop = o.stop.unwrap_or(self.stop);
+        self.max_len = o.max_len.or(self.max_len);
+        self
+    }
+}
+
+/// Sampling settings of a single prediction that differ from the [`PredictOptions`] it is
+/// made with, unset fields keep their value. The sampler chain is built for every prediction,
+/// so nothing has to be rebuilt for them.
+#[derive(Clone, Debug, Default, bon::Builder, serde::Deserialize, serde::Serialize)]
+pub struct SamplingOptions {
+    pub seed: Option<u32>,
+    pub temp: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub penalty_repeat: Option<f32>,
+    pub penalty_freq: Option<f32>,
+    pub penalty_present: Option<f32>,
+    pub grammar: Option<String>,
+    /// Replaces [`PredictOptions::stop`].
+    pub stop: Option<Vec<String>>,
+    pub max_len: Option<i32>,
+}
+
 impl From<PredictOptions> for SamplingParams {
     fn from(val: PredictOptions) -> SamplingParams {
         SamplingParams {
@@ -327,9 +732,116 @@ pub struct ContextOptions {
     #[builder(default = default_usize_2048())]
     #[serde(default = "default_usize_2048")]
     pub n_ctx: usize,
+    /// Threads used while generating.
     #[builder(default = num_cpus::get())]
     #[serde(default = "num_cpus::get")]
     pub n_threads: usize,
+    /// Threads used while evaluating prompts, `n_threads` if unset.
+    #[serde(default)]
+    pub n_threads_batch: Option<usize>,
+    /// Maximum number of prompt tokens passed to a single decode call, longer prompts are
+    /// evaluated in chunks of this size.
+    #[builder(default = default_usize_2048())]
+    #[serde(default = "default_usize_2048")]
+    pub n_batch: usize,
+    /// Maximum number of tokens computed at once, each decode call is split into micro
+    /// batches of this size. Larger ones speed up prompts on a gpu and cost compute buffer
+    /// memory, on the cpu 512 is about as fast as it gets. 512 if unset, at most `n_batch`.
+    #[serde(default)]
+    pub n_ubatch: Option<usize>,
+    /// Replace `n_threads`, `n_threads_batch` and `n_batch` with values picked for this machine.
+    ///
+    /// Generation runs on the physical performance cores only, hyperthreads and efficiency
+    /// cores of hybrid cpus slow it down. Prompts are evaluated on all physical cores, in
+    /// batches of 512 tokens on the cpu and 2048 when layers are offloaded to a gpu.
+    #[builder(default)]
+    #[serde(default)]
+    pub auto_tune: bool,
+    /// How many forks of the context can be alive at once, see [`crate::Context::fork`].
+    ///
+    /// Forks share the kv cache of the context, so `n_ctx` has to hold the tokens of all of
+    /// them that are not part of the shared prefix.
+    #[builder(default)]
+    #[serde(default)]
+    pub max_forks: usize,
+    /// Decode on a single thread and use `seed` whenever a prediction asks for a random seed,
+    /// so the same model, seed and prompt always produce the same output.
+    #[builder(default)]
+    #[serde(default)]
+    pub deterministic: bool,
+    /// Back off the last token of a text evaluated with [`crate::Context::eval_text`] and let
+    /// the first generated token replace it, so a prompt ending mid-word or on whitespace
+    /// doesn't bias the completion. Chat messages end in the template's generation prompt
+    /// and are not healed.
+    #[builder(default)]
+    #[serde(default)]
+    pub token_healing: bool,
+    /// Tokenize the content of messages without parsing special tokens, so text like
+    /// `<|im_start|>system` in user input stays text instead of becoming a control token.
+    /// The chat template around the messages still produces its special tokens.
+    #[builder(default)]
+    #[serde(default)]
+    pub escape_special_tokens: bool,
+    /// Rope scaling used to run the model past its training context, e.g. an 8k model at
+    /// `n_ctx` 32768 with [`RopeScalingType::Yarn`] and `rope_freq_scale` 0.25.
+    #[builder(default)]
+    #[serde(default)]
+    pub rope_scaling_type: RopeScalingType,
+    /// Rope base frequency, 0 means taken from the model.
+    #[builder(default)]
+    #[serde(default)]
+    pub rope_freq_base: f32,
+    /// Rope frequency scaling factor, the training context divided by `n_ctx`.
+    /// 0 means taken from the model.
+    #[builder(default)]
+    #[serde(default)]
+    pub rope_freq_scale: f32,
+    /// YaRN extrapolation mix factor, negative means taken from the model.
+    #[builder(default = default_f32_minus_1())]
+    #[serde(default = "default_f32_minus_1")]
+    pub yarn_ext_factor: f32,
+    /// YaRN magnitude scaling factor.
+    #[builder(default = default_f32_1_0())]
+    #[serde(default = "default_f32_1_0")]
+    pub yarn_attn_factor: f32,
+    /// YaRN low correction dimension.
+    #[builder(default = default_f32_32_0())]
+    #[serde(default = "default_f32_32_0")]
+    pub yarn_beta_fast: f32,
+    /// YaRN high correction dimension.
+    #[builder(default = default_f32_1_0())]
+    #[serde(default = "default_f32_1_0")]
+    pub yarn_beta_slow: f32,
+    /// Training context of the model, 0 means taken from the model.
+    #[builder(default)]
+    #[serde(default)]
+    pub yarn_orig_ctx: u32,
+    /// Self-extend: tokens older than the last `grp_attn_w` share one position per group of
+    /// `grp_attn_n`, so a model reads about `grp_attn_n` times its training context without
+    /// rope scaling. `n_ctx` still has to hold every token. 1 turns it off.
+    #[builder(default = 1)]
+    #[serde(default = "default_usize_1")]
+    pub grp_attn_n: usize,
+    /// Recent tokens keeping a position of their own with self-extend, a multiple of
+    /// `grp_attn_n`.
+    #[builder(default = default_usize_512())]
+    #[serde(default = "default_usize_512")]
+    pub grp_attn_w: usize,
+    /// Classifier-free guidance: text evaluated on a second sequence, whose logits are pushed
+    /// away from while sampling, see [`ContextOptions::with_negative_prompt`].
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    /// How strongly predictions move away from the negative prompt, 1 doesn't change them.
+    #[builder(default = default_f32_1_0())]
+    #[serde(default = "default_f32_1_0")]
+    pub guidance_scale: f32,
+    /// Most prompt and generated tokens the context and its forks handle over their lifetime,
+    /// the tokens [`crate::Context::classify`] evaluates included. Predictions stop with
+    /// [`crate::error::Error::QuotaExceeded`] once it is used up, the tokens generated until
+    /// then have been passed to the callback. Evaluating a prompt fails the same way once the
+    /// quota is used up, a prompt started before that is evaluated whole.
+    #[serde(default)]
+    pub token_quota: Option<usize>,
 }
 
 impl Default for ContextOptions {
@@ -338,6 +850,376 @@ impl Default for ContextOptions {
     }
 }
 
+impl ContextOptions {
+    /// Steers predictions away from `text` with classifier-free guidance.
+    ///
+    /// The negative prompt is evaluated as is on a sequence of its own and stands in for
+    /// everything the context evaluated before its first prediction. Later messages and the
+    /// generated tokens are evaluated into both sequences, and tokens are sampled from
+    /// `guidance_scale * (positive - negative) + negative` of their log-probabilities. Every
+    /// generated token costs a second decode, and like a fork the negative prompt's sequence
+    /// takes cells of `n_ctx`. [`PredictOptions::lookup_draft`] is ignored with guidance.
+    pub fn with_negative_prompt(mut self, text: impl Into<String>, guidance_scale: f32) -> Self {
+        self.negative_prompt = Some(text.into());
+        self.guidance_scale = guidance_scale;
+        self
+    }
+
+    /// Micro batch size llama.cpp uses for these options.
+    pub(crate) fn n_ubatch(&self) -> usize {
+        self.n_ubatch
+            .unwrap_or(DEFAULT_N_UBATCH)
+            .clamp(1, self.n_batch.max(1))
+    }
+
+    /// Kv cache sequences of the context, its forks and their negative prompts.
+    pub(crate) fn n_sequences(&self) -> usize {
+        (self.max_forks + 1) * (1 + self.negative_prompt.is_some() as usize)
+    }
+}
+
+/// What [`crate::history::ChatHistory`] does when the next turn doesn't fit into the context.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum Overflow {
+    /// Drop the oldest messages that are not pinned.
+    #[default]
+    DropOldest,
+    /// Replace the oldest messages that are not pinned with a summary written by the model.
+    Summarize,
+}
+
+#[derive(Clone, Debug, bon::Builder, serde::Deserialize)]
+pub struct ChatHistoryOptions {
+    /// Tokens kept free for the answer, the history fills at most `n_ctx` minus these.
+    #[builder(default = default_usize_512())]
+    #[serde(default = "default_usize_512")]
+    pub reserve: usize,
+    #[builder(default)]
+    #[serde(default)]
+    pub overflow: Overflow,
+    /// Pin system messages when they are pushed, so they are never dropped.
+    #[builder(default = true)]
+    #[serde(default = "default_true")]
+    pub pin_system: bool,
+    /// Longest summary [`Overflow::Summarize`] asks the model for.
+    #[builder(default = default_usize_256())]
+    #[serde(default = "default_usize_256")]
+    pub summary_tokens: usize,
+}
+
+impl Default for ChatHistoryOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// How [`crate::rag::chunk`] splits documents.
+#[derive(Clone, Debug, bon::Builder, serde::Deserialize)]
+pub struct ChunkOptions {
+    /// Most tokens of a chunk.
+    #[builder(default = default_usize_256())]
+    #[serde(default = "default_usize_256")]
+    pub tokens: usize,
+    /// Tokens at the end of a chunk repeated at the start of the next, so a passage cut in
+    /// two is still found in one piece.
+    #[builder(default = default_usize_32())]
+    #[serde(default = "default_usize_32")]
+    pub overlap: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// What [`crate::history::ChatHistory::ask_with_context`] retrieves.
+#[derive(Clone, Debug, bon::Builder, serde::Deserialize)]
+pub struct RetrievalOptions {
+    /// Chunks put into the prompt, the most similar first.
+    #[builder(default = default_usize_4())]
+    #[serde(default = "default_usize_4")]
+    pub top_k: usize,
+    /// Chunks less similar to the query than this are left out, even if fewer than `top_k`
+    /// remain.
+    #[serde(default)]
+    pub min_score: Option<f32>,
+}
+
+impl Default for RetrievalOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Target type of [`crate::quantize`], named like in llama.cpp's `quantize` tool.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum QuantType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q2_K,
+    Q2_K_S,
+    Q3_K_S,
+    Q3_K_M,
+    Q3_K_L,
+    Q4_K_S,
+    Q4_K_M,
+    Q5_K_S,
+    Q5_K_M,
+    Q6_K,
+}
+
+impl From<QuantType> for llama_cpp::quantize::llama_ftype {
+    fn from(val: QuantType) -> Self {
+        use llama_cpp::quantize::*;
+        match val {
+            QuantType::F32 => LLAMA_FTYPE_ALL_F32,
+            QuantType::F16 => LLAMA_FTYPE_MOSTLY_F16,
+            QuantType::BF16 => LLAMA_FTYPE_MOSTLY_BF16,
+            QuantType::Q4_0 => LLAMA_FTYPE_MOSTLY_Q4_0,
+            QuantType::Q4_1 => LLAMA_FTYPE_MOSTLY_Q4_1,
+            QuantType::Q5_0 => LLAMA_FTYPE_MOSTLY_Q5_0,
+            QuantType::Q5_1 => LLAMA_FTYPE_MOSTLY_Q5_1,
+            QuantType::Q8_0 => LLAMA_FTYPE_MOSTLY_Q8_0,
+            QuantType::Q2_K => LLAMA_FTYPE_MOSTLY_Q2_K,
+            QuantType::Q2_K_S => LLAMA_FTYPE_MOSTLY_Q2_K_S,
+            QuantType::Q3_K_S => LLAMA_FTYPE_MOSTLY_Q3_K_S,
+            QuantType::Q3_K_M => LLAMA_FTYPE_MOSTLY_Q3_K_M,
+            QuantType::Q3_K_L => LLAMA_FTYPE_MOSTLY_Q3_K_L,
+            QuantType::Q4_K_S => LLAMA_FTYPE_MOSTLY_Q4_K_S,
+            QuantType::Q4_K_M => LLAMA_FTYPE_MOSTLY_Q4_K_M,
+            QuantType::Q5_K_S => LLAMA_FTYPE_MOSTLY_Q5_K_S,
+            QuantType::Q5_K_M => LLAMA_FTYPE_MOSTLY_Q5_K_M,
+            QuantType::Q6_K => LLAMA_FTYPE_MOSTLY_Q6_K,
+        }
+    }
+}
+
+impl QuantType {
+    /// The type of a GGUF file's `general.file_type`, `None` for one quantize can't produce.
+    pub fn from_file_type(file_type: u64) -> Option<Self> {
+        use QuantType::*;
+        [
+            F32, F16, BF16, Q4_0, Q4_1, Q5_0, Q5_1, Q8_0, Q2_K, Q2_K_S, Q3_K_S, Q3_K_M, Q3_K_L,
+            Q4_K_S, Q4_K_M, Q5_K_S, Q5_K_M, Q6_K,
+        ]
+        .into_iter()
+        .find(|t| llama_cpp::quantize::llama_ftype::from(*t) as u64 == file_type)
+    }
+}
+
+/// Tensor type [`crate::convert::hf_to_gguf`] writes the weights in, norms and other one
+/// dimensional tensors stay F32.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum ConvertType {
+    F32,
+    #[default]
+    F16,
+    BF16,
+}
+
+/// Receives the quantization progress between 0 and 1.
+pub type QuantizeProgressCallback = dyn Fn(f32) + Send + Sync + 'static;
+
+#[derive(Clone, bon::Builder, serde::Deserialize)]
+pub struct QuantizeOptions {
+    /// Threads used, `0` uses all hardware threads.
+    #[builder(default)]
+    #[serde(default)]
+    pub n_threads: usize,
+    /// Allow quantizing a model that is quantized already, at a loss of quality.
+    #[builder(default)]
+    #[serde(default)]
+    pub allow_requantize: bool,
+    /// Quantize `output.weight` too.
+    #[builder(default = true)]
+    #[serde(default = "default_true")]
+    pub quantize_output_tensor: bool,
+    /// Quantize every tensor to the target type instead of the k-quant mixes, which keep
+    /// sensitive tensors at a higher precision.
+    #[builder(default)]
+    #[serde(default)]
+    pub pure: bool,
+    #[serde(skip_deserializing)]
+    pub progress: Option<std::sync::Arc<Box<QuantizeProgressCallback>>>,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl QuantizeOptions {
+    /// Reports the progress to `callback`, see [`QuantizeProgressCallback`].
+    pub fn with_progress(mut self, callback: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.progress = Some(std::sync::Arc::new(Box::new(callback)));
+        self
+    }
+}
+
+/// Receives the conversion progress between 0 and 1, quantizing included.
+pub type ConvertProgressCallback = dyn Fn(f32) + Send + Sync + 'static;
+
+#[derive(Clone, bon::Builder, serde::Deserialize)]
+pub struct ConvertOptions {
+    #[builder(default)]
+    #[serde(default)]
+    pub out_type: ConvertType,
+    /// Quantize the converted model to this type, see [`crate::quantize`].
+    #[serde(default)]
+    pub quant_type: Option<QuantType>,
+    /// Options of the quantization, its progress is reported to `progress`.
+    #[builder(default)]
+    #[serde(default)]
+    pub quantize: QuantizeOptions,
+    #[serde(skip_deserializing)]
+    pub progress: Option<std::sync::Arc<Box<ConvertProgressCallback>>>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl ConvertOptions {
+    /// Reports the progress to `callback`, see [`ConvertProgressCallback`].
+    pub fn with_progress(mut self, callback: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        self.progress = Some(std::sync::Arc::new(Box::new(callback)));
+        self
+    }
+}
+
+/// The configurations measured by [`crate::bench::run`], every combination is run once.
+#[derive(Clone, Debug, serde::Deserialize, bon::Builder)]
+pub struct BenchOptions {
+    /// Prompt tokens evaluated per run.
+    #[builder(default = 512)]
+    #[serde(default = "default_usize_512")]
+    pub n_prompt: usize,
+    /// Tokens generated per run.
+    #[builder(default = 128)]
+    #[serde(default = "default_i32_128")]
+    pub n_gen: i32,
+    #[builder(default = vec![default_usize_2048()])]
+    #[serde(default = "default_bench_n_batch")]
+    pub n_batch: Vec<usize>,
+    #[builder(default = vec![num_cpus::get()])]
+    #[serde(default = "default_bench_n_threads")]
+    pub n_threads: Vec<usize>,
+    /// `0` runs on the cpu, `-1` offloads every layer.
+    #[builder(default = vec![-1])]
+    #[serde(default = "default_bench_n_gpu_layers")]
+    pub n_gpu_layers: Vec<i32>,
+    /// Library variants to measure, see [`crate::devices::variants`]. Empty measures only the
+    /// variant chosen automatically.
+    #[builder(default)]
+    #[serde(default)]
+    pub variants: Vec<String>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+fn default_usize_1() -> usize {
+    1
+}
+
+fn default_usize_3() -> usize {
+    3
+}
+
+fn default_usize_4() -> usize {
+    4
+}
+
+fn default_usize_32() -> usize {
+    32
+}
+
+fn default_usize_512() -> usize {
+    512
+}
+
+fn default_usize_256() -> usize {
+    256
+}
+
+fn default_i32_128() -> i32 {
+    128
+}
+
+fn default_bench_n_batch() -> Vec<usize> {
+    vec![default_usize_2048()]
+}
+
+fn default_bench_n_threads() -> Vec<usize> {
+    vec![num_cpus::get()]
+}
+
+fn default_bench_n_gpu_layers() -> Vec<i32> {
+    vec![-1]
+}
+
+/// How the token embeddings of a text are combined into one vector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum Pooling {
+    /// Mean of all token embeddings.
+    #[default]
+    Mean,
+    /// Embedding of the first token, for models trained with a classification token.
+    Cls,
+    /// Embedding of the last token, for decoder-only embedding models.
+    Last,
+}
+
+impl From<Pooling> for llama_cpp::context::params::LlamaPoolingType {
+    fn from(val: Pooling) -> Self {
+        match val {
+            Pooling::Mean => llama_cpp::context::params::LlamaPoolingType::Mean,
+            Pooling::Cls => llama_cpp::context::params::LlamaPoolingType::Cls,
+            Pooling::Last => llama_cpp::context::params::LlamaPoolingType::Last,
+        }
+    }
+}
+
+/// Options of [`crate::Model::embed_batch`].
+#[derive(Clone, Debug, bon::Builder, serde::Deserialize)]
+pub struct EmbeddingOptions {
+    #[builder(default)]
+    #[serde(default)]
+    pub pooling: Pooling,
+    /// Scale every embedding to length 1, so the dot product is the cosine similarity.
+    #[builder(default = true)]
+    #[serde(default = "default_true")]
+    pub normalize: bool,
+    /// Maximum number of tokens decoded at once, as many texts as fit are packed into one
+    /// batch. Raised to the longest text if that doesn't fit.
+    #[builder(default = default_usize_2048())]
+    #[serde(default = "default_usize_2048")]
+    pub n_batch: usize,
+    #[builder(default = num_cpus::get())]
+    #[serde(default = "num_cpus::get")]
+    pub n_threads: usize,
+}
+
+impl Default for EmbeddingOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
 #[derive(bon::Builder)]
 pub struct NebulaOptions {
     #[builder(default = -1)]
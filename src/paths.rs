@@ -0,0 +1,211 @@
+//! Locations of everything nebula keeps on disk.
+//!
+//! Regenerable artifacts (native dependencies, downloaded models, converted projectors) live
+//! under [`cache_dir`], user state (saved sessions) under [`config_dir`]. Each base
+//! directory is resolved in this order: an explicit `set_*` override, the `NEBULA_CACHE_DIR` /
+//! `NEBULA_CONFIG_DIR` environment variables, the platform convention (XDG on Linux,
+//! `~/Library` on macOS, `%LOCALAPPDATA%` / `%APPDATA%` on Windows).
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+#[cfg(feature = "embeddings")]
+use hf_hub::api::sync::{Api, ApiBuilder, ApiError};
+
+use crate::{error::Error, Result};
+
+const APP_NAME: &str = "nebula";
+
+#[derive(Default)]
+struct Overrides {
+    cache: Option<PathBuf>,
+    config: Option<PathBuf>,
+    dependencies: Option<PathBuf>,
+}
+
+lazy_static::lazy_static! {
+    static ref OVERRIDES: RwLock<Overrides> = RwLock::new(Overrides::default());
+}
+
+#[cfg(not(target_os = "windows"))]
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|h| !h.is_empty())
+        .map(PathBuf::from)
+}
+
+fn env_dir(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+fn platform_cache_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    {
+        env_dir("LOCALAPPDATA").map(|p| p.join(APP_NAME).join("cache"))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        home_dir().map(|p| p.join("Library").join("Caches").join(APP_NAME))
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        env_dir("XDG_CACHE_HOME")
+            .or_else(|| home_dir().map(|p| p.join(".cache")))
+            .map(|p| p.join(APP_NAME))
+    }
+}
+
+fn platform_config_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    {
+        env_dir("APPDATA").map(|p| p.join(APP_NAME))
+    }
+    #[cfg(target_os = "macos")]
+    {
+        home_dir().map(|p| p.join("Library").join("Application Support").join(APP_NAME))
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        env_dir("XDG_CONFIG_HOME")
+            .or_else(|| home_dir().map(|p| p.join(".config")))
+            .map(|p| p.join(APP_NAME))
+    }
+}
+
+fn set(f: impl FnOnce(&mut Overrides)) {
+    let mut o = OVERRIDES.write().unwrap_or_else(|e| e.into_inner());
+    f(&mut o);
+}
+
+fn get(f: impl FnOnce(&Overrides) -> Option<PathBuf>) -> Option<PathBuf> {
+    let o = OVERRIDES.read().unwrap_or_else(|e| e.into_inner());
+    f(&o)
+}
+
+/// Overrides the base directory for regenerable artifacts.
+pub fn set_cache_dir(path: impl Into<PathBuf>) {
+    let path = path.into();
+    set(|o| o.cache = Some(path));
+}
+
+/// Overrides the base directory for user state.
+pub fn set_config_dir(path: impl Into<PathBuf>) {
+    let path = path.into();
+    set(|o| o.config = Some(path));
+}
+
+/// Overrides the directory the native llama.cpp libraries are loaded from.
+///
+/// The layout below it is `{windows,linux,darwin}/{x86_64,arm64}/{variant}`.
+pub fn set_dependencies_dir(path: impl Into<PathBuf>) -> Result<()> {
+    let path = path.into();
+    resource_path::set(path.clone()).map_err(Error::Unknown)?;
+    set(|o| o.dependencies = Some(path));
+    Ok(())
+}
+
+pub fn cache_dir() -> PathBuf {
+    get(|o| o.cache.clone())
+        .or_else(|| env_dir("NEBULA_CACHE_DIR"))
+        .or_else(platform_cache_dir)
+        .unwrap_or_else(|| std::env::temp_dir().join(APP_NAME).join("cache"))
+}
+
+pub fn config_dir() -> PathBuf {
+    get(|o| o.config.clone())
+        .or_else(|| env_dir("NEBULA_CONFIG_DIR"))
+        .or_else(platform_config_dir)
+        .unwrap_or_else(|| std::env::temp_dir().join(APP_NAME).join("config"))
+}
+
+pub fn dependencies_dir() -> PathBuf {
+    get(|o| o.dependencies.clone())
+        .or_else(|| env_dir("NEBULA_DEPENDENCIES_DIR"))
+        .unwrap_or_else(|| cache_dir().join("dependencies"))
+}
+
+/// Models downloaded from the Hugging Face hub, in its cache layout.
+pub fn models_dir() -> PathBuf {
+    cache_dir().join("models")
+}
+
+/// Vision projectors converted to another [`crate::options::ProjectorPrecision`].
+pub fn projectors_dir() -> PathBuf {
+    cache_dir().join("projectors")
+}
+
+/// Default location of [`crate::session::ChatSession`]s, see
+/// [`crate::session::ChatSession::path`].
+pub fn sessions_dir() -> PathBuf {
+    config_dir().join("sessions")
+}
+
+/// Creates `path` with all its parents and returns it.
+pub fn ensure(path: impl AsRef<Path>) -> Result<PathBuf> {
+    std::fs::create_dir_all(path.as_ref())?;
+    Ok(path.as_ref().to_path_buf())
+}
+
+/// A Hugging Face hub client downloading into [`models_dir`].
+#[cfg(feature = "embeddings")]
+pub(crate) fn hub_api() -> std::result::Result<Api, ApiError> {
+    ApiBuilder::new().with_cache_dir(models_dir()).build()
+}
+
+/// Points the native library loader at [`dependencies_dir`] unless it was configured already.
+pub(crate) fn init_dependencies() -> Result<()> {
+    if resource_path::get().is_err() {
+        resource_path::set(dependencies_dir()).map_err(Error::Unknown)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the overrides and variables are process wide, one test checks them in order and under
+    // the temp dir, where other tests may write meanwhile
+    #[test]
+    fn resolution_test() {
+        let saved = ["NEBULA_CACHE_DIR", "NEBULA_CONFIG_DIR"].map(|v| (v, std::env::var_os(v)));
+        let tmp = std::env::temp_dir().join("nebula-paths-test");
+        set(|o| (o.cache, o.config) = (None, None));
+        std::env::remove_var("NEBULA_CACHE_DIR");
+        std::env::remove_var("NEBULA_CONFIG_DIR");
+
+        // the platform convention
+        if let Some(dir) = platform_cache_dir() {
+            assert_eq!(cache_dir(), dir);
+            assert!(dir.ends_with(APP_NAME) || dir.ends_with("cache"));
+        }
+        if let Some(dir) = platform_config_dir() {
+            assert_eq!(config_dir(), dir);
+        }
+
+        // the environment over the platform
+        std::env::set_var("NEBULA_CACHE_DIR", tmp.join("env-cache"));
+        std::env::set_var("NEBULA_CONFIG_DIR", tmp.join("env-config"));
+        assert_eq!(cache_dir(), tmp.join("env-cache"));
+        assert_eq!(models_dir(), tmp.join("env-cache").join("models"));
+        assert_eq!(projectors_dir(), tmp.join("env-cache").join("projectors"));
+        assert_eq!(sessions_dir(), tmp.join("env-config").join("sessions"));
+
+        // overrides over the environment
+        set_cache_dir(tmp.join("cache"));
+        set_config_dir(tmp.join("config"));
+        assert_eq!(models_dir(), tmp.join("cache").join("models"));
+        assert_eq!(sessions_dir(), tmp.join("config").join("sessions"));
+
+        set(|o| (o.cache, o.config) = (None, None));
+        for (name, value) in saved {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
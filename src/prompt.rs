@@ -0,0 +1,94 @@
+//! Fills `{name}` placeholders in prompt templates.
+use crate::{error::Error, Result};
+
+/// How [`format`] treats a template.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum PromptFormat {
+    /// The template is the prompt, braces and all.
+    Raw,
+    /// Placeholders with a value are replaced, anything else, like the braces of a JSON
+    /// example, is kept as written.
+    #[default]
+    Lenient,
+    /// Every placeholder needs a value and literal braces are written `{{` and `}}`.
+    Strict,
+}
+
+/// Replaces the `{name}` placeholders of `template` with the values of `vars`.
+///
+/// Only [`PromptFormat::Strict`] fails, on unknown placeholders and unmatched braces.
+pub fn format(template: &str, vars: &[(&str, &str)], mode: PromptFormat) -> Result<String> {
+    if mode == PromptFormat::Raw {
+        return Ok(template.to_string());
+    }
+    let strict = mode == PromptFormat::Strict;
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        res.push_str(&rest[..i]);
+        let at = &rest[i..];
+        if strict && (at.starts_with("{{") || at.starts_with("}}")) {
+            res.push_str(&at[..1]);
+            rest = &at[2..];
+            continue;
+        }
+        if let Some(name) = at
+            .strip_prefix('{')
+            .and_then(|s| s.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| is_placeholder(name))
+        {
+            match vars.iter().find(|(k, _)| *k == name) {
+                Some((_, value)) => {
+                    res.push_str(value);
+                    rest = &at[name.len() + 2..];
+                    continue;
+                }
+                None if strict => {
+                    return Err(Error::PromptTemplate(format!("no value for {{{name}}}")));
+                }
+                None => {}
+            }
+        }
+        if strict {
+            return Err(Error::PromptTemplate(format!(
+                "unmatched `{}` at byte {}",
+                &at[..1],
+                template.len() - at.len()
+            )));
+        }
+        res.push_str(&at[..1]);
+        rest = &at[1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+fn is_placeholder(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format, PromptFormat};
+
+    #[test]
+    fn prompt_format_test() {
+        let vars = [("name", "nebula"), ("lang", "rust")];
+        let template = r#"Answer {name} in {lang} as {"id": {id}} or {"#;
+        assert_eq!(
+            format(template, &vars, PromptFormat::Lenient).unwrap(),
+            r#"Answer nebula in rust as {"id": {id}} or {"#
+        );
+        assert_eq!(
+            format(template, &vars, PromptFormat::Raw).unwrap(),
+            template
+        );
+        assert!(format(template, &vars, PromptFormat::Strict).is_err());
+        assert!(format("{missing}", &vars, PromptFormat::Strict).is_err());
+        assert_eq!(
+            format("{{\"lang\": \"{lang}\"}}", &vars, PromptFormat::Strict).unwrap(),
+            "{\"lang\": \"rust\"}"
+        );
+    }
+}
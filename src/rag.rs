@@ -0,0 +1,348 @@
+//! Answers grounded in local documents, on top of [`Model::embed_batch`] and
+//! [`crate::history::ChatHistory`].
+//!
+//! Documents are split into chunks of a few hundred tokens by [`chunk`] and indexed in a
+//! [`DocumentStore`]. [`crate::history::ChatHistory::ask_with_context`] puts the chunks most
+//! similar to a question into the prompt and returns the ids of the chunks the answer cites.
+//! [`MemoryStore`] keeps the index in memory, implement the trait to search another one.
+use crate::{
+    error::Error,
+    options::{ChunkOptions, EmbeddingOptions},
+    Model, Result,
+};
+
+/// A piece of a document.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Chunk {
+    /// `document#index` for chunks of [`MemoryStore::add_document`], answers cite it.
+    pub id: String,
+    pub document: String,
+    pub text: String,
+}
+
+/// A chunk found for a query, `score` is its cosine similarity to the query.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Retrieved {
+    pub chunk: Chunk,
+    pub score: f32,
+}
+
+/// Answer of [`crate::history::ChatHistory::ask_with_context`].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Answer {
+    pub text: String,
+    /// Ids of the sources the answer cites, in the order they are first cited.
+    pub citations: Vec<String>,
+    /// The chunks that were in the prompt.
+    pub sources: Vec<Retrieved>,
+}
+
+/// An index of chunks searched by questions.
+pub trait DocumentStore {
+    /// The `top_k` chunks most similar to `query`, the most similar first.
+    fn retrieve(&self, query: &str, top_k: usize) -> Result<Vec<Retrieved>>;
+}
+
+/// Chunks and their embeddings in memory, every search compares the query with all of them.
+///
+/// Fast enough for the documents of a desktop application, tens of thousands of chunks.
+pub struct MemoryStore {
+    model: Model,
+    options: EmbeddingOptions,
+    // embeddings are kept at length 1, the dot product is the cosine similarity
+    entries: Vec<(Chunk, Vec<f32>)>,
+}
+
+impl MemoryStore {
+    /// An empty store embedding with `model`, which can be another model than the one
+    /// answering.
+    pub fn new(model: &Model, options: EmbeddingOptions) -> Self {
+        Self {
+            model: model.clone(),
+            options,
+            entries: vec![],
+        }
+    }
+
+    /// Splits `text` into chunks with the tokenizer of the store's model, embeds and adds
+    /// them. Returns the chunks, their ids are `document#0`, `document#1` and so on.
+    pub fn add_document(
+        &mut self,
+        document: &str,
+        text: &str,
+        options: &ChunkOptions,
+    ) -> Result<Vec<Chunk>> {
+        let chunks: Vec<Chunk> = chunk(&self.model, text, options)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| Chunk {
+                id: format!("{document}#{i}"),
+                document: document.to_string(),
+                text,
+            })
+            .collect();
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        let embeddings = self.model.embed_batch(&texts, self.options.clone())?;
+        for (chunk, embedding) in chunks.iter().zip(embeddings) {
+            self.insert(chunk.clone(), embedding)?;
+        }
+        Ok(chunks)
+    }
+
+    /// Adds a chunk embedded elsewhere, with the same model as the rest of the store.
+    pub fn insert(&mut self, chunk: Chunk, embedding: Vec<f32>) -> Result<()> {
+        if let Some((_, first)) = self.entries.first() {
+            if first.len() != embedding.len() {
+                return Err(Error::EmbeddingSize(first.len(), embedding.len()));
+            }
+        }
+        self.entries.push((chunk, normalize(embedding)));
+        Ok(())
+    }
+
+    /// Removes the chunks of `document`, returns how many there were.
+    pub fn remove_document(&mut self, document: &str) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|(c, _)| c.document != document);
+        len - self.entries.len()
+    }
+
+    /// Number of chunks in the store.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The `top_k` chunks most similar to `embedding`, the most similar first.
+    pub fn search(&self, embedding: &[f32], top_k: usize) -> Result<Vec<Retrieved>> {
+        if let Some((_, first)) = self.entries.first() {
+            if first.len() != embedding.len() {
+                return Err(Error::EmbeddingSize(first.len(), embedding.len()));
+            }
+        }
+        let query = normalize(embedding.to_vec());
+        let mut res: Vec<Retrieved> = self
+            .entries
+            .iter()
+            .map(|(chunk, e)| Retrieved {
+                chunk: chunk.clone(),
+                score: e.iter().zip(&query).map(|(a, b)| a * b).sum(),
+            })
+            .collect();
+        res.sort_by(|a, b| b.score.total_cmp(&a.score));
+        res.truncate(top_k);
+        Ok(res)
+    }
+}
+
+impl DocumentStore for MemoryStore {
+    fn retrieve(&self, query: &str, top_k: usize) -> Result<Vec<Retrieved>> {
+        let embedding = self
+            .model
+            .embed_batch(&[query], self.options.clone())?
+            .remove(0);
+        self.search(&embedding, top_k)
+    }
+}
+
+fn normalize(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+/// Splits `text` into chunks of about `options.tokens` tokens of `model`.
+///
+/// Chunks end at the end of a sentence or line where possible, sentences longer than a chunk
+/// are split between words. Only a single word can make a chunk longer.
+pub fn chunk(model: &Model, text: &str, options: &ChunkOptions) -> Result<Vec<String>> {
+    split(text, options, |s| Ok(model.tokenize(s)?.len()))
+}
+
+/// [`chunk`] with tokens counted by `count`.
+fn split(
+    text: &str,
+    options: &ChunkOptions,
+    count: impl Fn(&str) -> Result<usize>,
+) -> Result<Vec<String>> {
+    let max = options.tokens.max(1);
+    let overlap = options.overlap.min(max / 2);
+    let mut pieces = vec![];
+    for sentence in sentences(text) {
+        let n = count(sentence)?;
+        if n <= max {
+            pieces.push((sentence, n));
+            continue;
+        }
+        for word in sentence.split_inclusive(char::is_whitespace) {
+            pieces.push((word, count(word)?));
+        }
+    }
+    let mut chunks = vec![];
+    let mut current: Vec<(&str, usize)> = vec![];
+    let mut n_current = 0;
+    // whether current has pieces no chunk holds yet
+    let mut fresh = false;
+    for (piece, n) in pieces {
+        if n_current + n > max && fresh {
+            chunks.push(join(&current));
+            // the end of the chunk starts the next one
+            let mut n_kept = 0;
+            let mut kept = 0;
+            while kept + 1 < current.len()
+                && n_kept + current[current.len() - 1 - kept].1 <= overlap
+            {
+                n_kept += current[current.len() - 1 - kept].1;
+                kept += 1;
+            }
+            current.drain(..current.len() - kept);
+            n_current = n_kept;
+            fresh = false;
+        }
+        while n_current + n > max && !current.is_empty() {
+            n_current -= current.remove(0).1;
+        }
+        current.push((piece, n));
+        n_current += n;
+        fresh = true;
+    }
+    if fresh {
+        chunks.push(join(&current));
+    }
+    Ok(chunks.into_iter().filter(|c| !c.is_empty()).collect())
+}
+
+/// Sentences and lines of `text` with the whitespace after them, together they are `text`.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let mut start = 0;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        if c == '\n' || (c.is_whitespace() && matches!(prev, '.' | '!' | '?')) {
+            let end = i + c.len_utf8();
+            res.push(&text[start..end]);
+            start = end;
+        }
+        prev = c;
+    }
+    if start < text.len() {
+        res.push(&text[start..]);
+    }
+    res
+}
+
+fn join(pieces: &[(&str, usize)]) -> String {
+    pieces
+        .iter()
+        .map(|p| p.0)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// The user message asking `query` about `sources`.
+pub(crate) fn prompt(query: &str, sources: &[Retrieved]) -> String {
+    let example = sources.first().map_or("doc#0", |s| s.chunk.id.as_str());
+    let mut prompt = format!(
+        "Answer the question using only the sources below. Cite the sources you use by their \
+         id in square brackets, like [{example}]. If the sources don't answer the question, \
+         say so.\n\n"
+    );
+    for source in sources {
+        prompt.push_str(&format!("[{}] {}\n\n", source.chunk.id, source.chunk.text));
+    }
+    prompt.push_str(&format!("Question: {query}"));
+    prompt
+}
+
+/// Ids of `sources` cited in `answer` as `[id]` or `[id, id]`, in the order they are first
+/// cited.
+pub(crate) fn citations(answer: &str, sources: &[Retrieved]) -> Vec<String> {
+    let mut res: Vec<String> = vec![];
+    let mut rest = answer;
+    while let Some(open) = rest.find('[') {
+        let inner = &rest[open + 1..];
+        let Some(close) = inner.find(']') else {
+            break;
+        };
+        for id in inner[..close].split(',').map(str::trim) {
+            if sources.iter().any(|s| s.chunk.id == id) && !res.iter().any(|r| r == id) {
+                res.push(id.to_string());
+            }
+        }
+        rest = &inner[close + 1..];
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::{citations, prompt, sentences, split, Chunk, Retrieved};
+    use crate::options::ChunkOptions;
+
+    fn words(text: &str) -> crate::Result<usize> {
+        Ok(text.split_whitespace().count())
+    }
+
+    #[test]
+    fn split_test() {
+        let text = "One two three. Four five six.\nSeven eight nine ten. Eleven!";
+        assert_eq!(
+            sentences(text),
+            [
+                "One two three. ",
+                "Four five six.\n",
+                "Seven eight nine ten. ",
+                "Eleven!"
+            ]
+        );
+        let options = ChunkOptions {
+            tokens: 7,
+            overlap: 3,
+        };
+        assert_eq!(
+            split(text, &options, words).unwrap(),
+            [
+                "One two three. Four five six.",
+                // the last sentence of a chunk is repeated if it's within the overlap
+                "Four five six.\nSeven eight nine ten.",
+                "Eleven!",
+            ]
+        );
+        // a sentence longer than a chunk is split between words
+        let options = ChunkOptions {
+            tokens: 4,
+            overlap: 0,
+        };
+        assert_eq!(
+            split("a b c d e f g h i j.", &options, words).unwrap(),
+            ["a b c d", "e f g h", "i j."]
+        );
+        assert!(split("", &options, words).unwrap().is_empty());
+    }
+
+    #[test]
+    fn citations_test() {
+        let sources: Vec<Retrieved> = ["guide#0", "guide#1", "faq#0"]
+            .iter()
+            .map(|id| Retrieved {
+                chunk: Chunk {
+                    id: id.to_string(),
+                    document: id.split('#').next().unwrap().to_string(),
+                    text: format!("text of {id}"),
+                },
+                score: 1.0,
+            })
+            .collect();
+        let prompt = prompt("How?", &sources);
+        assert!(prompt.contains("[guide#1] text of guide#1\n\n"));
+        assert!(prompt.ends_with("Question: How?"));
+        let answer = "Like this [faq#0]. Also [guide#0, faq#0] and [other#3] [guide#1";
+        assert_eq!(citations(answer, &sources), ["faq#0", "guide#0"]);
+    }
+}
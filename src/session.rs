@@ -0,0 +1,193 @@
+//! Conversations saved to disk and resumed later.
+//!
+//! A [`ChatSession`] bundles the messages of a conversation, the options to predict with,
+//! the model they belong to and optionally the compressed state of the context that
+//! evaluated them. Resuming restores that state instead of evaluating every message again,
+//! and falls back to evaluating them when the state can't be used.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::prelude::*;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{
+    error::Error,
+    options::{ContextOptions, Message, PredictOptions},
+    Context, Model, Result,
+};
+
+/// Version of the session format, bumped on incompatible changes.
+const SESSION_VERSION: u32 = 1;
+
+/// Room for what [`Context::state_bytes`] writes around the llama.cpp state.
+const STATE_HEADER_MAX: usize = 64 * 1024;
+
+/// The model file a session was created with.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ModelIdentity {
+    /// File name of the model, without its directory.
+    pub file_name: String,
+    /// Size of the model file in bytes.
+    pub file_size: u64,
+    /// `general.architecture` of the model.
+    pub architecture: String,
+}
+
+impl ModelIdentity {
+    pub fn of(model: &Model) -> Result<Self> {
+        let path = Path::new(model.backend.name()?);
+        Ok(Self {
+            file_name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            file_size: std::fs::metadata(path)?.len(),
+            architecture: model.architecture()?.name().to_string(),
+        })
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct SavedState {
+    // a state only restores into a context of the same size
+    n_ctx: usize,
+    // leading messages of the session the state holds
+    n_messages: usize,
+    // Context::state_bytes, zlib compressed and base64 encoded
+    data: String,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChatSession {
+    version: u32,
+    pub model: ModelIdentity,
+    pub messages: Vec<Message>,
+    pub predict_options: PredictOptions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<SavedState>,
+}
+
+impl ChatSession {
+    /// An empty conversation with `model`.
+    pub fn new(model: &Model, predict_options: PredictOptions) -> Result<Self> {
+        Ok(Self {
+            version: SESSION_VERSION,
+            model: ModelIdentity::of(model)?,
+            messages: vec![],
+            predict_options,
+            state: None,
+        })
+    }
+
+    /// Keeps the state of `context`, which has evaluated the messages of the session so far,
+    /// so resuming doesn't evaluate them again. Messages added later are evaluated on resume.
+    pub fn save_state(&mut self, context: &Context) -> Result<()> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+        encoder.write_all(&context.state_bytes()?)?;
+        self.state = Some(SavedState {
+            n_ctx: context._options.n_ctx,
+            n_messages: self.messages.len(),
+            data: BASE64_STANDARD.encode(encoder.finish()?),
+        });
+        Ok(())
+    }
+
+    pub fn clear_state(&mut self) {
+        self.state = None;
+    }
+
+    pub fn has_state(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// Where a session called `name` is kept by default, in [`crate::paths::sessions_dir`].
+    pub fn path(name: &str) -> PathBuf {
+        crate::paths::sessions_dir().join(format!("{name}.json"))
+    }
+
+    /// Writes the session as JSON to `path`, creating its directory.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        if let Some(dir) = path.as_ref().parent() {
+            crate::paths::ensure(dir)?;
+        }
+        let mut w = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let session: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if session.version != SESSION_VERSION {
+            return Err(Error::InvalidState(format!(
+                "unsupported session version {}",
+                session.version
+            )));
+        }
+        Ok(session)
+    }
+
+    /// A context of `model` continuing the conversation, ready to predict the next answer.
+    ///
+    /// The saved state is restored if it was taken with the same `n_ctx` and only the
+    /// messages after it are evaluated, otherwise all messages are. Fails with
+    /// [`Error::InvalidState`] if the session belongs to another model.
+    pub fn resume(&self, model: &Model, options: ContextOptions) -> Result<Context> {
+        let identity = ModelIdentity::of(model)?;
+        if identity != self.model {
+            return Err(Error::InvalidState(format!(
+                "the session belongs to {} ({}, {} bytes), not {} ({}, {} bytes)",
+                self.model.file_name,
+                self.model.architecture,
+                self.model.file_size,
+                identity.file_name,
+                identity.architecture,
+                identity.file_size
+            )));
+        }
+        let mut context = model.context(options.clone())?;
+        let mut n_restored = 0;
+        if let Some(state) = self
+            .state
+            .as_ref()
+            .filter(|s| s.n_ctx == options.n_ctx && s.n_messages <= self.messages.len())
+        {
+            match restore(&mut context, &state.data) {
+                Ok(()) => n_restored = state.n_messages,
+                Err(e) => {
+                    tracing::warn!(error = %e, "saved context state not restored");
+                    // the failed restore may have left part of the state behind
+                    drop(context);
+                    context = model.context(options)?;
+                }
+            }
+        }
+        let pending = self.messages[n_restored..].to_vec();
+        if !pending.is_empty() {
+            context.eval(pending)?;
+        }
+        Ok(context)
+    }
+}
+
+fn restore(context: &mut Context, data: &str) -> Result<()> {
+    let compressed = BASE64_STANDARD
+        .decode(data)
+        .map_err(|e| Error::InvalidState(e.to_string()))?;
+    // a damaged or hostile session must not inflate to more than a state of this context,
+    // restore_state checks the checksum of what's left before llama.cpp reads it
+    let limit = context.state_size() + STATE_HEADER_MAX;
+    let mut state = vec![];
+    ZlibDecoder::new(&compressed[..])
+        .take(limit as u64 + 1)
+        .read_to_end(&mut state)?;
+    if state.len() > limit {
+        return Err(Error::InvalidState(
+            "the saved state is larger than a state of the context".to_string(),
+        ));
+    }
+    context.restore_state(&state)
+}
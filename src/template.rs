@@ -0,0 +1,670 @@
+//! Renders chat messages into the prompt format of a model.
+//!
+//! Templates are given by name, like `"chatml"` or `"llama3"`, or as the jinja source stored
+//! in the `tokenizer.chat_template` gguf metadata, which is matched against the known formats.
+//! Unknown templates fall back to chatml.
+use std::io::{BufWriter, Write};
+
+use crate::{
+    error::Error,
+    options::{Message, Role},
+    Result,
+};
+
+/// A part of a rendered prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Templated {
+    Str(String),
+    Image(Vec<u8>),
+}
+
+/// Renders `messages` with `template`, images are split out into their own parts.
+///
+/// With `add_generation_prompt` the prompt ends with the header of an assistant turn.
+pub fn render(
+    messages: Vec<Message>,
+    template: &str,
+    add_generation_prompt: bool,
+) -> Result<Vec<Templated>> {
+    let (msgs, add_ass) = (messages, add_generation_prompt);
+    let mut res = vec![];
+    if template == "chatml" || template.contains("<|im_start|>") {
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.images.is_empty() {
+                    writeln!(buf, "<|im_start|>{}\n{}<|im_end|>", msg.role, msg.content)?;
+                } else {
+                    writeln!(buf, "<|im_start|>{}", msg.role)?;
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                    writeln!(buf, "{}<|im_end|>", msg.content)?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            writeln!(buf, "<|im_start|>assistant")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "llama2" || template == "mistral" || template.contains("[INST]") {
+        // llama2 template and its variants
+        // [variant] support system message
+        let support_system_message = template.contains("<<SYS>>") || template == "mistral";
+        // [variant] space before + after response
+        let space_around_response = template.contains("' ' + eos_token");
+        // [variant] add BOS inside history
+        let add_bos_inside_history = template.contains("bos_token + '[INST]");
+        // [variant] trim spaces from the input message
+        let strip_message = template.contains("content.strip()");
+        // construct the prompt
+        let mut is_inside_turn = true; // skip BOS at the beginning
+        let mut buf = BufWriter::new(Vec::new());
+        write!(buf, "[INST] ")?;
+        let buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
+            let content = if strip_message {
+                msg.content.trim().to_string()
+            } else {
+                msg.content
+            };
+            if !is_inside_turn {
+                is_inside_turn = true;
+                if add_bos_inside_history {
+                    write!(buf, "<s>[INST] ")?;
+                } else {
+                    write!(buf, "[INST] ")?;
+                }
+            }
+            if msg.role == Role::System {
+                if support_system_message {
+                    write!(buf, "<<SYS>>\n{}\n<</SYS>>\n\n", content)?;
+                } else {
+                    // if the model does not support system message, we still include it in the first message, but without <<SYS>>
+                    writeln!(buf, "{}", content)?;
+                }
+            } else if msg.role == Role::User {
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                write!(buf, "{} [/INST]", content)?;
+            } else {
+                write!(
+                    buf,
+                    "{}{}{}</s>",
+                    if space_around_response { " " } else { "" },
+                    content,
+                    if space_around_response { " " } else { "" }
+                )?;
+                is_inside_turn = false;
+            }
+            Ok::<_, Error>(buf)
+        })?;
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        // llama2 templates seem to not care about "add_generation_prompt"
+        Ok(res)
+    } else if template == "phi3"
+        || (template.contains("<|assistant|>") && template.contains("<|end|>"))
+    {
+        // Phi 3
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                writeln!(buf, "<|{}|>", msg.role)?;
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                writeln!(buf, "{}<|end|>", msg.content)?;
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            writeln!(buf, "<|assistant|>")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "zephyr" || template.contains("<|user|>") {
+        // zephyr template
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                writeln!(buf, "<|{}|>", msg.role)?;
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                writeln!(buf, "{}<|endoftext|>", msg.content)?;
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            writeln!(buf, "<|assistant|>")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "monarch" || template.contains("bos_token + message['role']") {
+        // mlabonne/AlphaMonarch-7B template (the <s> is included inside history)
+        let mut buf = msgs.into_iter().enumerate().try_fold(
+            BufWriter::new(Vec::new()),
+            |mut buf, (i, msg)| {
+                writeln!(buf, "{}{}", if i == 0 { "" } else { "<s>" }, msg.role)?;
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                writeln!(buf, "{}</s>", msg.content)?;
+                Ok::<_, Error>(buf)
+            },
+        )?;
+        if add_ass {
+            writeln!(buf, "<s>assistant")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "gemma" || template == "gemma2" || template.contains("<start_of_turn>") {
+        // google/gemma-7b-it
+        let mut system_prompt = "".to_string();
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    // there is no system message for gemma, but we will merge it with user prompt, so nothing is broken
+                    system_prompt = msg.content.trim().to_string();
+                } else {
+                    writeln!(
+                        buf,
+                        "<start_of_turn>{}",
+                        if msg.role == Role::Assistant {
+                            "model".to_string()
+                        } else {
+                            msg.role.to_string()
+                        }
+                    )?;
+                    if !system_prompt.is_empty() && msg.role == Role::User {
+                        write!(buf, "{}\n\n", system_prompt)?;
+                        system_prompt = "".to_string();
+                    }
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    writeln!(buf, "{}<end_of_turn>", msg.content.trim())?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            writeln!(buf, "<start_of_turn>model")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "orion" || template.contains("'\\n\\nAssistant: ' + eos_token") {
+        // OrionStarAI/Orion-14B-Chat
+        let mut system_prompt = "".to_string();
+        let buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    // there is no system message support, we will merge it with user prompt
+                    system_prompt = msg.content;
+                } else if msg.role == Role::User {
+                    write!(buf, "Human: ")?;
+                    if !system_prompt.is_empty() && msg.role == Role::User {
+                        write!(buf, "{}\n\n", system_prompt)?;
+                        system_prompt = "".to_string();
+                    }
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}\n\nAssistant: </s>", msg.content)?;
+                } else {
+                    write!(buf, "{}</s>", msg.content)?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "openchat" || template.contains("GPT4 Correct ") {
+        // openchat/openchat-3.5-0106,
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    write!(buf, "{}<|end_of_turn|>", msg.content)?;
+                } else {
+                    write!(
+                        buf,
+                        "GPT4 Correct {}: ",
+                        msg.role.to_string().to_uppercase()
+                    )?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}<|end_of_turn|>", msg.content)?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "GPT4 Correct Assistant:")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "vicuna"
+        || template == "vicuna-orca"
+        || (template.contains("USER: ") && template.contains("ASSISTANT: "))
+    {
+        // eachadea/vicuna-13b-1.1 (and Orca variant)
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    // Orca-Vicuna variant uses a system prefix
+                    if template == "vicuna-orca" || template.contains("SYSTEM: ") {
+                        writeln!(buf, "SYSTEM: {}", msg.content)?;
+                    } else {
+                        write!(buf, "{}\n\n", msg.content)?;
+                    }
+                } else if msg.role == Role::User {
+                    write!(buf, "USER: ")?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    writeln!(buf, "{}", msg.content)?;
+                } else {
+                    writeln!(buf, "ASSISTANT: {}</s>", msg.content)?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "ASSISTANT:")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "command-r"
+        || (template.contains("<|START_OF_TURN_TOKEN|>") && template.contains("<|USER_TOKEN|>"))
+    {
+        // CohereForAI/c4ai-command-r-plus
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    write!(
+                        buf,
+                        "<|START_OF_TURN_TOKEN|><|SYSTEM_TOKEN|>{}<|END_OF_TURN_TOKEN|>",
+                        msg.content.trim(),
+                    )?;
+                } else if msg.role == Role::User {
+                    write!(buf, "<|START_OF_TURN_TOKEN|><|USER_TOKEN|>")?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}<|END_OF_TURN_TOKEN|>", msg.content.trim())?;
+                } else {
+                    write!(
+                        buf,
+                        "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>{}<|END_OF_TURN_TOKEN|>",
+                        msg.content
+                    )?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "llama3"
+        || (template.contains("<|start_header_id|>") && template.contains("<|end_header_id|>"))
+    {
+        // Llama 3
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::User {
+                    write!(buf, "<|start_header_id|>{}<|end_header_id|>\n\n", msg.role)?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}<|eot_id|>", msg.content.trim())?;
+                } else {
+                    write!(
+                        buf,
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        msg.role, msg.content
+                    )?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "<|start_header_id|>assistant<|end_header_id|>\n\n")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "chatglm3" || template.contains("[gMASK]sop") {
+        // chatglm3-6b
+        let mut buf = BufWriter::new(Vec::new());
+        write!(buf, "[gMASK]sop")?;
+        let mut buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
+            if msg.role == Role::User {
+                write!(buf, "<|{}|>\n ", msg.role)?;
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                write!(buf, "{}", msg.content)?;
+            } else {
+                write!(buf, "<|{}|>\n {}", msg.role, msg.content)?;
+            }
+            Ok::<_, Error>(buf)
+        })?;
+        if add_ass {
+            write!(buf, "<|assistant|>")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "chatglm4" || template.contains("[gMASK]<sop>") {
+        // chatglm3-6b
+        let mut buf = BufWriter::new(Vec::new());
+        write!(buf, "[gMASK]<sop>")?;
+        let mut buf = msgs.into_iter().try_fold(buf, |mut buf, msg| {
+            if msg.role == Role::User {
+                write!(buf, "<|{}|>\n ", msg.role)?;
+                if !msg.images.is_empty() {
+                    res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                    msg.images.into_iter().for_each(|im| {
+                        res.push(Templated::Image(im.0));
+                    });
+                    buf = BufWriter::new(Vec::new());
+                }
+                write!(buf, "{}", msg.content)?;
+            } else {
+                write!(buf, "<|{}|>\n {}", msg.role, msg.content)?;
+            }
+            Ok::<_, Error>(buf)
+        })?;
+        if add_ass {
+            write!(buf, "<|assistant|>")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "minicpm" || template.contains("<用户>") {
+        // MiniCPM-3B-OpenHermes-2.5-v2-GGUF
+        let buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::User {
+                    write!(buf, "<用户>")?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}<AI>", msg.content.trim())?;
+                } else {
+                    write!(buf, "{}", msg.content.trim())?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "deepseek2"
+        || template.contains("'Assistant: ' + message['content'] + eos_token")
+    {
+        // DeepSeek-V2
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    write!(buf, "{}\n\n", msg.content)?;
+                } else if msg.role == Role::User {
+                    write!(buf, "User: :")?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    write!(buf, "{}\n\n", msg.content)?;
+                } else {
+                    write!(buf, "Assistant: {}<｜end▁of▁sentence｜>", msg.content)?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "Assistant:")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else if template == "exaone3"
+        || (template.contains("[|system|]")
+            && template.contains("[|assistant|]")
+            && template.contains("[|endofturn|]"))
+    {
+        // ref: https://huggingface.co/LGAI-EXAONE/EXAONE-3.0-7.8B-Instruct/discussions/8#66bae61b1893d14ee8ed85bb
+        // EXAONE-3.0-7.8B-Instruct
+        let mut buf = msgs
+            .into_iter()
+            .try_fold(BufWriter::new(Vec::new()), |mut buf, msg| {
+                if msg.role == Role::System {
+                    writeln!(buf, "[|system|]{}[|endofturn|]", msg.content.trim())?;
+                } else if msg.role == Role::User {
+                    write!(buf, "[|user|]")?;
+                    if !msg.images.is_empty() {
+                        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+                        msg.images.into_iter().for_each(|im| {
+                            res.push(Templated::Image(im.0));
+                        });
+                        buf = BufWriter::new(Vec::new());
+                    }
+                    writeln!(buf, "{}", msg.content.trim())?;
+                } else {
+                    writeln!(buf, "[|assistant|]{}[|endofturn|]", msg.content.trim())?;
+                }
+                Ok::<_, Error>(buf)
+            })?;
+        if add_ass {
+            write!(buf, "[|assistant|]")?;
+        }
+        res.push(Templated::Str(String::from_utf8(buf.into_inner()?)?));
+        Ok(res)
+    } else {
+        render(msgs, "chatml", add_ass)
+    }
+}
+
+/// Renders text only `messages` with `template` into a single string.
+pub fn render_str(
+    messages: Vec<Message>,
+    template: &str,
+    add_generation_prompt: bool,
+) -> Result<String> {
+    render(messages, template, add_generation_prompt)?
+        .into_iter()
+        .map(|t| match t {
+            Templated::Str(s) => Ok(s),
+            Templated::Image(_) => Err(Error::Unsupported("images in a text prompt")),
+        })
+        .collect()
+}
+
+/// The markers of `template` a completion should stop at.
+pub fn stops(template: &str) -> Vec<&'static str> {
+    if template == "chatml" || template.contains("<|im_start|>") {
+        vec!["<|im_start|>", "<|im_end|>"]
+    } else if template == "llama2" || template == "mistral" || template.contains("[INST]") {
+        vec!["[INST]", "[/INST]", "<<SYS>>", "<</SYS>>"]
+    } else if template == "phi3"
+        || (template.contains("<|assistant|>") && template.contains("<|end|>"))
+    {
+        vec!["<|end|>", "<|system|>", "<|user|>", "<|assistant|>"]
+    } else if template == "zephyr" || template.contains("<|user|>") {
+        vec!["<|system|>", "</s>", "<|user|>", "<|assistant|>"]
+    } else if template == "monarch" || template.contains("bos_token + message['role']") {
+        vec![]
+    } else if template == "gemma" || template == "gemma2" || template.contains("<start_of_turn>") {
+        vec!["<start_of_turn>", "<end_of_turn>"]
+    } else if template == "orion" || template.contains("'\\n\\nAssistant: ' + eos_token") {
+        vec![]
+    } else if template == "openchat" || template.contains("GPT4 Correct ") {
+        vec!["<|end_of_turn|>"]
+    } else if template == "vicuna"
+        || template == "vicuna-orca"
+        || (template.contains("USER: ") && template.contains("ASSISTANT: "))
+    {
+        vec!["USER:", "ASSISTANT:"]
+    } else if template == "command-r"
+        || (template.contains("<|START_OF_TURN_TOKEN|>") && template.contains("<|USER_TOKEN|>"))
+    {
+        vec![]
+    } else if template == "llama3"
+        || (template.contains("<|start_header_id|>") && template.contains("<|end_header_id|>"))
+    {
+        vec!["<|start_header_id|>", "<|end_header_id|>", "<|eot_id|>"]
+    } else if template == "chatglm3"
+        || template.contains("[gMASK]sop")
+        || template == "chatglm4"
+        || template.contains("[gMASK]<sop>")
+        || template == "minicpm"
+        || template.contains("<用户>")
+        || template == "deepseek2"
+        || template.contains("'Assistant: ' + message['content'] + eos_token")
+        || template == "exaone3"
+        || (template.contains("[|system|]")
+            && template.contains("[|assistant|]")
+            && template.contains("[|endofturn|]"))
+    {
+        vec![]
+    } else {
+        stops("chatml")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, render_str, stops, Templated};
+    use crate::options::{Image, Message, Role};
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            content: content.to_string(),
+            role,
+            images: vec![],
+        }
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message(Role::System, "You are a helpful assistant."),
+            message(Role::User, "Hello!"),
+            message(Role::Assistant, "Hi, how can I help?"),
+            message(Role::User, "Tell me a joke."),
+        ]
+    }
+
+    /// The fixtures are rendered from the reference tokenizers' templates by
+    /// `tests/fixtures/templates/generate.py`.
+    #[test]
+    fn golden_fixtures_test() {
+        macro_rules! fixture {
+            ($template:literal, $system:literal) => {
+                (
+                    $template,
+                    include_str!(concat!("../tests/fixtures/templates/", $template, ".jinja")),
+                    include_str!(concat!("../tests/fixtures/templates/", $template, ".txt")),
+                    $system,
+                )
+            };
+        }
+        let fixtures = [
+            fixture!("chatml", true),
+            fixture!("llama2", true),
+            fixture!("llama3", true),
+            fixture!("gemma", false),
+            fixture!("phi3", true),
+            fixture!("mistral", false),
+        ];
+        for (name, template, expected, system) in fixtures {
+            let mut messages = conversation();
+            if !system {
+                messages.remove(0);
+            }
+            let rendered = render_str(messages, template, true).unwrap();
+            assert_eq!(rendered, expected, "template {name}");
+        }
+    }
+
+    #[test]
+    fn jinja_detection_test() {
+        let llama3 = "{% for message in messages %}<|start_header_id|>{{ message['role'] }}\
+                      <|end_header_id|>\n\n{{ message['content'] }}<|eot_id|>{% endfor %}";
+        assert_eq!(
+            render_str(conversation(), llama3, true).unwrap(),
+            render_str(conversation(), "llama3", true).unwrap()
+        );
+        assert_eq!(stops(llama3), stops("llama3"));
+        // unknown templates are rendered as chatml
+        assert_eq!(
+            render_str(conversation(), "unknown", false).unwrap(),
+            render_str(conversation(), "chatml", false).unwrap()
+        );
+        assert_eq!(stops("unknown"), vec!["<|im_start|>", "<|im_end|>"]);
+    }
+
+    #[test]
+    fn images_test() {
+        let mut msg = message(Role::User, "What is this?");
+        msg.images = vec![Image(vec![1, 2, 3])];
+        let rendered = render(vec![msg.clone()], "chatml", true).unwrap();
+        assert_eq!(
+            rendered,
+            vec![
+                Templated::Str("<|im_start|>user\n".to_string()),
+                Templated::Image(vec![1, 2, 3]),
+                Templated::Str("What is this?<|im_end|>\n<|im_start|>assistant\n".to_string()),
+            ]
+        );
+        assert!(render_str(vec![msg], "chatml", true).is_err());
+    }
+}
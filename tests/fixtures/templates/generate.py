@@ -0,0 +1,55 @@
+"""Regenerates the golden chat template fixtures.
+
+Every `<name>.jinja` is the `chat_template` of a reference tokenizer, rendered the way
+transformers' `apply_chat_template` does with `add_generation_prompt=True`. The leading BOS is
+left out, llama.cpp adds it when the prompt is tokenized. Templates that reject a system
+message are rendered without it.
+
+    python3 tests/fixtures/templates/generate.py
+"""
+
+from pathlib import Path
+
+from jinja2.exceptions import TemplateError
+from jinja2.ext import loopcontrols
+from jinja2.sandbox import ImmutableSandboxedEnvironment
+
+# name: (reference tokenizer, bos_token, eos_token, accepts a system message)
+REFERENCES = {
+    "chatml": ("teknium/OpenHermes-2.5-Mistral-7B", "<s>", "<|im_end|>", True),
+    "llama2": ("meta-llama/Llama-2-7b-chat-hf", "<s>", "</s>", True),
+    "llama3": ("meta-llama/Meta-Llama-3-8B-Instruct", "<|begin_of_text|>", "<|eot_id|>", True),
+    "gemma": ("google/gemma-7b-it", "<bos>", "<eos>", False),
+    "phi3": ("microsoft/Phi-3.5-mini-instruct", "<s>", "<|endoftext|>", True),
+    "mistral": ("mistralai/Mistral-7B-Instruct-v0.1", "<s>", "</s>", False),
+}
+
+# keep in sync with `conversation` in src/template.rs
+CONVERSATION = [
+    {"role": "system", "content": "You are a helpful assistant."},
+    {"role": "user", "content": "Hello!"},
+    {"role": "assistant", "content": "Hi, how can I help?"},
+    {"role": "user", "content": "Tell me a joke."},
+]
+
+
+def raise_exception(message):
+    raise TemplateError(message)
+
+
+def main():
+    directory = Path(__file__).parent
+    env = ImmutableSandboxedEnvironment(trim_blocks=True, lstrip_blocks=True, extensions=[loopcontrols])
+    env.globals["raise_exception"] = raise_exception
+    for name, (_, bos, eos, system) in REFERENCES.items():
+        template = env.from_string((directory / f"{name}.jinja").read_text())
+        messages = CONVERSATION if system else CONVERSATION[1:]
+        rendered = template.render(
+            messages=messages, bos_token=bos, eos_token=eos, add_generation_prompt=True
+        )
+        rendered = rendered.removeprefix(bos)
+        (directory / f"{name}.txt").write_text(rendered)
+
+
+if __name__ == "__main__":
+    main()